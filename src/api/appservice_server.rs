@@ -1,7 +1,17 @@
 use crate::{services, utils, Error, Result};
 use bytes::BytesMut;
-use ruma::api::{IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken};
-use std::{fmt::Debug, mem, time::Duration};
+use ruma::{
+    api::{
+        appservice::ping::send_ping, IncomingResponse, MatrixVersion, OutgoingRequest,
+        SendAccessToken,
+    },
+    OwnedTransactionId,
+};
+use std::{
+    fmt::Debug,
+    mem,
+    time::{Duration, Instant},
+};
 use tracing::warn;
 
 #[tracing::instrument(skip(request))]
@@ -9,6 +19,35 @@ pub(crate) async fn send_request<T: OutgoingRequest>(
     registration: serde_yaml::Value,
     request: T,
 ) -> Result<T::IncomingResponse>
+where
+    T: Debug,
+{
+    send_request_with_client(services().globals.default_client(), registration, request).await
+}
+
+/// Sends a ping to the appservice and returns how long it took to respond
+#[tracing::instrument(skip(client))]
+pub(crate) async fn ping(
+    client: reqwest::Client,
+    registration: serde_yaml::Value,
+    transaction_id: Option<OwnedTransactionId>,
+) -> Result<Duration> {
+    let start = Instant::now();
+    send_request_with_client(
+        client,
+        registration,
+        send_ping::v1::Request { transaction_id },
+    )
+    .await?;
+
+    Ok(start.elapsed())
+}
+
+async fn send_request_with_client<T: OutgoingRequest>(
+    client: reqwest::Client,
+    registration: serde_yaml::Value,
+    request: T,
+) -> Result<T::IncomingResponse>
 where
     T: Debug,
 {
@@ -45,12 +84,7 @@ where
     *reqwest_request.timeout_mut() = Some(Duration::from_secs(30));
 
     let url = reqwest_request.url().clone();
-    let mut response = match services()
-        .globals
-        .default_client()
-        .execute(reqwest_request)
-        .await
-    {
+    let mut response = match client.execute(reqwest_request).await {
         Ok(r) => r,
         Err(e) => {
             warn!(
@@ -103,3 +137,86 @@ where
         Error::BadServerResponse("Server returned bad response.")
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use axum::{
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Json, Router,
+    };
+    use serde_json::{json, Value};
+
+    use super::ping;
+
+    /// Starts an appservice that answers pings authenticated with `hs_token` and returns its URL
+    fn mock_appservice(hs_token: &'static str) -> String {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let app = Router::new().route(
+            "/_matrix/app/v1/ping",
+            post(
+                move |headers: HeaderMap, Json(body): Json<Value>| async move {
+                    let authorized = headers
+                        .get("authorization")
+                        .and_then(|value| value.to_str().ok())
+                        == Some(format!("Bearer {hs_token}").as_str());
+
+                    if authorized && body["transaction_id"] == "txn" {
+                        (StatusCode::OK, Json(json!({})))
+                    } else {
+                        (
+                            StatusCode::FORBIDDEN,
+                            Json(json!({ "errcode": "M_FORBIDDEN", "error": "Bad token" })),
+                        )
+                    }
+                },
+            ),
+        );
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        url
+    }
+
+    fn registration(url: &str, hs_token: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(&format!(
+            r#"
+id: bridge
+url: "{url}"
+as_token: as_secret
+hs_token: {hs_token}
+sender_localpart: bridgebot
+namespaces: {{}}
+"#
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn ping_mock_appservice() {
+        let url = mock_appservice("hs_secret");
+        let client = reqwest::Client::new();
+
+        let duration = ping(
+            client.clone(),
+            registration(&url, "hs_secret"),
+            Some("txn".into()),
+        )
+        .await
+        .unwrap();
+        assert!(duration.as_secs() < 30);
+
+        assert!(
+            ping(client, registration(&url, "wrong"), Some("txn".into()))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,17 +1,27 @@
 use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
 use crate::{api::client_server, services, utils, Error, Result, Ruma};
+use axum::{body::Bytes, response::IntoResponse, Json};
+use hmac::{Hmac, Mac};
+use http::Uri;
 use ruma::{
     api::client::{
         account::{
-            change_password, deactivate, get_3pids, get_username_availability, register,
-            request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn,
-            whoami, ThirdPartyIdRemovalStatus,
+            add_3pid, change_password, deactivate, delete_3pid, get_3pids,
+            get_username_availability, register, request_3pid_management_token_via_email,
+            request_3pid_management_token_via_msisdn, whoami, ThirdPartyIdRemovalStatus,
         },
         error::ErrorKind,
         uiaa::{AuthFlow, AuthType, UiaaInfo},
     },
-    events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
-    push, UserId,
+    events::room::message::RoomMessageEventContent,
+    OwnedDeviceId, OwnedSessionId, UserId,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha1::Sha1;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
 };
 use tracing::{info, warn};
 
@@ -19,6 +29,13 @@ use register::RegistrationKind;
 
 const RANDOM_USER_ID_LENGTH: usize = 10;
 
+/// How long a nonce for shared secret registration can be used
+const REGISTRATION_NONCE_LIFETIME: Duration = Duration::from_secs(60);
+/// How many nonces for shared secret registration can be open at once
+const MAX_REGISTRATION_NONCES: usize = 1000;
+
+type HmacSha1 = Hmac<Sha1>;
+
 /// # `GET /_matrix/client/r0/register/available`
 ///
 /// Checks if a username is valid and available on this server.
@@ -170,33 +187,7 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         body.password.as_deref()
     };
 
-    // Create user
-    services().users.create(&user_id, password)?;
-
-    // Default to pretty displayname
-    let mut displayname = user_id.localpart().to_owned();
-
-    // If enabled append lightning bolt to display name (default true)
-    if services().globals.enable_lightning_bolt() {
-        displayname.push_str(" ⚡️");
-    }
-
-    services()
-        .users
-        .set_displayname(&user_id, Some(displayname.clone()))?;
-
-    // Initial account data
-    services().account_data.update(
-        None,
-        &user_id,
-        GlobalAccountDataEventType::PushRules.to_string().into(),
-        &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
-            content: ruma::events::push_rules::PushRulesEventContent {
-                global: push::Ruleset::server_default(&user_id),
-            },
-        })
-        .expect("to json always works"),
-    )?;
+    create_account(&user_id, password, None, !body.from_appservice && !is_guest).await?;
 
     // Inhibit login does not work for guests
     if !is_guest && body.inhibit_login {
@@ -228,8 +219,38 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         body.initial_device_display_name.clone(),
     )?;
 
+    Ok(register::v3::Response {
+        access_token: Some(token),
+        user_id,
+        device_id: Some(device_id),
+        refresh_token: None,
+        expires_in: None,
+    })
+}
+
+/// Creates a local account with the default displayname, or `displayname`, and push rules. Admins
+/// are told about the new user if `notify_admins` is set, and the first user becomes an admin.
+pub(crate) async fn create_account(
+    user_id: &UserId,
+    password: Option<&str>,
+    displayname: Option<&str>,
+    notify_admins: bool,
+) -> Result<()> {
+    services().users.create(user_id, password)?;
+    crate::service::admin::initialize_user(user_id)?;
+
+    if let Some(displayname) = displayname {
+        let mut displayname = displayname.to_owned();
+        if services().globals.enable_lightning_bolt() {
+            displayname.push_str(" ⚡️");
+        }
+        services()
+            .users
+            .set_displayname(user_id, Some(displayname))?;
+    }
+
     info!("New user {} registered on this server.", user_id);
-    if !body.from_appservice && !is_guest {
+    if notify_admins {
         services()
             .admin
             .send_message(RoomMessageEventContent::notice_plain(format!(
@@ -240,21 +261,214 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
     // If this is the first real user, grant them admin privileges
     // Note: the server user, @conduit:servername, is generated first
     if services().users.count()? == 2 {
+        let displayname = services()
+            .users
+            .displayname(user_id)?
+            .unwrap_or_else(|| user_id.localpart().to_owned());
         services()
             .admin
-            .make_user_admin(&user_id, displayname)
+            .make_user_admin(user_id, displayname)
             .await?;
 
         warn!("Granting {} admin privileges as the first user", user_id);
     }
 
-    Ok(register::v3::Response {
-        access_token: Some(token),
-        user_id,
-        device_id: Some(device_id),
-        refresh_token: None,
-        expires_in: None,
+    Ok(())
+}
+
+/// # `GET /_synapse/admin/v1/register`
+///
+/// Hands out a nonce for [`shared_secret_register_route`]. Nonces can be used once, within a
+/// minute.
+pub async fn shared_secret_register_nonce_route() -> Result<impl IntoResponse> {
+    if services().globals.registration_shared_secret().is_none() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Shared secret registration is not enabled.",
+        ));
+    }
+
+    let nonce = utils::random_string(SESSION_ID_LENGTH);
+
+    insert_registration_nonce(
+        &mut services().globals.registration_nonces.lock().unwrap(),
+        nonce.clone(),
+        Instant::now(),
+    );
+
+    Ok(Json(json!({ "nonce": nonce })))
+}
+
+/// Adds a nonce after removing expired ones. If there are still too many open nonces, the oldest
+/// one is dropped, so unauthenticated requests can't grow the map without bounds.
+fn insert_registration_nonce(nonces: &mut HashMap<String, Instant>, nonce: String, now: Instant) {
+    nonces.retain(|_, created| now.duration_since(*created) < REGISTRATION_NONCE_LIFETIME);
+
+    if nonces.len() >= MAX_REGISTRATION_NONCES {
+        if let Some(oldest) = nonces
+            .iter()
+            .min_by_key(|(_, created)| **created)
+            .map(|(nonce, _)| nonce.clone())
+        {
+            nonces.remove(&oldest);
+        }
+    }
+
+    nonces.insert(nonce, now);
+}
+
+/// # `POST /_synapse/admin/v1/register`
+///
+/// Registers a user without UIAA, for provisioning scripts that know the
+/// `registration_shared_secret`. Compatible with Synapse's `register_new_matrix_user`.
+///
+/// - The `mac` is the hex encoded HMAC-SHA1 of `nonce`, `username`, `password` and `admin` or
+/// `notadmin`, separated by NUL bytes
+/// - Works even if registration is disabled
+/// - Makes the user an admin if `admin` is true
+pub async fn shared_secret_register_route(body: Bytes) -> Result<impl IntoResponse> {
+    #[derive(Deserialize)]
+    struct Request {
+        nonce: String,
+        username: String,
+        password: String,
+        displayname: Option<String>,
+        #[serde(default)]
+        admin: bool,
+        mac: String,
+    }
+
+    let Some(secret) = services().globals.registration_shared_secret() else {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Shared secret registration is not enabled.",
+        ));
+    };
+
+    let body: Request = serde_json::from_slice(&body)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid registration request."))?;
+
+    // Nonces can't be reused, even if the mac was wrong
+    let nonce_created = services()
+        .globals
+        .registration_nonces
+        .lock()
+        .unwrap()
+        .remove(&body.nonce);
+    if !nonce_created.is_some_and(|created| created.elapsed() < REGISTRATION_NONCE_LIFETIME) {
+        return Err(Error::BadRequest(
+            ErrorKind::Unauthorized,
+            "Unknown or expired nonce.",
+        ));
+    }
+
+    if !registration_mac_is_valid(
+        secret,
+        &body.nonce,
+        &body.username,
+        &body.password,
+        body.admin,
+        &body.mac,
+    ) {
+        return Err(Error::BadRequest(ErrorKind::Forbidden, "Invalid mac."));
+    }
+
+    let user_id = UserId::parse_with_server_name(
+        body.username.to_lowercase(),
+        services().globals.server_name(),
+    )
+    .ok()
+    .filter(|user_id| {
+        !user_id.is_historical() && user_id.server_name() == services().globals.server_name()
     })
+    .ok_or(Error::BadRequest(
+        ErrorKind::InvalidUsername,
+        "Username is invalid.",
+    ))?;
+
+    if services().users.exists(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserInUse,
+            "Desired user ID is already taken.",
+        ));
+    }
+
+    create_account(
+        &user_id,
+        Some(&body.password),
+        body.displayname.as_deref(),
+        true,
+    )
+    .await?;
+
+    let device_id: OwnedDeviceId = utils::random_string(DEVICE_ID_LENGTH).into();
+    let token = utils::random_string(TOKEN_LENGTH);
+    services()
+        .users
+        .create_device(&user_id, &device_id, &token, None)?;
+
+    info!(
+        "User {} registered using the registration shared secret.",
+        user_id
+    );
+
+    // The first user is an admin already
+    if body.admin && !services().users.is_admin(&user_id)? {
+        let displayname = services()
+            .users
+            .displayname(&user_id)?
+            .unwrap_or_else(|| user_id.localpart().to_owned());
+        services()
+            .admin
+            .make_user_admin(&user_id, displayname)
+            .await?;
+
+        warn!("Granting {} admin privileges as requested", user_id);
+    }
+
+    Ok(Json(json!({
+        "user_id": user_id,
+        "access_token": token,
+        "device_id": device_id,
+        "home_server": services().globals.server_name(),
+    })))
+}
+
+/// Checks the `mac` of a shared secret registration request in constant time.
+fn registration_mac_is_valid(
+    secret: &str,
+    nonce: &str,
+    username: &str,
+    password: &str,
+    admin: bool,
+    mac: &str,
+) -> bool {
+    let Some(mac) = decode_hex(mac) else {
+        return false;
+    };
+
+    let mut expected =
+        HmacSha1::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    expected.update(nonce.as_bytes());
+    expected.update(b"\0");
+    expected.update(username.as_bytes());
+    expected.update(b"\0");
+    expected.update(password.as_bytes());
+    expected.update(b"\0");
+    expected.update(if admin { &b"admin"[..] } else { b"notadmin" });
+
+    expected.verify_slice(&mac).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
 }
 
 /// # `POST /_matrix/client/r0/account/password`
@@ -414,30 +628,126 @@ pub async fn deactivate_route(
 /// # `GET _matrix/client/v3/account/3pid`
 ///
 /// Get a list of third party identifiers associated with this account.
-///
-/// - Currently always returns empty list
 pub async fn third_party_route(
     body: Ruma<get_3pids::v3::Request>,
 ) -> Result<get_3pids::v3::Response> {
-    let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    Ok(get_3pids::v3::Response::new(Vec::new()))
+    Ok(get_3pids::v3::Response::new(
+        services()
+            .threepid
+            .threepids(sender_user)
+            .collect::<Result<_>>()?,
+    ))
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/email/requestToken`
 ///
 /// "This API should be used to request validation tokens when adding an email address to an account"
 ///
+/// - Sends an email with a verification link to the address, if SMTP is configured
 /// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
 pub async fn request_3pid_management_token_via_email_route(
-    _body: Ruma<request_3pid_management_token_via_email::v3::Request>,
+    body: Ruma<request_3pid_management_token_via_email::v3::Request>,
 ) -> Result<request_3pid_management_token_via_email::v3::Response> {
-    Err(Error::BadRequest(
-        ErrorKind::ThreepidDenied,
-        "Third party identifier is not allowed",
+    let sid = services()
+        .threepid
+        .request_email_token(&body.client_secret, &body.email, body.send_attempt)
+        .await?;
+
+    Ok(request_3pid_management_token_via_email::v3::Response::new(
+        sid,
     ))
 }
 
+/// # `GET /_conduit/client/3pid/email/validate`
+///
+/// The link in verification emails points here. Marks the email address as verified, so the
+/// client can add it using [`add_3pid_route`].
+pub async fn validate_3pid_email_route(uri: Uri) -> Result<impl IntoResponse> {
+    #[derive(Deserialize)]
+    struct QueryParams {
+        sid: OwnedSessionId,
+        client_secret: String,
+        token: String,
+    }
+
+    let query_params: QueryParams = serde_html_form::from_str(uri.query().unwrap_or_default())
+        .map_err(|_| Error::BadRequest(ErrorKind::MissingParam, "Invalid verification link."))?;
+
+    services().threepid.validate_email(
+        &query_params.sid,
+        &query_params.client_secret,
+        &query_params.token,
+    )?;
+
+    Ok("Your email address has been verified. You can return to your Matrix client now.")
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/add`
+///
+/// Adds a verified email address to this account.
+///
+/// - Requires UIAA to verify user password
+pub async fn add_3pid_route(body: Ruma<add_3pid::v3::Request>) -> Result<add_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::Password],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+
+    if let Some(auth) = &body.auth {
+        let (worked, uiaainfo) =
+            services()
+                .uiaa
+                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        if !worked {
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    // Success!
+    } else if let Some(json) = body.json_body {
+        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        services()
+            .uiaa
+            .create(sender_user, sender_device, &uiaainfo, &json)?;
+        return Err(Error::Uiaa(uiaainfo));
+    } else {
+        return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+    }
+
+    services()
+        .threepid
+        .add_threepid(sender_user, &body.sid, &body.client_secret)?;
+
+    Ok(add_3pid::v3::Response {})
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/delete`
+///
+/// Removes a third party identifier from this account.
+///
+/// - We don't bind identifiers on identity servers, so there is nothing to unbind there
+pub async fn delete_3pid_route(
+    body: Ruma<delete_3pid::v3::Request>,
+) -> Result<delete_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    services()
+        .threepid
+        .remove_threepid(sender_user, &body.medium, &body.address)?;
+
+    Ok(delete_3pid::v3::Response {
+        id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
+    })
+}
+
 /// # `POST /_matrix/client/v3/account/3pid/msisdn/requestToken`
 ///
 /// "This API should be used to request validation tokens when adding an phone number to an account"
@@ -451,3 +761,90 @@ pub async fn request_3pid_management_token_via_msisdn_route(
         "Third party identifier is not allowed",
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_secret_registration_mac() {
+        // Computed with Synapse's register_new_matrix_user
+        let mac = "4edcf2b3925a039bf8cc610bd2664121cccbe5da";
+
+        assert!(registration_mac_is_valid(
+            "hunter2",
+            "abc",
+            "alice",
+            "wonderland",
+            true,
+            mac
+        ));
+        assert!(registration_mac_is_valid(
+            "hunter2",
+            "abc",
+            "alice",
+            "wonderland",
+            true,
+            &mac.to_uppercase()
+        ));
+
+        // Wrong secret, or the request was changed
+        assert!(!registration_mac_is_valid(
+            "hunter3",
+            "abc",
+            "alice",
+            "wonderland",
+            true,
+            mac
+        ));
+        assert!(!registration_mac_is_valid(
+            "hunter2",
+            "abc",
+            "alice",
+            "wonderland",
+            false,
+            mac
+        ));
+        assert!(!registration_mac_is_valid(
+            "hunter2",
+            "abc",
+            "mallory",
+            "wonderland",
+            true,
+            mac
+        ));
+        assert!(!registration_mac_is_valid(
+            "hunter2",
+            "abc",
+            "alice",
+            "wonderland",
+            true,
+            "not hex"
+        ));
+    }
+
+    #[test]
+    fn registration_nonces_are_capped() {
+        let mut nonces = HashMap::new();
+        let start = Instant::now();
+
+        for i in 0..MAX_REGISTRATION_NONCES + 10 {
+            insert_registration_nonce(
+                &mut nonces,
+                i.to_string(),
+                start + Duration::from_millis(i as u64),
+            );
+        }
+        assert_eq!(nonces.len(), MAX_REGISTRATION_NONCES);
+        // The oldest nonces were dropped first
+        assert!(!nonces.contains_key("9"));
+        assert!(nonces.contains_key("10"));
+
+        insert_registration_nonce(
+            &mut nonces,
+            "late".to_owned(),
+            start + Duration::from_secs(120),
+        );
+        assert_eq!(nonces.len(), 1);
+    }
+}
@@ -0,0 +1,35 @@
+use crate::{api::appservice_server, services, Error, Result, Ruma};
+use ruma::api::client::{appservice::request_ping, error::ErrorKind};
+
+/// # `POST /_matrix/client/v1/appservice/{appserviceId}/ping`
+///
+/// Asks an appservice to respond to a ping and returns how long the round trip took.
+///
+/// - Only the appservice itself (authenticated with its as_token) may ping it
+pub async fn appservice_ping_route(
+    body: Ruma<request_ping::v1::Request>,
+) -> Result<request_ping::v1::Response> {
+    if body.appservice_id.as_deref() != Some(body.body.appservice_id.as_str()) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Appservices can only ping themselves.",
+        ));
+    }
+
+    let registration = services()
+        .appservice
+        .get_registration(&body.body.appservice_id)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Appservice not found.",
+        ))?;
+
+    let duration = appservice_server::ping(
+        services().globals.default_client(),
+        registration,
+        body.body.transaction_id.clone(),
+    )
+    .await?;
+
+    Ok(request_ping::v1::Response::new(duration))
+}
@@ -2,6 +2,7 @@ use crate::{services, Result, Ruma};
 use ruma::api::client::discovery::get_capabilities::{
     self, Capabilities, RoomVersionStability, RoomVersionsCapability,
 };
+use serde_json::json;
 use std::collections::BTreeMap;
 
 /// # `GET /_matrix/client/r0/capabilities`
@@ -23,6 +24,34 @@ pub async fn get_capabilities_route(
         default: services().globals.default_room_version(),
         available,
     };
+    set_motd(&mut capabilities, services().globals.motd());
 
     Ok(get_capabilities::v3::Response { capabilities })
 }
+
+/// Adds the `org.conduit.motd` capability if there is a message of the day.
+fn set_motd(capabilities: &mut Capabilities, motd: Option<String>) {
+    if let Some(motd) = motd {
+        capabilities
+            .set("org.conduit.motd", json!({ "motd": motd }))
+            .expect("motd is a custom capability");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn motd_capability() {
+        let mut capabilities = Capabilities::new();
+        set_motd(&mut capabilities, None);
+        assert!(capabilities.get("org.conduit.motd").is_none());
+
+        set_motd(&mut capabilities, Some("Maintenance on Sunday".to_owned()));
+        assert_eq!(
+            capabilities.get("org.conduit.motd").unwrap().into_owned(),
+            json!({ "motd": "Maintenance on Sunday" })
+        );
+    }
+}
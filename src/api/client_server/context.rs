@@ -69,8 +69,8 @@ pub async fn get_context_route(
         lazy_loaded.insert(base_event.sender.as_str().to_owned());
     }
 
-    // Use limit with maximum 100
-    let limit = u64::from(body.limit).min(100) as usize;
+    let (limit_before, limit_after) =
+        context_limits(services().globals.fetch_limit(Some(body.limit.into()), 10));
 
     let base_event = base_event.to_room_event();
 
@@ -78,7 +78,6 @@ pub async fn get_context_route(
         .rooms
         .timeline
         .pdus_until(sender_user, &room_id, base_token)?
-        .take(limit / 2)
         .filter_map(|r| r.ok()) // Remove buggy events
         .filter(|(_, pdu)| {
             services()
@@ -87,6 +86,7 @@ pub async fn get_context_route(
                 .user_can_see_event(sender_user, &room_id, &pdu.event_id)
                 .unwrap_or(false)
         })
+        .take(limit_before)
         .collect();
 
     for (_, event) in &events_before {
@@ -115,7 +115,6 @@ pub async fn get_context_route(
         .rooms
         .timeline
         .pdus_after(sender_user, &room_id, base_token)?
-        .take(limit / 2)
         .filter_map(|r| r.ok()) // Remove buggy events
         .filter(|(_, pdu)| {
             services()
@@ -124,6 +123,7 @@ pub async fn get_context_route(
                 .user_can_see_event(sender_user, &room_id, &pdu.event_id)
                 .unwrap_or(false)
         })
+        .take(limit_after)
         .collect();
 
     for (_, event) in &events_after {
@@ -207,3 +207,24 @@ pub async fn get_context_route(
 
     Ok(resp)
 }
+
+/// Splits the number of context events between the events before and after the base event. The
+/// extra event of an odd limit goes after the base event.
+fn context_limits(limit: usize) -> (usize, usize) {
+    let before = limit / 2;
+
+    (before, limit - before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::context_limits;
+
+    #[test]
+    fn symmetric_context() {
+        assert_eq!(context_limits(10), (5, 5));
+        assert_eq!(context_limits(0), (0, 0));
+        assert_eq!(context_limits(1), (0, 1));
+        assert_eq!(context_limits(7), (3, 4));
+    }
+}
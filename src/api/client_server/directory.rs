@@ -22,10 +22,11 @@ use ruma::{
             join_rules::{JoinRule, RoomJoinRulesEventContent},
             topic::RoomTopicEventContent,
         },
-        StateEventType,
+        StateEventType, TimelineEventType,
     },
-    ServerName, UInt,
+    RoomId, ServerName, UInt, UserId,
 };
+use std::cmp::Reverse;
 use tracing::{error, info, warn};
 
 /// # `POST /_matrix/client/r0/publicRooms`
@@ -75,7 +76,8 @@ pub async fn get_public_rooms_route(
 ///
 /// Sets the visibility of a given room in the room directory.
 ///
-/// - TODO: Access control checks
+/// - Only server admins and users who may change the canonical alias of the room can change its
+///   visibility
 pub async fn set_room_visibility_route(
     body: Ruma<set_room_visibility::v3::Request>,
 ) -> Result<set_room_visibility::v3::Response> {
@@ -86,12 +88,37 @@ pub async fn set_room_visibility_route(
         return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found"));
     }
 
-    match &body.visibility {
+    set_visibility(sender_user, &body.room_id, &body.visibility)?;
+
+    Ok(set_room_visibility::v3::Response {})
+}
+
+fn set_visibility(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    visibility: &room::Visibility,
+) -> Result<()> {
+    let allowed = services().users.is_admin(sender_user)?
+        || services().rooms.state_accessor.user_can_send(
+            sender_user,
+            room_id,
+            &TimelineEventType::RoomCanonicalAlias,
+            Some(""),
+        )?;
+
+    if !allowed {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are not allowed to change the visibility of this room.",
+        ));
+    }
+
+    match visibility {
         room::Visibility::Public => {
-            services().rooms.directory.set_public(&body.room_id)?;
-            info!("{} made {} public", sender_user, body.room_id);
+            services().rooms.directory.set_public(room_id)?;
+            info!("{} made {} public", sender_user, room_id);
         }
-        room::Visibility::Private => services().rooms.directory.set_not_public(&body.room_id)?,
+        room::Visibility::Private => services().rooms.directory.set_not_public(room_id)?,
         _ => {
             return Err(Error::BadRequest(
                 ErrorKind::InvalidParam,
@@ -100,7 +127,7 @@ pub async fn set_room_visibility_route(
         }
     }
 
-    Ok(set_room_visibility::v3::Response {})
+    Ok(())
 }
 
 /// # `GET /_matrix/client/r0/directory/list/room/{roomId}`
@@ -158,32 +185,9 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     }
 
     let limit = limit.map_or(10, u64::from);
-    let mut num_since = 0_u64;
-
-    if let Some(s) = &since {
-        let mut characters = s.chars();
-        let backwards = match characters.next() {
-            Some('n') => false,
-            Some('p') => true,
-            _ => {
-                return Err(Error::BadRequest(
-                    ErrorKind::InvalidParam,
-                    "Invalid `since` token",
-                ))
-            }
-        };
-
-        num_since = characters
-            .collect::<String>()
-            .parse()
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `since` token."))?;
-
-        if backwards {
-            num_since = num_since.saturating_sub(limit);
-        }
-    }
+    let since = since.map(DirectoryToken::parse).transpose()?;
 
-    let mut all_rooms: Vec<_> = services()
+    let all_rooms: Vec<_> = services()
         .rooms
         .directory
         .public_rooms()
@@ -338,27 +342,13 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         // We need to collect all, so we can sort by member count
         .collect();
 
-    all_rooms.sort_by(|l, r| r.num_joined_members.cmp(&l.num_joined_members));
-
     let total_room_count_estimate = (all_rooms.len() as u32).into();
 
-    let chunk: Vec<_> = all_rooms
-        .into_iter()
-        .skip(num_since as usize)
-        .take(limit as usize)
-        .collect();
-
-    let prev_batch = if num_since == 0 {
-        None
-    } else {
-        Some(format!("p{num_since}"))
-    };
-
-    let next_batch = if chunk.len() < limit as usize {
-        None
-    } else {
-        Some(format!("n{}", num_since + limit))
-    };
+    let (chunk, prev_batch, next_batch) = page_public_rooms(
+        all_rooms,
+        since.as_ref(),
+        limit.try_into().unwrap_or(usize::MAX),
+    );
 
     Ok(get_public_rooms_filtered::v3::Response {
         chunk,
@@ -367,3 +357,164 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         total_room_count_estimate: Some(total_room_count_estimate),
     })
 }
+
+/// A `since` token of the room directory. Pages continue before or after the room the token was
+/// made from, so rooms that are published in the meantime don't shift the pages.
+#[derive(Debug, PartialEq)]
+struct DirectoryToken {
+    backwards: bool,
+    num_joined_members: UInt,
+    room_id: String,
+}
+
+impl DirectoryToken {
+    fn new(backwards: bool, chunk: &PublicRoomsChunk) -> Self {
+        Self {
+            backwards,
+            num_joined_members: chunk.num_joined_members,
+            room_id: chunk.room_id.to_string(),
+        }
+    }
+
+    fn parse(token: &str) -> Result<Self> {
+        let invalid = || Error::BadRequest(ErrorKind::InvalidParam, "Invalid `since` token.");
+
+        let (backwards, rest) = if let Some(rest) = token.strip_prefix('n') {
+            (false, rest)
+        } else if let Some(rest) = token.strip_prefix('p') {
+            (true, rest)
+        } else {
+            return Err(invalid());
+        };
+        let (num_joined_members, room_id) = rest.split_once('_').ok_or_else(invalid)?;
+
+        Ok(Self {
+            backwards,
+            num_joined_members: num_joined_members.parse().map_err(|_| invalid())?,
+            room_id: room_id.to_owned(),
+        })
+    }
+
+    fn stringify(&self) -> String {
+        let direction = if self.backwards { 'p' } else { 'n' };
+        format!("{direction}{}_{}", self.num_joined_members, self.room_id)
+    }
+
+    fn sort_key(&self) -> (Reverse<UInt>, &str) {
+        (Reverse(self.num_joined_members), &self.room_id)
+    }
+}
+
+/// The order of the room directory: rooms with more members first, ties are broken by room id.
+fn directory_sort_key(chunk: &PublicRoomsChunk) -> (Reverse<UInt>, &str) {
+    (Reverse(chunk.num_joined_members), chunk.room_id.as_str())
+}
+
+/// Sorts the rooms and returns the page after or before `since`, with the tokens of the previous
+/// and next page.
+fn page_public_rooms(
+    mut rooms: Vec<PublicRoomsChunk>,
+    since: Option<&DirectoryToken>,
+    limit: usize,
+) -> (Vec<PublicRoomsChunk>, Option<String>, Option<String>) {
+    rooms.sort_by(|l, r| directory_sort_key(l).cmp(&directory_sort_key(r)));
+
+    let (start, end) = match since {
+        None => (0, limit.min(rooms.len())),
+        Some(since) if since.backwards => {
+            let end = rooms.partition_point(|chunk| directory_sort_key(chunk) < since.sort_key());
+            (end.saturating_sub(limit), end)
+        }
+        Some(since) => {
+            let start =
+                rooms.partition_point(|chunk| directory_sort_key(chunk) <= since.sort_key());
+            (start, start.saturating_add(limit).min(rooms.len()))
+        }
+    };
+
+    let has_more_before = start > 0;
+    let has_more_after = end < rooms.len();
+    let chunk: Vec<_> = rooms.drain(start..end).collect();
+
+    let prev_batch = chunk
+        .first()
+        .filter(|_| has_more_before)
+        .map(|chunk| DirectoryToken::new(true, chunk).stringify());
+    let next_batch = chunk
+        .last()
+        .filter(|_| has_more_after)
+        .map(|chunk| DirectoryToken::new(false, chunk).stringify());
+
+    (chunk, prev_batch, next_batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    #[tokio::test]
+    async fn visibility_requires_power() {
+        let services = testing::services();
+        let directory = &services.rooms.directory;
+        let creator = &testing::create_user();
+        let user = &testing::create_user();
+        let room_id = &testing::create_room(creator).await;
+
+        set_visibility(creator, room_id, &room::Visibility::Public).unwrap();
+        assert!(directory.is_public_room(room_id).unwrap());
+
+        // Users without the power to change the canonical alias can't unpublish the room
+        assert!(set_visibility(user, room_id, &room::Visibility::Private).is_err());
+        assert!(directory.is_public_room(room_id).unwrap());
+
+        set_visibility(creator, room_id, &room::Visibility::Private).unwrap();
+        assert!(!directory.is_public_room(room_id).unwrap());
+    }
+
+    #[test]
+    fn public_rooms_are_sorted_before_paging() {
+        let room = |id: &str, members: u32| {
+            let mut chunk = PublicRoomsChunk::new(RoomId::parse(id).unwrap());
+            chunk.num_joined_members = members.into();
+            chunk
+        };
+        let ids = |chunk: &[PublicRoomsChunk]| -> Vec<_> {
+            chunk
+                .iter()
+                .map(|chunk| chunk.room_id.to_string())
+                .collect()
+        };
+        let mut rooms = vec![
+            room("!c:example.org", 5),
+            room("!a:example.org", 1),
+            room("!e:example.org", 20),
+            room("!b:example.org", 5),
+            room("!d:example.org", 1),
+        ];
+
+        let (chunk, prev, next) = page_public_rooms(rooms.clone(), None, 2);
+        assert_eq!(ids(&chunk), ["!e:example.org", "!b:example.org"]);
+        assert_eq!(prev, None);
+
+        // A room published between the requests doesn't shift the next page
+        rooms.push(room("!big:example.org", 100));
+        let next = DirectoryToken::parse(&next.unwrap()).unwrap();
+        let (chunk, prev, next) = page_public_rooms(rooms.clone(), Some(&next), 2);
+        assert_eq!(ids(&chunk), ["!c:example.org", "!a:example.org"]);
+
+        let next = DirectoryToken::parse(&next.unwrap()).unwrap();
+        let (chunk, _, next) = page_public_rooms(rooms.clone(), Some(&next), 2);
+        assert_eq!(ids(&chunk), ["!d:example.org"]);
+        assert_eq!(next, None);
+
+        let prev = DirectoryToken::parse(&prev.unwrap()).unwrap();
+        assert!(prev.backwards);
+        let (chunk, prev, _) = page_public_rooms(rooms, Some(&prev), 2);
+        assert_eq!(ids(&chunk), ["!e:example.org", "!b:example.org"]);
+        assert!(prev.is_some());
+
+        assert!(DirectoryToken::parse("x1_!a:example.org").is_err());
+        assert!(DirectoryToken::parse("n12").is_err());
+    }
+}
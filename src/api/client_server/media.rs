@@ -1,6 +1,11 @@
-use std::time::Duration;
+use std::{fmt, time::Duration};
 
-use crate::{service::media::FileMeta, services, utils, Error, Result, Ruma};
+use crate::{
+    api::ruma_wrapper::MediaUpload, service::media::FileMeta, services, utils, Error, Result, Ruma,
+    RumaResponse,
+};
+use bytes::Bytes;
+use futures_util::Stream;
 use ruma::api::client::{
     error::ErrorKind,
     media::{
@@ -28,9 +33,27 @@ pub async fn get_media_config_route(
 ///
 /// - Some metadata will be saved in the database
 /// - Media will be saved in the media/ directory
-pub async fn create_content_route(
-    body: Ruma<create_content::v3::Request>,
-) -> Result<create_content::v3::Response> {
+/// - The body is streamed to disk instead of being buffered in memory
+/// - Content types that are not allowed by `media_allowed_content_types` and
+///   `media_blocked_content_types` are rejected
+pub async fn create_content_route<B, E>(
+    body: MediaUpload<B>,
+) -> Result<RumaResponse<create_content::v3::Response>>
+where
+    B: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: fmt::Display,
+{
+    if !services()
+        .globals
+        .config
+        .media_content_type_allowed(body.content_type.as_deref())
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Uploading media of this content type is not allowed.",
+        ));
+    }
+
     let mxc = format!(
         "mxc://{}/{}",
         services().globals.server_name(),
@@ -39,21 +62,22 @@ pub async fn create_content_route(
 
     services()
         .media
-        .create(
+        .create_from_stream(
             mxc.clone(),
             body.filename
                 .as_ref()
                 .map(|filename| "inline; filename=".to_owned() + filename)
                 .as_deref(),
             body.content_type.as_deref(),
-            &body.file,
+            body.body,
+            services().globals.max_request_size().into(),
         )
         .await?;
 
-    Ok(create_content::v3::Response {
+    Ok(RumaResponse(create_content::v3::Response {
         content_uri: mxc.try_into().expect("Invalid mxc:// URI"),
         blurhash: None,
-    })
+    }))
 }
 
 pub async fn get_remote_content(
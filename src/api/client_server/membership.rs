@@ -2,6 +2,7 @@ use ruma::{
     api::{
         client::{
             error::ErrorKind,
+            knock::knock_room,
             membership::{
                 ban_user, forget_room, get_member_events, invite_user, join_room_by_id,
                 join_room_by_id_or_alias, joined_members, joined_rooms, kick_user, leave_room,
@@ -15,7 +16,6 @@ use ruma::{
         room::{
             join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
             member::{MembershipState, RoomMemberEventContent},
-            power_levels::RoomPowerLevelsEventContent,
         },
         StateEventType, TimelineEventType,
     },
@@ -49,6 +49,9 @@ pub async fn join_room_by_id_route(
 ) -> Result<join_room_by_id::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    check_join_limit(sender_user, &body.room_id, body.from_appservice)?;
+    let counts_against_rate = check_join_rate(sender_user, &body.room_id, body.from_appservice)?;
+
     let mut servers = Vec::new(); // There is no body.server_name for /roomId/join
     servers.extend(
         services()
@@ -66,14 +69,20 @@ pub async fn join_room_by_id_route(
 
     servers.push(body.room_id.server_name().to_owned());
 
-    join_room_by_id_helper(
+    let join_room_response = join_room_by_id_helper(
         body.sender_user.as_deref(),
         &body.room_id,
         body.reason.clone(),
         &servers,
         body.third_party_signed.as_ref(),
     )
-    .await
+    .await?;
+
+    if counts_against_rate {
+        record_join(&body.room_id);
+    }
+
+    Ok(join_room_response)
 }
 
 /// # `POST /_matrix/client/r0/join/{roomIdOrAlias}`
@@ -86,6 +95,7 @@ pub async fn join_room_by_id_or_alias_route(
     body: Ruma<join_room_by_id_or_alias::v3::Request>,
 ) -> Result<join_room_by_id_or_alias::v3::Response> {
     let sender_user = body.sender_user.as_deref().expect("user is authenticated");
+    let from_appservice = body.from_appservice;
     let body = body.body;
 
     let (servers, room_id) = match OwnedRoomId::try_from(body.room_id_or_alias) {
@@ -116,6 +126,9 @@ pub async fn join_room_by_id_or_alias_route(
         }
     };
 
+    check_join_limit(sender_user, &room_id, from_appservice)?;
+    let counts_against_rate = check_join_rate(sender_user, &room_id, from_appservice)?;
+
     let join_room_response = join_room_by_id_helper(
         Some(sender_user),
         &room_id,
@@ -125,11 +138,96 @@ pub async fn join_room_by_id_or_alias_route(
     )
     .await?;
 
+    if counts_against_rate {
+        record_join(&room_id);
+    }
+
     Ok(join_room_by_id_or_alias::v3::Response {
         room_id: join_room_response.room_id,
     })
 }
 
+/// # `POST /_matrix/client/v3/knock/{roomIdOrAlias}`
+///
+/// Tries to knock on a room to ask for an invite.
+///
+/// - If the server knowns about this room: creates the knock event and does auth rules locally
+/// - If the server does not know about the room: asks other servers over federation
+pub async fn knock_room_route(
+    body: Ruma<knock_room::v3::Request>,
+) -> Result<knock_room::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let (servers, room_id) = match OwnedRoomId::try_from(body.room_id_or_alias.clone()) {
+        Ok(room_id) => {
+            let mut servers = body.server_name.clone();
+            servers.push(room_id.server_name().to_owned());
+
+            (servers, room_id)
+        }
+        Err(room_alias) => {
+            let response = get_alias_helper(room_alias).await?;
+
+            (response.servers, response.room_id)
+        }
+    };
+
+    let mutex_state = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    if services()
+        .rooms
+        .state_cache
+        .server_in_room(services().globals.server_name(), &room_id)?
+    {
+        if !services().rooms.state_accessor.allows_knocking(&room_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "This room does not allow knocking.",
+            ));
+        }
+
+        let content = RoomMemberEventContent {
+            membership: MembershipState::Knock,
+            displayname: services().users.displayname(sender_user)?,
+            avatar_url: services().users.avatar_url(sender_user)?,
+            is_direct: None,
+            third_party_invite: None,
+            blurhash: services().users.blurhash(sender_user)?,
+            reason: body.reason.clone(),
+            join_authorized_via_users_server: None,
+        };
+
+        services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content: to_raw_value(&content).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(sender_user.to_string()),
+                redacts: None,
+            },
+            sender_user,
+            &room_id,
+            &state_lock,
+        )?;
+    } else {
+        drop(state_lock);
+
+        info!("Knocking on {room_id} over federation.");
+        remote_knock_room(sender_user, &room_id, body.reason.clone(), &servers).await?;
+    }
+
+    Ok(knock_room::v3::Response { room_id })
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/leave`
 ///
 /// Tries to leave the sender user from a room.
@@ -176,6 +274,13 @@ pub async fn kick_user_route(
 ) -> Result<kick_user::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    check_membership_power(
+        sender_user,
+        &body.room_id,
+        &body.user_id,
+        MembershipState::Leave,
+    )?;
+
     let mut event: RoomMemberEventContent = serde_json::from_str(
         services()
             .rooms
@@ -232,6 +337,13 @@ pub async fn kick_user_route(
 pub async fn ban_user_route(body: Ruma<ban_user::v3::Request>) -> Result<ban_user::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    check_membership_power(
+        sender_user,
+        &body.room_id,
+        &body.user_id,
+        MembershipState::Ban,
+    )?;
+
     let event = services()
         .rooms
         .state_accessor
@@ -298,6 +410,14 @@ pub async fn unban_user_route(
 ) -> Result<unban_user::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    // Unbanning needs the same power as banning
+    check_membership_power(
+        sender_user,
+        &body.room_id,
+        &body.user_id,
+        MembershipState::Ban,
+    )?;
+
     let mut event: RoomMemberEventContent = serde_json::from_str(
         services()
             .rooms
@@ -465,6 +585,87 @@ pub async fn joined_members_route(
     Ok(joined_members::v3::Response { joined })
 }
 
+/// Enforces `max_joined_rooms_per_user`. Joining a room the user is already in always works.
+fn check_join_limit(sender_user: &UserId, room_id: &RoomId, from_appservice: bool) -> Result<()> {
+    if services()
+        .rooms
+        .state_cache
+        .is_joined(sender_user, room_id)?
+    {
+        return Ok(());
+    }
+
+    super::room::check_room_limit(
+        services().globals.max_joined_rooms_per_user(),
+        || Ok(from_appservice || services().users.is_admin(sender_user)?),
+        || {
+            Ok(services()
+                .rooms
+                .state_cache
+                .rooms_joined(sender_user)
+                .count())
+        },
+        "You are in too many rooms, leave one of them first.",
+    )
+}
+
+/// Rejects joins beyond `room_join_rate_limit`. Invited users, admins, appservices and users that
+/// are already in the room are exempt.
+///
+/// This only checks that the room has a join left, so joins that fail later don't use up its
+/// allowance. Returns whether the join counts against the limit, then it has to be recorded with
+/// `record_join` once it succeeded.
+pub(crate) fn check_join_rate(
+    user_id: &UserId,
+    room_id: &RoomId,
+    from_appservice: bool,
+) -> Result<bool> {
+    let Some(limiter) = &services().globals.room_join_rate_limiter else {
+        return Ok(false);
+    };
+
+    if from_appservice
+        || services().users.is_admin(user_id)?
+        || services().rooms.state_cache.is_joined(user_id, room_id)?
+    {
+        return Ok(false);
+    }
+    let invited = services().rooms.state_cache.is_invited(user_id, room_id)?;
+
+    join_rate_allows(limiter, room_id, invited, Instant::now()).map_err(|retry_after| {
+        Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: Some(retry_after),
+            },
+            "Too many users are joining this room, try again later.",
+        )
+    })
+}
+
+/// Checks that the room's bucket has a join left and returns whether the join counts against it.
+/// Invited users don't use up joins, so they can always get in during a raid.
+fn join_rate_allows(
+    limiter: &utils::RateLimiter<OwnedRoomId>,
+    room_id: &RoomId,
+    invited: bool,
+    now: Instant,
+) -> std::result::Result<bool, Duration> {
+    if invited {
+        return Ok(false);
+    }
+
+    limiter.peek(&room_id.to_owned(), now).map(|()| true)
+}
+
+/// Takes a join from the room's bucket after a join that `check_join_rate` counted succeeded.
+pub(crate) fn record_join(room_id: &RoomId) {
+    if let Some(limiter) = &services().globals.room_join_rate_limiter {
+        // Concurrent joins may have used up the allowance since the check, but this join already
+        // happened
+        let _ = limiter.check(room_id.to_owned(), Instant::now());
+    }
+}
+
 async fn join_room_by_id_helper(
     sender_user: Option<&UserId>,
     room_id: &RoomId,
@@ -567,11 +768,11 @@ async fn join_room_by_id_helper(
         // Generate event id
         let event_id = format!(
             "${}",
-            ruma::signatures::reference_hash(&join_event_stub, &room_version_id)
-                .expect("ruma can calculate reference hashes")
+            utils::reference_hash(&join_event_stub, &room_version_id)
+                .expect("reference hashes can be calculated for events we created")
         );
-        let event_id = <&EventId>::try_from(event_id.as_str())
-            .expect("ruma's reference hashes are valid event ids");
+        let event_id =
+            <&EventId>::try_from(event_id.as_str()).expect("reference hashes are valid event ids");
 
         // Add event_id back
         join_event_stub.insert(
@@ -792,11 +993,6 @@ async fn join_room_by_id_helper(
             &StateEventType::RoomJoinRules,
             "",
         )?;
-        let power_levels_event = services().rooms.state_accessor.room_state_get(
-            room_id,
-            &StateEventType::RoomPowerLevels,
-            "",
-        )?;
 
         let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
             .as_ref()
@@ -807,17 +1003,8 @@ async fn join_room_by_id_helper(
                 })
             })
             .transpose()?;
-        let power_levels_event_content: Option<RoomPowerLevelsEventContent> = power_levels_event
-            .as_ref()
-            .map(|power_levels_event| {
-                serde_json::from_str(power_levels_event.content.get()).map_err(|e| {
-                    warn!("Invalid power levels event: {}", e);
-                    Error::bad_database("Invalid power levels event in db.")
-                })
-            })
-            .transpose()?;
 
-        let restriction_rooms = match join_rules_event_content {
+        let restriction_rooms: Vec<_> = match join_rules_event_content {
             Some(RoomJoinRulesEventContent {
                 join_rule: JoinRule::Restricted(restricted),
             })
@@ -834,47 +1021,12 @@ async fn join_room_by_id_helper(
             _ => Vec::new(),
         };
 
-        let authorized_user = restriction_rooms
-            .iter()
-            .find_map(|restriction_room_id| {
-                if !services()
-                    .rooms
-                    .state_cache
-                    .is_joined(sender_user, restriction_room_id)
-                    .ok()?
-                {
-                    return None;
-                }
-                let authorized_user = power_levels_event_content
-                    .as_ref()
-                    .and_then(|c| {
-                        c.users
-                            .iter()
-                            .filter(|(uid, i)| {
-                                uid.server_name() == services().globals.server_name()
-                                    && **i > ruma::int!(0)
-                                    && services()
-                                        .rooms
-                                        .state_cache
-                                        .is_joined(uid, restriction_room_id)
-                                        .unwrap_or(false)
-                            })
-                            .max_by_key(|(_, i)| *i)
-                            .map(|(u, _)| u.to_owned())
-                    })
-                    .or_else(|| {
-                        // TODO: Check here if user is actually allowed to invite. Currently the auth
-                        // check will just fail in this case.
-                        services()
-                            .rooms
-                            .state_cache
-                            .room_members(restriction_room_id)
-                            .filter_map(|r| r.ok())
-                            .find(|uid| uid.server_name() == services().globals.server_name())
-                    });
-                Some(authorized_user)
-            })
-            .flatten();
+        // If this fails, we try to join over federation below
+        let authorized_user = services()
+            .rooms
+            .state_accessor
+            .restricted_join_authoriser(room_id, sender_user)
+            .unwrap_or_default();
 
         let event = RoomMemberEventContent {
             membership: MembershipState::Join,
@@ -983,11 +1135,11 @@ async fn join_room_by_id_helper(
             // Generate event id
             let event_id = format!(
                 "${}",
-                ruma::signatures::reference_hash(&join_event_stub, &room_version_id)
-                    .expect("ruma can calculate reference hashes")
+                utils::reference_hash(&join_event_stub, &room_version_id)
+                    .expect("reference hashes can be calculated for events we created")
             );
             let event_id = <&EventId>::try_from(event_id.as_str())
-                .expect("ruma's reference hashes are valid event ids");
+                .expect("reference hashes are valid event ids");
 
             // Add event_id back
             join_event_stub.insert(
@@ -1056,6 +1208,136 @@ async fn join_room_by_id_helper(
     Ok(join_room_by_id::v3::Response::new(room_id.to_owned()))
 }
 
+async fn remote_knock_room(
+    user_id: &UserId,
+    room_id: &RoomId,
+    reason: Option<String>,
+    servers: &[OwnedServerName],
+) -> Result<()> {
+    let mut make_knock_response_and_server = Err(Error::BadServerResponse(
+        "No server available to assist in knocking.",
+    ));
+
+    for remote_server in servers {
+        if remote_server == services().globals.server_name() {
+            continue;
+        }
+        let make_knock_response = services()
+            .sending
+            .send_federation_request(
+                remote_server,
+                federation::knock::create_knock_event_template::v1::Request {
+                    room_id: room_id.to_owned(),
+                    user_id: user_id.to_owned(),
+                    ver: services().globals.supported_room_versions(),
+                },
+            )
+            .await;
+
+        make_knock_response_and_server = make_knock_response.map(|r| (r, remote_server.clone()));
+
+        if make_knock_response_and_server.is_ok() {
+            break;
+        }
+    }
+
+    let (make_knock_response, remote_server) = make_knock_response_and_server?;
+
+    let room_version_id = make_knock_response.room_version;
+    if !services()
+        .globals
+        .supported_room_versions()
+        .contains(&room_version_id)
+    {
+        return Err(Error::BadServerResponse("Room version is not supported"));
+    }
+
+    let mut knock_event_stub = serde_json::from_str::<CanonicalJsonObject>(
+        make_knock_response.event.get(),
+    )
+    .map_err(|_| Error::BadServerResponse("Invalid make_knock event json received from server."))?;
+
+    // TODO: Is origin needed?
+    knock_event_stub.insert(
+        "origin".to_owned(),
+        CanonicalJsonValue::String(services().globals.server_name().as_str().to_owned()),
+    );
+    knock_event_stub.insert(
+        "origin_server_ts".to_owned(),
+        CanonicalJsonValue::Integer(
+            utils::millis_since_unix_epoch()
+                .try_into()
+                .expect("Timestamp is valid js_int value"),
+        ),
+    );
+    knock_event_stub.insert(
+        "content".to_owned(),
+        to_canonical_value(RoomMemberEventContent {
+            membership: MembershipState::Knock,
+            displayname: services().users.displayname(user_id)?,
+            avatar_url: services().users.avatar_url(user_id)?,
+            is_direct: None,
+            third_party_invite: None,
+            blurhash: services().users.blurhash(user_id)?,
+            reason,
+            join_authorized_via_users_server: None,
+        })
+        .expect("event is valid, we just created it"),
+    );
+
+    // We don't leave the event id in the pdu because that's only allowed in v1 or v2 rooms
+    knock_event_stub.remove("event_id");
+
+    // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
+    ruma::signatures::hash_and_sign_event(
+        services().globals.server_name().as_str(),
+        services().globals.keypair(),
+        &mut knock_event_stub,
+        &room_version_id,
+    )
+    .expect("event is valid, we just created it");
+
+    // Generate event id
+    let event_id = EventId::parse(format!(
+        "${}",
+        utils::reference_hash(&knock_event_stub, &room_version_id)
+            .expect("reference hashes can be calculated for events we created")
+    ))
+    .expect("reference hashes are valid event ids");
+
+    // Add event_id back
+    knock_event_stub.insert(
+        "event_id".to_owned(),
+        CanonicalJsonValue::String(event_id.as_str().to_owned()),
+    );
+
+    // It has enough fields to be called a proper event now
+    let knock_event = knock_event_stub;
+
+    let send_knock_response = services()
+        .sending
+        .send_federation_request(
+            &remote_server,
+            federation::knock::send_knock::v1::Request {
+                room_id: room_id.to_owned(),
+                event_id,
+                pdu: PduEvent::convert_to_outgoing_federation_event(knock_event),
+            },
+        )
+        .await?;
+
+    services().rooms.state_cache.update_membership(
+        room_id,
+        user_id,
+        MembershipState::Knock,
+        user_id,
+        Some(send_knock_response.knock_room_state),
+        true,
+    )?;
+
+    Ok(())
+}
+
 async fn make_join_request(
     sender_user: &UserId,
     room_id: &RoomId,
@@ -1106,10 +1388,10 @@ fn validate_and_add_event_id(
     })?;
     let event_id = EventId::parse(format!(
         "${}",
-        ruma::signatures::reference_hash(&value, room_version)
-            .expect("ruma can calculate reference hashes")
+        utils::reference_hash(&value, room_version)
+            .map_err(|_| Error::BadServerResponse("Invalid PDU in server response"))?
     ))
-    .expect("ruma's reference hashes are valid event ids");
+    .expect("reference hashes are valid event ids");
 
     let back_off = |id| match services()
         .globals
@@ -1163,6 +1445,28 @@ fn validate_and_add_event_id(
     Ok((event_id, value))
 }
 
+/// Fails with `M_FORBIDDEN` if the sender's power level does not allow the membership change.
+fn check_membership_power(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    user_id: &UserId,
+    membership: MembershipState,
+) -> Result<()> {
+    if !services().rooms.state_accessor.user_can_set_membership(
+        sender_user,
+        room_id,
+        user_id,
+        &membership,
+    )? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Your power level is too low for this membership change.",
+        ));
+    }
+
+    Ok(())
+}
+
 pub(crate) async fn invite_helper<'a>(
     sender_user: &UserId,
     user_id: &UserId,
@@ -1170,6 +1474,8 @@ pub(crate) async fn invite_helper<'a>(
     reason: Option<String>,
     is_direct: bool,
 ) -> Result<()> {
+    check_membership_power(sender_user, room_id, user_id, MembershipState::Invite)?;
+
     if user_id.server_name() != services().globals.server_name() {
         let (pdu, pdu_json, invite_room_state) = {
             let mutex_state = Arc::clone(
@@ -1373,12 +1679,13 @@ pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<Strin
             .rooms
             .state_cache
             .invite_state(user_id, room_id)?
+            .or(services().rooms.state_cache.knock_state(user_id, room_id)?)
             .map_or_else(
                 || services().rooms.state_cache.left_state(user_id, room_id),
                 |s| Ok(Some(s)),
             )?;
 
-        // We always drop the invite, we can't rely on other servers
+        // We always drop the invite or knock, we can't rely on other servers
         services().rooms.state_cache.update_membership(
             room_id,
             user_id,
@@ -1455,9 +1762,13 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
         .rooms
         .state_cache
         .invite_state(user_id, room_id)?
+        .map_or_else(
+            || services().rooms.state_cache.knock_state(user_id, room_id),
+            |s| Ok(Some(s)),
+        )?
         .ok_or(Error::BadRequest(
             ErrorKind::BadState,
-            "User is not invited.",
+            "User is not invited and did not knock.",
         ))?;
 
     let servers: HashSet<_> = invite_state
@@ -1535,10 +1846,10 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
     // Generate event id
     let event_id = EventId::parse(format!(
         "${}",
-        ruma::signatures::reference_hash(&leave_event_stub, &room_version_id)
-            .expect("ruma can calculate reference hashes")
+        utils::reference_hash(&leave_event_stub, &room_version_id)
+            .expect("reference hashes can be calculated for events we created")
     ))
-    .expect("ruma's reference hashes are valid event ids");
+    .expect("reference hashes are valid event ids");
 
     // Add event_id back
     leave_event_stub.insert(
@@ -1563,3 +1874,43 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use ruma::room_id;
+
+    use super::*;
+    use crate::config::RateLimitConfig;
+
+    #[test]
+    fn rapid_joins_are_throttled() {
+        let limiter = utils::RateLimiter::new(&RateLimitConfig {
+            per_second: 1.0,
+            burst: 3,
+        });
+        let room = room_id!("!raided:example.com");
+        let other_room = room_id!("!quiet:example.com");
+        let now = Instant::now();
+
+        // Checks don't use up the allowance, so failed joins don't count
+        for _ in 0..5 {
+            assert_eq!(join_rate_allows(&limiter, room, false, now), Ok(true));
+        }
+        // Three joins succeeded
+        for _ in 0..3 {
+            limiter.check(room.to_owned(), now).unwrap();
+        }
+        let retry_after = join_rate_allows(&limiter, room, false, now).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(1));
+
+        // Invited users still get in without counting
+        assert_eq!(join_rate_allows(&limiter, room, true, now), Ok(false));
+        // Other rooms are not affected
+        assert_eq!(join_rate_allows(&limiter, other_room, false, now), Ok(true));
+
+        assert_eq!(
+            join_rate_allows(&limiter, room, false, now + Duration::from_secs(1)),
+            Ok(true)
+        );
+    }
+}
@@ -1,17 +1,22 @@
+use super::sync::event_filter_allows;
 use crate::{
     service::{pdu::PduBuilder, rooms::timeline::PduCount},
-    services, utils, Error, Result, Ruma,
+    services, utils, Error, PduEvent, Result, Ruma,
 };
 use ruma::{
-    api::client::{
-        error::ErrorKind,
-        message::{get_message_events, send_message_event},
+    api::{
+        client::{
+            error::ErrorKind,
+            message::{get_message_events, send_message_event},
+        },
+        Direction,
     },
     events::{StateEventType, TimelineEventType},
 };
 use std::{
     collections::{BTreeMap, HashSet},
     sync::Arc,
+    time::Instant,
 };
 
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/send/{eventType}/{txnId}`
@@ -21,6 +26,7 @@ use std::{
 /// - Is a NOOP if the txn id was already used before and returns the same event id again
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is allowed
+/// - Limited by `message_rate_limit` per user and room, except for admins and appservices
 pub async fn send_message_event_route(
     body: Ruma<send_message_event::v3::Request>,
 ) -> Result<send_message_event::v3::Response> {
@@ -70,6 +76,33 @@ pub async fn send_message_event_route(
         return Ok(send_message_event::v3::Response { event_id });
     }
 
+    if let Some(limiter) = &services().globals.message_rate_limiter {
+        if !body.from_appservice && !services().users.is_admin(sender_user)? {
+            limiter
+                .check((sender_user.clone(), body.room_id.clone()), Instant::now())
+                .map_err(|retry_after| {
+                    Error::BadRequest(
+                        ErrorKind::LimitExceeded {
+                            retry_after_ms: Some(retry_after),
+                        },
+                        "Too many messages, slow down.",
+                    )
+                })?;
+        }
+    }
+
+    if !services().rooms.state_accessor.user_can_send(
+        sender_user,
+        &body.room_id,
+        &body.event_type.to_string().into(),
+        None,
+    )? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Your power level is too low to send this event.",
+        ));
+    }
+
     let mut unsigned = BTreeMap::new();
     unsigned.insert("transaction_id".to_owned(), body.txn_id.to_string().into());
 
@@ -116,8 +149,8 @@ pub async fn get_message_events_route(
     let from = match body.from.clone() {
         Some(from) => PduCount::try_from_string(&from)?,
         None => match body.dir {
-            ruma::api::Direction::Forward => PduCount::min(),
-            ruma::api::Direction::Backward => PduCount::max(),
+            Direction::Forward => PduCount::min(),
+            Direction::Backward => PduCount::max(),
         },
     };
 
@@ -133,7 +166,7 @@ pub async fn get_message_events_route(
         from,
     )?;
 
-    let limit = u64::from(body.limit).min(100) as usize;
+    let limit = services().globals.fetch_limit(Some(body.limit.into()), 10);
 
     let next_token;
 
@@ -142,22 +175,25 @@ pub async fn get_message_events_route(
     let mut lazy_loaded = HashSet::new();
 
     match body.dir {
-        ruma::api::Direction::Forward => {
-            let events_after: Vec<_> = services()
-                .rooms
-                .timeline
-                .pdus_after(sender_user, &body.room_id, from)?
-                .take(limit)
-                .filter_map(|r| r.ok()) // Filter out buggy events
-                .filter(|(_, pdu)| {
-                    services()
-                        .rooms
-                        .state_accessor
-                        .user_can_see_event(sender_user, &body.room_id, &pdu.event_id)
-                        .unwrap_or(false)
-                })
-                .take_while(|&(k, _)| Some(k) != to) // Stop at `to`
-                .collect();
+        Direction::Forward => {
+            let events_after = paginate(
+                services()
+                    .rooms
+                    .timeline
+                    .pdus_after(sender_user, &body.room_id, from)?
+                    .filter_map(|r| r.ok()), // Filter out buggy events
+                Direction::Forward,
+                to,
+                limit,
+                |pdu| {
+                    event_filter_allows(&body.filter, pdu)
+                        && services()
+                            .rooms
+                            .state_accessor
+                            .user_can_see_event(sender_user, &body.room_id, &pdu.event_id)
+                            .unwrap_or(false)
+                },
+            );
 
             for (_, event) in &events_after {
                 /* TODO: Remove this when these are resolved:
@@ -186,27 +222,30 @@ pub async fn get_message_events_route(
             resp.end = next_token.map(|count| count.stringify());
             resp.chunk = events_after;
         }
-        ruma::api::Direction::Backward => {
+        Direction::Backward => {
             services()
                 .rooms
                 .timeline
                 .backfill_if_required(&body.room_id, from)
                 .await?;
-            let events_before: Vec<_> = services()
-                .rooms
-                .timeline
-                .pdus_until(sender_user, &body.room_id, from)?
-                .take(limit)
-                .filter_map(|r| r.ok()) // Filter out buggy events
-                .filter(|(_, pdu)| {
-                    services()
-                        .rooms
-                        .state_accessor
-                        .user_can_see_event(sender_user, &body.room_id, &pdu.event_id)
-                        .unwrap_or(false)
-                })
-                .take_while(|&(k, _)| Some(k) != to) // Stop at `to`
-                .collect();
+            let events_before = paginate(
+                services()
+                    .rooms
+                    .timeline
+                    .pdus_until(sender_user, &body.room_id, from)?
+                    .filter_map(|r| r.ok()), // Filter out buggy events
+                Direction::Backward,
+                to,
+                limit,
+                |pdu| {
+                    event_filter_allows(&body.filter, pdu)
+                        && services()
+                            .rooms
+                            .state_accessor
+                            .user_can_see_event(sender_user, &body.room_id, &pdu.event_id)
+                            .unwrap_or(false)
+                },
+            );
 
             for (_, event) in &events_before {
                 /* TODO: Remove this when these are resolved:
@@ -263,3 +302,113 @@ pub async fn get_message_events_route(
 
     Ok(resp)
 }
+
+/// Takes up to `limit` events the filter accepts from a scan in direction `dir`, stopping before
+/// the `to` token.
+fn paginate(
+    events: impl Iterator<Item = (PduCount, PduEvent)>,
+    dir: Direction,
+    to: Option<PduCount>,
+    limit: usize,
+    mut filter: impl FnMut(&PduEvent) -> bool,
+) -> Vec<(PduCount, PduEvent)> {
+    events
+        .take_while(|&(count, _)| match (to, &dir) {
+            (None, _) => true,
+            (Some(to), Direction::Forward) => count < to,
+            (Some(to), Direction::Backward) => count > to,
+        })
+        .filter(|(_, pdu)| filter(pdu))
+        .take(limit)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::pdu::EventHash;
+    use ruma::{room_id, uint, user_id, EventId, MilliSecondsSinceUnixEpoch};
+    use serde_json::value::to_raw_value;
+
+    /// A room with the events `$1` to `$10`, where every third one is a reaction.
+    fn seeded_room() -> Vec<(PduCount, PduEvent)> {
+        (1..=10)
+            .map(|i| {
+                let pdu = PduEvent {
+                    event_id: EventId::parse_arc(format!("${i}")).unwrap(),
+                    room_id: room_id!("!room:example.com").to_owned(),
+                    sender: user_id!("@alice:example.com").to_owned(),
+                    origin_server_ts: MilliSecondsSinceUnixEpoch::now().get(),
+                    kind: if i % 3 == 0 {
+                        TimelineEventType::Reaction
+                    } else {
+                        TimelineEventType::RoomMessage
+                    },
+                    content: to_raw_value(&serde_json::json!({})).unwrap(),
+                    state_key: None,
+                    prev_events: Vec::new(),
+                    depth: uint!(1),
+                    auth_events: Vec::new(),
+                    redacts: None,
+                    unsigned: None,
+                    hashes: EventHash {
+                        sha256: String::new(),
+                    },
+                    signatures: None,
+                };
+                (PduCount::Normal(i), pdu)
+            })
+            .collect()
+    }
+
+    fn counts(events: &[(PduCount, PduEvent)]) -> Vec<u64> {
+        events
+            .iter()
+            .map(|(count, _)| match count {
+                PduCount::Normal(c) => *c,
+                PduCount::Backfilled(c) => *c,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn forward() {
+        let room = seeded_room();
+        let page = paginate(room.into_iter(), Direction::Forward, None, 4, |_| true);
+        assert_eq!(counts(&page), [1, 2, 3, 4]);
+
+        let room = seeded_room();
+        let page = paginate(
+            room.into_iter().skip(4),
+            Direction::Forward,
+            Some(PduCount::Normal(8)),
+            10,
+            |_| true,
+        );
+        assert_eq!(counts(&page), [5, 6, 7]);
+    }
+
+    #[test]
+    fn backward_with_filter() {
+        let room = seeded_room();
+        let page = paginate(
+            room.into_iter().rev(),
+            Direction::Backward,
+            Some(PduCount::Normal(2)),
+            10,
+            |pdu| pdu.kind == TimelineEventType::RoomMessage,
+        );
+        assert_eq!(counts(&page), [10, 8, 7, 5, 4]);
+
+        // The limit counts the events that pass the filter
+        let room = seeded_room();
+        let page = paginate(
+            room.into_iter().rev(),
+            Direction::Backward,
+            None,
+            3,
+            |pdu| pdu.kind == TimelineEventType::RoomMessage,
+        );
+        assert_eq!(counts(&page), [10, 8, 7]);
+    }
+}
@@ -1,5 +1,6 @@
 mod account;
 mod alias;
+mod appservice;
 mod backup;
 mod capabilities;
 mod config;
@@ -35,6 +36,7 @@ mod voip;
 
 pub use account::*;
 pub use alias::*;
+pub use appservice::*;
 pub use backup::*;
 pub use capabilities::*;
 pub use config::*;
@@ -1,13 +1,10 @@
 use crate::{service::pdu::PduBuilder, services, utils, Error, Result, Ruma};
 use ruma::{
-    api::{
-        client::{
-            error::ErrorKind,
-            profile::{
-                get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name,
-            },
+    api::client::{
+        error::ErrorKind,
+        profile::{
+            get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name,
         },
-        federation::{self, query::get_profile_information::v1::ProfileField},
     },
     events::{room::member::RoomMemberEventContent, StateEventType, TimelineEventType},
 };
@@ -19,11 +16,14 @@ use std::sync::Arc;
 /// Updates the displayname.
 ///
 /// - Also makes sure other users receive the update using presence EDUs
+/// - Rejected if the profile was changed within `profile_update_interval_secs`
 pub async fn set_displayname_route(
     body: Ruma<set_display_name::v3::Request>,
 ) -> Result<set_display_name::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    services().users.check_profile_update(sender_user)?;
+
     services()
         .users
         .set_displayname(sender_user, body.displayname.clone())?;
@@ -119,24 +119,19 @@ pub async fn set_displayname_route(
 ///
 /// Returns the displayname of the user.
 ///
-/// - If user is on another server: Fetches displayname over federation
+/// - If user is on another server: Fetches displayname over federation, cached for a while
 pub async fn get_displayname_route(
     body: Ruma<get_display_name::v3::Request>,
 ) -> Result<get_display_name::v3::Response> {
     if body.user_id.server_name() != services().globals.server_name() {
-        let response = services()
-            .sending
-            .send_federation_request(
-                body.user_id.server_name(),
-                federation::query::get_profile_information::v1::Request {
-                    user_id: body.user_id.clone(),
-                    field: Some(ProfileField::DisplayName),
-                },
-            )
-            .await?;
+        let profile = services()
+            .users
+            .get_profile(&body.user_id, true)
+            .await?
+            .unwrap_or_default();
 
         return Ok(get_display_name::v3::Response {
-            displayname: response.displayname,
+            displayname: profile.displayname,
         });
     }
 
@@ -150,11 +145,14 @@ pub async fn get_displayname_route(
 /// Updates the avatar_url and blurhash.
 ///
 /// - Also makes sure other users receive the update using presence EDUs
+/// - Rejected if the profile was changed within `profile_update_interval_secs`
 pub async fn set_avatar_url_route(
     body: Ruma<set_avatar_url::v3::Request>,
 ) -> Result<set_avatar_url::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    services().users.check_profile_update(sender_user)?;
+
     services()
         .users
         .set_avatar_url(sender_user, body.avatar_url.clone())?;
@@ -254,25 +252,21 @@ pub async fn set_avatar_url_route(
 ///
 /// Returns the avatar_url and blurhash of the user.
 ///
-/// - If user is on another server: Fetches avatar_url and blurhash over federation
+/// - If user is on another server: Fetches avatar_url and blurhash over federation, cached for a
+///   while
 pub async fn get_avatar_url_route(
     body: Ruma<get_avatar_url::v3::Request>,
 ) -> Result<get_avatar_url::v3::Response> {
     if body.user_id.server_name() != services().globals.server_name() {
-        let response = services()
-            .sending
-            .send_federation_request(
-                body.user_id.server_name(),
-                federation::query::get_profile_information::v1::Request {
-                    user_id: body.user_id.clone(),
-                    field: Some(ProfileField::AvatarUrl),
-                },
-            )
-            .await?;
+        let profile = services()
+            .users
+            .get_profile(&body.user_id, true)
+            .await?
+            .unwrap_or_default();
 
         return Ok(get_avatar_url::v3::Response {
-            avatar_url: response.avatar_url,
-            blurhash: response.blurhash,
+            avatar_url: profile.avatar_url,
+            blurhash: profile.blurhash,
         });
     }
 
@@ -290,36 +284,19 @@ pub async fn get_avatar_url_route(
 pub async fn get_profile_route(
     body: Ruma<get_profile::v3::Request>,
 ) -> Result<get_profile::v3::Response> {
-    if body.user_id.server_name() != services().globals.server_name() {
-        let response = services()
-            .sending
-            .send_federation_request(
-                body.user_id.server_name(),
-                federation::query::get_profile_information::v1::Request {
-                    user_id: body.user_id.clone(),
-                    field: None,
-                },
-            )
-            .await?;
-
-        return Ok(get_profile::v3::Response {
-            displayname: response.displayname,
-            avatar_url: response.avatar_url,
-            blurhash: response.blurhash,
-        });
-    }
-
-    if !services().users.exists(&body.user_id)? {
-        // Return 404 if this user doesn't exist
-        return Err(Error::BadRequest(
+    // Return 404 if this user doesn't exist
+    let profile = services()
+        .users
+        .get_profile(&body.user_id, true)
+        .await?
+        .ok_or(Error::BadRequest(
             ErrorKind::NotFound,
             "Profile was not found.",
-        ));
-    }
+        ))?;
 
     Ok(get_profile::v3::Response {
-        avatar_url: services().users.avatar_url(&body.user_id)?,
-        blurhash: services().users.blurhash(&body.user_id)?,
-        displayname: services().users.displayname(&body.user_id)?,
+        avatar_url: profile.avatar_url,
+        blurhash: profile.blurhash,
+        displayname: profile.displayname,
     })
 }
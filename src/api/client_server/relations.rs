@@ -25,12 +25,9 @@ pub async fn get_relating_events_with_rel_type_and_event_type_route(
         .as_ref()
         .and_then(|t| PduCount::try_from_string(&t).ok());
 
-    // Use limit or else 10, with maximum 100
-    let limit = body
-        .limit
-        .and_then(|u| u32::try_from(u).ok())
-        .map_or(10_usize, |u| u as usize)
-        .min(100);
+    let limit = services()
+        .globals
+        .fetch_limit(body.limit.map(u64::from), 10);
 
     let res = services()
         .rooms
@@ -75,12 +72,9 @@ pub async fn get_relating_events_with_rel_type_route(
         .as_ref()
         .and_then(|t| PduCount::try_from_string(&t).ok());
 
-    // Use limit or else 10, with maximum 100
-    let limit = body
-        .limit
-        .and_then(|u| u32::try_from(u).ok())
-        .map_or(10_usize, |u| u as usize)
-        .min(100);
+    let limit = services()
+        .globals
+        .fetch_limit(body.limit.map(u64::from), 10);
 
     let res = services()
         .rooms
@@ -123,12 +117,9 @@ pub async fn get_relating_events_route(
         .as_ref()
         .and_then(|t| PduCount::try_from_string(&t).ok());
 
-    // Use limit or else 10, with maximum 100
-    let limit = body
-        .limit
-        .and_then(|u| u32::try_from(u).ok())
-        .map_or(10_usize, |u| u as usize)
-        .min(100);
+    let limit = services()
+        .globals
+        .fetch_limit(body.limit.map(u64::from), 10);
 
     services()
         .rooms
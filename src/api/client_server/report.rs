@@ -1,21 +1,24 @@
-use crate::{services, utils::HtmlEscape, Error, Result, Ruma};
+use crate::{service::reports::Report, services, utils::HtmlEscape, Error, Result, Ruma};
 use ruma::{
     api::client::{error::ErrorKind, room::report_content},
     events::room::message,
-    int,
+    int, MilliSecondsSinceUnixEpoch,
 };
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/report/{eventId}`
 ///
 /// Reports an inappropriate event to homeserver admins
 ///
+/// - The reporter has to be able to see the event
+/// - The report is stored until an admin resolves it with `resolve-report`
+/// - The admin room is notified if `notify_admins_of_reports` is enabled
 pub async fn report_event_route(
     body: Ruma<report_content::v3::Request>,
 ) -> Result<report_content::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
     let pdu = match services().rooms.timeline.get_pdu(&body.event_id)? {
-        Some(pdu) => pdu,
+        Some(pdu) if pdu.room_id == body.room_id => pdu,
         _ => {
             return Err(Error::BadRequest(
                 ErrorKind::InvalidParam,
@@ -24,6 +27,14 @@ pub async fn report_event_route(
         }
     };
 
+    if !services().rooms.state_accessor.user_can_see_event(
+        sender_user,
+        &pdu.room_id,
+        &pdu.event_id,
+    )? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
     if let Some(true) = body.score.map(|s| s > int!(0) || s < int!(-100)) {
         return Err(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -38,19 +49,33 @@ pub async fn report_event_route(
         ));
     };
 
+    let report_id = services().reports.file(&Report {
+        reporter: sender_user.clone(),
+        room_id: pdu.room_id.clone(),
+        event_id: pdu.event_id.as_ref().to_owned(),
+        sender: pdu.sender.clone(),
+        score: body.score,
+        reason: body.reason.clone(),
+        received_ts: MilliSecondsSinceUnixEpoch::now(),
+    })?;
+
+    if !services().globals.notify_admins_of_reports() {
+        return Ok(report_content::v3::Response {});
+    }
+
     services().admin
         .send_message(message::RoomMessageEventContent::text_html(
             format!(
-                "Report received from: {}\n\n\
+                "Report {} received from: {}\n\n\
                 Event ID: {:?}\n\
                 Room ID: {:?}\n\
                 Sent By: {:?}\n\n\
                 Report Score: {:?}\n\
                 Report Reason: {:?}",
-                sender_user, pdu.event_id, pdu.room_id, pdu.sender, body.score, body.reason
+                report_id, sender_user, pdu.event_id, pdu.room_id, pdu.sender, body.score, body.reason
             ),
             format!(
-                "<details><summary>Report received from: <a href=\"https://matrix.to/#/{0:?}\">{0:?}\
+                "<details><summary>Report {6} received from: <a href=\"https://matrix.to/#/{0:?}\">{0:?}\
                 </a></summary><ul><li>Event Info<ul><li>Event ID: <code>{1:?}</code>\
                 <a href=\"https://matrix.to/#/{2:?}/{1:?}\">🔗</a></li><li>Room ID: <code>{2:?}</code>\
                 </li><li>Sent By: <a href=\"https://matrix.to/#/{3:?}\">{3:?}</a></li></ul></li><li>\
@@ -61,7 +86,8 @@ pub async fn report_event_route(
                 pdu.room_id,
                 pdu.sender,
                 body.score,
-                HtmlEscape(body.reason.as_deref().unwrap_or(""))
+                HtmlEscape(body.reason.as_deref().unwrap_or("")),
+                report_id
             ),
         ));
 
@@ -1,5 +1,6 @@
 use crate::{
-    api::client_server::invite_helper, service::pdu::PduBuilder, services, Error, Result, Ruma,
+    api::client_server::invite_helper, service::pdu::PduBuilder, services, Error, PduEvent, Result,
+    Ruma,
 };
 use ruma::{
     api::client::{
@@ -19,11 +20,11 @@ use ruma::{
             tombstone::RoomTombstoneEventContent,
             topic::RoomTopicEventContent,
         },
-        StateEventType, TimelineEventType,
+        AnyInitialStateEvent, StateEventType, TimelineEventType,
     },
     int,
-    serde::JsonObject,
-    CanonicalJsonObject, OwnedRoomAliasId, RoomAliasId, RoomId,
+    serde::{JsonObject, Raw},
+    CanonicalJsonObject, OwnedRoomAliasId, OwnedUserId, RoomAliasId, RoomId, UserId,
 };
 use serde_json::{json, value::to_raw_value};
 use std::{cmp::max, collections::BTreeMap, sync::Arc};
@@ -77,6 +78,29 @@ pub async fn create_room_route(
         ));
     }
 
+    check_room_limit(
+        services().globals.max_rooms_per_user(),
+        || Ok(body.from_appservice || services().users.is_admin(sender_user)?),
+        || {
+            Ok(services()
+                .rooms
+                .state_cache
+                .rooms_joined(sender_user)
+                .filter_map(|r| r.ok())
+                .filter(|room_id| {
+                    services()
+                        .rooms
+                        .state_accessor
+                        .room_state_get(room_id, &StateEventType::RoomCreate, "")
+                        .ok()
+                        .flatten()
+                        .is_some_and(|create| &create.sender == sender_user)
+                })
+                .count())
+        },
+        "You have created too many rooms, leave one of them first.",
+    )?;
+
     let alias: Option<OwnedRoomAliasId> =
         body.room_alias_name
             .as_ref()
@@ -301,21 +325,24 @@ pub async fn create_room_route(
     )?;
 
     // 5.2 History Visibility
-    services().rooms.timeline.build_and_append_pdu(
-        PduBuilder {
-            event_type: TimelineEventType::RoomHistoryVisibility,
-            content: to_raw_value(&RoomHistoryVisibilityEventContent::new(
-                HistoryVisibility::Shared,
-            ))
-            .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        sender_user,
-        &room_id,
-        &state_lock,
-    )?;
+    if let Some(history_visibility) = preset_history_visibility(
+        &body.initial_state,
+        services().globals.default_history_visibility(),
+    ) {
+        services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomHistoryVisibility,
+                content: to_raw_value(&RoomHistoryVisibilityEventContent::new(history_visibility))
+                    .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            sender_user,
+            &room_id,
+            &state_lock,
+        )?;
+    }
 
     // 5.3 Guest Access
     services().rooms.timeline.build_and_append_pdu(
@@ -419,31 +446,29 @@ pub async fn create_room_route(
 ///
 /// Gets a single event.
 ///
-/// - You have to currently be joined to the room (TODO: Respect history visibility)
+/// - The event has to be in the given room and visible to the user according to the history
+/// visibility, otherwise it is reported as not found
 pub async fn get_room_event_route(
     body: Ruma<get_room_event::v3::Request>,
 ) -> Result<get_room_event::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let event = services()
-        .rooms
-        .timeline
-        .get_pdu(&body.event_id)?
-        .ok_or_else(|| {
-            warn!("Event not found, event ID: {:?}", &body.event_id);
-            Error::BadRequest(ErrorKind::NotFound, "Event not found.")
-        })?;
+    let event = services().rooms.timeline.get_pdu(&body.event_id)?;
 
-    if !services().rooms.state_accessor.user_can_see_event(
-        sender_user,
-        &event.room_id,
-        &body.event_id,
-    )? {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view this event.",
-        ));
-    }
+    let event = visible_event(event, &body.room_id, |event| {
+        services().rooms.state_accessor.user_can_see_event(
+            sender_user,
+            &event.room_id,
+            &event.event_id,
+        )
+    })
+    .map_err(|e| {
+        warn!(
+            "Event {} not returned to {}: {}",
+            body.event_id, sender_user, e
+        );
+        e
+    })?;
 
     let mut event = (*event).clone();
     event.add_age()?;
@@ -453,6 +478,24 @@ pub async fn get_room_event_route(
     })
 }
 
+/// Returns the event if it is in `room_id` and `can_see` allows it. Events the user can't see
+/// are indistinguishable from missing ones.
+fn visible_event(
+    event: Option<Arc<PduEvent>>,
+    room_id: &RoomId,
+    can_see: impl FnOnce(&PduEvent) -> Result<bool>,
+) -> Result<Arc<PduEvent>> {
+    let not_found = || Error::BadRequest(ErrorKind::NotFound, "Event not found.");
+
+    let event = event.ok_or_else(not_found)?;
+
+    if &*event.room_id != room_id || !can_see(&event)? {
+        return Err(not_found());
+    }
+
+    Ok(event)
+}
+
 /// # `GET /_matrix/client/r0/rooms/{roomId}/aliases`
 ///
 /// Lists all aliases of the room.
@@ -689,6 +732,34 @@ pub async fn upgrade_room_route(
         )?;
     }
 
+    // Keep the bans, banned users shouldn't be able to follow the upgrade
+    for (user_id, content) in bans(
+        services()
+            .rooms
+            .state_accessor
+            .room_state_full(&body.room_id)
+            .await?
+            .into_values(),
+    ) {
+        if let Err(e) = services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content: to_raw_value(&content).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            },
+            sender_user,
+            &replacement_room,
+            &state_lock,
+        ) {
+            warn!(
+                "Failed to ban {} in upgraded room {}: {}",
+                user_id, replacement_room, e
+            );
+        }
+    }
+
     // Moves any local aliases to the new room
     for alias in services()
         .rooms
@@ -736,6 +807,207 @@ pub async fn upgrade_room_route(
 
     drop(state_lock);
 
+    services()
+        .rooms
+        .metadata
+        .set_replacement_room(&body.room_id, &replacement_room)?;
+
     // Return the replacement room id
     Ok(upgrade_room::v3::Response { replacement_room })
 }
+
+/// The banned users of a room with the content for banning them again in its replacement.
+fn bans(state: impl Iterator<Item = Arc<PduEvent>>) -> Vec<(OwnedUserId, RoomMemberEventContent)> {
+    state
+        .filter(|pdu| pdu.kind == TimelineEventType::RoomMember)
+        .filter_map(|pdu| {
+            let user_id = UserId::parse(pdu.state_key.as_deref()?).ok()?;
+            let content: RoomMemberEventContent = serde_json::from_str(pdu.content.get()).ok()?;
+            (content.membership == MembershipState::Ban).then(|| {
+                let mut ban = RoomMemberEventContent::new(MembershipState::Ban);
+                ban.reason = content.reason;
+                (user_id, ban)
+            })
+        })
+        .collect()
+}
+
+/// The history visibility a new room starts with: `default_history_visibility`, unless the
+/// creator sets one in `initial_state`.
+fn preset_history_visibility(
+    initial_state: &[Raw<AnyInitialStateEvent>],
+    default: &HistoryVisibility,
+) -> Option<HistoryVisibility> {
+    let set_by_creator = initial_state.iter().any(|event| {
+        event.get_field::<String>("type").ok().flatten().as_deref()
+            == Some("m.room.history_visibility")
+    });
+
+    (!set_by_creator).then(|| default.clone())
+}
+
+/// Fails with `M_LIMIT_EXCEEDED` if the user already has `max_rooms` rooms. Users for which
+/// `exempt` returns true, like admins and appservices, have no limit.
+pub(crate) fn check_room_limit(
+    max_rooms: Option<usize>,
+    exempt: impl FnOnce() -> Result<bool>,
+    rooms: impl FnOnce() -> Result<usize>,
+    error: &'static str,
+) -> Result<()> {
+    let Some(max_rooms) = max_rooms else {
+        return Ok(());
+    };
+
+    if !exempt()? && rooms()? >= max_rooms {
+        return Err(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: None,
+            },
+            error,
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::pdu::EventHash;
+    use ruma::{event_id, room_id, uint, user_id, MilliSecondsSinceUnixEpoch};
+
+    fn event() -> Arc<PduEvent> {
+        Arc::new(PduEvent {
+            event_id: event_id!("$event").into(),
+            room_id: room_id!("!room:example.com").to_owned(),
+            sender: user_id!("@alice:example.com").to_owned(),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now().get(),
+            kind: TimelineEventType::RoomMessage,
+            content: to_raw_value(&json!({ "body": "hi", "msgtype": "m.text" })).unwrap(),
+            state_key: None,
+            prev_events: Vec::new(),
+            depth: uint!(1),
+            auth_events: Vec::new(),
+            redacts: None,
+            unsigned: None,
+            hashes: EventHash {
+                sha256: String::new(),
+            },
+            signatures: None,
+        })
+    }
+
+    fn is_not_found(result: Result<Arc<PduEvent>>) -> bool {
+        matches!(result, Err(Error::BadRequest(ErrorKind::NotFound, _)))
+    }
+
+    #[test]
+    fn visible() {
+        let room_id = room_id!("!room:example.com");
+        let event = visible_event(Some(event()), room_id, |_| Ok(true)).unwrap();
+        assert_eq!(&*event.event_id, event_id!("$event"));
+    }
+
+    #[test]
+    fn missing() {
+        let room_id = room_id!("!room:example.com");
+        assert!(is_not_found(visible_event(None, room_id, |_| Ok(true))));
+    }
+
+    #[test]
+    fn not_visible() {
+        let room_id = room_id!("!room:example.com");
+        assert!(is_not_found(visible_event(Some(event()), room_id, |_| Ok(
+            false
+        ))));
+
+        // Events from other rooms are not found either, even if the user could see them
+        let other_room = room_id!("!other:example.com");
+        assert!(is_not_found(visible_event(
+            Some(event()),
+            other_room,
+            |_| Ok(true)
+        )));
+    }
+
+    #[test]
+    fn room_creation_limit() {
+        let check = |rooms, exempt| check_room_limit(Some(2), || Ok(exempt), || Ok(rooms), "");
+        let limited = |result| {
+            matches!(
+                result,
+                Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))
+            )
+        };
+
+        assert!(check(1, false).is_ok());
+        assert!(limited(check(2, false)));
+        assert!(limited(check(3, false)));
+
+        // Admins don't even need to count their rooms
+        assert!(check_room_limit(Some(2), || Ok(true), || unreachable!(), "").is_ok());
+        assert!(check(5, true).is_ok());
+
+        assert!(check_room_limit(None, || unreachable!(), || unreachable!(), "").is_ok());
+    }
+
+    #[test]
+    fn bans_are_carried_over() {
+        let member = |state_key: &str, membership: &str| {
+            let mut pdu = (*event()).clone();
+            pdu.kind = TimelineEventType::RoomMember;
+            pdu.state_key = Some(state_key.to_owned());
+            pdu.content =
+                to_raw_value(&json!({ "membership": membership, "reason": "spam" })).unwrap();
+            Arc::new(pdu)
+        };
+
+        let bans = bans(
+            vec![
+                member("@alice:example.com", "join"),
+                member("@mallory:example.com", "ban"),
+                member("@bob:example.com", "leave"),
+                event(),
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].0.as_str(), "@mallory:example.com");
+        assert_eq!(bans[0].1.membership, MembershipState::Ban);
+        assert_eq!(bans[0].1.reason.as_deref(), Some("spam"));
+    }
+
+    #[test]
+    fn default_history_visibility() {
+        let initial_state = |events: serde_json::Value| -> Vec<Raw<AnyInitialStateEvent>> {
+            serde_json::from_value(events).unwrap()
+        };
+
+        // Without an explicit visibility the configured default is used
+        let without = initial_state(json!([{
+            "type": "m.room.encryption",
+            "state_key": "",
+            "content": { "algorithm": "m.megolm.v1.aes-sha2" },
+        }]));
+        assert_eq!(
+            preset_history_visibility(&without, &HistoryVisibility::Joined),
+            Some(HistoryVisibility::Joined)
+        );
+        assert_eq!(
+            preset_history_visibility(&[], &HistoryVisibility::Invited),
+            Some(HistoryVisibility::Invited)
+        );
+
+        // The creator's choice is sent with the initial state instead
+        let with = initial_state(json!([{
+            "type": "m.room.history_visibility",
+            "state_key": "",
+            "content": { "history_visibility": "world_readable" },
+        }]));
+        assert_eq!(
+            preset_history_visibility(&with, &HistoryVisibility::Joined),
+            None
+        );
+    }
+}
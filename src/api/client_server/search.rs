@@ -1,19 +1,36 @@
-use crate::{services, Error, Result, Ruma};
-use ruma::api::client::{
-    error::ErrorKind,
-    search::search_events::{
-        self,
-        v3::{EventContextResult, ResultCategories, ResultRoomEvents, SearchResult},
+use crate::{
+    service::rooms::{search, timeline::PduCount},
+    services, Error, PduEvent, Result, Ruma,
+};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        search::search_events::{
+            self,
+            v3::{
+                EventContext, EventContextResult, OrderBy, ResultCategories, ResultRoomEvents,
+                SearchResult, UserProfile,
+            },
+        },
     },
+    OwnedRoomId, UserId,
 };
+use serde::Deserialize;
+
+use std::{cmp, collections::BTreeMap};
 
-use std::collections::BTreeMap;
+/// How many pages of the newest matches are ranked at most, so that searching for a common word
+/// doesn't load the whole history of every room.
+const RANKED_PAGES: usize = 10;
 
 /// # `POST /_matrix/client/r0/search`
 ///
 /// Searches rooms for messages.
 ///
-/// - Only works if the user is currently joined to the room (TODO: Respect history visibility)
+/// - Only works if the user is currently joined to the room
+/// - Only returns events the user is allowed to see by the history visibility of the room
+/// - Results are found through the `tokenids` index and come with the surrounding events the
+/// client asked for
 pub async fn search_events_route(
     body: Ruma<search_events::v3::Request>,
 ) -> Result<search_events::v3::Response> {
@@ -31,30 +48,21 @@ pub async fn search_events_route(
             .collect()
     });
 
-    // Use limit or else 10, with maximum 100
-    let limit = filter.limit.map_or(10, u64::from).min(100) as usize;
+    let limit = services()
+        .globals
+        .fetch_limit(filter.limit.map(u64::from), 10);
 
-    let mut searches = Vec::new();
-
-    for room_id in room_ids {
+    for room_id in &room_ids {
         if !services()
             .rooms
             .state_cache
-            .is_joined(sender_user, &room_id)?
+            .is_joined(sender_user, room_id)?
         {
             return Err(Error::BadRequest(
                 ErrorKind::Forbidden,
                 "You don't have permission to view this room.",
             ));
         }
-
-        if let Some(search) = services()
-            .rooms
-            .search
-            .search_pdus(&room_id, &search_criteria.search_term)?
-        {
-            searches.push(search.0.peekable());
-        }
     }
 
     let skip = match body.next_batch.as_ref().map(|s| s.parse()) {
@@ -68,58 +76,27 @@ pub async fn search_events_route(
         None => 0, // Default to the start
     };
 
-    let mut results = Vec::new();
-    for _ in 0..skip + limit {
-        if let Some(s) = searches
-            .iter_mut()
-            .map(|s| (s.peek().cloned(), s))
-            .max_by_key(|(peek, _)| peek.clone())
-            .and_then(|(_, i)| i.next())
-        {
-            results.push(s);
-        }
-    }
+    let words = search::tokenize(
+        services().globals.search_tokenizer(),
+        &search_criteria.search_term,
+    );
+
+    let (events, more) = matching_events(
+        sender_user,
+        &room_ids,
+        &search_criteria.search_term,
+        &words,
+        matches!(search_criteria.order_by, Some(OrderBy::Rank)),
+        skip,
+        limit,
+    )?;
 
-    let results: Vec<_> = results
+    let results = events
         .iter()
-        .filter_map(|result| {
-            services()
-                .rooms
-                .timeline
-                .get_pdu_from_id(result)
-                .ok()?
-                .filter(|pdu| {
-                    services()
-                        .rooms
-                        .state_accessor
-                        .user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
-                        .unwrap_or(false)
-                })
-                .map(|pdu| pdu.to_room_event())
-        })
-        .map(|result| {
-            Ok::<_, Error>(SearchResult {
-                context: EventContextResult {
-                    end: None,
-                    events_after: Vec::new(),
-                    events_before: Vec::new(),
-                    profile_info: BTreeMap::new(),
-                    start: None,
-                },
-                rank: None,
-                result: Some(result),
-            })
-        })
-        .filter_map(|r| r.ok())
-        .skip(skip)
-        .take(limit)
-        .collect();
+        .map(|(rank, pdu)| search_result(sender_user, pdu, *rank, &search_criteria.event_context))
+        .collect::<Result<Vec<_>>>()?;
 
-    let next_batch = if results.len() < limit {
-        None
-    } else {
-        Some((skip + limit).to_string())
-    };
+    let next_batch = more.then(|| (skip + limit).to_string());
 
     Ok(search_events::v3::Response::new(ResultCategories {
         room_events: ResultRoomEvents {
@@ -128,11 +105,280 @@ pub async fn search_events_route(
             next_batch,
             results,
             state: BTreeMap::new(), // TODO
-            highlights: search_criteria
-                .search_term
-                .split_terminator(|c: char| !c.is_alphanumeric())
-                .map(str::to_lowercase)
-                .collect(),
+            highlights: words,
         },
     }))
 }
+
+/// Finds the events in the given rooms that match the search term and that the user can see.
+///
+/// Returns the `limit` events after the first `skip` ones together with their ranks, and whether
+/// there are more events. Events are ordered by rank if `order_by_rank` is set and newest first
+/// otherwise. Ranking happens before paging, so every page continues where the previous one
+/// ended. Only the newest `RANKED_PAGES` pages of matches are ranked.
+fn matching_events(
+    sender_user: &UserId,
+    room_ids: &[OwnedRoomId],
+    search_term: &str,
+    words: &[String],
+    order_by_rank: bool,
+    skip: usize,
+    limit: usize,
+) -> Result<(Vec<(f64, PduEvent)>, bool)> {
+    let mut searches = Vec::new();
+    for room_id in room_ids {
+        if let Some((pdu_ids, _)) = services().rooms.search.search_pdus(room_id, search_term)? {
+            searches.push(pdu_ids.peekable());
+        }
+    }
+
+    // The rooms are merged by the count of their next event, pdu ids start with the shortroomid
+    let pdu_ids = std::iter::from_fn(|| {
+        searches
+            .iter_mut()
+            .filter_map(|s| {
+                let count = PduCount::from_pdu_id(s.peek()?).unwrap_or(PduCount::min());
+                Some((count, s))
+            })
+            .max_by_key(|(count, _)| *count)
+            .and_then(|(_, s)| s.next())
+    });
+
+    let visible = pdu_ids.filter_map(|pdu_id| {
+        let pdu = match services().rooms.timeline.get_pdu_from_id(&pdu_id) {
+            Ok(Some(pdu)) => pdu,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+
+        match services().rooms.state_accessor.user_can_see_event(
+            sender_user,
+            &pdu.room_id,
+            &pdu.event_id,
+        ) {
+            Ok(true) => Some(Ok((rank(&pdu, words), pdu))),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    });
+
+    let mut events = if order_by_rank {
+        let mut events = visible
+            .take(limit.saturating_mul(RANKED_PAGES))
+            .collect::<Result<Vec<_>>>()?;
+        events.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(cmp::Ordering::Equal));
+        events
+    } else {
+        visible.take(skip + limit + 1).collect::<Result<Vec<_>>>()?
+    };
+
+    let more = events.len() > skip + limit;
+    events.truncate(skip + limit);
+    let page = events.split_off(skip.min(events.len()));
+
+    Ok((page, more))
+}
+
+/// Builds a single search result, including the events around it and the profiles of their
+/// senders if the client asked for them.
+fn search_result(
+    sender_user: &UserId,
+    pdu: &PduEvent,
+    rank: f64,
+    context: &EventContext,
+) -> Result<SearchResult> {
+    let count = services()
+        .rooms
+        .timeline
+        .get_pdu_count(&pdu.event_id)?
+        .ok_or_else(|| Error::bad_database("Search result has no pdu count."))?;
+
+    let before_limit = services()
+        .globals
+        .fetch_limit(Some(context.before_limit.into()), 0);
+    let after_limit = services()
+        .globals
+        .fetch_limit(Some(context.after_limit.into()), 0);
+
+    let can_see = |pdu: &PduEvent| {
+        services()
+            .rooms
+            .state_accessor
+            .user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
+            .unwrap_or(false)
+    };
+
+    let events_before: Vec<_> = services()
+        .rooms
+        .timeline
+        .pdus_until(sender_user, &pdu.room_id, count)?
+        .take(before_limit)
+        .filter_map(|r| r.ok()) // Remove buggy events
+        .filter(|(_, pdu)| can_see(pdu))
+        .collect();
+
+    let events_after: Vec<_> = services()
+        .rooms
+        .timeline
+        .pdus_after(sender_user, &pdu.room_id, count)?
+        .take(after_limit)
+        .filter_map(|r| r.ok()) // Remove buggy events
+        .filter(|(_, pdu)| can_see(pdu))
+        .collect();
+
+    let mut profile_info = BTreeMap::new();
+    if context.include_profile {
+        for sender in std::iter::once(&pdu.sender)
+            .chain(events_before.iter().map(|(_, pdu)| &pdu.sender))
+            .chain(events_after.iter().map(|(_, pdu)| &pdu.sender))
+        {
+            if profile_info.contains_key(sender) {
+                continue;
+            }
+
+            profile_info.insert(
+                sender.clone(),
+                UserProfile {
+                    displayname: services().users.displayname(sender)?,
+                    avatar_url: services().users.avatar_url(sender)?,
+                },
+            );
+        }
+    }
+
+    Ok(SearchResult {
+        context: EventContextResult {
+            start: events_before.last().map(|(count, _)| count.stringify()),
+            end: events_after.last().map(|(count, _)| count.stringify()),
+            events_before: events_before
+                .into_iter()
+                .map(|(_, pdu)| pdu.to_room_event())
+                .collect(),
+            events_after: events_after
+                .into_iter()
+                .map(|(_, pdu)| pdu.to_room_event())
+                .collect(),
+            profile_info,
+        },
+        rank: Some(rank),
+        result: Some(pdu.to_room_event()),
+    })
+}
+
+/// Ranks a result by how much of its body is made up of the searched words.
+fn rank(pdu: &PduEvent, words: &[String]) -> f64 {
+    #[derive(Deserialize)]
+    struct ExtractBody {
+        body: Option<String>,
+    }
+
+    let body = match serde_json::from_str::<ExtractBody>(pdu.content.get()) {
+        Ok(ExtractBody { body: Some(body) }) => body,
+        _ => return 0.0,
+    };
+
+    let tokens = search::tokenize(services().globals.search_tokenizer(), &body);
+
+    if tokens.is_empty() {
+        return 0.0;
+    }
+
+    let matches = tokens.iter().filter(|token| words.contains(token)).count();
+
+    matches as f64 / tokens.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    fn bodies(events: &[(f64, PduEvent)]) -> Vec<String> {
+        events
+            .iter()
+            .map(|(_, pdu)| {
+                serde_json::from_str::<serde_json::Value>(pdu.content.get()).unwrap()["body"]
+                    .as_str()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn results_are_ranked_before_paging() {
+        let services = testing::services();
+        let user = testing::create_user();
+        let room_id = testing::create_room(&user).await;
+
+        for body in ["apple banana cherry date", "apple", "apple pie", "banana"] {
+            testing::send_message(&room_id, &user, body).await;
+        }
+
+        let search = |order_by_rank, skip, limit| {
+            let words = search::tokenize(services.globals.search_tokenizer(), "apple");
+            matching_events(
+                &user,
+                &[room_id.clone()],
+                "apple",
+                &words,
+                order_by_rank,
+                skip,
+                limit,
+            )
+            .unwrap()
+        };
+
+        // The best match is on the first page, even though it is neither the newest nor the oldest
+        let (first, more) = search(true, 0, 1);
+        assert_eq!(bodies(&first), ["apple"]);
+        assert!(more);
+        let (second, more) = search(true, 1, 1);
+        assert_eq!(bodies(&second), ["apple pie"]);
+        assert!(more);
+        let (last, more) = search(true, 2, 1);
+        assert_eq!(bodies(&last), ["apple banana cherry date"]);
+        assert!(!more);
+        assert!(first[0].0 > second[0].0 && second[0].0 > last[0].0);
+
+        // Otherwise the newest results come first
+        let (recent, more) = search(false, 0, 2);
+        assert_eq!(bodies(&recent), ["apple pie", "apple"]);
+        assert!(more);
+        let (recent, more) = search(false, 2, 2);
+        assert_eq!(bodies(&recent), ["apple banana cherry date"]);
+        assert!(!more);
+
+        // Pages past the end are empty
+        let (past_end, more) = search(true, 5, 1);
+        assert!(past_end.is_empty());
+        assert!(!more);
+    }
+
+    #[tokio::test]
+    async fn rooms_are_merged_by_recency() {
+        let services = testing::services();
+        let user = testing::create_user();
+        let older_room = testing::create_room(&user).await;
+        let newer_room = testing::create_room(&user).await;
+
+        // The newer room has the larger shortroomid, but the older room has the newest message
+        testing::send_message(&newer_room, &user, "kiwi one").await;
+        testing::send_message(&older_room, &user, "kiwi two").await;
+        testing::send_message(&newer_room, &user, "kiwi three").await;
+
+        let words = search::tokenize(services.globals.search_tokenizer(), "kiwi");
+        let (events, more) = matching_events(
+            &user,
+            &[older_room, newer_room],
+            "kiwi",
+            &words,
+            false,
+            0,
+            10,
+        )
+        .unwrap();
+        assert_eq!(bodies(&events), ["kiwi three", "kiwi two", "kiwi one"]);
+        assert!(!more);
+    }
+}
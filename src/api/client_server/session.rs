@@ -1,9 +1,11 @@
 use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
 use crate::{services, utils, Error, Result, Ruma};
+use axum::response::IntoResponse;
+use http::{header::LOCATION, StatusCode, Uri};
 use ruma::{
     api::client::{
         error::ErrorKind,
-        session::{get_login_types, login, logout, logout_all},
+        session::{get_login_types, login, logout, logout_all, sso_login},
         uiaa::UserIdentifier,
     },
     UserId,
@@ -24,10 +26,45 @@ struct Claims {
 pub async fn get_login_types_route(
     _body: Ruma<get_login_types::v3::Request>,
 ) -> Result<get_login_types::v3::Response> {
-    Ok(get_login_types::v3::Response::new(vec![
-        get_login_types::v3::LoginType::Password(Default::default()),
-        get_login_types::v3::LoginType::ApplicationService(Default::default()),
-    ]))
+    Ok(get_login_types::v3::Response::new(
+        services().globals.login_flows(),
+    ))
+}
+
+/// # `GET /_matrix/client/r0/login/sso/redirect`
+///
+/// Sends the user to the identity provider to log in. Afterwards the identity provider sends them
+/// to [`sso_callback_route`], which redirects them back to the client.
+pub async fn sso_login_route(
+    body: Ruma<sso_login::v3::Request>,
+) -> Result<sso_login::v3::Response> {
+    let location = services().sso.start_login(&body.redirect_url).await?;
+
+    Ok(sso_login::v3::Response {
+        location: location.into(),
+    })
+}
+
+/// # `GET /_conduit/client/oidc/callback`
+///
+/// The identity provider sends users here after they logged in. Redirects them back to the
+/// client with a `loginToken` for `m.login.token`.
+pub async fn sso_callback_route(uri: Uri) -> Result<impl IntoResponse> {
+    #[derive(Deserialize)]
+    struct QueryParams {
+        code: String,
+        state: String,
+    }
+
+    let query_params: QueryParams = serde_html_form::from_str(uri.query().unwrap_or_default())
+        .map_err(|_| Error::BadRequest(ErrorKind::MissingParam, "Missing code or state."))?;
+
+    let location = services()
+        .sso
+        .finish_login(&query_params.code, &query_params.state)
+        .await?;
+
+    Ok((StatusCode::FOUND, [(LOCATION, location.to_string())]))
 }
 
 /// # `POST /_matrix/client/r0/login`
@@ -87,7 +124,9 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
             user_id
         }
         login::v3::LoginInfo::Token(login::v3::Token { token }) => {
-            if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
+            if let Some(user_id) = services().sso.take_login_token(token) {
+                user_id
+            } else if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
                 let token = jsonwebtoken::decode::<Claims>(
                     token,
                     jwt_decoding_key,
@@ -98,6 +137,8 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                 UserId::parse_with_server_name(username, services().globals.server_name()).map_err(
                     |_| Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid."),
                 )?
+            } else if services().globals.oidc().is_some() {
+                return Err(Error::BadRequest(ErrorKind::Forbidden, "Token is invalid."));
             } else {
                 return Err(Error::BadRequest(
                     ErrorKind::Unknown,
@@ -180,7 +221,7 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
 ///
 /// - Invalidates access token
 /// - Deletes device metadata (device id, device display name, last seen ip, last seen ts)
-/// - Forgets to-device events
+/// - Forgets to-device events and unclaimed one-time keys
 /// - Triggers device list updates
 pub async fn logout_route(body: Ruma<logout::v3::Request>) -> Result<logout::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -200,14 +241,20 @@ pub async fn logout_route(body: Ruma<logout::v3::Request>) -> Result<logout::v3:
 /// - Forgets all to-device events
 /// - Triggers device list updates
 ///
-/// Note: This is equivalent to calling [`GET /_matrix/client/r0/logout`](fn.logout_route.html)
+/// Note: This is equivalent to calling [`POST /_matrix/client/r0/logout`](fn.logout_route.html)
 /// from each device of this user.
 pub async fn logout_all_route(
     body: Ruma<logout_all::v3::Request>,
 ) -> Result<logout_all::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    for device_id in services().users.all_device_ids(sender_user).flatten() {
+    // Collect first, removing devices while iterating over them is not supported by all backends
+    let device_ids = services()
+        .users
+        .all_device_ids(sender_user)
+        .collect::<Result<Vec<_>>>()?;
+
+    for device_id in device_ids {
         services().users.remove_device(sender_user, &device_id)?;
     }
 
@@ -15,7 +15,9 @@ pub async fn get_hierarchy_route(
         .and_then(|s| s.parse::<usize>().ok())
         .unwrap_or(0);
 
-    let limit = body.limit.map_or(10, u64::from).min(100) as usize;
+    let limit = services()
+        .globals
+        .fetch_limit(body.limit.map(u64::from), 10);
 
     let max_depth = body.max_depth.map_or(3, u64::from).min(10) as usize + 1; // +1 to skip the space room itself
 
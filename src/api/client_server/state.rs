@@ -193,6 +193,18 @@ async fn send_state_event_for_key_helper(
 ) -> Result<Arc<EventId>> {
     let sender_user = sender;
 
+    if !services().rooms.state_accessor.user_can_send(
+        sender_user,
+        room_id,
+        &event_type.to_string().into(),
+        Some(&state_key),
+    )? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Your power level is too low to send this state event.",
+        ));
+    }
+
     // TODO: Review this check, error if event is unparsable, use event type, allow alias if it
     // previously existed
     if let Ok(canonical_alias) =
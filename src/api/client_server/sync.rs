@@ -1,14 +1,17 @@
 use crate::{
     service::rooms::timeline::PduCount, services, Error, PduEvent, Result, Ruma, RumaResponse,
 };
+use futures_util::future::Either;
 use ruma::{
     api::client::{
-        filter::{FilterDefinition, LazyLoadOptions},
+        error::ErrorKind,
+        filter::{FilterDefinition, LazyLoadOptions, RoomEventFilter, RoomFilter},
         sync::sync_events::{
             self,
             v3::{
                 Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom, JoinedRoom,
-                LeftRoom, Presence, RoomAccountData, RoomSummary, Rooms, State, Timeline, ToDevice,
+                KnockState, KnockedRoom, LeftRoom, Presence, RoomAccountData, RoomSummary, Rooms,
+                State, Timeline, ToDevice,
             },
             v4::SlidingOp,
             DeviceLists, UnreadNotificationsCount,
@@ -28,7 +31,7 @@ use std::{
     time::Duration,
 };
 use tokio::sync::watch::Sender;
-use tracing::error;
+use tracing::{error, warn};
 
 /// # `GET /_matrix/client/r0/sync`
 ///
@@ -71,6 +74,20 @@ pub async fn sync_events_route(
     let sender_device = body.sender_device.expect("user is authenticated");
     let body = body.body;
 
+    let _connection = services()
+        .globals
+        .sync_connections
+        .acquire(
+            &sender_user,
+            services().globals.max_sync_connections_per_user(),
+        )
+        .ok_or(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: None,
+            },
+            "Too many simultaneous syncs.",
+        ))?;
+
     let mut rx = match services()
         .globals
         .sync_receivers
@@ -173,13 +190,6 @@ async fn sync_helper(
     // TODO: match body.set_presence {
     services().rooms.edus.presence.ping_presence(&sender_user)?;
 
-    // Setup watchers, so if there's no response, we can wait for them
-    let watcher = services().globals.watch(&sender_user, &sender_device);
-
-    let next_batch = services().globals.current_count()?;
-    let next_batchcount = PduCount::Normal(next_batch);
-    let next_batch_string = next_batch.to_string();
-
     // Load filter
     let filter = match body.filter {
         None => FilterDefinition::default(),
@@ -190,6 +200,22 @@ async fn sync_helper(
             .unwrap_or_default(),
     };
 
+    // Setup watchers, so if there's no response, we can wait for them. Clients that exclude all
+    // rooms (`"rooms": []`) only get woken up by account data and to-device events.
+    let watcher = if filter.room.rooms.as_ref().is_some_and(Vec::is_empty) {
+        Either::Left(
+            services()
+                .globals
+                .watch_account_data(&sender_user, &sender_device),
+        )
+    } else {
+        Either::Right(services().globals.watch(&sender_user, &sender_device))
+    };
+
+    let next_batch = services().globals.current_count()?;
+    let next_batchcount = PduCount::Normal(next_batch);
+    let next_batch_string = next_batch.to_string();
+
     let (lazy_load_enabled, lazy_load_send_redundant) = match filter.room.state.lazy_load_options {
         LazyLoadOptions::Enabled {
             include_redundant_members: redundant,
@@ -197,14 +223,26 @@ async fn sync_helper(
         _ => (false, false),
     };
 
-    let full_state = body.full_state;
-
     let mut joined_rooms = BTreeMap::new();
-    let since = body
+    let requested_since = body
         .since
         .as_ref()
         .and_then(|string| string.parse().ok())
         .unwrap_or(0);
+    let (since, full_state) = match validate_since(
+        requested_since,
+        next_batch,
+        services().globals.sync_max_since_gap(),
+    ) {
+        Some(since) => (since, body.full_state),
+        None => {
+            warn!(
+                "{} sent a since token {} that does not fit the current count {}, sending a full sync",
+                sender_user, requested_since, next_batch
+            );
+            (0, true)
+        }
+    };
     let sincecount = PduCount::Normal(since);
 
     let mut presence_updates = HashMap::new();
@@ -227,10 +265,15 @@ async fn sync_helper(
         .collect::<Vec<_>>();
     for room_id in all_joined_rooms {
         let room_id = room_id?;
+        if !room_filter_allows(&filter.room, &room_id) {
+            continue;
+        }
+
         if let Ok(joined_room) = load_joined_room(
             &sender_user,
             &sender_device,
             &room_id,
+            &filter.room,
             since,
             sincecount,
             next_batch,
@@ -293,6 +336,10 @@ async fn sync_helper(
     for result in all_left_rooms {
         let (room_id, _) = result?;
 
+        if !room_filter_allows(&filter.room, &room_id) {
+            continue;
+        }
+
         let mut left_state_events = Vec::new();
 
         {
@@ -427,6 +474,10 @@ async fn sync_helper(
     for result in all_invited_rooms {
         let (room_id, invite_state_events) = result?;
 
+        if !room_filter_allows(&filter.room, &room_id) {
+            continue;
+        }
+
         {
             // Get and drop the lock to wait for remaining operations to finish
             let mutex_insert = Arc::clone(
@@ -462,6 +513,39 @@ async fn sync_helper(
         );
     }
 
+    let mut knocked_rooms = BTreeMap::new();
+    let all_knocked_rooms: Vec<_> = services()
+        .rooms
+        .state_cache
+        .rooms_knocked(&sender_user)
+        .collect();
+    for result in all_knocked_rooms {
+        let (room_id, knock_state_events) = result?;
+
+        if !room_filter_allows(&filter.room, &room_id) {
+            continue;
+        }
+
+        let knock_count = services()
+            .rooms
+            .state_cache
+            .get_knock_count(&room_id, &sender_user)?;
+
+        // Knocked before last sync
+        if Some(since) >= knock_count {
+            continue;
+        }
+
+        knocked_rooms.insert(
+            room_id.clone(),
+            KnockedRoom {
+                knock_state: KnockState {
+                    events: knock_state_events,
+                },
+            },
+        );
+    }
+
     for user_id in left_encrypted_users {
         let dont_share_encrypted_room = services()
             .rooms
@@ -497,7 +581,7 @@ async fn sync_helper(
             leave: left_rooms,
             join: joined_rooms,
             invite: invited_rooms,
-            knock: BTreeMap::new(), // TODO
+            knock: knocked_rooms,
         },
         presence: Presence {
             events: presence_updates
@@ -558,6 +642,7 @@ async fn load_joined_room(
     sender_user: &UserId,
     sender_device: &DeviceId,
     room_id: &RoomId,
+    filter: &RoomFilter,
     since: u64,
     sincecount: PduCount,
     next_batch: u64,
@@ -584,7 +669,16 @@ async fn load_joined_room(
         drop(insert_lock);
     }
 
-    let (timeline_pdus, limited) = load_timeline(sender_user, room_id, sincecount, 10)?;
+    let timeline_limit = services()
+        .globals
+        .fetch_limit(filter.timeline.limit.map(u64::from), 10) as u64;
+    let (timeline_pdus, limited) = load_timeline(
+        sender_user,
+        room_id,
+        sincecount,
+        timeline_limit,
+        &filter.timeline,
+    )?;
 
     let send_notification_counts = !timeline_pdus.is_empty()
         || services()
@@ -841,12 +935,20 @@ async fn load_joined_room(
                             };
 
                             if pdu.kind == TimelineEventType::RoomMember {
-                                match UserId::parse(
-                                    pdu.state_key
-                                        .as_ref()
-                                        .expect("State event has state key")
-                                        .clone(),
-                                ) {
+                                let state_key =
+                                    pdu.state_key.as_ref().expect("State event has state key");
+
+                                // Membership changes of users that are not in the timeline are
+                                // left to the client to fetch when it needs them
+                                if lazy_load_enabled
+                                    && !full_state
+                                    && !timeline_users.contains(state_key)
+                                    && sender_user.as_str() != state_key.as_str()
+                                {
+                                    continue;
+                                }
+
+                                match UserId::parse(state_key.clone()) {
                                     Ok(state_key_userid) => {
                                         lazy_loaded.insert(state_key_userid);
                                     }
@@ -860,26 +962,25 @@ async fn load_joined_room(
                     }
                 }
 
-                for (_, event) in &timeline_pdus {
-                    if lazy_loaded.contains(&event.sender) {
-                        continue;
-                    }
+                let missing_members = services().rooms.lazy_loading.lazy_load_missing_members(
+                    &sender_user,
+                    &sender_device,
+                    &room_id,
+                    timeline_pdus
+                        .iter()
+                        .map(|(_, event)| &*event.sender)
+                        .filter(|sender| !lazy_loaded.contains(*sender)),
+                    lazy_load_send_redundant,
+                )?;
 
-                    if !services().rooms.lazy_loading.lazy_load_was_sent_before(
-                        &sender_user,
-                        &sender_device,
+                for sender in missing_members {
+                    if let Some(member_event) = services().rooms.state_accessor.room_state_get(
                         &room_id,
-                        &event.sender,
-                    )? || lazy_load_send_redundant
-                    {
-                        if let Some(member_event) = services().rooms.state_accessor.room_state_get(
-                            &room_id,
-                            &StateEventType::RoomMember,
-                            event.sender.as_str(),
-                        )? {
-                            lazy_loaded.insert(event.sender.clone());
-                            state_events.push(member_event);
-                        }
+                        &StateEventType::RoomMember,
+                        sender.as_str(),
+                    )? {
+                        lazy_loaded.insert(sender);
+                        state_events.push(member_event);
                     }
                 }
 
@@ -1018,6 +1119,15 @@ async fn load_joined_room(
         None
     };
 
+    let mut state_events = state_events;
+    apply_filter(
+        filter,
+        lazy_load_enabled && !full_state,
+        sender_user,
+        &timeline_pdus,
+        &mut state_events,
+    );
+
     let prev_batch = timeline_pdus
         .first()
         .map_or(Ok::<_, Error>(None), |(pdu_count, _)| {
@@ -1099,11 +1209,124 @@ async fn load_joined_room(
     })
 }
 
+/// Checks that an incremental sync can be sent for `since`. Tokens from the future, e.g. after the
+/// database was restored from a backup, or further than `max_gap` behind return `None`, so that
+/// the client gets a full sync instead of missing events. 0 is an initial sync.
+fn validate_since(since: u64, current: u64, max_gap: Option<u64>) -> Option<u64> {
+    if since > current || since > 0 && max_gap.is_some_and(|max_gap| current - since > max_gap) {
+        None
+    } else {
+        Some(since)
+    }
+}
+
+/// Trims the state of a room according to the room part of a sync filter. The timeline is already
+/// filtered by `load_timeline`.
+///
+/// With `lazy_load_members`, member events are only kept for the senders of the timeline events
+/// and for the syncing user.
+fn apply_filter(
+    filter: &RoomFilter,
+    lazy_load_members: bool,
+    sender_user: &UserId,
+    timeline: &[(PduCount, PduEvent)],
+    state: &mut Vec<PduEvent>,
+) {
+    let timeline_senders: HashSet<_> = timeline
+        .iter()
+        .map(|(_, pdu)| pdu.sender.as_str())
+        .collect();
+
+    state.retain(|pdu| {
+        if !event_filter_allows(&filter.state, pdu) {
+            return false;
+        }
+
+        if lazy_load_members && pdu.kind == TimelineEventType::RoomMember {
+            return pdu.state_key.as_deref().is_some_and(|state_key| {
+                state_key == sender_user.as_str() || timeline_senders.contains(state_key)
+            });
+        }
+
+        true
+    });
+}
+
+/// Checks the `rooms` and `not_rooms` of a filter.
+fn room_filter_allows(filter: &RoomFilter, room_id: &RoomId) -> bool {
+    if filter.not_rooms.iter().any(|r| &**r == room_id) {
+        return false;
+    }
+
+    filter
+        .rooms
+        .as_ref()
+        .map_or(true, |rooms| rooms.iter().any(|r| &**r == room_id))
+}
+
+/// Checks the `types`, `not_types`, `senders` and `not_senders` of a filter. Exclusions take
+/// precedence over inclusions.
+pub(super) fn event_filter_allows(filter: &RoomEventFilter, pdu: &PduEvent) -> bool {
+    if filter.not_senders.contains(&pdu.sender) {
+        return false;
+    }
+
+    if let Some(senders) = &filter.senders {
+        if !senders.contains(&pdu.sender) {
+            return false;
+        }
+    }
+
+    let event_type = pdu.kind.to_string();
+
+    if filter
+        .not_types
+        .iter()
+        .any(|pattern| event_type_matches(pattern, &event_type))
+    {
+        return false;
+    }
+
+    filter.types.as_ref().map_or(true, |types| {
+        types
+            .iter()
+            .any(|pattern| event_type_matches(pattern, &event_type))
+    })
+}
+
+/// Matches an event type against a filter pattern, where `*` stands for any sequence of
+/// characters.
+fn event_type_matches(pattern: &str, event_type: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = event_type.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No wildcard in the pattern
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// Loads the last `limit` events since `sincecount` that pass the `filter`, and whether there are
+/// more of them.
 fn load_timeline(
     sender_user: &UserId,
     room_id: &RoomId,
     sincecount: PduCount,
     limit: u64,
+    filter: &RoomEventFilter,
 ) -> Result<(Vec<(PduCount, PduEvent)>, bool), Error> {
     let timeline_pdus;
     let limited;
@@ -1124,7 +1347,8 @@ fn load_timeline(
                 }
                 r.ok()
             })
-            .take_while(|(pducount, _)| pducount > &sincecount);
+            .take_while(|(pducount, _)| pducount > &sincecount)
+            .filter(|(_, pdu)| event_filter_allows(filter, pdu));
 
         // Take the last events for the timeline
         timeline_pdus = non_timeline_pdus
@@ -1175,6 +1399,21 @@ pub async fn sync_events_v4_route(
     let sender_user = body.sender_user.expect("user is authenticated");
     let sender_device = body.sender_device.expect("user is authenticated");
     let mut body = body.body;
+
+    let _connection = services()
+        .globals
+        .sync_connections
+        .acquire(
+            &sender_user,
+            services().globals.max_sync_connections_per_user(),
+        )
+        .ok_or(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: None,
+            },
+            "Too many simultaneous syncs.",
+        ))?;
+
     // Setup watchers, so if there's no response, we can wait for them
     let watcher = services().globals.watch(&sender_user, &sender_device);
 
@@ -1431,11 +1670,10 @@ pub async fn sync_events_v4_route(
                                 0,
                                 true,
                             ));
-                            let limit = list
-                                .room_details
-                                .timeline_limit
-                                .map_or(10, u64::from)
-                                .min(100);
+                            let limit = services()
+                                .globals
+                                .fetch_limit(list.room_details.timeline_limit.map(u64::from), 10)
+                                as u64;
                             todo_room
                                 .0
                                 .extend(list.room_details.required_state.iter().cloned());
@@ -1474,7 +1712,9 @@ pub async fn sync_events_v4_route(
         let todo_room = todo_rooms
             .entry(room_id.clone())
             .or_insert((BTreeSet::new(), 0, true));
-        let limit = room.timeline_limit.map_or(10, u64::from).min(100);
+        let limit = services()
+            .globals
+            .fetch_limit(room.timeline_limit.map(u64::from), 10) as u64;
         todo_room.0.extend(room.required_state.iter().cloned());
         todo_room.1 = todo_room.1.max(limit);
         if known_rooms
@@ -1514,8 +1754,13 @@ pub async fn sync_events_v4_route(
     let mut rooms = BTreeMap::new();
     for (room_id, (required_state_request, timeline_limit, known)) in &todo_rooms {
         // TODO: per-room sync tokens
-        let (timeline_pdus, limited) =
-            load_timeline(&sender_user, &room_id, sincecount, *timeline_limit)?;
+        let (timeline_pdus, limited) = load_timeline(
+            &sender_user,
+            &room_id,
+            sincecount,
+            *timeline_limit,
+            &RoomEventFilter::default(),
+        )?;
 
         if *known && timeline_pdus.is_empty() {
             continue;
@@ -1737,3 +1982,148 @@ pub async fn sync_events_v4_route(
         delta_token: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{service::pdu::EventHash, utils::testing};
+    use ruma::{event_id, room_id, user_id, MilliSecondsSinceUnixEpoch};
+    use serde_json::value::to_raw_value;
+
+    fn pdu(kind: TimelineEventType, sender: &UserId, state_key: Option<&str>) -> PduEvent {
+        PduEvent {
+            event_id: event_id!("$event").into(),
+            room_id: room_id!("!room:example.com").to_owned(),
+            sender: sender.to_owned(),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now().get(),
+            kind,
+            content: to_raw_value(&serde_json::json!({})).unwrap(),
+            state_key: state_key.map(ToOwned::to_owned),
+            prev_events: Vec::new(),
+            depth: uint!(1),
+            auth_events: Vec::new(),
+            redacts: None,
+            unsigned: None,
+            hashes: EventHash {
+                sha256: String::new(),
+            },
+            signatures: None,
+        }
+    }
+
+    #[test]
+    fn event_type_patterns() {
+        assert!(event_type_matches("m.room.message", "m.room.message"));
+        assert!(!event_type_matches(
+            "m.room.message",
+            "m.room.message.extra"
+        ));
+        assert!(event_type_matches("m.room.*", "m.room.member"));
+        assert!(!event_type_matches("m.room.*", "m.reaction"));
+        assert!(event_type_matches("*", "m.reaction"));
+        assert!(event_type_matches("m.*.member", "m.room.member"));
+        assert!(!event_type_matches("m.*.member", "m.room.name"));
+    }
+
+    #[test]
+    fn types_and_not_types() {
+        let alice = user_id!("@alice:example.com");
+        let mut filter = RoomFilter::default();
+        filter.timeline.types = Some(vec!["m.room.*".to_owned()]);
+        filter.timeline.not_types = vec!["m.room.member".to_owned()];
+
+        let timeline = [
+            pdu(TimelineEventType::RoomMessage, alice, None),
+            pdu(TimelineEventType::RoomMember, alice, Some(alice.as_str())),
+            pdu(TimelineEventType::Reaction, alice, None),
+        ];
+        let kept: Vec<_> = timeline
+            .iter()
+            .filter(|pdu| event_filter_allows(&filter.timeline, pdu))
+            .map(|pdu| &pdu.kind)
+            .collect();
+
+        assert_eq!(kept, [&TimelineEventType::RoomMessage]);
+    }
+
+    #[test]
+    fn lazy_loaded_members() {
+        let alice = user_id!("@alice:example.com");
+        let bob = user_id!("@bob:example.com");
+        let carol = user_id!("@carol:example.com");
+
+        let timeline = vec![(
+            PduCount::Normal(1),
+            pdu(TimelineEventType::RoomMessage, bob, None),
+        )];
+        let mut state = vec![
+            pdu(TimelineEventType::RoomCreate, alice, Some("")),
+            pdu(TimelineEventType::RoomMember, alice, Some(alice.as_str())),
+            pdu(TimelineEventType::RoomMember, bob, Some(bob.as_str())),
+            pdu(TimelineEventType::RoomMember, carol, Some(carol.as_str())),
+        ];
+        apply_filter(&RoomFilter::default(), true, alice, &timeline, &mut state);
+
+        let kept: Vec<_> = state
+            .iter()
+            .map(|pdu| pdu.state_key.as_deref().unwrap())
+            .collect();
+        assert_eq!(kept, ["", alice.as_str(), bob.as_str()]);
+    }
+
+    #[tokio::test]
+    async fn timeline_is_filtered_before_the_limit() {
+        let user = testing::create_user();
+        let room_id = testing::create_room(&user).await;
+        for body in ["a", "b", "c"] {
+            testing::send_message(&room_id, &user, body).await;
+        }
+        for topic in ["x", "y", "z"] {
+            testing::send(
+                &room_id,
+                &user,
+                TimelineEventType::RoomTopic,
+                serde_json::json!({ "topic": topic }),
+                Some(""),
+            )
+            .await
+            .unwrap();
+        }
+
+        let mut filter = RoomEventFilter::default();
+        filter.types = Some(vec!["m.room.message".to_owned()]);
+        let bodies = |limit| {
+            let (timeline, limited) =
+                load_timeline(&user, &room_id, PduCount::Normal(0), limit, &filter).unwrap();
+            let bodies: Vec<_> = timeline
+                .iter()
+                .map(|(_, pdu)| {
+                    serde_json::from_str::<serde_json::Value>(pdu.content.get()).unwrap()["body"]
+                        .as_str()
+                        .unwrap()
+                        .to_owned()
+                })
+                .collect();
+            (bodies, limited)
+        };
+
+        // The newer topic changes don't take up the limit
+        assert_eq!(bodies(2), (vec!["b".to_owned(), "c".to_owned()], true));
+        assert_eq!(
+            bodies(3),
+            (vec!["a".to_owned(), "b".to_owned(), "c".to_owned()], false)
+        );
+    }
+
+    #[test]
+    fn future_since_forces_full_sync() {
+        assert_eq!(validate_since(50, 100, None), Some(50));
+        assert_eq!(validate_since(100, 100, None), Some(100));
+        assert_eq!(validate_since(0, 100, Some(10)), Some(0));
+        assert_eq!(validate_since(50, 100, Some(10)), None);
+        assert_eq!(validate_since(95, 100, Some(10)), Some(95));
+
+        // A token from before the database was restored
+        assert_eq!(validate_since(150, 100, None), None);
+    }
+}
@@ -28,12 +28,22 @@ pub async fn create_typing_event_route(
             &body.room_id,
             duration.as_millis() as u64 + utils::millis_since_unix_epoch(),
         )?;
+        services()
+            .rooms
+            .edus
+            .typing
+            .federate(sender_user, &body.room_id, true)?;
     } else {
         services()
             .rooms
             .edus
             .typing
             .typing_remove(sender_user, &body.room_id)?;
+        services()
+            .rooms
+            .edus
+            .typing
+            .federate(sender_user, &body.room_id, false)?;
     }
 
     Ok(create_typing_event::v3::Response {})
@@ -33,6 +33,15 @@ pub async fn get_supported_versions_route(
     Ok(resp)
 }
 
+/// # `GET /_conduit/version`
+///
+/// Get the version of Conduit, the database backend in use and which major features are enabled.
+///
+/// - Does not require authentication
+pub async fn conduit_version_route() -> impl IntoResponse {
+    Json(services().globals.server_info())
+}
+
 /// # `GET /.well-known/matrix/client`
 pub async fn well_known_client_route(
     _body: Ruma<get_supported_versions::Request>,
@@ -1,90 +1,69 @@
+use std::collections::HashSet;
+
 use crate::{services, Result, Ruma};
-use ruma::{
-    api::client::user_directory::search_users,
-    events::{
-        room::join_rules::{JoinRule, RoomJoinRulesEventContent},
-        StateEventType,
-    },
-};
+use ruma::{api::client::user_directory::search_users, OwnedUserId, UserId};
 
 /// # `POST /_matrix/client/r0/user_directory/search`
 ///
 /// Searches all known users for a match.
 ///
-/// - Hides any local users that aren't in any public rooms (i.e. those that have the join rule set to public)
-/// and don't share a room with the sender
+/// - Matches the search term against the localpart and the displayname
+/// - Hides deactivated users
+/// - Hides users that don't share a room with the sender, unless `user_directory_search_all_users`
+/// is enabled
+/// - Remote users are only found through the rooms they share with the sender, and only if
+/// federation is enabled
 pub async fn search_users_route(
     body: Ruma<search_users::v3::Request>,
 ) -> Result<search_users::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let limit = u64::from(body.limit) as usize;
 
-    let mut users = services().users.iter().filter_map(|user_id| {
+    let shared_room_users: HashSet<OwnedUserId> = services()
+        .rooms
+        .state_cache
+        .rooms_joined(sender_user)
+        .filter_map(|r| r.ok())
+        .flat_map(|room_id| {
+            services()
+                .rooms
+                .state_cache
+                .room_members(&room_id)
+                .filter_map(|r| r.ok())
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let local_users = services()
+        .users
+        .iter()
         // Filter out buggy users (they should not exist, but you never know...)
-        let user_id = user_id.ok()?;
+        .filter_map(|r| r.ok())
+        .filter(|user_id| !services().users.is_deactivated(user_id).unwrap_or(true));
+
+    let remote_users = shared_room_users
+        .iter()
+        .filter(|user_id| {
+            services().globals.allow_federation()
+                && user_id.server_name() != services().globals.server_name()
+        })
+        .cloned();
+
+    let candidates = local_users.chain(remote_users).map(|user_id| {
+        let displayname = services().users.displayname(&user_id).ok().flatten();
+        (user_id, displayname)
+    });
 
-        let user = search_users::v3::User {
-            user_id: user_id.clone(),
-            display_name: services().users.displayname(&user_id).ok()?,
+    let search_all_users = services().globals.user_directory_search_all_users();
+    let mut users = search_matching_users(candidates, &body.search_term, |user_id| {
+        search_all_users || shared_room_users.contains(user_id)
+    })
+    .filter_map(|(user_id, display_name)| {
+        Some(search_users::v3::User {
             avatar_url: services().users.avatar_url(&user_id).ok()?,
-        };
-
-        let user_id_matches = user
-            .user_id
-            .to_string()
-            .to_lowercase()
-            .contains(&body.search_term.to_lowercase());
-
-        let user_displayname_matches = user
-            .display_name
-            .as_ref()
-            .filter(|name| {
-                name.to_lowercase()
-                    .contains(&body.search_term.to_lowercase())
-            })
-            .is_some();
-
-        if !user_id_matches && !user_displayname_matches {
-            return None;
-        }
-
-        let user_is_in_public_rooms = services()
-            .rooms
-            .state_cache
-            .rooms_joined(&user_id)
-            .filter_map(|r| r.ok())
-            .any(|room| {
-                services()
-                    .rooms
-                    .state_accessor
-                    .room_state_get(&room, &StateEventType::RoomJoinRules, "")
-                    .map_or(false, |event| {
-                        event.map_or(false, |event| {
-                            serde_json::from_str(event.content.get())
-                                .map_or(false, |r: RoomJoinRulesEventContent| {
-                                    r.join_rule == JoinRule::Public
-                                })
-                        })
-                    })
-            });
-
-        if user_is_in_public_rooms {
-            return Some(user);
-        }
-
-        let user_is_in_shared_rooms = services()
-            .rooms
-            .user
-            .get_shared_rooms(vec![sender_user.clone(), user_id])
-            .ok()?
-            .next()
-            .is_some();
-
-        if user_is_in_shared_rooms {
-            return Some(user);
-        }
-
-        None
+            user_id,
+            display_name,
+        })
     });
 
     let results = users.by_ref().take(limit).collect();
@@ -92,3 +71,79 @@ pub async fn search_users_route(
 
     Ok(search_users::v3::Response { results, limited })
 }
+
+/// Keeps the visible users whose localpart or displayname contains the search term, ignoring case.
+fn search_matching_users<'a>(
+    candidates: impl Iterator<Item = (OwnedUserId, Option<String>)> + 'a,
+    search_term: &str,
+    is_visible: impl Fn(&UserId) -> bool + 'a,
+) -> impl Iterator<Item = (OwnedUserId, Option<String>)> + 'a {
+    let search_term = search_term.to_lowercase();
+
+    candidates.filter(move |(user_id, displayname)| {
+        let matches = user_id.localpart().to_lowercase().contains(&search_term)
+            || displayname
+                .as_ref()
+                .is_some_and(|name| name.to_lowercase().contains(&search_term));
+
+        matches && is_visible(user_id)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruma::user_id;
+
+    fn candidates() -> Vec<(OwnedUserId, Option<String>)> {
+        vec![
+            (
+                user_id!("@alice:example.com").to_owned(),
+                Some("Alice Liddell".to_owned()),
+            ),
+            (
+                user_id!("@bob:example.com").to_owned(),
+                Some("Bobby".to_owned()),
+            ),
+            (user_id!("@carol:example.com").to_owned(), None),
+        ]
+    }
+
+    #[test]
+    fn matches_localpart_and_displayname() {
+        let by_displayname: Vec<_> =
+            search_matching_users(candidates().into_iter(), "liddell", |_| true)
+                .map(|(user_id, _)| user_id)
+                .collect();
+        assert_eq!(by_displayname, [user_id!("@alice:example.com")]);
+
+        let by_localpart: Vec<_> = search_matching_users(candidates().into_iter(), "CAR", |_| true)
+            .map(|(user_id, _)| user_id)
+            .collect();
+        assert_eq!(by_localpart, [user_id!("@carol:example.com")]);
+
+        // The server name is not part of the search
+        assert_eq!(
+            search_matching_users(candidates().into_iter(), "example", |_| true).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn hides_users_without_shared_rooms() {
+        let shared: HashSet<OwnedUserId> = [user_id!("@alice:example.com").to_owned()].into();
+
+        let found: Vec<_> = search_matching_users(candidates().into_iter(), "b", |user_id| {
+            shared.contains(user_id)
+        })
+        .collect();
+        assert!(found.is_empty());
+
+        let found: Vec<_> = search_matching_users(candidates().into_iter(), "alice", |user_id| {
+            shared.contains(user_id)
+        })
+        .map(|(user_id, _)| user_id)
+        .collect();
+        assert_eq!(found, [user_id!("@alice:example.com")]);
+    }
+}
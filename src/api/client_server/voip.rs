@@ -1,7 +1,7 @@
 use crate::{services, Result, Ruma};
 use base64::{engine::general_purpose, Engine as _};
 use hmac::{Hmac, Mac};
-use ruma::{api::client::voip::get_turn_server_info, SecondsSinceUnixEpoch};
+use ruma::{api::client::voip::get_turn_server_info, SecondsSinceUnixEpoch, UserId};
 use sha1::Sha1;
 use std::time::{Duration, SystemTime};
 
@@ -9,29 +9,27 @@ type HmacSha1 = Hmac<Sha1>;
 
 /// # `GET /_matrix/client/r0/voip/turnServer`
 ///
-/// TODO: Returns information about the recommended turn server.
+/// Returns information about the recommended turn server.
+///
+/// - If `turn_secret` is set, the credentials expire after `turn_ttl` seconds (TURN REST API)
+/// - Otherwise the static `turn_username` and `turn_password` are returned
+/// - Without any `turn_uris`, the server list and credentials are empty
 pub async fn turn_server_route(
     body: Ruma<get_turn_server_info::v3::Request>,
 ) -> Result<get_turn_server_info::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let turn_secret = services().globals.turn_secret().clone();
-
-    let (username, password) = if !turn_secret.is_empty() {
-        let expiry = SecondsSinceUnixEpoch::from_system_time(
-            SystemTime::now() + Duration::from_secs(services().globals.turn_ttl()),
-        )
-        .expect("time is valid");
-
-        let username: String = format!("{}:{}", expiry.get(), sender_user);
+    let uris = services().globals.turn_uris().to_vec();
+    let ttl = Duration::from_secs(services().globals.turn_ttl());
+    let turn_secret = services().globals.turn_secret();
 
-        let mut mac = HmacSha1::new_from_slice(turn_secret.as_bytes())
-            .expect("HMAC can take key of any size");
-        mac.update(username.as_bytes());
+    let (username, password) = if uris.is_empty() {
+        (String::new(), String::new())
+    } else if !turn_secret.is_empty() {
+        let expiry = SecondsSinceUnixEpoch::from_system_time(SystemTime::now() + ttl)
+            .expect("time is valid");
 
-        let password: String = general_purpose::STANDARD.encode(mac.finalize().into_bytes());
-
-        (username, password)
+        turn_credentials(turn_secret, sender_user, expiry)
     } else {
         (
             services().globals.turn_username().clone(),
@@ -42,7 +40,51 @@ pub async fn turn_server_route(
     Ok(get_turn_server_info::v3::Response {
         username,
         password,
-        uris: services().globals.turn_uris().to_vec(),
-        ttl: Duration::from_secs(services().globals.turn_ttl()),
+        uris,
+        ttl,
     })
 }
+
+/// Time-limited credentials as understood by coturn's `use-auth-secret`: the username is the
+/// expiry timestamp and the user ID, the password is the base64 encoded HMAC-SHA1 of the username.
+fn turn_credentials(
+    secret: &str,
+    user_id: &UserId,
+    expiry: SecondsSinceUnixEpoch,
+) -> (String, String) {
+    let username = format!("{}:{}", expiry.get(), user_id);
+
+    let mut mac =
+        HmacSha1::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(username.as_bytes());
+
+    let password = general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+
+    (username, password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruma::{uint, user_id};
+
+    #[test]
+    fn credentials_validate_against_secret() {
+        let expiry = SecondsSinceUnixEpoch(uint!(1700000000));
+        let (username, password) =
+            turn_credentials("hunter2", user_id!("@alice:example.com"), expiry);
+
+        assert_eq!(username, "1700000000:@alice:example.com");
+
+        // What the TURN server does with the shared secret
+        let verify = |secret: &str| {
+            let mut mac = HmacSha1::new_from_slice(secret.as_bytes()).unwrap();
+            mac.update(username.as_bytes());
+            mac.verify_slice(&general_purpose::STANDARD.decode(&password).unwrap())
+                .is_ok()
+        };
+
+        assert!(verify("hunter2"));
+        assert!(!verify("hunter3"));
+    }
+}
@@ -1,9 +1,12 @@
-use std::{collections::BTreeMap, iter::FromIterator, str};
+use std::{collections::BTreeMap, iter::FromIterator, net::SocketAddr, str};
 
 use axum::{
     async_trait,
     body::{Full, HttpBody},
-    extract::{rejection::TypedHeaderRejectionReason, FromRequest, Path, TypedHeader},
+    extract::{
+        rejection::TypedHeaderRejectionReason, BodyStream, ConnectInfo, FromRequest, Path,
+        TypedHeader,
+    },
     headers::{
         authorization::{Bearer, Credentials},
         Authorization,
@@ -12,16 +15,16 @@ use axum::{
     BoxError, RequestExt, RequestPartsExt,
 };
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use http::{Request, StatusCode};
+use http::{header::CONTENT_TYPE, Request, StatusCode};
 use ruma::{
     api::{client::error::ErrorKind, AuthScheme, IncomingRequest, OutgoingResponse},
-    CanonicalJsonValue, OwnedDeviceId, OwnedServerName, UserId,
+    CanonicalJsonValue, OwnedDeviceId, OwnedServerName, OwnedUserId, UserId,
 };
 use serde::Deserialize;
 use tracing::{debug, error, warn};
 
-use super::{Ruma, RumaResponse};
-use crate::{services, Error, Result};
+use super::{MediaUpload, Ruma, RumaResponse};
+use crate::{services, utils, Error, Result};
 
 #[async_trait]
 impl<T, S, B> FromRequest<S, B> for Ruma<T>
@@ -57,6 +60,19 @@ where
             }
         };
 
+        let max_request_size = if parts.uri.path().starts_with("/_matrix/federation/") {
+            services().globals.max_federation_request_size()
+        } else {
+            services().globals.max_request_size()
+        };
+
+        if body.len() > max_request_size as usize {
+            return Err(Error::BadRequest(
+                ErrorKind::TooLarge,
+                "Request body is too large.",
+            ));
+        }
+
         let metadata = T::METADATA;
         let auth_header: Option<TypedHeader<Authorization<Bearer>>> = parts.extract().await?;
         let path_params: Path<Vec<String>> = parts.extract().await?;
@@ -80,7 +96,7 @@ where
 
         let mut json_body = serde_json::from_slice::<CanonicalJsonValue>(&body).ok();
 
-        let appservices = services().appservice.all().unwrap();
+        let appservices = services().appservice.all()?;
         let appservice_registration = appservices.iter().find(|(_id, registration)| {
             registration
                 .get("as_token")
@@ -88,26 +104,39 @@ where
                 .map_or(false, |as_token| token == Some(as_token))
         });
 
+        let appservice_id = appservice_registration.map(|(id, _)| id.clone());
+
         let (sender_user, sender_device, sender_servername, from_appservice) =
             if let Some((_id, registration)) = appservice_registration {
                 match metadata.authentication {
                     AuthScheme::AccessToken => {
-                        let user_id = query_params.user_id.map_or_else(
-                            || {
+                        let user_id = match query_params.user_id {
+                            Some(user_id) => UserId::parse(user_id).map_err(|_| {
+                                Error::BadRequest(ErrorKind::InvalidUsername, "Invalid user_id.")
+                            })?,
+                            None => {
+                                let sender_localpart = registration
+                                    .get("sender_localpart")
+                                    .and_then(|localpart| localpart.as_str())
+                                    .ok_or_else(|| {
+                                        Error::bad_config(
+                                            "Appservice registration has no sender_localpart.",
+                                        )
+                                    })?;
+
                                 UserId::parse_with_server_name(
-                                    registration
-                                        .get("sender_localpart")
-                                        .unwrap()
-                                        .as_str()
-                                        .unwrap(),
+                                    sender_localpart,
                                     services().globals.server_name(),
                                 )
-                                .unwrap()
-                            },
-                            |s| UserId::parse(s).unwrap(),
-                        );
+                                .map_err(|_| {
+                                    Error::bad_config(
+                                        "Appservice registration has an invalid sender_localpart.",
+                                    )
+                                })?
+                            }
+                        };
 
-                        if !services().users.exists(&user_id).unwrap() {
+                        if !services().users.exists(&user_id)? {
                             return Err(Error::BadRequest(
                                 ErrorKind::Forbidden,
                                 "User does not exist.",
@@ -133,19 +162,44 @@ where
                             }
                         };
 
-                        match services().users.find_from_token(token).unwrap() {
+                        match services().users.find_from_token(token)? {
                             None => {
                                 return Err(Error::BadRequest(
                                     ErrorKind::UnknownToken { soft_logout: false },
                                     "Unknown access token.",
                                 ))
                             }
-                            Some((user_id, device_id)) => (
-                                Some(user_id),
-                                Some(OwnedDeviceId::from(device_id)),
-                                None,
-                                false,
-                            ),
+                            Some((user_id, device_id)) => {
+                                let device_id = OwnedDeviceId::from(device_id);
+
+                                let forwarded_for = parts
+                                    .headers
+                                    .get("X-Forwarded-For")
+                                    .and_then(|header| header.to_str().ok());
+                                let trusted_proxies = services().globals.trusted_proxies();
+                                let ip = match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+                                    Some(ConnectInfo(peer)) => Some(utils::client_ip(
+                                        peer.ip(),
+                                        forwarded_for,
+                                        trusted_proxies,
+                                    )),
+                                    // Served on a unix socket
+                                    None => {
+                                        utils::forwarded_client_ip(forwarded_for, trusted_proxies)
+                                    }
+                                };
+
+                                if let Some(ip) = ip {
+                                    if let Err(e) = services()
+                                        .users
+                                        .update_device_last_seen(&user_id, &device_id, ip)
+                                    {
+                                        warn!("Failed to update last seen of {device_id}: {e}");
+                                    }
+                                }
+
+                                (Some(user_id), Some(device_id), None, false)
+                            }
                         }
                     }
                     AuthScheme::ServerSignatures => {
@@ -165,47 +219,29 @@ where
                                     _ => "Unknown header-related error",
                                 };
 
-                                Error::BadRequest(ErrorKind::Forbidden, msg)
+                                Error::BadRequest(ErrorKind::Unauthorized, msg)
                             })?;
 
-                        let origin_signatures = BTreeMap::from_iter([(
-                            x_matrix.key.clone(),
-                            CanonicalJsonValue::String(x_matrix.sig),
-                        )]);
-
-                        let signatures = BTreeMap::from_iter([(
-                            x_matrix.origin.as_str().to_owned(),
-                            CanonicalJsonValue::Object(origin_signatures),
-                        )]);
-
-                        let mut request_map = BTreeMap::from_iter([
-                            (
-                                "method".to_owned(),
-                                CanonicalJsonValue::String(parts.method.to_string()),
-                            ),
-                            (
-                                "uri".to_owned(),
-                                CanonicalJsonValue::String(parts.uri.to_string()),
-                            ),
-                            (
-                                "origin".to_owned(),
-                                CanonicalJsonValue::String(x_matrix.origin.as_str().to_owned()),
-                            ),
-                            (
-                                "destination".to_owned(),
-                                CanonicalJsonValue::String(
-                                    services().globals.server_name().as_str().to_owned(),
-                                ),
-                            ),
-                            (
-                                "signatures".to_owned(),
-                                CanonicalJsonValue::Object(signatures),
-                            ),
-                        ]);
-
-                        if let Some(json_body) = &json_body {
-                            request_map.insert("content".to_owned(), json_body.clone());
-                        };
+                        if let Some(destination) = &x_matrix.destination {
+                            if &**destination != services().globals.server_name() {
+                                warn!(
+                                    "Request from {} was meant for {}",
+                                    x_matrix.origin, destination
+                                );
+                                return Err(Error::BadRequest(
+                                    ErrorKind::Unauthorized,
+                                    "X-Matrix destination is not this server.",
+                                ));
+                            }
+                        }
+
+                        let request_map = x_matrix_request_map(
+                            &x_matrix,
+                            parts.method.as_str(),
+                            &parts.uri.to_string(),
+                            services().globals.server_name().as_str(),
+                            json_body.as_ref(),
+                        );
 
                         let keys_result = services()
                             .rooms
@@ -244,7 +280,7 @@ where
                                 }
 
                                 return Err(Error::BadRequest(
-                                    ErrorKind::Forbidden,
+                                    ErrorKind::Unauthorized,
                                     "Failed to verify X-Matrix signatures.",
                                 ));
                             }
@@ -289,7 +325,11 @@ where
 
         let http_request = http_request.body(&*body).unwrap();
 
-        debug!("{:?}", http_request);
+        debug!(
+            method = %http_request.method(),
+            path = %utils::redacted_path_and_query(http_request.uri()),
+            "Parsed request"
+        );
 
         let body = T::try_from_http_request(http_request, &path_params).map_err(|e| {
             warn!("try_from_http_request failed: {:?}", e);
@@ -303,17 +343,172 @@ where
             sender_device,
             sender_servername,
             from_appservice,
+            appservice_id,
             json_body,
         })
     }
 }
 
+#[async_trait]
+impl<S, B> FromRequest<S, B> for MediaUpload<BodyStream>
+where
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Into<Bytes>,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        #[derive(Deserialize)]
+        struct QueryParams {
+            access_token: Option<String>,
+            user_id: Option<String>,
+            filename: Option<String>,
+        }
+
+        let (mut parts, body) = req.into_parts();
+
+        let auth_header: Option<TypedHeader<Authorization<Bearer>>> = parts.extract().await?;
+
+        let query = parts.uri.query().unwrap_or_default();
+        let query_params: QueryParams = match serde_html_form::from_str(query) {
+            Ok(params) => params,
+            Err(e) => {
+                error!(%query, "Failed to deserialize query parameters: {}", e);
+                return Err(Error::BadRequest(
+                    ErrorKind::Unknown,
+                    "Failed to read query parameters",
+                ));
+            }
+        };
+
+        let token = match &auth_header {
+            Some(TypedHeader(Authorization(bearer))) => Some(bearer.token()),
+            None => query_params.access_token.as_deref(),
+        }
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "Missing access token.",
+        ))?;
+
+        let sender_user = find_sender_user(token, query_params.user_id)?;
+
+        let content_type = parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        let body = BodyStream::from_request(Request::from_parts(parts, body), state).await?;
+
+        Ok(MediaUpload {
+            sender_user,
+            filename: query_params.filename,
+            content_type,
+            body,
+        })
+    }
+}
+
+/// Finds the user an access token belongs to, which is either a device or an appservice
+/// masquerading as one of its users.
+fn find_sender_user(token: &str, user_id: Option<String>) -> Result<OwnedUserId> {
+    let appservices = services().appservice.all()?;
+    let appservice_registration = appservices.iter().find(|(_id, registration)| {
+        registration
+            .get("as_token")
+            .and_then(|as_token| as_token.as_str())
+            .map_or(false, |as_token| token == as_token)
+    });
+
+    if let Some((_id, registration)) = appservice_registration {
+        let user_id = match user_id {
+            Some(user_id) => UserId::parse(user_id).map_err(|_| {
+                Error::BadRequest(ErrorKind::InvalidParam, "Invalid user_id parameter.")
+            })?,
+            None => UserId::parse_with_server_name(
+                registration
+                    .get("sender_localpart")
+                    .and_then(|localpart| localpart.as_str())
+                    .ok_or_else(|| Error::bad_config("Appservice has no sender_localpart."))?,
+                services().globals.server_name(),
+            )
+            .map_err(|_| Error::bad_config("Appservice sender_localpart is invalid."))?,
+        };
+
+        if !services().users.exists(&user_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "User does not exist.",
+            ));
+        }
+
+        return Ok(user_id);
+    }
+
+    match services().users.find_from_token(token)? {
+        Some((user_id, _device_id)) => Ok(user_id),
+        None => Err(Error::BadRequest(
+            ErrorKind::UnknownToken { soft_logout: false },
+            "Unknown access token.",
+        )),
+    }
+}
+
 struct XMatrix {
     origin: OwnedServerName,
+    destination: Option<OwnedServerName>,
     key: String, // KeyName?
     sig: String,
 }
 
+/// Builds the JSON object that the origin server signed for a request, see
+/// https://spec.matrix.org/v1.6/server-server-api/#request-authentication
+fn x_matrix_request_map(
+    x_matrix: &XMatrix,
+    method: &str,
+    uri: &str,
+    destination: &str,
+    content: Option<&CanonicalJsonValue>,
+) -> BTreeMap<String, CanonicalJsonValue> {
+    let origin_signatures = BTreeMap::from_iter([(
+        x_matrix.key.clone(),
+        CanonicalJsonValue::String(x_matrix.sig.clone()),
+    )]);
+
+    let signatures = BTreeMap::from_iter([(
+        x_matrix.origin.as_str().to_owned(),
+        CanonicalJsonValue::Object(origin_signatures),
+    )]);
+
+    let mut request_map = BTreeMap::from_iter([
+        (
+            "method".to_owned(),
+            CanonicalJsonValue::String(method.to_owned()),
+        ),
+        ("uri".to_owned(), CanonicalJsonValue::String(uri.to_owned())),
+        (
+            "origin".to_owned(),
+            CanonicalJsonValue::String(x_matrix.origin.as_str().to_owned()),
+        ),
+        (
+            "destination".to_owned(),
+            CanonicalJsonValue::String(destination.to_owned()),
+        ),
+        (
+            "signatures".to_owned(),
+            CanonicalJsonValue::Object(signatures),
+        ),
+    ]);
+
+    if let Some(content) = content {
+        request_map.insert("content".to_owned(), content.clone());
+    }
+
+    request_map
+}
+
 impl Credentials for XMatrix {
     const SCHEME: &'static str = "X-Matrix";
 
@@ -328,6 +523,7 @@ impl Credentials for XMatrix {
             .trim_start();
 
         let mut origin = None;
+        let mut destination = None;
         let mut key = None;
         let mut sig = None;
 
@@ -344,6 +540,7 @@ impl Credentials for XMatrix {
             // FIXME: Catch multiple fields of the same name
             match name {
                 "origin" => origin = Some(value.try_into().ok()?),
+                "destination" => destination = Some(value.try_into().ok()?),
                 "key" => key = Some(value.to_owned()),
                 "sig" => sig = Some(value.to_owned()),
                 _ => debug!(
@@ -355,6 +552,7 @@ impl Credentials for XMatrix {
 
         Some(Self {
             origin: origin?,
+            destination,
             key: key?,
             sig: sig?,
         })
@@ -425,3 +623,135 @@ where
 
     Ok(vec.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use axum::{body::Body, extract::FromRequest};
+    use http::Request;
+    use ruma::{
+        api::{
+            client::{error::ErrorKind, message::send_message_event},
+            federation::transactions::send_transaction_message,
+        },
+        serde::Base64,
+        signatures::{sign_json, verify_json, Ed25519KeyPair},
+        CanonicalJsonValue,
+    };
+
+    use super::{x_matrix_request_map, XMatrix};
+    use crate::{utils::testing, Error, Ruma};
+
+    /// Signs a request like a remote server would and returns its X-Matrix header fields and the
+    /// public keys of the remote server.
+    fn signed_request(uri: &str) -> (XMatrix, BTreeMap<String, BTreeMap<String, Base64>>) {
+        let keypair =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "1".to_owned()).unwrap();
+
+        let mut request_map = BTreeMap::from_iter([
+            (
+                "method".to_owned(),
+                CanonicalJsonValue::String("GET".to_owned()),
+            ),
+            ("uri".to_owned(), CanonicalJsonValue::String(uri.to_owned())),
+            (
+                "origin".to_owned(),
+                CanonicalJsonValue::String("remote.test".to_owned()),
+            ),
+            (
+                "destination".to_owned(),
+                CanonicalJsonValue::String("local.test".to_owned()),
+            ),
+        ]);
+        sign_json("remote.test", &keypair, &mut request_map).unwrap();
+
+        let sig = match &request_map["signatures"] {
+            CanonicalJsonValue::Object(signatures) => match &signatures["remote.test"] {
+                CanonicalJsonValue::Object(signatures) => match &signatures["ed25519:1"] {
+                    CanonicalJsonValue::String(sig) => sig.clone(),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+
+        let x_matrix = XMatrix {
+            origin: "remote.test".try_into().unwrap(),
+            destination: None,
+            key: "ed25519:1".to_owned(),
+            sig,
+        };
+        let keys = BTreeMap::from_iter([(
+            "remote.test".to_owned(),
+            BTreeMap::from_iter([(
+                "ed25519:1".to_owned(),
+                Base64::new(keypair.public_key().to_vec()),
+            )]),
+        )]);
+
+        (x_matrix, keys)
+    }
+
+    #[test]
+    fn valid_x_matrix_signature() {
+        let (x_matrix, keys) = signed_request("/_matrix/federation/v1/version");
+        let request_map = x_matrix_request_map(
+            &x_matrix,
+            "GET",
+            "/_matrix/federation/v1/version",
+            "local.test",
+            None,
+        );
+
+        assert!(verify_json(&keys, &request_map).is_ok());
+    }
+
+    #[test]
+    fn invalid_x_matrix_signature() {
+        let (x_matrix, keys) = signed_request("/_matrix/federation/v1/version");
+        let request_map = x_matrix_request_map(
+            &x_matrix,
+            "GET",
+            "/_matrix/federation/v1/query/profile",
+            "local.test",
+            None,
+        );
+
+        assert!(verify_json(&keys, &request_map).is_err());
+    }
+
+    #[tokio::test]
+    async fn federation_requests_have_their_own_size_limit() {
+        // The test server allows 1 MB for federation requests and the default 20 MB otherwise
+        testing::services();
+        let request = |path: &str| {
+            Request::builder()
+                .method("PUT")
+                .uri(path)
+                .body(Body::from(vec![b' '; 1_500_000]))
+                .unwrap()
+        };
+
+        let federation = Ruma::<send_transaction_message::v1::Request>::from_request(
+            request("/_matrix/federation/v1/send/1"),
+            &(),
+        )
+        .await;
+        assert!(matches!(
+            federation,
+            Err(Error::BadRequest(ErrorKind::TooLarge, _))
+        ));
+
+        let client = Ruma::<send_message_event::v3::Request>::from_request(
+            request("/_matrix/client/v3/rooms/!room:example.com/send/m.room.message/1"),
+            &(),
+        )
+        .await;
+        assert!(!matches!(
+            client,
+            Err(Error::BadRequest(ErrorKind::TooLarge, _))
+        ));
+    }
+}
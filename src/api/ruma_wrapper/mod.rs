@@ -17,6 +17,8 @@ pub struct Ruma<T> {
     // This is None when body is not a valid string
     pub json_body: Option<CanonicalJsonValue>,
     pub from_appservice: bool,
+    /// The ID of the appservice whose as_token was used
+    pub appservice_id: Option<String>,
 }
 
 impl<T> Deref for Ruma<T> {
@@ -27,6 +29,17 @@ impl<T> Deref for Ruma<T> {
     }
 }
 
+/// Extractor for media uploads
+///
+/// Unlike [`Ruma`], this doesn't read the request body into memory. The body is handed over as a
+/// stream, so large files can be written to disk chunk by chunk.
+pub struct MediaUpload<B> {
+    pub sender_user: OwnedUserId,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub body: B,
+}
+
 #[derive(Clone)]
 pub struct RumaResponse<T>(pub T);
 
@@ -2,12 +2,18 @@
 
 use crate::{
     api::client_server::{self, claim_keys_helper, get_keys_helper},
-    service::pdu::{gen_event_id_canonical_json, PduBuilder},
-    services, utils, Error, PduEvent, Result, Ruma,
+    service::{
+        pdu::{gen_event_id_canonical_json, PduBuilder},
+        users::Profile,
+    },
+    services, utils, DestinationOverride, Error, FederationCategory, PduEvent, Result, Ruma,
 };
 use axum::{response::IntoResponse, Json};
 use get_profile_information::v1::ProfileField;
-use http::header::{HeaderValue, AUTHORIZATION};
+use http::{
+    header::{HeaderValue, AUTHORIZATION, RETRY_AFTER},
+    StatusCode,
+};
 
 use ruma::{
     api::{
@@ -20,6 +26,7 @@ use ruma::{
             discovery::{get_server_keys, get_server_version, ServerSigningKeys, VerifyKey},
             event::{get_event, get_missing_events, get_room_state, get_room_state_ids},
             keys::{claim_keys, get_keys},
+            knock::{create_knock_event_template, send_knock},
             membership::{create_invite, create_join_event, prepare_join_event},
             query::{get_profile_information, get_room_information},
             transactions::{
@@ -33,11 +40,8 @@ use ruma::{
     directory::{Filter, RoomNetwork},
     events::{
         receipt::{ReceiptEvent, ReceiptEventContent, ReceiptType},
-        room::{
-            join_rules::{JoinRule, RoomJoinRulesEventContent},
-            member::{MembershipState, RoomMemberEventContent},
-        },
-        StateEventType, TimelineEventType,
+        room::member::{MembershipState, RoomMemberEventContent},
+        TimelineEventType,
     },
     serde::{Base64, JsonObject, Raw},
     to_device::DeviceIdOrAllDevices,
@@ -47,7 +51,7 @@ use ruma::{
 };
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet, VecDeque},
     fmt::Debug,
     mem,
     net::{IpAddr, SocketAddr},
@@ -55,6 +59,7 @@ use std::{
     time::{Duration, Instant, SystemTime},
 };
 
+use tokio::sync::Semaphore;
 use tracing::{debug, error, trace, warn};
 
 /// Wraps either an literal IP address plus port, or a hostname plus complement
@@ -96,7 +101,7 @@ impl FedDest {
         }
     }
 
-    fn hostname(&self) -> String {
+    pub(crate) fn hostname(&self) -> String {
         match &self {
             Self::Literal(addr) => addr.ip().to_string(),
             Self::Named(host, _) => host.clone(),
@@ -278,6 +283,23 @@ where
                 );
             }
 
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                if let Some(retry_after) = http_response_builder
+                    .headers_ref()
+                    .and_then(|headers| headers.get(RETRY_AFTER))
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| parse_retry_after(value, SystemTime::now()))
+                {
+                    debug!("{destination} asked us to retry after {retry_after:?}");
+                    services()
+                        .globals
+                        .federation_retry_after
+                        .write()
+                        .unwrap()
+                        .insert(destination.to_owned(), Instant::now() + retry_after);
+                }
+            }
+
             let http_response = http_response_builder
                 .body(body)
                 .expect("reqwest body is valid http body");
@@ -322,6 +344,22 @@ where
     }
 }
 
+/// Parses the value of a `Retry-After` header, which is either a number of seconds or an
+/// HTTP-date. The result is capped at one day, like our own backoff.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+
+    let retry_after = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(now)
+            .unwrap_or_default(),
+    };
+
+    Some(retry_after.min(Duration::from_secs(60 * 60 * 24)))
+}
+
 fn get_ip_with_port(destination_str: &str) -> Option<FedDest> {
     if let Ok(destination) = destination_str.parse::<SocketAddr>() {
         Some(FedDest::Literal(destination))
@@ -345,6 +383,28 @@ fn add_port_to_hostname(destination_str: &str) -> FedDest {
 /// Numbers in comments below refer to bullet points in linked section of specification
 async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDest) {
     debug!("Finding actual destination for {destination}");
+
+    if let Some(destination_override) = services().globals.destination_override(destination) {
+        debug!("0: Destination override in config");
+        let actual_destination = overridden_destination(destination, destination_override);
+
+        // The federation client resolves the SNI name to the configured address
+        services()
+            .globals
+            .tls_name_override
+            .write()
+            .unwrap()
+            .insert(
+                actual_destination.hostname(),
+                (
+                    vec![destination_override.connect_addr.ip()],
+                    destination_override.connect_addr.port(),
+                ),
+            );
+
+        return (actual_destination.clone(), actual_destination);
+    }
+
     let destination_str = destination.as_str().to_owned();
     let mut hostname = destination_str.clone();
     let actual_destination = match get_ip_with_port(&destination_str) {
@@ -475,6 +535,21 @@ async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDe
     (actual_destination, hostname)
 }
 
+fn overridden_destination(
+    destination: &ServerName,
+    destination_override: &DestinationOverride,
+) -> FedDest {
+    let sni = destination_override
+        .sni
+        .clone()
+        .unwrap_or_else(|| destination.host().to_owned());
+
+    FedDest::Named(
+        sni,
+        format!(":{}", destination_override.connect_addr.port()),
+    )
+}
+
 async fn query_srv_record(hostname: &'_ str) -> Option<FedDest> {
     let hostname = hostname.trim_end_matches('.');
     if let Ok(Some(host_port)) = services()
@@ -500,7 +575,7 @@ async fn query_srv_record(hostname: &'_ str) -> Option<FedDest> {
 async fn request_well_known(destination: &str) -> Option<String> {
     let response = services()
         .globals
-        .default_client()
+        .well_known_client()
         .get(&format!("https://{destination}/.well-known/matrix/server"))
         .send()
         .await;
@@ -526,13 +601,18 @@ pub async fn get_server_version_route(
     }
 
     Ok(get_server_version::v1::Response {
-        server: Some(get_server_version::v1::Server {
-            name: Some("Conduit".to_owned()),
-            version: Some(env!("CARGO_PKG_VERSION").to_owned()),
-        }),
+        server: Some(server_version()),
     })
 }
 
+/// The name and version of the server software, as reported to other servers.
+fn server_version() -> get_server_version::v1::Server {
+    get_server_version::v1::Server {
+        name: Some("Conduit".to_owned()),
+        version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+    }
+}
+
 /// # `GET /_matrix/key/v2/server`
 ///
 /// Gets the public signing keys of this server.
@@ -666,6 +746,12 @@ pub fn parse_incoming_pdu(
 
     let room_version_id = services().rooms.state.get_room_version(&room_id)?;
 
+    utils::validate_canonical_event(
+        pdu.get(),
+        &room_version_id,
+        services().globals.max_event_bytes(),
+    )?;
+
     let (event_id, value) = match gen_event_id_canonical_json(&pdu, &room_version_id) {
         Ok(t) => t,
         Err(_) => {
@@ -679,6 +765,18 @@ pub fn parse_incoming_pdu(
     Ok((event_id, value, room_id))
 }
 
+/// Fails with `M_FORBIDDEN` if the operator disabled this part of federation.
+fn check_federation_category(category: FederationCategory) -> Result<()> {
+    if !services().globals.config.federation_allows(category) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This server does not allow this kind of federation request.",
+        ));
+    }
+
+    Ok(())
+}
+
 /// # `PUT /_matrix/federation/v1/send/{txnId}`
 ///
 /// Push EDUs and PDUs to this server.
@@ -688,12 +786,41 @@ pub async fn send_transaction_message_route(
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
+    check_federation_category(FederationCategory::IncomingEvents)?;
 
     let sender_servername = body
         .sender_servername
         .as_ref()
         .expect("server is authenticated");
 
+    // Servers send their transactions one after another, so we don't let a single server use up
+    // more than a few workers
+    let txn_ratelimiter = Arc::clone(
+        services()
+            .globals
+            .servername_txn_ratelimiter
+            .write()
+            .unwrap()
+            .entry(sender_servername.to_owned())
+            .or_insert_with(|| {
+                Arc::new(Semaphore::new(
+                    services().globals.max_concurrent_transactions_per_origin(),
+                ))
+            }),
+    );
+    let _permit = txn_ratelimiter.try_acquire_owned().map_err(|_| {
+        warn!(
+            "Rejecting transaction {} from {}: too many concurrent transactions",
+            body.transaction_id, sender_servername
+        );
+        Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: Some(Duration::from_secs(1)),
+            },
+            "Too many concurrent transactions from this server.",
+        )
+    })?;
+
     let mut resolved_map = BTreeMap::new();
 
     let pub_key_map = RwLock::new(BTreeMap::new());
@@ -1121,12 +1248,16 @@ pub async fn get_missing_events_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
-    let mut queued_events = body.latest_events.clone();
-    let mut events = Vec::new();
+    let events = collect_missing_events(
+        &body.latest_events,
+        &body.earliest_events,
+        u64::from(body.limit) as usize,
+        |event_id| {
+            let pdu = match services().rooms.timeline.get_pdu_json(event_id)? {
+                Some(pdu) => pdu,
+                None => return Ok(None),
+            };
 
-    let mut i = 0;
-    while i < queued_events.len() && events.len() < u64::from(body.limit) as usize {
-        if let Some(pdu) = services().rooms.timeline.get_pdu_json(&queued_events[i])? {
             let room_id_str = pdu
                 .get("room_id")
                 .and_then(|val| val.as_str())
@@ -1138,7 +1269,7 @@ pub async fn get_missing_events_route(
             if event_room_id != body.room_id {
                 warn!(
                     "Evil event detected: Event {} found while searching in room {}",
-                    queued_events[i], body.room_id
+                    event_id, body.room_id
                 );
                 return Err(Error::BadRequest(
                     ErrorKind::InvalidParam,
@@ -1146,41 +1277,78 @@ pub async fn get_missing_events_route(
                 ));
             }
 
-            if body.earliest_events.contains(&queued_events[i]) {
-                i += 1;
-                continue;
-            }
-
             if !services().rooms.state_accessor.server_can_see_event(
                 sender_servername,
                 &body.room_id,
-                &queued_events[i],
+                event_id,
             )? {
-                i += 1;
-                continue;
+                return Ok(None);
             }
 
-            queued_events.extend_from_slice(
-                &serde_json::from_value::<Vec<OwnedEventId>>(
+            let prev_events =
+                serde_json::from_value::<Vec<OwnedEventId>>(
                     serde_json::to_value(pdu.get("prev_events").cloned().ok_or_else(|| {
                         Error::bad_database("Event in db has no prev_events field.")
                     })?)
                     .expect("canonical json is valid json value"),
                 )
-                .map_err(|_| Error::bad_database("Invalid prev_events content in pdu in db."))?,
-            );
-            events.push(PduEvent::convert_to_outgoing_federation_event(pdu));
+                .map_err(|_| Error::bad_database("Invalid prev_events content in pdu in db."))?;
+
+            Ok(Some((
+                prev_events,
+                PduEvent::convert_to_outgoing_federation_event(pdu),
+            )))
+        },
+    )?;
+
+    Ok(get_missing_events::v1::Response { events })
+}
+
+/// Walks the event graph backwards, breadth first, starting at the prev_events of `latest` and
+/// stopping at `earliest`. Every event is returned at most once and neither `latest` nor `earliest`
+/// events are part of the result.
+///
+/// `load` returns the prev_events and the value of an event, or `None` if the event is unknown
+/// or must not be returned, in which case the walk does not continue past it.
+fn collect_missing_events<T>(
+    latest: &[OwnedEventId],
+    earliest: &[OwnedEventId],
+    limit: usize,
+    mut load: impl FnMut(&EventId) -> Result<Option<(Vec<OwnedEventId>, T)>>,
+) -> Result<Vec<T>> {
+    let mut seen: HashSet<OwnedEventId> = latest.iter().chain(earliest).cloned().collect();
+    let mut queued_events = VecDeque::new();
+    let mut events = Vec::new();
+
+    for event_id in latest {
+        if let Some((prev_events, _)) = load(event_id)? {
+            queued_events.extend(prev_events);
         }
-        i += 1;
     }
 
-    Ok(get_missing_events::v1::Response { events })
+    while let Some(event_id) = queued_events.pop_front() {
+        if events.len() >= limit {
+            break;
+        }
+
+        if !seen.insert(event_id.clone()) {
+            continue;
+        }
+
+        if let Some((prev_events, value)) = load(&event_id)? {
+            queued_events.extend(prev_events);
+            events.push(value);
+        }
+    }
+
+    Ok(events)
 }
 
 /// # `GET /_matrix/federation/v1/event_auth/{roomId}/{eventId}`
 ///
 /// Retrieves the auth chain for a given event.
 ///
+/// - The event has to be in the requested room, which the requesting server has to be in
 /// - This does not include the event itself
 pub async fn get_event_authorization_route(
     body: Ruma<get_event_authorization::v1::Request>,
@@ -1227,6 +1395,13 @@ pub async fn get_event_authorization_route(
     let room_id = <&RoomId>::try_from(room_id_str)
         .map_err(|_| Error::bad_database("Invalid room id field in event in database"))?;
 
+    if room_id != body.room_id {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Event not found in this room.",
+        ));
+    }
+
     let auth_chain_ids = services()
         .rooms
         .auth_chain
@@ -1391,6 +1566,7 @@ pub async fn create_join_event_template_route(
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
+    check_federation_category(FederationCategory::IncomingEvents)?;
 
     if !services().rooms.metadata.exists(&body.room_id)? {
         return Err(Error::BadRequest(
@@ -1409,6 +1585,8 @@ pub async fn create_join_event_template_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
+    client_server::check_join_rate(&body.user_id, &body.room_id, false)?;
+
     let mutex_state = Arc::clone(
         services()
             .globals
@@ -1420,34 +1598,10 @@ pub async fn create_join_event_template_route(
     );
     let state_lock = mutex_state.lock().await;
 
-    // TODO: Conduit does not implement restricted join rules yet, we always reject
-    let join_rules_event = services().rooms.state_accessor.room_state_get(
-        &body.room_id,
-        &StateEventType::RoomJoinRules,
-        "",
-    )?;
-
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-        .as_ref()
-        .map(|join_rules_event| {
-            serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                warn!("Invalid join rules event: {}", e);
-                Error::bad_database("Invalid join rules event in db.")
-            })
-        })
-        .transpose()?;
-
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. } | JoinRule::KnockRestricted { .. }
-        ) {
-            return Err(Error::BadRequest(
-                ErrorKind::UnableToAuthorizeJoin,
-                "Conduit does not support restricted rooms yet.",
-            ));
-        }
-    }
+    let join_authorized_via_users_server = services()
+        .rooms
+        .state_accessor
+        .restricted_join_authoriser(&body.room_id, &body.user_id)?;
 
     let room_version_id = services().rooms.state.get_room_version(&body.room_id)?;
     if !body.ver.contains(&room_version_id) {
@@ -1467,7 +1621,7 @@ pub async fn create_join_event_template_route(
         membership: MembershipState::Join,
         third_party_invite: None,
         reason: None,
-        join_authorized_via_users_server: None,
+        join_authorized_via_users_server,
     })
     .expect("member event is valid value");
 
@@ -1494,14 +1648,86 @@ pub async fn create_join_event_template_route(
     })
 }
 
+/// Checks that a PDU sent to `/send_join` or `/send_knock` is a membership event with the
+/// expected `membership` for a user that belongs to the requesting server, and returns that user.
+fn validate_membership_event(
+    value: &CanonicalJsonObject,
+    sender_servername: &ServerName,
+    expected_membership: &str,
+) -> Result<OwnedUserId> {
+    let get_str = |field| match value.get(field) {
+        Some(CanonicalJsonValue::String(s)) => Some(s.as_str()),
+        _ => None,
+    };
+
+    if get_str("type") != Some("m.room.member") {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event is not a membership event.",
+        ));
+    }
+
+    let sender = get_str("sender")
+        .and_then(|s| OwnedUserId::try_from(s).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event sender is invalid.",
+        ))?;
+
+    if get_str("state_key") != Some(sender.as_str()) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "State key does not match the sender.",
+        ));
+    }
+
+    if sender.server_name() != sender_servername {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Not allowed to send membership events on behalf of another server.",
+        ));
+    }
+
+    let membership = match value.get("content") {
+        Some(CanonicalJsonValue::Object(content)) => content.get("membership"),
+        _ => None,
+    };
+    if !matches!(membership, Some(CanonicalJsonValue::String(m)) if m == expected_membership) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event has the wrong membership.",
+        ));
+    }
+
+    Ok(sender)
+}
+
+/// Returns the user that authorised a join to a restricted room, see
+/// `join_authorised_via_users_server`.
+fn join_authoriser_of(value: &CanonicalJsonObject) -> Option<OwnedUserId> {
+    match value.get("content") {
+        Some(CanonicalJsonValue::Object(content)) => {
+            match content.get("join_authorised_via_users_server") {
+                Some(CanonicalJsonValue::String(user_id)) => {
+                    OwnedUserId::try_from(user_id.as_str()).ok()
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
 async fn create_join_event(
     sender_servername: &ServerName,
     room_id: &RoomId,
+    expected_event_id: &EventId,
     pdu: &RawJsonValue,
 ) -> Result<create_join_event::v1::RoomState> {
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
+    check_federation_category(FederationCategory::IncomingEvents)?;
 
     if !services().rooms.metadata.exists(room_id)? {
         return Err(Error::BadRequest(
@@ -1515,35 +1741,6 @@ async fn create_join_event(
         .event_handler
         .acl_check(sender_servername, room_id)?;
 
-    // TODO: Conduit does not implement restricted join rules yet, we always reject
-    let join_rules_event = services().rooms.state_accessor.room_state_get(
-        room_id,
-        &StateEventType::RoomJoinRules,
-        "",
-    )?;
-
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-        .as_ref()
-        .map(|join_rules_event| {
-            serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                warn!("Invalid join rules event: {}", e);
-                Error::bad_database("Invalid join rules event in db.")
-            })
-        })
-        .transpose()?;
-
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. } | JoinRule::KnockRestricted { .. }
-        ) {
-            return Err(Error::BadRequest(
-                ErrorKind::UnableToAuthorizeJoin,
-                "Conduit does not support restricted rooms yet.",
-            ));
-        }
-    }
-
     // We need to return the state prior to joining, let's keep a reference to that here
     let shortstatehash = services()
         .rooms
@@ -1570,6 +1767,40 @@ async fn create_join_event(
         }
     };
 
+    if &*event_id != expected_event_id {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event id does not match the event.",
+        ));
+    }
+
+    let sender = validate_membership_event(&value, sender_servername, "join")?;
+    let counts_against_rate = client_server::check_join_rate(&sender, room_id, false)?;
+
+    // Joins to restricted rooms need to be signed by the server of the authorising user
+    let mut value = value;
+    let signed_event = match join_authoriser_of(&value) {
+        Some(authoriser) if authoriser.server_name() == services().globals.server_name() => {
+            // The auth rules only check the authoriser's power level, not whether the joining
+            // user is allowed in
+            services()
+                .rooms
+                .state_accessor
+                .check_join_authoriser(room_id, &sender, &authoriser)?;
+
+            ruma::signatures::hash_and_sign_event(
+                services().globals.server_name().as_str(),
+                services().globals.keypair(),
+                &mut value,
+                &room_version_id,
+            )
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Failed to sign event."))?;
+
+            Some(to_raw_value(&value).expect("CanonicalJson can be serialized to JSON"))
+        }
+        _ => None,
+    };
+
     let origin: OwnedServerName = serde_json::from_value(
         serde_json::to_value(value.get("origin").ok_or(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -1600,6 +1831,10 @@ async fn create_join_event(
         ))?;
     drop(mutex_lock);
 
+    if counts_against_rate {
+        client_server::record_join(room_id);
+    }
+
     let state_ids = services()
         .rooms
         .state_accessor
@@ -1630,7 +1865,7 @@ async fn create_join_event(
             .filter_map(|(_, id)| services().rooms.timeline.get_pdu_json(id).ok().flatten())
             .map(PduEvent::convert_to_outgoing_federation_event)
             .collect(),
-        event: None, // TODO: handle restricted joins
+        event: signed_event,
     })
 }
 
@@ -1645,7 +1880,8 @@ pub async fn create_join_event_v1_route(
         .as_ref()
         .expect("server is authenticated");
 
-    let room_state = create_join_event(sender_servername, &body.room_id, &body.pdu).await?;
+    let room_state =
+        create_join_event(sender_servername, &body.room_id, &body.event_id, &body.pdu).await?;
 
     Ok(create_join_event::v1::Response { room_state })
 }
@@ -1665,7 +1901,7 @@ pub async fn create_join_event_v2_route(
         auth_chain,
         state,
         event,
-    } = create_join_event(sender_servername, &body.room_id, &body.pdu).await?;
+    } = create_join_event(sender_servername, &body.room_id, &body.event_id, &body.pdu).await?;
     let room_state = create_join_event::v2::RoomState {
         members_omitted: false,
         auth_chain,
@@ -1677,6 +1913,217 @@ pub async fn create_join_event_v2_route(
     Ok(create_join_event::v2::Response { room_state })
 }
 
+/// # `GET /_matrix/federation/v1/make_knock/{roomId}/{userId}`
+///
+/// Creates a knock template.
+pub async fn create_knock_event_template_route(
+    body: Ruma<create_knock_event_template::v1::Request>,
+) -> Result<create_knock_event_template::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+    check_federation_category(FederationCategory::IncomingEvents)?;
+
+    if !services().rooms.metadata.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    if body.user_id.server_name() != sender_servername {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Not allowed to knock on behalf of another server.",
+        ));
+    }
+
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+
+    let mutex_state = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(body.room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    if !services()
+        .rooms
+        .state_accessor
+        .allows_knocking(&body.room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room does not allow knocking.",
+        ));
+    }
+
+    let room_version_id = services().rooms.state.get_room_version(&body.room_id)?;
+    if !body.ver.contains(&room_version_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::IncompatibleRoomVersion {
+                room_version: room_version_id,
+            },
+            "Room version not supported.",
+        ));
+    }
+
+    let content = to_raw_value(&RoomMemberEventContent {
+        avatar_url: None,
+        blurhash: None,
+        displayname: None,
+        is_direct: None,
+        membership: MembershipState::Knock,
+        third_party_invite: None,
+        reason: None,
+        join_authorized_via_users_server: None,
+    })
+    .expect("member event is valid value");
+
+    let (_pdu, mut pdu_json) = services().rooms.timeline.create_hash_and_sign_event(
+        PduBuilder {
+            event_type: TimelineEventType::RoomMember,
+            content,
+            unsigned: None,
+            state_key: Some(body.user_id.to_string()),
+            redacts: None,
+        },
+        &body.user_id,
+        &body.room_id,
+        &state_lock,
+    )?;
+
+    drop(state_lock);
+
+    pdu_json.remove("event_id");
+
+    Ok(create_knock_event_template::v1::Response {
+        room_version: room_version_id,
+        event: to_raw_value(&pdu_json).expect("CanonicalJson can be serialized to JSON"),
+    })
+}
+
+/// # `PUT /_matrix/federation/v1/send_knock/{roomId}/{eventId}`
+///
+/// Submits a signed knock event.
+pub async fn create_knock_event_v1_route(
+    body: Ruma<send_knock::v1::Request>,
+) -> Result<send_knock::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+    check_federation_category(FederationCategory::IncomingEvents)?;
+
+    if !services().rooms.metadata.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+
+    if !services()
+        .rooms
+        .state_accessor
+        .allows_knocking(&body.room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room does not allow knocking.",
+        ));
+    }
+
+    let room_version_id = services().rooms.state.get_room_version(&body.room_id)?;
+    let (event_id, value) = match gen_event_id_canonical_json(&body.pdu, &room_version_id) {
+        Ok(t) => t,
+        Err(_) => {
+            // Event could not be converted to canonical json
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Could not convert event to canonical json.",
+            ));
+        }
+    };
+
+    if event_id != body.event_id {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event id does not match the event.",
+        ));
+    }
+
+    validate_membership_event(&value, sender_servername, "knock")?;
+
+    let pub_key_map = RwLock::new(BTreeMap::new());
+
+    let mutex = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_federation
+            .write()
+            .unwrap()
+            .entry(body.room_id.to_owned())
+            .or_default(),
+    );
+    let mutex_lock = mutex.lock().await;
+    let pdu_id: Vec<u8> = services()
+        .rooms
+        .event_handler
+        .handle_incoming_pdu(
+            sender_servername,
+            &event_id,
+            &body.room_id,
+            value,
+            true,
+            &pub_key_map,
+        )
+        .await?
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Could not accept incoming PDU as timeline event.",
+        ))?;
+    drop(mutex_lock);
+
+    let pdu = services()
+        .rooms
+        .timeline
+        .get_pdu_from_id(&pdu_id)?
+        .ok_or_else(|| Error::bad_database("Knock event we just added is missing."))?;
+    let knock_room_state = services().rooms.state.calculate_invite_state(&pdu)?;
+
+    let servers = services()
+        .rooms
+        .state_cache
+        .room_servers(&body.room_id)
+        .filter_map(|r| r.ok())
+        .filter(|server| &**server != services().globals.server_name());
+
+    services().sending.send_pdu(servers, &pdu_id)?;
+
+    Ok(send_knock::v1::Response { knock_room_state })
+}
+
 /// # `PUT /_matrix/federation/v2/invite/{roomId}/{eventId}`
 ///
 /// Invites a remote user to a room.
@@ -1686,6 +2133,7 @@ pub async fn create_invite_route(
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
+    check_federation_category(FederationCategory::IncomingEvents)?;
 
     let sender_servername = body
         .sender_servername
@@ -1724,10 +2172,10 @@ pub async fn create_invite_route(
     // Generate event id
     let event_id = EventId::parse(format!(
         "${}",
-        ruma::signatures::reference_hash(&signed_event, &body.room_version)
-            .expect("ruma can calculate reference hashes")
+        utils::reference_hash(&signed_event, &body.room_version)
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invite event is invalid."))?
     ))
-    .expect("ruma's reference hashes are valid event ids");
+    .expect("reference hashes are valid event ids");
 
     // Add event_id back
     signed_event.insert(
@@ -1803,6 +2251,7 @@ pub async fn get_devices_route(
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
+    check_federation_category(FederationCategory::KeyQueries)?;
 
     let sender_servername = body
         .sender_servername
@@ -1853,6 +2302,13 @@ pub async fn get_room_information_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    if body.room_alias.server_name() != services().globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Room alias does not belong to this server.",
+        ));
+    }
+
     let room_id = services()
         .rooms
         .alias
@@ -1862,48 +2318,90 @@ pub async fn get_room_information_route(
             "Room alias not found.",
         ))?;
 
-    Ok(get_room_information::v1::Response {
-        room_id,
-        servers: vec![services().globals.server_name().to_owned()],
-    })
+    let servers = alias_servers(
+        services().globals.server_name(),
+        services()
+            .rooms
+            .state_cache
+            .room_servers(&room_id)
+            .filter_map(|r| r.ok()),
+    );
+
+    Ok(get_room_information::v1::Response { room_id, servers })
+}
+
+/// The servers that can be used to join a room: this server first, then the other servers in it.
+fn alias_servers(
+    own_server: &ServerName,
+    room_servers: impl Iterator<Item = OwnedServerName>,
+) -> Vec<OwnedServerName> {
+    let mut servers = vec![own_server.to_owned()];
+    servers.extend(room_servers.filter(|server| server != own_server));
+    servers
 }
 
 /// # `GET /_matrix/federation/v1/query/profile`
 ///
 /// Gets information on a profile.
+///
+/// - Only answers for local users, if `allow_profile_lookup_over_federation` is enabled
 pub async fn get_profile_information_route(
     body: Ruma<get_profile_information::v1::Request>,
 ) -> Result<get_profile_information::v1::Response> {
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
+    check_federation_category(FederationCategory::ProfileQueries)?;
+
+    if !services().globals.allow_profile_lookup_over_federation() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Profile lookup over federation is not allowed on this server.",
+        ));
+    }
+
+    if body.user_id.server_name() != services().globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "User does not belong to this server.",
+        ));
+    }
 
-    let mut displayname = None;
-    let mut avatar_url = None;
-    let mut blurhash = None;
+    let profile = services()
+        .users
+        .get_profile(&body.user_id, false)
+        .await?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Profile was not found.",
+        ))?;
 
-    match &body.field {
-        Some(ProfileField::DisplayName) => {
-            displayname = services().users.displayname(&body.user_id)?
-        }
+    Ok(profile_information(body.field.as_ref(), profile))
+}
+
+/// Only includes the requested field of the profile.
+fn profile_information(
+    field: Option<&ProfileField>,
+    profile: Profile,
+) -> get_profile_information::v1::Response {
+    let mut response = get_profile_information::v1::Response::new();
+
+    match field {
+        Some(ProfileField::DisplayName) => response.displayname = profile.displayname,
         Some(ProfileField::AvatarUrl) => {
-            avatar_url = services().users.avatar_url(&body.user_id)?;
-            blurhash = services().users.blurhash(&body.user_id)?
+            response.avatar_url = profile.avatar_url;
+            response.blurhash = profile.blurhash;
         }
         // TODO: what to do with custom
         Some(_) => {}
         None => {
-            displayname = services().users.displayname(&body.user_id)?;
-            avatar_url = services().users.avatar_url(&body.user_id)?;
-            blurhash = services().users.blurhash(&body.user_id)?;
+            response.displayname = profile.displayname;
+            response.avatar_url = profile.avatar_url;
+            response.blurhash = profile.blurhash;
         }
     }
 
-    Ok(get_profile_information::v1::Response {
-        blurhash,
-        displayname,
-        avatar_url,
-    })
+    response
 }
 
 /// # `POST /_matrix/federation/v1/user/keys/query`
@@ -1913,6 +2411,7 @@ pub async fn get_keys_route(body: Ruma<get_keys::v1::Request>) -> Result<get_key
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
+    check_federation_category(FederationCategory::KeyQueries)?;
 
     let result = get_keys_helper(None, &body.device_keys, |u| {
         Some(u.server_name()) == body.sender_servername.as_deref()
@@ -1935,6 +2434,7 @@ pub async fn claim_keys_route(
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
+    check_federation_category(FederationCategory::KeyQueries)?;
 
     let result = claim_keys_helper(&body.one_time_keys).await?;
 
@@ -1945,7 +2445,21 @@ pub async fn claim_keys_route(
 
 #[cfg(test)]
 mod tests {
-    use super::{add_port_to_hostname, get_ip_with_port, FedDest};
+    use std::time::{Duration, SystemTime};
+
+    use ruma::server_name;
+
+    use std::collections::HashMap;
+
+    use ruma::{CanonicalJsonObject, OwnedEventId};
+    use serde_json::json;
+
+    use super::{
+        add_port_to_hostname, alias_servers, collect_missing_events, get_ip_with_port,
+        overridden_destination, parse_retry_after, profile_information, server_version,
+        validate_membership_event, FedDest, ProfileField,
+    };
+    use crate::{service::users::Profile, DestinationOverride};
 
     #[test]
     fn ips_get_default_ports() {
@@ -1986,4 +2500,192 @@ mod tests {
             FedDest::Named(String::from("example.com"), String::from(":1337"))
         )
     }
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(
+            parse_retry_after("120", SystemTime::now()),
+            Some(Duration::from_secs(120))
+        );
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777 - 120);
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        // Dates in the past mean we can retry right away
+        assert_eq!(
+            parse_retry_after(
+                "Sun, 06 Nov 1994 08:49:37 GMT",
+                now + Duration::from_secs(3600)
+            ),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        assert_eq!(
+            parse_retry_after("99999999", SystemTime::now()),
+            Some(Duration::from_secs(60 * 60 * 24))
+        );
+    }
+
+    #[test]
+    fn retry_after_invalid() {
+        assert_eq!(parse_retry_after("soon", SystemTime::now()), None);
+    }
+
+    #[test]
+    fn destination_override_uses_server_name_as_sni() {
+        let destination_override = DestinationOverride {
+            connect_addr: "10.0.0.5:8449".parse().unwrap(),
+            sni: None,
+        };
+        assert_eq!(
+            overridden_destination(server_name!("example.com"), &destination_override),
+            FedDest::Named(String::from("example.com"), String::from(":8449"))
+        );
+    }
+
+    #[test]
+    fn destination_override_uses_configured_sni() {
+        let destination_override = DestinationOverride {
+            connect_addr: "10.0.0.5:8448".parse().unwrap(),
+            sni: Some(String::from("matrix.example.com")),
+        };
+        assert_eq!(
+            overridden_destination(server_name!("example.com"), &destination_override),
+            FedDest::Named(String::from("matrix.example.com"), String::from(":8448"))
+        );
+    }
+
+    fn join_event(sender: &str, state_key: &str, membership: &str) -> CanonicalJsonObject {
+        serde_json::from_value(json!({
+            "type": "m.room.member",
+            "sender": sender,
+            "state_key": state_key,
+            "content": { "membership": membership },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn join_event_from_own_user_is_accepted() {
+        let event = join_event("@alice:remote.test", "@alice:remote.test", "join");
+        assert!(validate_membership_event(&event, server_name!("remote.test"), "join").is_ok());
+    }
+
+    #[test]
+    fn join_event_for_other_server_is_rejected() {
+        let event = join_event("@alice:other.test", "@alice:other.test", "join");
+        assert!(validate_membership_event(&event, server_name!("remote.test"), "join").is_err());
+    }
+
+    #[test]
+    fn join_event_with_other_membership_is_rejected() {
+        let event = join_event("@alice:remote.test", "@alice:remote.test", "leave");
+        assert!(validate_membership_event(&event, server_name!("remote.test"), "join").is_err());
+
+        let event = join_event("@alice:remote.test", "@bob:remote.test", "join");
+        assert!(validate_membership_event(&event, server_name!("remote.test"), "join").is_err());
+    }
+
+    /// D has the prev_events B and C, which both point at A.
+    fn diamond(latest: &[&str], earliest: &[&str], limit: usize) -> Vec<&'static str> {
+        let graph: HashMap<&str, (&str, Vec<&str>)> = [
+            ("$a", ("$a", vec![])),
+            ("$b", ("$b", vec!["$a"])),
+            ("$c", ("$c", vec!["$a"])),
+            ("$d", ("$d", vec!["$b", "$c"])),
+        ]
+        .into_iter()
+        .collect();
+        let ids = |ids: &[&str]| -> Vec<OwnedEventId> {
+            ids.iter().map(|id| (*id).try_into().unwrap()).collect()
+        };
+
+        collect_missing_events(&ids(latest), &ids(earliest), limit, |event_id| {
+            Ok(graph
+                .get(event_id.as_str())
+                .map(|(id, prev_events)| (ids(prev_events), *id)))
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn missing_events_are_returned_once() {
+        assert_eq!(diamond(&["$d"], &[], 10), ["$b", "$c", "$a"]);
+    }
+
+    #[test]
+    fn missing_events_stop_at_earliest_and_limit() {
+        assert_eq!(diamond(&["$d"], &["$a"], 10), ["$b", "$c"]);
+        assert_eq!(diamond(&["$d"], &[], 1), ["$b"]);
+        assert_eq!(diamond(&["$b", "$d"], &[], 10), ["$a", "$c"]);
+    }
+
+    #[test]
+    fn profile_query_returns_requested_fields() {
+        let profile = Profile {
+            displayname: Some("Alice".to_owned()),
+            avatar_url: Some("mxc://example.com/avatar".into()),
+            blurhash: Some("LEHV6nWB2yk8".to_owned()),
+        };
+
+        let response = profile_information(None, profile.clone());
+        assert_eq!(response.displayname.as_deref(), Some("Alice"));
+        assert_eq!(response.avatar_url, profile.avatar_url);
+        assert_eq!(response.blurhash, profile.blurhash);
+
+        let response = profile_information(Some(&ProfileField::DisplayName), profile.clone());
+        assert_eq!(response.displayname.as_deref(), Some("Alice"));
+        assert!(response.avatar_url.is_none());
+        assert!(response.blurhash.is_none());
+
+        let response = profile_information(Some(&ProfileField::AvatarUrl), profile.clone());
+        assert!(response.displayname.is_none());
+        assert_eq!(response.avatar_url, profile.avatar_url);
+    }
+
+    #[test]
+    fn directory_query_lists_own_server_first() {
+        let servers = alias_servers(
+            server_name!("example.com"),
+            [
+                server_name!("remote.test").to_owned(),
+                server_name!("example.com").to_owned(),
+                server_name!("other.test").to_owned(),
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(
+            servers,
+            [
+                server_name!("example.com"),
+                server_name!("remote.test"),
+                server_name!("other.test")
+            ]
+        );
+    }
+
+    #[test]
+    fn reported_server_version() {
+        let server = server_version();
+        assert_eq!(server.name.as_deref(), Some("Conduit"));
+
+        // Like 0.7.0-alpha
+        let version = server.version.unwrap();
+        let (release, _) = version.split_once('-').unwrap_or((&version, ""));
+        let parts: Vec<_> = release.split('.').collect();
+        assert_eq!(parts.len(), 3, "{version}");
+        assert!(
+            parts.iter().all(|part| part.parse::<u32>().is_ok()),
+            "{version}"
+        );
+    }
 }
@@ -1,16 +1,25 @@
 use std::{
     collections::BTreeMap,
     fmt,
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
 };
 
-use ruma::{OwnedServerName, RoomVersionId};
+use ruma::{
+    events::room::history_visibility::HistoryVisibility,
+    push::{ConditionalPushRule, Ruleset},
+    OwnedRoomAliasId, OwnedServerName, RoomVersionId,
+};
 use serde::{de::IgnoredAny, Deserialize};
 use tracing::warn;
 
+mod oidc;
 mod proxy;
+mod smtp;
 
 use self::proxy::ProxyConfig;
+pub use self::{oidc::OidcConfig, smtp::SmtpConfig};
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
@@ -18,53 +27,167 @@ pub struct Config {
     pub address: IpAddr,
     #[serde(default = "default_port")]
     pub port: u16,
+    pub unix_socket_path: Option<PathBuf>,
     pub tls: Option<TlsConfig>,
+    #[serde(default = "Vec::new")]
+    pub trusted_proxies: Vec<IpAddr>,
+    #[serde(default = "true_fn")]
+    pub http1_keep_alive: bool,
+    #[serde(default = "true_fn")]
+    pub http2: bool,
+    #[serde(default)]
+    pub http2_keep_alive_interval: u64,
+    #[serde(default = "default_http2_keep_alive_timeout")]
+    pub http2_keep_alive_timeout: u64,
 
     pub server_name: OwnedServerName,
     #[serde(default = "default_database_backend")]
     pub database_backend: String,
     pub database_path: String,
+    #[serde(default = "false_fn")]
+    pub require_migration_confirmation: bool,
+    #[serde(default = "false_fn")]
+    pub auto_migrate: bool,
     #[serde(default = "default_db_cache_capacity_mb")]
     pub db_cache_capacity_mb: f64,
     #[serde(default = "true_fn")]
     pub enable_lightning_bolt: bool,
     #[serde(default = "true_fn")]
     pub allow_check_for_updates: bool,
+    pub server_notice_motd: Option<String>,
     #[serde(default = "default_conduit_cache_capacity_modifier")]
     pub conduit_cache_capacity_modifier: f64,
     #[serde(default = "default_rocksdb_max_open_files")]
     pub rocksdb_max_open_files: i32,
+    #[serde(default)]
+    pub sqlite_group_commit_ms: u64,
+    #[serde(default = "default_count_allocation_block")]
+    pub count_allocation_block: u64,
     #[serde(default = "default_pdu_cache_capacity")]
     pub pdu_cache_capacity: u32,
+    #[serde(default = "default_state_cache_capacity")]
+    pub state_cache_capacity: u32,
+    #[serde(default = "default_auth_chain_cache_max_bytes")]
+    pub auth_chain_cache_max_bytes: u64,
+    #[serde(default)]
+    pub auth_chain_cache_warmup_rooms: usize,
+    #[serde(default = "default_max_auth_chain_depth")]
+    pub max_auth_chain_depth: usize,
     #[serde(default = "default_cleanup_second_interval")]
     pub cleanup_second_interval: u32,
+    #[serde(default)]
+    pub durable_event_types: Vec<String>,
+    #[serde(default)]
+    pub optimize_state_second_interval: u32,
+    pub referenced_events_keep_depth: Option<u64>,
+    #[serde(default)]
+    pub retention_second_interval: u32,
+    pub default_message_retention_days: Option<u32>,
+    pub outlier_retention_days: Option<u32>,
+    pub thumbnail_retention_days: Option<u32>,
     #[serde(default = "default_max_request_size")]
     pub max_request_size: u32,
+    pub max_federation_request_size: Option<u32>,
+    #[serde(default = "default_federation_max_redirects")]
+    pub federation_max_redirects: usize,
+    pub federation_bind_address: Option<IpAddr>,
+    #[serde(default = "default_media_max_decode_pixels")]
+    pub media_max_decode_pixels: u64,
+    #[serde(default)]
+    pub media_allowed_content_types: Vec<String>,
+    #[serde(default = "default_media_blocked_content_types")]
+    pub media_blocked_content_types: Vec<String>,
+    #[serde(default = "default_media_content_security_policy")]
+    pub media_content_security_policy: String,
     #[serde(default = "default_max_concurrent_requests")]
     pub max_concurrent_requests: u16,
+    pub federation_sender_fairness: Option<usize>,
+    #[serde(default = "true_fn")]
+    pub persist_federation_backoff: bool,
     #[serde(default = "default_max_fetch_prev_events")]
     pub max_fetch_prev_events: u16,
+    #[serde(default = "default_max_concurrent_transactions_per_origin")]
+    pub max_concurrent_transactions_per_origin: NonZeroUsize,
+    #[serde(default = "default_max_event_bytes")]
+    pub max_event_bytes: usize,
+    #[serde(default = "default_max_fetch_limit")]
+    pub max_fetch_limit: u64,
+    pub sync_max_since_gap: Option<u64>,
+    pub max_state_events_per_room: Option<usize>,
+    pub max_devices_per_user: Option<usize>,
+    pub max_to_device_events_per_device: Option<usize>,
+    pub max_rooms_per_user: Option<usize>,
+    pub max_joined_rooms_per_user: Option<usize>,
+    pub max_sync_connections_per_user: Option<usize>,
+    pub message_rate_limit: Option<RateLimitConfig>,
+    pub room_join_rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub device_limit_action: DeviceLimitAction,
+    #[serde(default)]
+    pub search_tokenizer: SearchTokenizer,
     #[serde(default = "false_fn")]
     pub allow_registration: bool,
     pub registration_token: Option<String>,
+    pub registration_shared_secret: Option<String>,
     #[serde(default = "true_fn")]
     pub allow_encryption: bool,
     #[serde(default = "false_fn")]
     pub allow_federation: bool,
     #[serde(default = "true_fn")]
+    pub federation_allow_incoming_events: bool,
+    #[serde(default = "true_fn")]
+    pub federation_allow_profile_queries: bool,
+    #[serde(default = "true_fn")]
+    pub federation_allow_key_queries: bool,
+    #[serde(default = "true_fn")]
     pub allow_room_creation: bool,
     #[serde(default = "true_fn")]
+    pub allow_presence: bool,
+    #[serde(default = "true_fn")]
+    pub federate_presence: bool,
+    #[serde(default = "true_fn")]
+    pub federate_typing: bool,
+    #[serde(default = "default_remote_profile_cache_ttl_secs")]
+    pub remote_profile_cache_ttl_secs: u64,
+    #[serde(default = "true_fn")]
+    pub allow_profile_lookup_over_federation: bool,
+    #[serde(default = "false_fn")]
+    pub user_directory_search_all_users: bool,
+    #[serde(default = "true_fn")]
+    pub notify_admins_of_reports: bool,
+    #[serde(default)]
+    pub push_rules: PushRulesConfig,
+    #[serde(default)]
+    pub profile_update_interval_secs: u64,
+    #[serde(default = "default_signing_key_refresh_interval_secs")]
+    pub signing_key_refresh_interval_secs: u64,
+    pub admin_room_alias: Option<OwnedRoomAliasId>,
+    #[serde(default = "true_fn")]
     pub allow_unstable_room_versions: bool,
     #[serde(default = "default_default_room_version")]
     pub default_room_version: RoomVersionId,
+    #[serde(default = "default_history_visibility")]
+    pub default_history_visibility: HistoryVisibility,
     pub well_known_client: Option<String>,
     #[serde(default = "false_fn")]
     pub allow_jaeger: bool,
     #[serde(default = "false_fn")]
     pub tracing_flame: bool,
+    #[serde(default = "false_fn")]
+    pub access_log: bool,
+    #[serde(default = "false_fn")]
+    pub response_compression: bool,
+    #[serde(default = "default_response_compression_min_size")]
+    pub response_compression_min_size: u16,
     #[serde(default)]
     pub proxy: ProxyConfig,
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
     pub jwt_secret: Option<String>,
+    pub oidc: Option<OidcConfig>,
+    pub smtp: Option<SmtpConfig>,
+    #[serde(default)]
+    pub federation_destination_overrides: BTreeMap<OwnedServerName, DestinationOverride>,
     #[serde(default = "default_trusted_servers")]
     pub trusted_servers: Vec<OwnedServerName>,
     #[serde(default = "default_log")]
@@ -92,9 +215,166 @@ pub struct TlsConfig {
     pub key: String,
 }
 
+/// Forces where and how we connect to a federation destination, skipping .well-known and SRV
+/// lookups.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DestinationOverride {
+    /// The address we open the connection to
+    pub connect_addr: SocketAddr,
+    /// The name we send in the TLS handshake, defaults to the server name
+    pub sni: Option<String>,
+}
+
+/// Push rules that new users get in addition to the server default rules.
+///
+/// ## Example:
+/// ```toml
+/// [[global.push_rules.override]]
+/// rule_id = "mute_notices"
+/// default = false
+/// enabled = true
+/// conditions = [{ kind = "event_match", key = "room_id", pattern = "!notices:example.com" }]
+/// actions = []
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PushRulesConfig {
+    #[serde(default, rename = "override")]
+    pub override_: Vec<ConditionalPushRule>,
+    #[serde(default)]
+    pub underride: Vec<ConditionalPushRule>,
+}
+
+impl PushRulesConfig {
+    /// Adds the configured rules to the server default rules. They take precedence over the
+    /// default rules of the same kind, except for the master rule, which always comes first.
+    pub fn apply(&self, mut ruleset: Ruleset) -> Ruleset {
+        let (master, rest): (Vec<_>, Vec<_>) = ruleset
+            .override_
+            .into_iter()
+            .partition(|rule| rule.rule_id == ".m.rule.master");
+
+        ruleset.override_ = master
+            .into_iter()
+            .chain(self.override_.iter().cloned())
+            .chain(rest)
+            .collect();
+        ruleset.underride = self
+            .underride
+            .iter()
+            .cloned()
+            .chain(ruleset.underride)
+            .collect();
+
+        ruleset
+    }
+}
+
+/// ## Example:
+/// ```toml
+/// [global.message_rate_limit]
+/// per_second = 0.5
+/// burst = 10
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitConfig {
+    /// How many requests are allowed per second on average
+    pub per_second: f64,
+    /// How many requests are allowed at once
+    pub burst: u32,
+}
+
+/// What happens when a user with `max_devices_per_user` devices logs in again.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceLimitAction {
+    /// The login fails
+    #[default]
+    Reject,
+    /// The device that was seen the longest time ago is logged out
+    EvictOldest,
+}
+
+/// How message bodies and search terms are split into the words of the search index.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SearchTokenizer {
+    /// Words are separated by whitespace, punctuation stays part of them
+    Whitespace,
+    /// Words are separated by everything that is not a letter or digit
+    #[default]
+    UnicodeWords,
+    /// Like `unicode-words`, but words are split further into overlapping pairs of characters.
+    /// Finds parts of words and text without spaces, like Chinese or Japanese.
+    Ngram,
+}
+
+impl SearchTokenizer {
+    pub const ALL: [Self; 3] = [Self::Whitespace, Self::UnicodeWords, Self::Ngram];
+}
+
+/// Parts of federation that can be disabled separately, see [`Config::federation_allows`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FederationCategory {
+    /// Transactions, joins, knocks and invites from other servers
+    IncomingEvents,
+    /// Other servers looking up profiles of our users
+    ProfileQueries,
+    /// Other servers querying and claiming device keys of our users
+    KeyQueries,
+}
+
 const DEPRECATED_KEYS: &[&str] = &["cache_capacity"];
 
+/// Where the server accepts connections.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
 impl Config {
+    /// Whether federation is enabled and this part of it was not disabled.
+    pub fn federation_allows(&self, category: FederationCategory) -> bool {
+        self.allow_federation
+            && match category {
+                FederationCategory::IncomingEvents => self.federation_allow_incoming_events,
+                FederationCategory::ProfileQueries => self.federation_allow_profile_queries,
+                FederationCategory::KeyQueries => self.federation_allow_key_queries,
+            }
+    }
+
+    /// A configured `unix_socket_path` takes precedence over `address` and `port`.
+    pub fn listen_addr(&self) -> ListenAddr {
+        match &self.unix_socket_path {
+            Some(path) => ListenAddr::Unix(path.clone()),
+            None => ListenAddr::Tcp(SocketAddr::from((self.address, self.port))),
+        }
+    }
+
+    /// Whether media with this content type may be uploaded, see `media_allowed_content_types` and
+    /// `media_blocked_content_types`. Uploads without a content type are treated as
+    /// `application/octet-stream`.
+    pub fn media_content_type_allowed(&self, content_type: Option<&str>) -> bool {
+        // Parameters like `; charset=utf-8` don't matter
+        let essence = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|essence| essence.trim().to_ascii_lowercase())
+            .filter(|essence| !essence.is_empty())
+            .unwrap_or_else(|| "application/octet-stream".to_owned());
+
+        let matches = |pattern: &String| {
+            let pattern = pattern.trim().to_ascii_lowercase();
+            match pattern.strip_suffix("/*") {
+                Some(top_level) => essence.split('/').next() == Some(top_level),
+                None => pattern == "*" || pattern == essence,
+            }
+        };
+
+        (self.media_allowed_content_types.is_empty()
+            || self.media_allowed_content_types.iter().any(matches))
+            && !self.media_blocked_content_types.iter().any(matches)
+    }
+
     pub fn warn_deprecated(&self) {
         let mut was_deprecated = false;
         for key in self
@@ -117,8 +397,20 @@ impl fmt::Display for Config {
         // Prepare a list of config values to show
         let lines = [
             ("Server name", self.server_name.host()),
+            (
+                "Listening on",
+                &match self.listen_addr() {
+                    ListenAddr::Tcp(addr) => addr.to_string(),
+                    ListenAddr::Unix(path) => path.display().to_string(),
+                },
+            ),
             ("Database backend", &self.database_backend),
             ("Database path", &self.database_path),
+            (
+                "Require migration confirmation",
+                &self.require_migration_confirmation.to_string(),
+            ),
+            ("Automatically migrate", &self.auto_migrate.to_string()),
             (
                 "Database cache capacity (MB)",
                 &self.db_cache_capacity_mb.to_string(),
@@ -132,24 +424,290 @@ impl fmt::Display for Config {
                 "Maximum open files for RocksDB",
                 &self.rocksdb_max_open_files.to_string(),
             ),
+            #[cfg(feature = "sqlite")]
+            (
+                "SQLite group commit window (ms)",
+                &self.sqlite_group_commit_ms.to_string(),
+            ),
+            (
+                "Count allocation block",
+                &self.count_allocation_block.to_string(),
+            ),
             ("PDU cache capacity", &self.pdu_cache_capacity.to_string()),
+            (
+                "State cache capacity",
+                &self.state_cache_capacity.to_string(),
+            ),
+            (
+                "Auth chain cache max bytes",
+                &self.auth_chain_cache_max_bytes.to_string(),
+            ),
+            (
+                "Auth chain cache warmup rooms",
+                &self.auth_chain_cache_warmup_rooms.to_string(),
+            ),
+            (
+                "Maximum auth chain depth",
+                &self.max_auth_chain_depth.to_string(),
+            ),
             (
                 "Cleanup interval in seconds",
                 &self.cleanup_second_interval.to_string(),
             ),
+            ("Durable event types", &self.durable_event_types.join(", ")),
+            (
+                "State optimization interval in seconds",
+                &self.optimize_state_second_interval.to_string(),
+            ),
+            (
+                "Referenced events kept below the newest event",
+                &self
+                    .referenced_events_keep_depth
+                    .map_or_else(|| "all".to_owned(), |depth| depth.to_string()),
+            ),
+            (
+                "Message retention interval in seconds",
+                &self.retention_second_interval.to_string(),
+            ),
+            (
+                "Default message retention in days",
+                &self
+                    .default_message_retention_days
+                    .map_or_else(|| "forever".to_owned(), |days| days.to_string()),
+            ),
+            (
+                "Thumbnail retention in days",
+                &self
+                    .thumbnail_retention_days
+                    .map_or_else(|| "forever".to_owned(), |days| days.to_string()),
+            ),
+            (
+                "Outlier retention in days",
+                &self
+                    .outlier_retention_days
+                    .map_or_else(|| "forever".to_owned(), |days| days.to_string()),
+            ),
             ("Maximum request size", &self.max_request_size.to_string()),
+            (
+                "Maximum federation request size",
+                &self
+                    .max_federation_request_size
+                    .unwrap_or(self.max_request_size)
+                    .to_string(),
+            ),
+            (
+                "Maximum federation redirects",
+                &self.federation_max_redirects.to_string(),
+            ),
+            (
+                "Federation bind address",
+                &self
+                    .federation_bind_address
+                    .map_or_else(|| "any".to_owned(), |address| address.to_string()),
+            ),
+            (
+                "Maximum pixels of decoded images",
+                &self.media_max_decode_pixels.to_string(),
+            ),
+            (
+                "Allowed media content types",
+                &if self.media_allowed_content_types.is_empty() {
+                    "all".to_owned()
+                } else {
+                    self.media_allowed_content_types.join(", ")
+                },
+            ),
+            (
+                "Blocked media content types",
+                &self.media_blocked_content_types.join(", "),
+            ),
+            (
+                "Media Content-Security-Policy",
+                &self.media_content_security_policy,
+            ),
             (
                 "Maximum concurrent requests",
                 &self.max_concurrent_requests.to_string(),
             ),
+            (
+                "Maximum concurrent requests per server",
+                &self
+                    .federation_sender_fairness
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Persist federation backoff",
+                &self.persist_federation_backoff.to_string(),
+            ),
+            (
+                "Maximum concurrent transactions per origin",
+                &self.max_concurrent_transactions_per_origin.to_string(),
+            ),
+            ("Maximum event size", &self.max_event_bytes.to_string()),
+            ("Maximum fetch limit", &self.max_fetch_limit.to_string()),
+            (
+                "Maximum sync since gap",
+                &self
+                    .sync_max_since_gap
+                    .map_or_else(|| "unlimited".to_owned(), |gap| gap.to_string()),
+            ),
+            (
+                "Maximum state events per room",
+                &self
+                    .max_state_events_per_room
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Maximum devices per user",
+                &self
+                    .max_devices_per_user
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Maximum queued to-device events per device",
+                &self
+                    .max_to_device_events_per_device
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Maximum created rooms per user",
+                &self
+                    .max_rooms_per_user
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Maximum joined rooms per user",
+                &self
+                    .max_joined_rooms_per_user
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Maximum sync connections per user",
+                &self
+                    .max_sync_connections_per_user
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Message rate limit",
+                &self.message_rate_limit.as_ref().map_or_else(
+                    || "unlimited".to_owned(),
+                    |limit| format!("{} per second, burst {}", limit.per_second, limit.burst),
+                ),
+            ),
+            (
+                "Room join rate limit",
+                &self.room_join_rate_limit.as_ref().map_or_else(
+                    || "unlimited".to_owned(),
+                    |limit| format!("{} per second, burst {}", limit.per_second, limit.burst),
+                ),
+            ),
+            (
+                "Device limit action",
+                &format!("{:?}", self.device_limit_action),
+            ),
+            ("Search tokenizer", &format!("{:?}", self.search_tokenizer)),
             ("Allow registration", &self.allow_registration.to_string()),
+            (
+                "Registration shared secret",
+                if self.registration_shared_secret.is_some() {
+                    "set"
+                } else {
+                    "not set"
+                },
+            ),
             (
                 "Enabled lightning bolt",
                 &self.enable_lightning_bolt.to_string(),
             ),
             ("Allow encryption", &self.allow_encryption.to_string()),
+            (
+                "Default history visibility",
+                self.default_history_visibility.as_str(),
+            ),
             ("Allow federation", &self.allow_federation.to_string()),
+            (
+                "Allow incoming federated events",
+                &self.federation_allow_incoming_events.to_string(),
+            ),
+            (
+                "Allow federated profile queries",
+                &self.federation_allow_profile_queries.to_string(),
+            ),
+            (
+                "Allow federated key queries",
+                &self.federation_allow_key_queries.to_string(),
+            ),
             ("Allow room creation", &self.allow_room_creation.to_string()),
+            (
+                "Message of the day",
+                self.server_notice_motd.as_deref().unwrap_or("not set"),
+            ),
+            ("Allow presence", &self.allow_presence.to_string()),
+            ("Federate presence", &self.federate_presence.to_string()),
+            ("Federate typing", &self.federate_typing.to_string()),
+            (
+                "Allow profile lookup over federation",
+                &self.allow_profile_lookup_over_federation.to_string(),
+            ),
+            (
+                "Extra default push rules",
+                &format!(
+                    "{} override, {} underride",
+                    self.push_rules.override_.len(),
+                    self.push_rules.underride.len()
+                ),
+            ),
+            (
+                "Notify admins of reports",
+                &self.notify_admins_of_reports.to_string(),
+            ),
+            (
+                "User directory searches all users",
+                &self.user_directory_search_all_users.to_string(),
+            ),
+            (
+                "Profile update interval (seconds)",
+                &self.profile_update_interval_secs.to_string(),
+            ),
+            (
+                "Signing key refresh interval (seconds)",
+                &self.signing_key_refresh_interval_secs.to_string(),
+            ),
+            (
+                "Remote profile cache TTL (seconds)",
+                &self.remote_profile_cache_ttl_secs.to_string(),
+            ),
+            (
+                "Admin room alias",
+                &self
+                    .admin_room_alias
+                    .as_ref()
+                    .map_or_else(|| "#admins:<server_name>".to_owned(), |a| a.to_string()),
+            ),
+            ("User agent", &self.user_agent),
+            ("Access log", &self.access_log.to_string()),
+            ("HTTP/1 keep-alive", &self.http1_keep_alive.to_string()),
+            ("HTTP/2", &self.http2.to_string()),
+            (
+                "HTTP/2 keep-alive interval in seconds",
+                &if self.http2_keep_alive_interval == 0 {
+                    "disabled".to_owned()
+                } else {
+                    self.http2_keep_alive_interval.to_string()
+                },
+            ),
+            (
+                "HTTP/2 keep-alive timeout in seconds",
+                &self.http2_keep_alive_timeout.to_string(),
+            ),
+            (
+                "Response compression",
+                &if self.response_compression {
+                    format!("above {} bytes", self.response_compression_min_size)
+                } else {
+                    "disabled".to_owned()
+                },
+            ),
             (
                 "JWT secret",
                 match self.jwt_secret {
@@ -157,6 +715,20 @@ impl fmt::Display for Config {
                     None => "not set",
                 },
             ),
+            (
+                "OIDC issuer",
+                match &self.oidc {
+                    Some(oidc) => oidc.issuer.as_str(),
+                    None => "not set",
+                },
+            ),
+            (
+                "SMTP host",
+                match &self.smtp {
+                    Some(smtp) => smtp.host.as_str(),
+                    None => "not set",
+                },
+            ),
             ("Trusted servers", {
                 let mut lst = vec![];
                 for server in &self.trusted_servers {
@@ -164,6 +736,13 @@ impl fmt::Display for Config {
                 }
                 &lst.join(", ")
             }),
+            ("Federation destination overrides", {
+                let mut lst = vec![];
+                for server in self.federation_destination_overrides.keys() {
+                    lst.push(server.host());
+                }
+                &lst.join(", ")
+            }),
             (
                 "TURN username",
                 if self.turn_username.is_empty() {
@@ -243,6 +822,22 @@ fn default_pdu_cache_capacity() -> u32 {
     150_000
 }
 
+fn default_state_cache_capacity() -> u32 {
+    100
+}
+
+fn default_auth_chain_cache_max_bytes() -> u64 {
+    128 * 1024 * 1024 // 128 MiB
+}
+
+fn default_count_allocation_block() -> u64 {
+    1
+}
+
+fn default_max_auth_chain_depth() -> usize {
+    10_000
+}
+
 fn default_cleanup_second_interval() -> u32 {
     60 // every minute
 }
@@ -251,6 +846,31 @@ fn default_max_request_size() -> u32 {
     20 * 1024 * 1024 // Default to 20 MB
 }
 
+fn default_http2_keep_alive_timeout() -> u64 {
+    20
+}
+
+fn default_response_compression_min_size() -> u16 {
+    1024
+}
+
+fn default_federation_max_redirects() -> usize {
+    5
+}
+
+fn default_media_blocked_content_types() -> Vec<String> {
+    // Can run scripts when opened directly
+    vec!["image/svg+xml".to_owned()]
+}
+
+fn default_media_content_security_policy() -> String {
+    "sandbox; default-src 'none'".to_owned()
+}
+
+fn default_media_max_decode_pixels() -> u64 {
+    8192 * 8192
+}
+
 fn default_max_concurrent_requests() -> u16 {
     100
 }
@@ -259,6 +879,22 @@ fn default_max_fetch_prev_events() -> u16 {
     100_u16
 }
 
+fn default_max_concurrent_transactions_per_origin() -> NonZeroUsize {
+    NonZeroUsize::new(1).expect("1 is not 0")
+}
+
+fn default_max_event_bytes() -> usize {
+    65_536 // The limit from the spec
+}
+
+fn default_max_fetch_limit() -> u64 {
+    100
+}
+
+fn default_user_agent() -> String {
+    format!("Conduit/{}", env!("CARGO_PKG_VERSION"))
+}
+
 fn default_trusted_servers() -> Vec<OwnedServerName> {
     vec![OwnedServerName::try_from("matrix.org").unwrap()]
 }
@@ -267,6 +903,14 @@ fn default_log() -> String {
     "warn,state_res=warn,_=off,sled=off".to_owned()
 }
 
+fn default_remote_profile_cache_ttl_secs() -> u64 {
+    60 * 10
+}
+
+fn default_signing_key_refresh_interval_secs() -> u64 {
+    60 * 60 * 24
+}
+
 fn default_turn_ttl() -> u64 {
     60 * 60 * 24
 }
@@ -275,3 +919,210 @@ fn default_turn_ttl() -> u64 {
 pub fn default_default_room_version() -> RoomVersionId {
     RoomVersionId::V9
 }
+
+fn default_history_visibility() -> HistoryVisibility {
+    HistoryVisibility::Shared
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+
+    fn config(toml: &str) -> Config {
+        Figment::new().merge(Toml::string(toml)).extract().unwrap()
+    }
+
+    #[test]
+    fn state_cache_capacity() {
+        let base = r#"
+            server_name = "example.com"
+            database_path = "/var/lib/matrix-conduit/"
+        "#;
+
+        assert_eq!(config(base).state_cache_capacity, 100);
+        assert_eq!(
+            config(&format!("{base}\nstate_cache_capacity = 5")).state_cache_capacity,
+            5
+        );
+    }
+
+    #[test]
+    fn no_concurrent_transactions_are_rejected() {
+        let base = r#"
+            server_name = "example.com"
+            database_path = "/var/lib/matrix-conduit/"
+        "#;
+
+        assert_eq!(config(base).max_concurrent_transactions_per_origin.get(), 1);
+        assert!(Figment::new()
+            .merge(Toml::string(&format!(
+                "{base}\nmax_concurrent_transactions_per_origin = 0"
+            )))
+            .extract::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn listen_addr() {
+        let base = r#"
+            server_name = "example.com"
+            database_path = "/var/lib/matrix-conduit/"
+        "#;
+
+        assert_eq!(
+            config(base).listen_addr(),
+            ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 8000)))
+        );
+
+        assert_eq!(
+            config(&format!("{base}\naddress = \"0.0.0.0\"\nport = 6167")).listen_addr(),
+            ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 6167)))
+        );
+
+        assert_eq!(
+            config(&format!(
+                "{base}\nport = 6167\nunix_socket_path = \"/run/conduit/conduit.sock\""
+            ))
+            .listen_addr(),
+            ListenAddr::Unix(PathBuf::from("/run/conduit/conduit.sock"))
+        );
+    }
+
+    #[test]
+    fn extra_push_rules_are_added_to_defaults() {
+        let config = config(
+            r#"
+            server_name = "example.com"
+            database_path = "/var/lib/matrix-conduit/"
+
+            [[push_rules.override]]
+            rule_id = "mute_notices"
+            default = false
+            enabled = true
+            conditions = [{ kind = "event_match", key = "room_id", pattern = "!notices:example.com" }]
+            actions = []
+        "#,
+        );
+
+        let user_id = ruma::user_id!("@alice:example.com");
+        let ruleset = config.push_rules.apply(Ruleset::server_default(user_id));
+        let override_ids: Vec<_> = ruleset
+            .override_
+            .iter()
+            .map(|rule| rule.rule_id.as_str())
+            .collect();
+
+        assert_eq!(&override_ids[..2], [".m.rule.master", "mute_notices"]);
+        assert_eq!(
+            override_ids.len(),
+            Ruleset::server_default(user_id).override_.len() + 1
+        );
+        assert_eq!(
+            ruleset.underride,
+            Ruleset::server_default(user_id).underride
+        );
+    }
+
+    #[test]
+    fn federation_categories() {
+        let base = r#"
+            server_name = "example.com"
+            database_path = "/var/lib/matrix-conduit/"
+            allow_federation = true
+        "#;
+        let categories = [
+            (
+                "federation_allow_incoming_events",
+                FederationCategory::IncomingEvents,
+            ),
+            (
+                "federation_allow_profile_queries",
+                FederationCategory::ProfileQueries,
+            ),
+            (
+                "federation_allow_key_queries",
+                FederationCategory::KeyQueries,
+            ),
+        ];
+
+        for (_, category) in categories {
+            assert!(config(base).federation_allows(category));
+        }
+
+        for (option, disabled) in categories {
+            let config = config(&format!("{base}\n{option} = false"));
+            for (_, category) in categories {
+                assert_eq!(config.federation_allows(category), category != disabled);
+            }
+        }
+
+        let config = config(&base.replace("allow_federation = true", "allow_federation = false"));
+        for (_, category) in categories {
+            assert!(!config.federation_allows(category));
+        }
+    }
+
+    #[test]
+    fn media_content_types() {
+        let base = r#"
+            server_name = "example.com"
+            database_path = "/var/lib/matrix-conduit/"
+        "#;
+
+        // SVGs are blocked by default, everything else is allowed
+        let config = config(base);
+        assert!(!config.media_content_type_allowed(Some("image/svg+xml")));
+        assert!(!config.media_content_type_allowed(Some("Image/SVG+XML; charset=utf-8")));
+        assert!(config.media_content_type_allowed(Some("image/png")));
+        assert!(config.media_content_type_allowed(Some("text/html")));
+        assert!(config.media_content_type_allowed(None));
+
+        let config = config(&format!(
+            r#"{base}
+            media_allowed_content_types = ["image/*", "video/mp4"]
+            media_blocked_content_types = ["image/svg+xml", "image/gif"]
+            "#
+        ));
+        assert!(config.media_content_type_allowed(Some("image/png")));
+        assert!(config.media_content_type_allowed(Some("video/mp4")));
+        assert!(!config.media_content_type_allowed(Some("image/gif")));
+        assert!(!config.media_content_type_allowed(Some("image/svg+xml")));
+        assert!(!config.media_content_type_allowed(Some("video/webm")));
+        assert!(!config.media_content_type_allowed(Some("imagex/png")));
+        assert!(!config.media_content_type_allowed(None));
+
+        // SVGs can be allowed again
+        let config = config(&format!(
+            "{base}
+media_blocked_content_types = []"
+        ));
+        assert!(config.media_content_type_allowed(Some("image/svg+xml")));
+    }
+
+    #[test]
+    fn federation_bind_address() {
+        let base = r#"
+            server_name = "example.com"
+            database_path = "/var/lib/matrix-conduit/"
+        "#;
+
+        assert_eq!(config(base).federation_bind_address, None);
+        assert_eq!(
+            config(&format!(r#"{base} federation_bind_address = "192.0.2.1""#))
+                .federation_bind_address,
+            Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))
+        );
+
+        let error = Figment::new()
+            .merge(Toml::string(&format!(
+                r#"{base} federation_bind_address = "192.0.2.256""#
+            )))
+            .extract::<Config>()
+            .unwrap_err();
+        assert!(error.to_string().contains("invalid IP address"));
+    }
+}
@@ -0,0 +1,34 @@
+use reqwest::Url;
+use serde::Deserialize;
+
+/// ## Example:
+/// ```toml
+/// [global.oidc]
+/// issuer = "https://auth.example.com/realms/matrix"
+/// client_id = "conduit"
+/// client_secret = "hunter2"
+/// redirect_url = "https://matrix.example.com/_conduit/client/oidc/callback"
+/// client_redirect_urls = ["https://app.element.io/"]
+/// ```
+/// The `redirect_url` has to be registered with the provider and has to point at
+/// `/_conduit/client/oidc/callback` on this server.
+///
+/// Clients can only receive login tokens at urls below one of the `client_redirect_urls`, so they
+/// can't be sent to other sites.
+#[derive(Clone, Debug, Deserialize)]
+pub struct OidcConfig {
+    #[serde(deserialize_with = "crate::utils::deserialize_from_str")]
+    pub issuer: Url,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(deserialize_with = "crate::utils::deserialize_from_str")]
+    pub redirect_url: Url,
+    #[serde(default)]
+    pub client_redirect_urls: Vec<String>,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_owned(), "profile".to_owned()]
+}
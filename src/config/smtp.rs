@@ -0,0 +1,30 @@
+use lettre::message::Mailbox;
+use reqwest::Url;
+use serde::Deserialize;
+
+/// ## Example:
+/// ```toml
+/// [global.smtp]
+/// host = "mail.example.com"
+/// username = "conduit@example.com"
+/// password = "hunter2"
+/// from = "Conduit <conduit@example.com>"
+/// base_url = "https://matrix.example.com"
+/// ```
+/// `base_url` is where clients reach this server, it's used for the links in verification emails.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(deserialize_with = "crate::utils::deserialize_from_str")]
+    pub from: Mailbox,
+    #[serde(deserialize_with = "crate::utils::deserialize_from_str")]
+    pub base_url: Url,
+}
+
+fn default_port() -> u16 {
+    587
+}
@@ -1,5 +1,5 @@
 use super::Config;
-use crate::Result;
+use crate::{Error, Result};
 
 use std::{future::Future, pin::Pin, sync::Arc};
 
@@ -31,7 +31,13 @@ pub trait KeyValueDatabaseEngine: Send + Sync {
     where
         Self: Sized;
     fn open_tree(&self, name: &'static str) -> Result<Arc<dyn KvTree>>;
+    /// The name of the backend, as used for `database_backend` in the config.
+    fn backend_name(&self) -> &'static str;
     fn flush(&self) -> Result<()>;
+    /// Makes sure all writes so far survive a crash, for the few that can't wait for `cleanup`.
+    fn persist(&self) -> Result<()> {
+        self.flush()
+    }
     fn cleanup(&self) -> Result<()> {
         Ok(())
     }
@@ -39,6 +45,11 @@ pub trait KeyValueDatabaseEngine: Send + Sync {
         Ok("Current database engine does not support memory usage reporting.".to_owned())
     }
     fn clear_caches(&self) {}
+    /// Runs the backend's own integrity check and returns the problems it found, or `None` if
+    /// the backend has no such check.
+    fn check_integrity(&self) -> Result<Option<Vec<String>>> {
+        Ok(None)
+    }
 }
 
 pub trait KvTree: Send + Sync {
@@ -60,6 +71,15 @@ pub trait KvTree: Send + Sync {
     fn increment(&self, key: &[u8]) -> Result<Vec<u8>>;
     fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()>;
 
+    /// Atomically replaces the value of `key` with `new` if it currently is `old`. `None` stands
+    /// for a missing key, both as `old` and as `new`.
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), CasError>>;
+
     fn scan_prefix<'a>(
         &'a self,
         prefix: Vec<u8>,
@@ -67,6 +87,20 @@ pub trait KvTree: Send + Sync {
 
     fn watch_prefix<'a>(&'a self, prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
 
+    /// Removes all keys that start with `prefix` and returns how many there were.
+    fn remove_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        let keys: Vec<_> = self
+            .scan_prefix(prefix.to_vec())
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in &keys {
+            self.remove(key)?;
+        }
+
+        Ok(keys.len())
+    }
+
     fn clear(&self) -> Result<()> {
         for (key, _) in self.iter() {
             self.remove(&key)?;
@@ -75,3 +109,436 @@ pub trait KvTree: Send + Sync {
         Ok(())
     }
 }
+
+/// Returned by `KvTree::compare_and_swap` if the value was not the expected one
+#[derive(Debug, PartialEq, Eq)]
+pub struct CasError {
+    pub current: Option<Vec<u8>>,
+}
+
+/// The smallest key that is greater than all keys starting with `prefix`, or `None` if there is no
+/// such key because the prefix is empty or only consists of 0xff bytes.
+pub fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut bound = prefix.to_vec();
+
+    while let Some(last) = bound.pop() {
+        if last < u8::MAX {
+            bound.push(last + 1);
+            return Some(bound);
+        }
+    }
+
+    None
+}
+
+/// Removes the smallest keys that start with `prefix`, so that at most `keep` of them are left.
+/// Returns how many keys were removed.
+pub fn trim_prefix(tree: &dyn KvTree, prefix: &[u8], keep: usize) -> Result<usize> {
+    let keys: Vec<_> = tree
+        .scan_prefix(prefix.to_vec())
+        .map(|(key, _)| key)
+        .collect();
+    let excess = keys.len().saturating_sub(keep);
+
+    for key in &keys[..excess] {
+        tree.remove(key)?;
+    }
+
+    Ok(excess)
+}
+
+/// A scan over the entries whose keys start with a prefix, in either direction. A scan can be
+/// continued after the last key of a page, which unlike an offset stays correct when entries are
+/// inserted between pages.
+#[derive(Clone, Debug)]
+pub struct PaginatedScan {
+    prefix: Vec<u8>,
+    start_after: Option<Vec<u8>>,
+    backwards: bool,
+}
+
+impl PaginatedScan {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            prefix,
+            start_after: None,
+            backwards: false,
+        }
+    }
+
+    /// Only returns entries after `key` in the direction of the scan, excluding `key` itself.
+    pub fn after(mut self, key: Vec<u8>) -> Self {
+        self.start_after = Some(key);
+        self
+    }
+
+    /// Scans from the largest key to the smallest one.
+    ///
+    /// Backwards scans need a prefix with an upper bound, see [`prefix_upper_bound`], or a key
+    /// to start after.
+    pub fn backwards(mut self) -> Self {
+        self.backwards = true;
+        self
+    }
+
+    /// Fails for backwards scans that have neither a start nor a prefix with an upper bound.
+    pub fn iter<'a>(
+        &self,
+        tree: &'a dyn KvTree,
+    ) -> Result<Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>> {
+        let prefix = self.prefix.clone();
+
+        let entries: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> = if self.backwards {
+            // Backwards iteration includes the key it starts from
+            let start = self
+                .start_after
+                .clone()
+                .or_else(|| prefix_upper_bound(&prefix))
+                .ok_or_else(|| {
+                    Error::bad_database("Backwards scan has no start and no upper bound.")
+                })?;
+            let entries = tree.iter_from(&start, true);
+            Box::new(entries.skip_while(move |(key, _)| *key == start))
+        } else {
+            // The smallest key greater than the start is the start followed by a 0 byte
+            let start = self
+                .start_after
+                .as_ref()
+                .map_or_else(|| prefix.clone(), |key| [&key[..], &[0]].concat());
+            tree.iter_from(&start, false)
+        };
+
+        Ok(Box::new(
+            entries.take_while(move |(key, _)| key.starts_with(&prefix)),
+        ))
+    }
+
+    /// Returns at most `limit` entries and the key to continue after, if there may be more.
+    pub fn page(
+        &self,
+        tree: &dyn KvTree,
+        limit: usize,
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>)> {
+        let entries: Vec<_> = self.iter(tree)?.take(limit).collect();
+        let next = if entries.len() == limit {
+            entries.last().map(|(key, _)| key.clone())
+        } else {
+            None
+        };
+
+        Ok((entries, next))
+    }
+}
+
+/// Checks that the values of the first `limit` entries in `from` exist as keys in `to` and returns
+/// the keys of the entries whose value is missing.
+pub fn dangling_references(
+    from: &dyn KvTree,
+    to: &dyn KvTree,
+    limit: usize,
+) -> Result<Vec<Vec<u8>>> {
+    let mut dangling = Vec::new();
+
+    for (key, value) in from.iter().take(limit) {
+        if to.get(&value)?.is_none() {
+            dangling.push(key);
+        }
+    }
+
+    Ok(dangling)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::BTreeMap, sync::RwLock};
+
+    /// A tree that only implements the required methods, to test the provided ones.
+    #[derive(Default)]
+    struct MemoryTree(RwLock<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+    impl KvTree for MemoryTree {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.read().unwrap().get(key).cloned())
+        }
+
+        fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+            self.0.write().unwrap().insert(key.to_vec(), value.to_vec());
+            Ok(())
+        }
+
+        fn insert_batch(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+            self.0.write().unwrap().extend(iter);
+            Ok(())
+        }
+
+        fn remove(&self, key: &[u8]) -> Result<()> {
+            self.0.write().unwrap().remove(key);
+            Ok(())
+        }
+
+        fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+            let entries: Vec<_> = self.0.read().unwrap().clone().into_iter().collect();
+            Box::new(entries.into_iter())
+        }
+
+        fn iter_from<'a>(
+            &'a self,
+            from: &[u8],
+            backwards: bool,
+        ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+            let from = from.to_vec();
+            let entries: Vec<_> = if backwards {
+                self.iter()
+                    .filter(|(k, _)| *k <= from)
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                    .collect()
+            } else {
+                self.iter().filter(|(k, _)| *k >= from).collect()
+            };
+            Box::new(entries.into_iter())
+        }
+
+        fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+            let new = crate::utils::increment(self.get(key)?.as_deref()).unwrap();
+            self.insert(key, &new)?;
+            Ok(new)
+        }
+
+        fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+            for key in iter {
+                self.increment(&key)?;
+            }
+            Ok(())
+        }
+
+        fn compare_and_swap(
+            &self,
+            key: &[u8],
+            old: Option<&[u8]>,
+            new: Option<&[u8]>,
+        ) -> Result<Result<(), CasError>> {
+            let mut tree = self.0.write().unwrap();
+            let current = tree.get(key);
+            if current.map(Vec::as_slice) != old {
+                return Ok(Err(CasError {
+                    current: current.cloned(),
+                }));
+            }
+
+            match new {
+                Some(new) => tree.insert(key.to_vec(), new.to_vec()),
+                None => tree.remove(key),
+            };
+            Ok(Ok(()))
+        }
+
+        fn scan_prefix<'a>(
+            &'a self,
+            prefix: Vec<u8>,
+        ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+            Box::new(
+                self.iter_from(&prefix, false)
+                    .take_while(move |(key, _)| key.starts_with(&prefix)),
+            )
+        }
+
+        fn watch_prefix<'a>(
+            &'a self,
+            _prefix: &[u8],
+        ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    const KEYS: &[&[u8]] = &[
+        b"@alice:example.com\xffPHONE\xff!a",
+        b"@alice:example.com\xffPHONE\xff!b",
+        b"@alice:example.com\xffPHONEX\xff!a",
+        b"@alice:example.com\xffLAPTOP\xff!a",
+        b"@alice:example.com.evil\xffPHONE\xff!a",
+        b"@bob:example.com\xffPHONE\xff!a",
+        b"\xff\xff",
+    ];
+
+    #[test]
+    fn remove_prefix_keeps_unrelated_keys() {
+        let tree = MemoryTree::default();
+        for key in KEYS {
+            tree.insert(key, b"").unwrap();
+        }
+
+        assert_eq!(
+            tree.remove_prefix(b"@alice:example.com\xffPHONE\xff")
+                .unwrap(),
+            2
+        );
+        assert_eq!(tree.iter().count(), KEYS.len() - 2);
+        assert!(tree
+            .get(b"@alice:example.com\xffPHONEX\xff!a")
+            .unwrap()
+            .is_some());
+
+        assert_eq!(tree.remove_prefix(b"@nobody").unwrap(), 0);
+        assert_eq!(tree.iter().count(), KEYS.len() - 2);
+    }
+
+    #[test]
+    fn trimming_keeps_newest_keys() {
+        let tree = MemoryTree::default();
+        let prefix = b"@alice:example.com\xffPHONE\xff";
+        let key = |count: u64| [&prefix[..], &count.to_be_bytes()[..]].concat();
+        for count in 1..=10 {
+            tree.insert(&key(count), b"{}").unwrap();
+        }
+        tree.insert(b"@alice:example.com\xffLAPTOP\xff1", b"{}")
+            .unwrap();
+
+        assert_eq!(trim_prefix(&tree, prefix, 4).unwrap(), 6);
+        let left: Vec<_> = tree.scan_prefix(prefix.to_vec()).map(|(k, _)| k).collect();
+        assert_eq!(left, (7..=10).map(key).collect::<Vec<_>>());
+        // Other devices keep their events
+        assert!(tree
+            .get(b"@alice:example.com\xffLAPTOP\xff1")
+            .unwrap()
+            .is_some());
+
+        assert_eq!(trim_prefix(&tree, prefix, 4).unwrap(), 0);
+        assert_eq!(trim_prefix(&tree, prefix, 0).unwrap(), 4);
+    }
+
+    #[test]
+    fn healthy_references() {
+        let eventid_pduid = MemoryTree::default();
+        let pduid_pdu = MemoryTree::default();
+
+        for i in 0..10_u64 {
+            eventid_pduid
+                .insert(format!("$event{i}").as_bytes(), &i.to_be_bytes())
+                .unwrap();
+            pduid_pdu.insert(&i.to_be_bytes(), b"{}").unwrap();
+        }
+
+        assert!(dangling_references(&eventid_pduid, &pduid_pdu, 100)
+            .unwrap()
+            .is_empty());
+
+        pduid_pdu.remove(&3_u64.to_be_bytes()).unwrap();
+        assert_eq!(
+            dangling_references(&eventid_pduid, &pduid_pdu, 100).unwrap(),
+            vec![b"$event3".to_vec()]
+        );
+        // Only the sample is checked
+        assert!(dangling_references(&eventid_pduid, &pduid_pdu, 3)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn backend_names_match_features() {
+        #[cfg(feature = "sqlite")]
+        assert_eq!(sqlite::Engine::NAME, "sqlite");
+        #[cfg(feature = "rocksdb")]
+        assert_eq!(rocksdb::Engine::NAME, "rocksdb");
+        #[cfg(feature = "persy")]
+        assert_eq!(persy::Engine::NAME, "persy");
+    }
+
+    #[test]
+    fn upper_bounds() {
+        assert_eq!(prefix_upper_bound(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_upper_bound(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_upper_bound(b"\xff\xff"), None);
+        assert_eq!(prefix_upper_bound(b""), None);
+
+        // The range [prefix, bound) contains exactly the keys that start with the prefix
+        for prefix in [
+            &b"@alice:example.com\xff"[..],
+            b"@alice:example.com\xffPHONE",
+        ] {
+            let bound = prefix_upper_bound(prefix).unwrap();
+            for key in KEYS {
+                assert_eq!(
+                    key.starts_with(prefix),
+                    *key >= prefix && *key < &bound[..],
+                    "{key:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn paginated_scans_continue_after_insertions() {
+        let tree = MemoryTree::default();
+        let key = |room: u8, count: u64| [&[room][..], &count.to_be_bytes()[..]].concat();
+        for count in [10, 20, 30, 40, 50] {
+            tree.insert(&key(1, count), b"").unwrap();
+        }
+        // Neighbouring prefixes must not leak into the scan
+        tree.insert(&key(0, 60), b"").unwrap();
+        tree.insert(&key(2, 0), b"").unwrap();
+
+        let keys = |entries: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<_> {
+            entries.into_iter().map(|(k, _)| k).collect()
+        };
+
+        let forwards = PaginatedScan::new(vec![1]);
+        let (page, next) = forwards.page(&tree, 2).unwrap();
+        assert_eq!(keys(page), [key(1, 10), key(1, 20)]);
+
+        // Entries inserted before the continuation key are not returned, later ones are
+        tree.insert(&key(1, 15), b"").unwrap();
+        tree.insert(&key(1, 25), b"").unwrap();
+        let (page, next) = forwards
+            .clone()
+            .after(next.unwrap())
+            .page(&tree, 2)
+            .unwrap();
+        assert_eq!(keys(page), [key(1, 25), key(1, 30)]);
+
+        let (page, next) = forwards
+            .clone()
+            .after(next.unwrap())
+            .page(&tree, 2)
+            .unwrap();
+        assert_eq!(keys(page), [key(1, 40), key(1, 50)]);
+        let (page, next) = forwards.after(next.unwrap()).page(&tree, 2).unwrap();
+        assert!(page.is_empty());
+        assert_eq!(next, None);
+
+        let backwards = PaginatedScan::new(vec![1]).backwards();
+        let (page, next) = backwards.page(&tree, 3).unwrap();
+        assert_eq!(keys(page), [key(1, 50), key(1, 40), key(1, 30)]);
+
+        tree.insert(&key(1, 35), b"").unwrap();
+        tree.insert(&key(1, 5), b"").unwrap();
+        let (page, next) = backwards.after(next.unwrap()).page(&tree, 3).unwrap();
+        assert_eq!(keys(page), [key(1, 25), key(1, 20), key(1, 15)]);
+
+        // Starting after a key that doesn't exist works too
+        let (page, next) = PaginatedScan::new(vec![1])
+            .after(key(1, 12))
+            .backwards()
+            .page(&tree, 3)
+            .unwrap();
+        assert_eq!(keys(page), [key(1, 10), key(1, 5)]);
+        assert_eq!(next, None);
+
+        // There is no largest key to start a backwards scan of everything from
+        assert!(PaginatedScan::new(Vec::new())
+            .backwards()
+            .iter(&tree)
+            .is_err());
+        assert!(PaginatedScan::new(vec![0xff])
+            .backwards()
+            .iter(&tree)
+            .is_err());
+        assert!(PaginatedScan::new(Vec::new())
+            .after(key(1, 30))
+            .backwards()
+            .iter(&tree)
+            .is_ok());
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     database::{
-        abstraction::{watchers::Watchers, KeyValueDatabaseEngine, KvTree},
+        abstraction::{watchers::Watchers, CasError, KeyValueDatabaseEngine, KvTree},
         Config,
     },
     Result,
@@ -15,6 +15,10 @@ pub struct Engine {
     persy: Persy,
 }
 
+impl Engine {
+    pub const NAME: &str = "persy";
+}
+
 impl KeyValueDatabaseEngine for Arc<Engine> {
     fn open(config: &Config) -> Result<Self> {
         let mut cfg = persy::Config::new();
@@ -42,6 +46,10 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
         }))
     }
 
+    fn backend_name(&self) -> &'static str {
+        Engine::NAME
+    }
+
     fn flush(&self) -> Result<()> {
         Ok(())
     }
@@ -104,6 +112,36 @@ impl KvTree for PersyTree {
         Ok(())
     }
 
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), CasError>> {
+        let mut tx = self.begin()?;
+        let current = tx
+            .get::<ByteVec, ByteVec>(&self.name, &ByteVec::from(key))?
+            .next()
+            .map(|v| (*v).to_owned());
+        if current.as_deref() != old {
+            return Ok(Err(CasError { current }));
+        }
+
+        match new {
+            Some(new) => {
+                tx.put::<ByteVec, ByteVec>(&self.name, ByteVec::from(key), ByteVec::from(new))?
+            }
+            None => tx.remove::<ByteVec, ByteVec>(&self.name, ByteVec::from(key), None)?,
+        }
+        tx.prepare()?.commit()?;
+
+        if new.is_some() {
+            self.watchers.wake(key);
+        }
+
+        Ok(Ok(()))
+    }
+
     fn remove(&self, key: &[u8]) -> Result<()> {
         let mut tx = self.begin()?;
         tx.remove::<ByteVec, ByteVec>(&self.name, ByteVec::from(key), None)?;
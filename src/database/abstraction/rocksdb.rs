@@ -1,4 +1,4 @@
-use super::{super::Config, watchers::Watchers, KeyValueDatabaseEngine, KvTree};
+use super::{super::Config, watchers::Watchers, CasError, KeyValueDatabaseEngine, KvTree};
 use crate::{utils, Result};
 use std::{
     future::Future,
@@ -62,6 +62,10 @@ fn db_options(max_open_files: i32, rocksdb_cache: &rocksdb::Cache) -> rocksdb::O
     db_opts
 }
 
+impl Engine {
+    pub const NAME: &str = "rocksdb";
+}
+
 impl KeyValueDatabaseEngine for Arc<Engine> {
     fn open(config: &Config) -> Result<Self> {
         let cache_capacity_bytes = (config.db_cache_capacity_mb * 1024.0 * 1024.0) as usize;
@@ -110,11 +114,19 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
         }))
     }
 
+    fn backend_name(&self) -> &'static str {
+        Engine::NAME
+    }
+
     fn flush(&self) -> Result<()> {
         // TODO?
         Ok(())
     }
 
+    fn persist(&self) -> Result<()> {
+        Ok(self.rocks.flush_wal(true)?)
+    }
+
     fn memory_usage(&self) -> Result<String> {
         let stats =
             rocksdb::perf::get_memory_usage_stats(Some(&[&self.rocks]), Some(&[&self.cache]))?;
@@ -228,6 +240,32 @@ impl KvTree for RocksDbEngineTree<'_> {
         Ok(())
     }
 
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), CasError>> {
+        let lock = self.write_lock.write().unwrap();
+
+        let current = self.db.rocks.get_cf(&self.cf(), key)?;
+        if current.as_deref() != old {
+            return Ok(Err(CasError { current }));
+        }
+
+        match new {
+            Some(new) => self.db.rocks.put_cf(&self.cf(), key, new)?,
+            None => self.db.rocks.delete_cf(&self.cf(), key)?,
+        }
+        drop(lock);
+
+        if new.is_some() {
+            self.watchers.wake(key);
+        }
+
+        Ok(Ok(()))
+    }
+
     fn scan_prefix<'a>(
         &'a self,
         prefix: Vec<u8>,
@@ -3,7 +3,7 @@ use crate::{utils, Result};
 use std::{future::Future, pin::Pin, sync::Arc};
 use tracing::warn;
 
-use super::{DatabaseEngine, Tree};
+use super::{CasError, DatabaseEngine, Tree};
 
 pub struct Engine(sled::Db);
 
@@ -100,6 +100,20 @@ impl Tree for SledEngineTree {
             .map(|o| o.expect("increment always sets a value").to_vec())?)
     }
 
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), CasError>> {
+        Ok(self
+            .0
+            .compare_and_swap(key, old, new)?
+            .map_err(|e| CasError {
+                current: e.current.map(|v| v.to_vec()),
+            }))
+    }
+
     fn scan_prefix<'a>(
         &'a self,
         prefix: Vec<u8>,
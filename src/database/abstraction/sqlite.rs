@@ -1,13 +1,17 @@
-use super::{watchers::Watchers, KeyValueDatabaseEngine, KvTree};
-use crate::{database::Config, Result};
-use parking_lot::{Mutex, MutexGuard};
+use super::{watchers::Watchers, CasError, KeyValueDatabaseEngine, KvTree};
+use crate::{database::Config, Error, Result};
+use parking_lot::{Condvar, Mutex, MutexGuard};
 use rusqlite::{Connection, DatabaseName::Main, OptionalExtension};
 use std::{
     cell::RefCell,
+    collections::HashMap,
     future::Future,
+    mem,
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    thread,
+    time::Duration,
 };
 use thread_local::ThreadLocal;
 use tracing::debug;
@@ -39,6 +43,7 @@ impl<T> Drop for NonAliasingBox<T> {
 
 pub struct Engine {
     writer: Mutex<Connection>,
+    group_commit: Option<GroupCommit>,
     read_conn_tls: ThreadLocal<Connection>,
     read_iterator_conn_tls: ThreadLocal<Connection>,
 
@@ -46,7 +51,43 @@ pub struct Engine {
     cache_size_per_thread: u32,
 }
 
+/// Single writes that are collected for `sqlite_group_commit_ms` and committed in one transaction
+struct GroupCommit {
+    window: Duration,
+    pending: Mutex<PendingWrites>,
+    committed: Condvar,
+}
+
+#[derive(Default)]
+struct PendingWrites {
+    writes: Vec<Write>,
+    /// The batch new writes are added to
+    batch: u64,
+    /// Whether a thread is already waiting to commit the current batch
+    leader: bool,
+    /// Writes that haven't returned yet, including the ones of batches that are being committed
+    in_flight: usize,
+    /// All batches before this one are committed
+    done: u64,
+    /// Failed batches and how many of their writers still have to see the error
+    failed: HashMap<u64, usize>,
+}
+
+enum Write {
+    Insert {
+        table: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        table: String,
+        key: Vec<u8>,
+    },
+}
+
 impl Engine {
+    pub const NAME: &str = "sqlite";
+
     fn prepare_conn(path: &Path, cache_size_kb: u32) -> Result<Connection> {
         let conn = Connection::open(path)?;
 
@@ -73,6 +114,80 @@ impl Engine {
             .get_or(|| Self::prepare_conn(&self.path, self.cache_size_per_thread).unwrap())
     }
 
+    /// Adds the write to the current batch and returns once the batch is committed. The first
+    /// writer of a batch waits for the group commit window and then commits all writes of the
+    /// batch in one transaction. The window is skipped if no other writes are in flight, because
+    /// there would be nothing to collect.
+    fn group_write(&self, group: &GroupCommit, write: Write) -> Result<()> {
+        let mut pending = group.pending.lock();
+        pending.writes.push(write);
+        pending.in_flight += 1;
+        let batch = pending.batch;
+
+        if pending.leader {
+            while pending.done <= batch {
+                group.committed.wait(&mut pending);
+            }
+            pending.in_flight -= 1;
+
+            let Some(remaining) = pending.failed.get_mut(&batch) else {
+                return Ok(());
+            };
+            *remaining -= 1;
+            if *remaining == 0 {
+                pending.failed.remove(&batch);
+            }
+            return Err(Error::bad_database("Group commit failed."));
+        }
+
+        pending.leader = true;
+        if pending.in_flight > 1 {
+            MutexGuard::unlocked(&mut pending, || thread::sleep(group.window));
+        }
+        drop(pending);
+
+        // Batches are taken and marked as done while holding the writer, so they are committed
+        // in order
+        let writer = self.write_lock();
+        let writes = {
+            let mut pending = group.pending.lock();
+            pending.batch += 1;
+            pending.leader = false;
+            mem::take(&mut pending.writes)
+        };
+        let result = Self::commit_writes(&writer, &writes);
+
+        let mut pending = group.pending.lock();
+        pending.done = pending.done.max(batch + 1);
+        pending.in_flight -= 1;
+        if result.is_err() && writes.len() > 1 {
+            pending.failed.insert(batch, writes.len() - 1);
+        }
+        drop(pending);
+        drop(writer);
+        group.committed.notify_all();
+
+        result
+    }
+
+    fn commit_writes(conn: &Connection, writes: &[Write]) -> Result<()> {
+        let transaction = conn.unchecked_transaction()?;
+        for write in writes {
+            match write {
+                Write::Insert { table, key, value } => transaction.execute(
+                    &format!("INSERT OR REPLACE INTO {table} (key, value) VALUES (?, ?)"),
+                    [key, value],
+                )?,
+                Write::Remove { table, key } => {
+                    transaction.execute(&format!("DELETE FROM {table} WHERE key = ?"), [key])?
+                }
+            };
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
     pub fn flush_wal(self: &Arc<Self>) -> Result<()> {
         self.write_lock()
             .pragma_update(Some(Main), "wal_checkpoint", "RESTART")?;
@@ -94,8 +209,15 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
 
         let writer = Mutex::new(Engine::prepare_conn(&path, cache_size_per_thread)?);
 
+        let group_commit = (config.sqlite_group_commit_ms > 0).then(|| GroupCommit {
+            window: Duration::from_millis(config.sqlite_group_commit_ms),
+            pending: Mutex::default(),
+            committed: Condvar::new(),
+        });
+
         let arc = Arc::new(Engine {
             writer,
+            group_commit,
             read_conn_tls: ThreadLocal::new(),
             read_iterator_conn_tls: ThreadLocal::new(),
             path,
@@ -115,14 +237,34 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
         }))
     }
 
+    fn backend_name(&self) -> &'static str {
+        Engine::NAME
+    }
+
     fn flush(&self) -> Result<()> {
         // we enabled PRAGMA synchronous=normal, so this should not be necessary
         Ok(())
     }
 
+    fn persist(&self) -> Result<()> {
+        // The checkpoint syncs the WAL, which synchronous=normal doesn't do on every commit
+        self.flush_wal()
+    }
+
     fn cleanup(&self) -> Result<()> {
         self.flush_wal()
     }
+
+    fn check_integrity(&self) -> Result<Option<Vec<String>>> {
+        let conn = self.read_lock();
+        let mut statement = conn.prepare("PRAGMA integrity_check")?;
+        let problems = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter(|result| !matches!(result.as_deref(), Ok("ok")))
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(Some(problems))
+    }
 }
 
 pub struct SqliteTable {
@@ -190,9 +332,20 @@ impl KvTree for SqliteTable {
     }
 
     fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        let guard = self.engine.write_lock();
-        self.insert_with_guard(&guard, key, value)?;
-        drop(guard);
+        if let Some(group) = &self.engine.group_commit {
+            self.engine.group_write(
+                group,
+                Write::Insert {
+                    table: self.name.clone(),
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                },
+            )?;
+        } else {
+            let guard = self.engine.write_lock();
+            self.insert_with_guard(&guard, key, value)?;
+            drop(guard);
+        }
         self.watchers.wake(key);
         Ok(())
     }
@@ -228,7 +381,48 @@ impl KvTree for SqliteTable {
         Ok(())
     }
 
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), CasError>> {
+        let guard = self.engine.write_lock();
+
+        let current = self.get_with_guard(&guard, key)?;
+        if current.as_deref() != old {
+            return Ok(Err(CasError { current }));
+        }
+
+        match new {
+            Some(new) => self.insert_with_guard(&guard, key, new)?,
+            None => {
+                guard.execute(
+                    format!("DELETE FROM {} WHERE key = ?", self.name).as_str(),
+                    [key],
+                )?;
+            }
+        }
+        drop(guard);
+
+        if new.is_some() {
+            self.watchers.wake(key);
+        }
+
+        Ok(Ok(()))
+    }
+
     fn remove(&self, key: &[u8]) -> Result<()> {
+        if let Some(group) = &self.engine.group_commit {
+            return self.engine.group_write(
+                group,
+                Write::Remove {
+                    table: self.name.clone(),
+                    key: key.to_vec(),
+                },
+            );
+        }
+
         let guard = self.engine.write_lock();
 
         guard.execute(
@@ -327,6 +521,23 @@ impl KvTree for SqliteTable {
         self.watchers.watch(prefix)
     }
 
+    fn remove_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        let guard = self.engine.write_lock();
+
+        let removed = match super::prefix_upper_bound(prefix) {
+            Some(upper_bound) => guard.execute(
+                format!("DELETE FROM {} WHERE key >= ? AND key < ?", self.name).as_str(),
+                [prefix, upper_bound.as_slice()],
+            )?,
+            None => guard.execute(
+                format!("DELETE FROM {} WHERE key >= ?", self.name).as_str(),
+                [prefix],
+            )?,
+        };
+
+        Ok(removed)
+    }
+
     fn clear(&self) -> Result<()> {
         debug!("clear: running");
         self.engine
@@ -336,3 +547,144 @@ impl KvTree for SqliteTable {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+    use futures_util::FutureExt;
+
+    fn open_engine(group_commit_ms: u64) -> (Arc<Engine>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("conduit-test-{}", utils::random_string(8)));
+        std::fs::create_dir(&dir).unwrap();
+        let config: Config = Figment::new()
+            .merge(Toml::string(&format!(
+                r#"
+                server_name = "example.com"
+                database_path = "{}"
+                sqlite_group_commit_ms = {group_commit_ms}
+                "#,
+                dir.display()
+            )))
+            .extract()
+            .unwrap();
+
+        (Arc::<Engine>::open(&config).unwrap(), dir)
+    }
+
+    /// Inserts from several threads at once and returns how many rows were committed and
+    /// whether a watcher was woken after its key could be read
+    fn write_concurrently(group_commit_ms: u64) -> (usize, bool) {
+        let (engine, dir) = open_engine(group_commit_ms);
+        let tree = engine.open_tree("test").unwrap();
+
+        let watch = tree.watch_prefix(b"watched");
+        thread::scope(|s| {
+            for t in 0..8_u8 {
+                let tree = &tree;
+                s.spawn(move || {
+                    for i in 0..50_u8 {
+                        tree.insert(&[t, i], b"value").unwrap();
+                    }
+                });
+            }
+            tree.insert(b"watched", b"value").unwrap();
+        });
+        let notified = watch.now_or_never().is_some() && tree.get(b"watched").unwrap().is_some();
+        let rows = tree.iter().count();
+
+        drop(tree);
+        drop(engine);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        (rows, notified)
+    }
+
+    #[test]
+    fn group_commit() {
+        assert_eq!(write_concurrently(0), (8 * 50 + 1, true));
+        assert_eq!(write_concurrently(5), (8 * 50 + 1, true));
+    }
+
+    #[test]
+    fn failed_group_commit_is_reported_to_every_writer() {
+        let (engine, dir) = open_engine(5);
+        let tree = engine.open_tree("test").unwrap();
+        let group = engine.group_commit.as_ref().unwrap();
+
+        let failures = thread::scope(|s| {
+            let threads: Vec<_> = (0..8_u8)
+                .map(|t| {
+                    let engine = &engine;
+                    s.spawn(move || {
+                        engine.group_write(
+                            group,
+                            Write::Insert {
+                                table: "missing".to_owned(),
+                                key: vec![t],
+                                value: b"value".to_vec(),
+                            },
+                        )
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .filter(|thread| thread.join().unwrap().is_err())
+                .count()
+        });
+        assert_eq!(failures, 8);
+        assert!(group.pending.lock().failed.is_empty());
+
+        // Later batches are not affected
+        tree.insert(b"key", b"value").unwrap();
+        assert!(tree.get(b"key").unwrap().is_some());
+
+        drop(tree);
+        drop(engine);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let (engine, dir) = open_engine(0);
+        let tree = engine.open_tree("test").unwrap();
+
+        tree.compare_and_swap(b"key", None, Some(&b"1"[..]))
+            .unwrap()
+            .unwrap();
+        tree.compare_and_swap(b"key", Some(&b"1"[..]), Some(&b"2"[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(tree.get(b"key").unwrap().as_deref(), Some(&b"2"[..]));
+
+        assert_eq!(
+            tree.compare_and_swap(b"key", Some(&b"1"[..]), Some(&b"3"[..]))
+                .unwrap(),
+            Err(CasError {
+                current: Some(b"2".to_vec())
+            })
+        );
+        assert_eq!(
+            tree.compare_and_swap(b"key", None, Some(&b"3"[..]))
+                .unwrap(),
+            Err(CasError {
+                current: Some(b"2".to_vec())
+            })
+        );
+        assert_eq!(tree.get(b"key").unwrap().as_deref(), Some(&b"2"[..]));
+
+        tree.compare_and_swap(b"key", Some(&b"2"[..]), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(tree.get(b"key").unwrap(), None);
+
+        drop(tree);
+        drop(engine);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -7,7 +7,12 @@ use ruma::{
     RoomId, UserId,
 };
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use crate::{
+    database::KeyValueDatabase,
+    service, services,
+    utils::{self, KeyBuilder},
+    Error, Result,
+};
 
 impl service::account_data::Data for KeyValueDatabase {
     /// Places one event in the account data of the user and removes the previous entry.
@@ -19,22 +24,15 @@ impl service::account_data::Data for KeyValueDatabase {
         event_type: RoomAccountDataEventType,
         data: &serde_json::Value,
     ) -> Result<()> {
-        let mut prefix = room_id
-            .map(|r| r.to_string())
-            .unwrap_or_default()
-            .as_bytes()
-            .to_vec();
-        prefix.push(0xff);
-        prefix.extend_from_slice(user_id.as_bytes());
-        prefix.push(0xff);
-
-        let mut roomuserdataid = prefix.clone();
-        roomuserdataid.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
-        roomuserdataid.push(0xff);
-        roomuserdataid.extend_from_slice(event_type.to_string().as_bytes());
-
-        let mut key = prefix;
-        key.extend_from_slice(event_type.to_string().as_bytes());
+        let prefix = account_data_prefix(room_id, user_id);
+
+        let roomuserdataid = prefix
+            .clone()
+            .push_u64_be(services().globals.next_count()?)
+            .push_field(event_type.to_string().as_bytes())
+            .build();
+
+        let key = prefix.push_field(event_type.to_string().as_bytes()).build();
 
         if data.get("type").is_none() || data.get("content").is_none() {
             return Err(Error::BadRequest(
@@ -69,15 +67,9 @@ impl service::account_data::Data for KeyValueDatabase {
         user_id: &UserId,
         kind: RoomAccountDataEventType,
     ) -> Result<Option<Box<serde_json::value::RawValue>>> {
-        let mut key = room_id
-            .map(|r| r.to_string())
-            .unwrap_or_default()
-            .as_bytes()
-            .to_vec();
-        key.push(0xff);
-        key.extend_from_slice(user_id.as_bytes());
-        key.push(0xff);
-        key.extend_from_slice(kind.to_string().as_bytes());
+        let key = account_data_prefix(room_id, user_id)
+            .push_field(kind.to_string().as_bytes())
+            .build();
 
         self.roomusertype_roomuserdataid
             .get(&key)?
@@ -104,18 +96,11 @@ impl service::account_data::Data for KeyValueDatabase {
     ) -> Result<HashMap<RoomAccountDataEventType, Raw<AnyEphemeralRoomEvent>>> {
         let mut userdata = HashMap::new();
 
-        let mut prefix = room_id
-            .map(|r| r.to_string())
-            .unwrap_or_default()
-            .as_bytes()
-            .to_vec();
-        prefix.push(0xff);
-        prefix.extend_from_slice(user_id.as_bytes());
-        prefix.push(0xff);
+        let prefix = account_data_prefix(room_id, user_id);
 
         // Skip the data that's exactly at since, because we sent that last time
-        let mut first_possible = prefix.clone();
-        first_possible.extend_from_slice(&(since + 1).to_be_bytes());
+        let first_possible = prefix.clone().push_u64_be(since + 1).build();
+        let prefix = prefix.prefix();
 
         for r in self
             .roomuserdataid_accountdata
@@ -123,15 +108,13 @@ impl service::account_data::Data for KeyValueDatabase {
             .take_while(move |(k, _)| k.starts_with(&prefix))
             .map(|(k, v)| {
                 Ok::<_, Error>((
-                    RoomAccountDataEventType::try_from(
-                        utils::string_from_bytes(k.rsplit(|&b| b == 0xff).next().ok_or_else(
-                            || Error::bad_database("RoomUserData ID in db is invalid."),
-                        )?)
-                        .map_err(|_| Error::bad_database("RoomUserData ID in db is invalid."))?,
-                    )
-                    .map_err(|_| Error::bad_database("RoomUserData ID in db is invalid."))?,
+                    parse_roomuserdataid_kind(&k)?,
                     serde_json::from_slice::<Raw<AnyEphemeralRoomEvent>>(&v).map_err(|_| {
-                        Error::bad_database("Database contains invalid account data.")
+                        Error::corruption(
+                            "roomuserdataid_accountdata",
+                            &k,
+                            "Database contains invalid account data.",
+                        )
                     })?,
                 ))
             })
@@ -143,3 +126,55 @@ impl service::account_data::Data for KeyValueDatabase {
         Ok(userdata)
     }
 }
+
+/// The room (empty for global account data) and user fields that all account data keys start with
+fn account_data_prefix(room_id: Option<&RoomId>, user_id: &UserId) -> KeyBuilder {
+    KeyBuilder::new()
+        .push_field(room_id.map(RoomId::as_bytes).unwrap_or_default())
+        .push_field(user_id.as_bytes())
+}
+
+/// Reads the event type from the end of a `roomuserdataid_accountdata` key.
+fn parse_roomuserdataid_kind(key: &[u8]) -> Result<RoomAccountDataEventType> {
+    let invalid = || {
+        Error::corruption(
+            "roomuserdataid_accountdata",
+            key,
+            "RoomUserData ID in db is invalid.",
+        )
+    };
+
+    let kind = key.rsplit(|&b| b == 0xff).next().ok_or_else(invalid)?;
+
+    Ok(RoomAccountDataEventType::from(
+        utils::string_from_bytes(kind).map_err(|_| invalid())?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_roomuserdataid() {
+        let mut key = b"\xff@alice:example.com\xff".to_vec();
+        key.extend_from_slice(&5_u64.to_be_bytes());
+        key.push(0xff);
+
+        let mut valid = key.clone();
+        valid.extend_from_slice(b"m.tag");
+        assert_eq!(
+            parse_roomuserdataid_kind(&valid).unwrap(),
+            RoomAccountDataEventType::Tag
+        );
+
+        key.extend_from_slice(b"m.\xc3\x28");
+        match parse_roomuserdataid_kind(&key) {
+            Err(Error::Corruption { tree, key: k, .. }) => {
+                assert_eq!(tree, "roomuserdataid_accountdata");
+                assert_eq!(k, key);
+            }
+            other => panic!("expected a corruption error, got {other:?}"),
+        }
+    }
+}
@@ -6,10 +6,13 @@ use lru_cache::LruCache;
 use ruma::{
     api::federation::discovery::{ServerSigningKeys, VerifyKey},
     signatures::Ed25519KeyPair,
-    DeviceId, MilliSecondsSinceUnixEpoch, OwnedServerSigningKeyId, ServerName, UserId,
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedServerSigningKeyId, RoomId, ServerName, UserId,
 };
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use crate::{
+    database::{abstraction, KeyValueDatabase},
+    service, services, utils, Error, Result,
+};
 
 pub const COUNTER: &[u8] = b"c";
 pub const LAST_CHECK_FOR_UPDATES_COUNT: &[u8] = b"u";
@@ -17,8 +20,39 @@ pub const LAST_CHECK_FOR_UPDATES_COUNT: &[u8] = b"u";
 #[async_trait]
 impl service::globals::Data for KeyValueDatabase {
     fn next_count(&self) -> Result<u64> {
-        utils::u64_from_bytes(&self.global.increment(COUNTER)?)
-            .map_err(|_| Error::bad_database("Count has invalid bytes."))
+        let count = utils::u64_from_bytes(&self.global.increment(COUNTER)?)
+            .map_err(|_| Error::bad_database("Count has invalid bytes."))?;
+
+        // The counter saturates, so from here on every count would be the same. u64::MAX is also
+        // used as the "latest" pagination token.
+        if count == u64::MAX {
+            return Err(Error::bad_database("Global counter is exhausted."));
+        }
+
+        Ok(count)
+    }
+
+    fn reserve_counts(&self, amount: u64) -> Result<u64> {
+        loop {
+            let current = self.global.get(COUNTER)?;
+            let count = current.as_deref().map_or(Ok(0_u64), |bytes| {
+                utils::u64_from_bytes(bytes)
+                    .map_err(|_| Error::bad_database("Count has invalid bytes."))
+            })?;
+
+            let end = count
+                .checked_add(amount)
+                .filter(|end| *end < u64::MAX)
+                .ok_or_else(|| Error::bad_database("Global counter is exhausted."))?;
+
+            if self
+                .global
+                .compare_and_swap(COUNTER, current.as_deref(), Some(&end.to_be_bytes()))?
+                .is_ok()
+            {
+                return Ok(end);
+            }
+        }
     }
 
     fn current_count(&self) -> Result<u64> {
@@ -62,6 +96,7 @@ impl service::globals::Data for KeyValueDatabase {
 
         futures.push(self.userroomid_joined.watch_prefix(&userid_prefix));
         futures.push(self.userroomid_invitestate.watch_prefix(&userid_prefix));
+        futures.push(self.userroomid_knockstate.watch_prefix(&userid_prefix));
         futures.push(self.userroomid_leftstate.watch_prefix(&userid_prefix));
         futures.push(
             self.userroomid_notificationcount
@@ -133,14 +168,71 @@ impl service::globals::Data for KeyValueDatabase {
         Ok(())
     }
 
+    async fn watch_account_data(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        let mut userdeviceid_prefix = user_id.as_bytes().to_vec();
+        userdeviceid_prefix.push(0xff);
+        userdeviceid_prefix.extend_from_slice(device_id.as_bytes());
+        userdeviceid_prefix.push(0xff);
+
+        let joined_rooms: Vec<_> = services()
+            .rooms
+            .state_cache
+            .rooms_joined(user_id)
+            .filter_map(|r| r.ok())
+            .collect();
+
+        let mut futures = FuturesUnordered::new();
+
+        futures.push(self.todeviceid_events.watch_prefix(&userdeviceid_prefix));
+
+        for prefix in account_data_watch_prefixes(user_id, joined_rooms.iter().map(|r| &**r)) {
+            futures.push(self.roomusertype_roomuserdataid.watch_prefix(&prefix));
+        }
+
+        futures.push(Box::pin(services().globals.rotate.watch()));
+
+        futures.next().await;
+
+        Ok(())
+    }
+
     fn cleanup(&self) -> Result<()> {
         self._db.cleanup()
     }
 
+    fn persist(&self) -> Result<()> {
+        self._db.persist()
+    }
+
+    fn database_backend(&self) -> &'static str {
+        self._db.backend_name()
+    }
+
+    fn check_integrity(&self, sample_size: usize) -> Result<Vec<String>> {
+        if let Some(problems) = self._db.check_integrity()? {
+            return Ok(problems);
+        }
+
+        Ok(
+            abstraction::dangling_references(&*self.eventid_pduid, &*self.pduid_pdu, sample_size)?
+                .into_iter()
+                .map(|event_id| {
+                    format!(
+                        "eventid_pduid entry for {} points to a missing PDU",
+                        String::from_utf8_lossy(&event_id)
+                    )
+                })
+                .collect(),
+        )
+    }
+
     fn memory_usage(&self) -> String {
         let pdu_cache = self.pdu_cache.lock().unwrap().len();
         let shorteventid_cache = self.shorteventid_cache.lock().unwrap().len();
-        let auth_chain_cache = self.auth_chain_cache.lock().unwrap().len();
+        let (auth_chain_cache, auth_chain_cache_bytes) = {
+            let c = self.auth_chain_cache.lock().unwrap();
+            (c.len(), c.bytes())
+        };
         let eventidshort_cache = self.eventidshort_cache.lock().unwrap().len();
         let statekeyshort_cache = self.statekeyshort_cache.lock().unwrap().len();
         let our_real_users_cache = self.our_real_users_cache.read().unwrap().len();
@@ -151,7 +243,7 @@ impl service::globals::Data for KeyValueDatabase {
             "\
 pdu_cache: {pdu_cache}
 shorteventid_cache: {shorteventid_cache}
-auth_chain_cache: {auth_chain_cache}
+auth_chain_cache: {auth_chain_cache} ({auth_chain_cache_bytes} bytes)
 eventidshort_cache: {eventidshort_cache}
 statekeyshort_cache: {statekeyshort_cache}
 our_real_users_cache: {our_real_users_cache}
@@ -175,8 +267,7 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
             *c = LruCache::new(c.capacity());
         }
         if amount > 2 {
-            let c = &mut *self.auth_chain_cache.lock().unwrap();
-            *c = LruCache::new(c.capacity());
+            self.auth_chain_cache.lock().unwrap().clear();
         }
         if amount > 3 {
             let c = &mut *self.eventidshort_cache.lock().unwrap();
@@ -210,26 +301,7 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
             |s| Ok(s.to_vec()),
         )?;
 
-        let mut parts = keypair_bytes.splitn(2, |&b| b == 0xff);
-
-        utils::string_from_bytes(
-            // 1. version
-            parts
-                .next()
-                .expect("splitn always returns at least one element"),
-        )
-        .map_err(|_| Error::bad_database("Invalid version bytes in keypair."))
-        .and_then(|version| {
-            // 2. key
-            parts
-                .next()
-                .ok_or_else(|| Error::bad_database("Invalid keypair format in database."))
-                .map(|key| (version, key))
-        })
-        .and_then(|(version, key)| {
-            Ed25519KeyPair::from_der(key, version)
-                .map_err(|_| Error::bad_database("Private or public keys are invalid."))
-        })
+        parse_keypair(&keypair_bytes)
     }
     fn remove_keypair(&self) -> Result<()> {
         self.global.remove(b"keypair")
@@ -309,3 +381,112 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
         Ok(())
     }
 }
+
+/// Prefixes of `roomusertype_roomuserdataid` that change with the global account data of a user
+/// and their room account data in the given rooms.
+fn account_data_watch_prefixes<'a>(
+    user_id: &UserId,
+    rooms: impl Iterator<Item = &'a RoomId>,
+) -> Vec<Vec<u8>> {
+    let mut userid_prefix = user_id.as_bytes().to_vec();
+    userid_prefix.push(0xff);
+
+    // Global account data is stored with an empty room id
+    let mut globaluserdata_prefix = vec![0xff];
+    globaluserdata_prefix.extend_from_slice(&userid_prefix);
+
+    let mut prefixes = vec![globaluserdata_prefix];
+    for room_id in rooms {
+        let mut roomuser_prefix = room_id.as_bytes().to_vec();
+        roomuser_prefix.push(0xff);
+        roomuser_prefix.extend_from_slice(&userid_prefix);
+        prefixes.push(roomuser_prefix);
+    }
+
+    prefixes
+}
+
+fn parse_keypair(keypair_bytes: &[u8]) -> Result<Ed25519KeyPair> {
+    let corruption = |reason: &'static str| Error::corruption("global", b"keypair", reason);
+
+    let mut parts = keypair_bytes.splitn(2, |&b| b == 0xff);
+
+    utils::string_from_bytes(
+        // 1. version
+        parts
+            .next()
+            .expect("splitn always returns at least one element"),
+    )
+    .map_err(|_| corruption("Invalid version bytes in keypair."))
+    .and_then(|version| {
+        // 2. key
+        parts
+            .next()
+            .ok_or_else(|| corruption("Invalid keypair format in database."))
+            .map(|key| (version, key))
+    })
+    .and_then(|(version, key)| {
+        Ed25519KeyPair::from_der(key, version)
+            .map_err(|_| corruption("Private or public keys are invalid."))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruma::{room_id, user_id};
+
+    fn wakes(prefixes: &[Vec<u8>], key: &[u8]) -> bool {
+        prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    #[test]
+    fn account_data_watchers() {
+        let user = user_id!("@alice:example.com");
+        let room = room_id!("!room:example.com");
+        let prefixes = account_data_watch_prefixes(user, [room].into_iter());
+
+        // Keys as written by `account_data::Data::update`
+        let mut global_key = vec![0xff];
+        global_key.extend_from_slice(b"@alice:example.com\xffm.push_rules");
+        assert!(wakes(&prefixes, &global_key));
+
+        let mut room_key = room.as_bytes().to_vec();
+        room_key.push(0xff);
+        room_key.extend_from_slice(b"@alice:example.com\xffm.fully_read");
+        assert!(wakes(&prefixes, &room_key));
+
+        // Another user's account data or a PDU in the room must not wake the watcher
+        let mut other_key = vec![0xff];
+        other_key.extend_from_slice(b"@alice:example.com.evil\xffm.push_rules");
+        assert!(!wakes(&prefixes, &other_key));
+
+        let mut pdu_id = 1_u64.to_be_bytes().to_vec();
+        pdu_id.extend_from_slice(&42_u64.to_be_bytes());
+        assert!(!wakes(&prefixes, &pdu_id));
+    }
+
+    #[test]
+    fn corrupt_keypair() {
+        assert!(parse_keypair(&utils::generate_keypair()).is_ok());
+
+        for (bytes, expected) in [
+            (&b"\xc3\x28\xffkey"[..], "Invalid version bytes in keypair."),
+            (b"version", "Invalid keypair format in database."),
+            (
+                b"version\xffnot a key",
+                "Private or public keys are invalid.",
+            ),
+        ] {
+            match parse_keypair(bytes) {
+                Err(Error::Corruption { tree, key, reason }) => {
+                    assert_eq!(tree, "global");
+                    assert_eq!(key, b"keypair");
+                    assert_eq!(reason, expected);
+                }
+                Err(e) => panic!("expected a corruption error, got {e}"),
+                Ok(_) => panic!("corrupt keypair was accepted"),
+            }
+        }
+    }
+}
@@ -1,8 +1,30 @@
 use ruma::api::client::error::ErrorKind;
 
-use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+use crate::{
+    database::KeyValueDatabase,
+    service,
+    utils::{self, KeyBuilder},
+    Error, Result,
+};
 
 impl service::media::Data for KeyValueDatabase {
+    fn file_metadata_key(
+        &self,
+        mxc: &str,
+        width: u32,
+        height: u32,
+        content_disposition: Option<&str>,
+        content_type: Option<&str>,
+    ) -> Vec<u8> {
+        KeyBuilder::new()
+            .push_field(mxc.as_bytes())
+            .push_field(&width.to_be_bytes())
+            .extend_field(&height.to_be_bytes())
+            .push_field(content_disposition.unwrap_or_default().as_bytes())
+            .push_field(content_type.unwrap_or_default().as_bytes())
+            .build()
+    }
+
     fn create_file_metadata(
         &self,
         mxc: String,
@@ -11,41 +33,42 @@ impl service::media::Data for KeyValueDatabase {
         content_disposition: Option<&str>,
         content_type: Option<&str>,
     ) -> Result<Vec<u8>> {
-        let mut key = mxc.as_bytes().to_vec();
-        key.push(0xff);
-        key.extend_from_slice(&width.to_be_bytes());
-        key.extend_from_slice(&height.to_be_bytes());
-        key.push(0xff);
-        key.extend_from_slice(
-            content_disposition
-                .as_ref()
-                .map(|f| f.as_bytes())
-                .unwrap_or_default(),
-        );
-        key.push(0xff);
-        key.extend_from_slice(
-            content_type
-                .as_ref()
-                .map(|c| c.as_bytes())
-                .unwrap_or_default(),
-        );
+        let key = self.file_metadata_key(&mxc, width, height, content_disposition, content_type);
 
         self.mediaid_file.insert(&key, &[])?;
 
         Ok(key)
     }
 
+    fn remove_file_metadata(&self, key: &[u8]) -> Result<()> {
+        self.thumbnailid_created.remove(key)?;
+        self.mediaid_file.remove(key)
+    }
+
+    fn set_thumbnail_created(&self, key: &[u8], created: u64) -> Result<()> {
+        self.thumbnailid_created.insert(key, &created.to_be_bytes())
+    }
+
+    fn thumbnails<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(Vec<u8>, u64)>> + 'a> {
+        Box::new(self.thumbnailid_created.iter().map(|(key, created)| {
+            let created = utils::u64_from_bytes(&created).map_err(|_| {
+                Error::corruption("thumbnailid_created", &key, "Invalid creation time.")
+            })?;
+            Ok((key, created))
+        }))
+    }
+
     fn search_file_metadata(
         &self,
         mxc: String,
         width: u32,
         height: u32,
     ) -> Result<(Option<String>, Option<String>, Vec<u8>)> {
-        let mut prefix = mxc.as_bytes().to_vec();
-        prefix.push(0xff);
-        prefix.extend_from_slice(&width.to_be_bytes());
-        prefix.extend_from_slice(&height.to_be_bytes());
-        prefix.push(0xff);
+        let prefix = KeyBuilder::new()
+            .push_field(mxc.as_bytes())
+            .push_field(&width.to_be_bytes())
+            .extend_field(&height.to_be_bytes())
+            .prefix();
 
         let (key, _) = self
             .mediaid_file
@@ -53,30 +76,83 @@ impl service::media::Data for KeyValueDatabase {
             .next()
             .ok_or(Error::BadRequest(ErrorKind::NotFound, "Media not found"))?;
 
-        let mut parts = key.rsplit(|&b| b == 0xff);
+        let (content_disposition, content_type) = parse_mediaid(&key)?;
+        Ok((content_disposition, content_type, key))
+    }
+}
 
-        let content_type = parts
-            .next()
-            .map(|bytes| {
-                utils::string_from_bytes(bytes).map_err(|_| {
-                    Error::bad_database("Content type in mediaid_file is invalid unicode.")
-                })
+/// Reads the content disposition and content type from the end of a `mediaid_file` key.
+fn parse_mediaid(key: &[u8]) -> Result<(Option<String>, Option<String>)> {
+    let mut parts = key.rsplit(|&b| b == 0xff);
+
+    let content_type = parts
+        .next()
+        .map(|bytes| {
+            utils::string_from_bytes(bytes).map_err(|_| {
+                Error::corruption(
+                    "mediaid_file",
+                    key,
+                    "Content type in mediaid_file is invalid unicode.",
+                )
             })
-            .transpose()?;
+        })
+        .transpose()?;
 
-        let content_disposition_bytes = parts
-            .next()
-            .ok_or_else(|| Error::bad_database("Media ID in db is invalid."))?;
-
-        let content_disposition = if content_disposition_bytes.is_empty() {
-            None
-        } else {
-            Some(
-                utils::string_from_bytes(content_disposition_bytes).map_err(|_| {
-                    Error::bad_database("Content Disposition in mediaid_file is invalid unicode.")
-                })?,
-            )
-        };
-        Ok((content_disposition, content_type, key))
+    let content_disposition_bytes = parts
+        .next()
+        .ok_or_else(|| Error::corruption("mediaid_file", key, "Media ID in db is invalid."))?;
+
+    let content_disposition = if content_disposition_bytes.is_empty() {
+        None
+    } else {
+        Some(
+            utils::string_from_bytes(content_disposition_bytes).map_err(|_| {
+                Error::corruption(
+                    "mediaid_file",
+                    key,
+                    "Content Disposition in mediaid_file is invalid unicode.",
+                )
+            })?,
+        )
+    };
+
+    Ok((content_disposition, content_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_mediaid() {
+        let mut key = b"mxc://example.com/abc\xff".to_vec();
+        key.extend_from_slice(&0_u32.to_be_bytes());
+        key.extend_from_slice(&0_u32.to_be_bytes());
+        key.push(0xff);
+
+        let mut valid = key.clone();
+        valid.extend_from_slice(b"inline\xffimage/png");
+        assert_eq!(
+            parse_mediaid(&valid).unwrap(),
+            (Some("inline".to_owned()), Some("image/png".to_owned()))
+        );
+
+        key.extend_from_slice(b"inline\xffimage/\xc3\x28");
+        assert!(matches!(
+            parse_mediaid(&key),
+            Err(Error::Corruption {
+                tree: "mediaid_file",
+                ..
+            })
+        ));
+
+        // A key without any separator
+        assert!(matches!(
+            parse_mediaid(b"image/png"),
+            Err(Error::Corruption {
+                reason: "Media ID in db is invalid.",
+                ..
+            })
+        ));
     }
 }
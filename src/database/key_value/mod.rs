@@ -6,8 +6,11 @@ mod key_backups;
 mod media;
 //mod pdu;
 mod pusher;
+mod reports;
 mod rooms;
 mod sending;
+mod sso;
+mod threepid;
 mod transaction_ids;
 mod uiaa;
 mod users;
@@ -0,0 +1,41 @@
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, reports::Report},
+    services, utils, Error, Result,
+};
+
+impl service::reports::Data for KeyValueDatabase {
+    fn add_report(&self, report: &Report) -> Result<u64> {
+        let report_id = services().globals.next_count()?;
+
+        self.reportid_report.insert(
+            &report_id.to_be_bytes(),
+            &serde_json::to_vec(report).expect("Report::to_vec always works"),
+        )?;
+
+        Ok(report_id)
+    }
+
+    fn pending_reports<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(u64, Report)>> + 'a> {
+        Box::new(self.reportid_report.iter().map(|(key, value)| {
+            let report_id = utils::u64_from_bytes(&key).map_err(|_| {
+                Error::corruption("reportid_report", &key, "Report ID in db is invalid.")
+            })?;
+            let report = serde_json::from_slice(&value).map_err(|_| {
+                Error::corruption("reportid_report", &key, "Report in db is invalid.")
+            })?;
+
+            Ok((report_id, report))
+        }))
+    }
+
+    fn remove_report(&self, report_id: u64) -> Result<bool> {
+        let key = report_id.to_be_bytes();
+        if self.reportid_report.get(&key)?.is_none() {
+            return Ok(false);
+        }
+
+        self.reportid_report.remove(&key)?;
+        Ok(true)
+    }
+}
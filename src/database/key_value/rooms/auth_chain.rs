@@ -5,8 +5,8 @@ use crate::{database::KeyValueDatabase, service, utils, Result};
 impl service::rooms::auth_chain::Data for KeyValueDatabase {
     fn get_cached_eventid_authchain(&self, key: &[u64]) -> Result<Option<Arc<HashSet<u64>>>> {
         // Check RAM cache
-        if let Some(result) = self.auth_chain_cache.lock().unwrap().get_mut(key) {
-            return Ok(Some(Arc::clone(result)));
+        if let Some(result) = self.auth_chain_cache.lock().unwrap().get(key) {
+            return Ok(Some(result));
         }
 
         // We only save auth chains for single events in the db
@@ -56,9 +56,18 @@ impl service::rooms::lazy_loading::Data for KeyValueDatabase {
         prefix.extend_from_slice(room_id.as_bytes());
         prefix.push(0xff);
 
-        for (key, _) in self.lazyloadedids.scan_prefix(prefix) {
-            self.lazyloadedids.remove(&key)?;
-        }
+        self.lazyloadedids.remove_prefix(&prefix)?;
+
+        Ok(())
+    }
+
+    fn lazy_load_forget_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+
+        self.lazyloadedids.remove_prefix(&prefix)?;
 
         Ok(())
     }
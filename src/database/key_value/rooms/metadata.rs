@@ -42,4 +42,23 @@ impl service::rooms::metadata::Data for KeyValueDatabase {
 
         Ok(())
     }
+
+    fn set_replacement_room(&self, room_id: &RoomId, replacement_room: &RoomId) -> Result<()> {
+        self.roomid_replacementroomid
+            .insert(room_id.as_bytes(), replacement_room.as_bytes())
+    }
+
+    fn replacement_room(&self, room_id: &RoomId) -> Result<Option<OwnedRoomId>> {
+        self.roomid_replacementroomid
+            .get(room_id.as_bytes())?
+            .map(|bytes| {
+                RoomId::parse(
+                    utils::string_from_bytes(&bytes).map_err(|_| {
+                        Error::bad_database("Replacement room ID is invalid unicode.")
+                    })?,
+                )
+                .map_err(|_| Error::bad_database("Replacement room ID is invalid."))
+            })
+            .transpose()
+    }
 }
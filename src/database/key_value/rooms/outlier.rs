@@ -1,6 +1,6 @@
-use ruma::{CanonicalJsonObject, EventId};
+use ruma::{CanonicalJsonObject, EventId, OwnedEventId};
 
-use crate::{database::KeyValueDatabase, service, Error, PduEvent, Result};
+use crate::{database::KeyValueDatabase, service, utils, Error, PduEvent, Result};
 
 impl service::rooms::outlier::Data for KeyValueDatabase {
     fn get_outlier_pdu_json(&self, event_id: &EventId) -> Result<Option<CanonicalJsonObject>> {
@@ -23,6 +23,41 @@ impl service::rooms::outlier::Data for KeyValueDatabase {
         self.eventid_outlierpdu.insert(
             event_id.as_bytes(),
             &serde_json::to_vec(&pdu).expect("CanonicalJsonObject is valid"),
-        )
+        )?;
+        self.set_outlier_inserted(event_id, utils::millis_since_unix_epoch())
+    }
+
+    fn all_outlier_pdus<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedEventId, PduEvent, Option<u64>)>> + 'a> {
+        Box::new(self.eventid_outlierpdu.iter().map(|(event_id, pdu)| {
+            let event_id = EventId::parse(
+                utils::string_from_bytes(&event_id)
+                    .map_err(|_| Error::bad_database("Invalid event ID bytes in outlier tree."))?,
+            )
+            .map_err(|_| Error::bad_database("Invalid event ID in outlier tree."))?;
+            let pdu = serde_json::from_slice(&pdu)
+                .map_err(|_| Error::bad_database("Invalid PDU in db."))?;
+            let inserted = self
+                .eventid_outlierinserted
+                .get(event_id.as_bytes())?
+                .map(|bytes| {
+                    utils::u64_from_bytes(&bytes)
+                        .map_err(|_| Error::bad_database("Invalid outlier insertion time in db."))
+                })
+                .transpose()?;
+
+            Ok((event_id, pdu, inserted))
+        }))
+    }
+
+    fn set_outlier_inserted(&self, event_id: &EventId, inserted: u64) -> Result<()> {
+        self.eventid_outlierinserted
+            .insert(event_id.as_bytes(), &inserted.to_be_bytes())
+    }
+
+    fn remove_pdu_outlier(&self, event_id: &EventId) -> Result<()> {
+        self.eventid_outlierpdu.remove(event_id.as_bytes())?;
+        self.eventid_outlierinserted.remove(event_id.as_bytes())
     }
 }
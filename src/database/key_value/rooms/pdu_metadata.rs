@@ -1,6 +1,6 @@
 use std::{mem, sync::Arc};
 
-use ruma::{EventId, RoomId, UserId};
+use ruma::{EventId, OwnedEventId, RoomId, UserId};
 
 use crate::{
     database::KeyValueDatabase,
@@ -75,6 +75,34 @@ impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
         Ok(self.referencedevents.get(&key)?.is_some())
     }
 
+    fn referenced_events<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> Box<dyn Iterator<Item = Result<OwnedEventId>> + 'a> {
+        let prefix = room_id.as_bytes().to_vec();
+        let prefix_len = prefix.len();
+
+        Box::new(
+            self.referencedevents
+                .scan_prefix(prefix)
+                .map(move |(key, _)| key[prefix_len..].to_vec())
+                // Keys of rooms whose ID starts with this room ID don't continue with an event ID
+                .filter(|event_id| event_id.first() == Some(&b'$'))
+                .map(|event_id| {
+                    EventId::parse(utils::string_from_bytes(&event_id).map_err(|_| {
+                        Error::bad_database("Invalid event ID bytes in referencedevents.")
+                    })?)
+                    .map_err(|_| Error::bad_database("Invalid event ID in referencedevents."))
+                }),
+        )
+    }
+
+    fn unmark_as_referenced(&self, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.extend_from_slice(event_id.as_bytes());
+        self.referencedevents.remove(&key)
+    }
+
     fn mark_event_soft_failed(&self, event_id: &EventId) -> Result<()> {
         self.softfailedeventids.insert(event_id.as_bytes(), &[])
     }
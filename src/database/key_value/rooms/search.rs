@@ -1,43 +1,57 @@
+use std::collections::BTreeSet;
+
 use ruma::RoomId;
 
-use crate::{database::KeyValueDatabase, service, services, utils, Result};
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, rooms::search},
+    services, utils, Result, SearchTokenizer,
+};
 
 impl service::rooms::search::Data for KeyValueDatabase {
     fn index_pdu<'a>(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-        let mut batch = message_body
-            .split_terminator(|c: char| !c.is_alphanumeric())
-            .filter(|s| !s.is_empty())
-            .filter(|word| word.len() <= 50)
-            .map(str::to_lowercase)
-            .map(|word| {
-                let mut key = shortroomid.to_be_bytes().to_vec();
-                key.extend_from_slice(word.as_bytes());
-                key.push(0xff);
-                key.extend_from_slice(pdu_id); // TODO: currently we save the room id a second time here
-                (key, Vec::new())
-            });
+        let mut batch = token_keys(
+            services().globals.search_tokenizer(),
+            shortroomid,
+            pdu_id,
+            message_body,
+        )
+        .map(|key| (key, Vec::new()));
 
         self.tokenids.insert_batch(&mut batch)
     }
 
+    fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
+        // The tokenizer may have changed since the message was indexed
+        let keys: BTreeSet<_> = SearchTokenizer::ALL
+            .into_iter()
+            .flat_map(|tokenizer| token_keys(tokenizer, shortroomid, pdu_id, message_body))
+            .collect();
+
+        for key in keys {
+            self.tokenids.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
+    fn clear_index(&self, shortroomid: u64) -> Result<()> {
+        self.tokenids.remove_prefix(&shortroomid.to_be_bytes())?;
+
+        Ok(())
+    }
+
     fn search_pdus<'a>(
         &'a self,
         room_id: &RoomId,
         search_string: &str,
     ) -> Result<Option<(Box<dyn Iterator<Item = Vec<u8>> + 'a>, Vec<String>)>> {
-        let prefix = services()
-            .rooms
-            .short
-            .get_shortroomid(room_id)?
-            .expect("room exists")
-            .to_be_bytes()
-            .to_vec();
-
-        let words: Vec<_> = search_string
-            .split_terminator(|c: char| !c.is_alphanumeric())
-            .filter(|s| !s.is_empty())
-            .map(str::to_lowercase)
-            .collect();
+        let prefix = match services().rooms.short.get_shortroomid(room_id)? {
+            Some(shortroomid) => shortroomid.to_be_bytes().to_vec(),
+            None => return Ok(None),
+        };
+
+        let words = search::tokenize(services().globals.search_tokenizer(), search_string);
 
         let iterators = words.clone().into_iter().map(move |word| {
             let mut prefix2 = prefix.clone();
@@ -65,3 +79,22 @@ impl service::rooms::search::Data for KeyValueDatabase {
         Ok(Some((Box::new(common_elements), words)))
     }
 }
+
+/// The `tokenids` keys of all words in a message body.
+fn token_keys<'a>(
+    tokenizer: SearchTokenizer,
+    shortroomid: u64,
+    pdu_id: &'a [u8],
+    message_body: &'a str,
+) -> impl Iterator<Item = Vec<u8>> + 'a {
+    search::tokenize(tokenizer, message_body)
+        .into_iter()
+        .filter(|word| word.len() <= 50)
+        .map(move |word| {
+            let mut key = shortroomid.to_be_bytes().to_vec();
+            key.extend_from_slice(word.as_bytes());
+            key.push(0xff);
+            key.extend_from_slice(pdu_id); // TODO: currently we save the room id a second time here
+            key
+        })
+}
@@ -31,6 +31,20 @@ impl service::rooms::short::Data for KeyValueDatabase {
         Ok(short)
     }
 
+    fn get_shorteventid(&self, event_id: &EventId) -> Result<Option<u64>> {
+        if let Some(short) = self.eventidshort_cache.lock().unwrap().get_mut(event_id) {
+            return Ok(Some(*short));
+        }
+
+        self.eventid_shorteventid
+            .get(event_id.as_bytes())?
+            .map(|shorteventid| {
+                utils::u64_from_bytes(&shorteventid)
+                    .map_err(|_| Error::bad_database("Invalid shorteventid in db."))
+            })
+            .transpose()
+    }
+
     fn get_shortstatekey(
         &self,
         event_type: &StateEventType,
@@ -58,9 +58,7 @@ impl service::rooms::state::Data for KeyValueDatabase {
         let mut prefix = room_id.as_bytes().to_vec();
         prefix.push(0xff);
 
-        for (key, _) in self.roomid_pduleaves.scan_prefix(prefix.clone()) {
-            self.roomid_pduleaves.remove(&key)?;
-        }
+        self.roomid_pduleaves.remove_prefix(&prefix)?;
 
         for event_id in event_ids {
             let mut key = prefix.to_owned();
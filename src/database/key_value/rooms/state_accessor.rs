@@ -10,10 +10,7 @@ impl service::rooms::state_accessor::Data for KeyValueDatabase {
         let full_state = services()
             .rooms
             .state_compressor
-            .load_shortstatehash_info(shortstatehash)?
-            .pop()
-            .expect("there is always one layer")
-            .1;
+            .full_state(shortstatehash)?;
         let mut result = HashMap::new();
         let mut i = 0;
         for compressed in full_state.iter() {
@@ -38,10 +35,7 @@ impl service::rooms::state_accessor::Data for KeyValueDatabase {
         let full_state = services()
             .rooms
             .state_compressor
-            .load_shortstatehash_info(shortstatehash)?
-            .pop()
-            .expect("there is always one layer")
-            .1;
+            .full_state(shortstatehash)?;
 
         let mut result = HashMap::new();
         let mut i = 0;
@@ -90,10 +84,7 @@ impl service::rooms::state_accessor::Data for KeyValueDatabase {
         let full_state = services()
             .rooms
             .state_compressor
-            .load_shortstatehash_info(shortstatehash)?
-            .pop()
-            .expect("there is always one layer")
-            .1;
+            .full_state(shortstatehash)?;
         Ok(full_state
             .iter()
             .find(|bytes| bytes.starts_with(&shortstatekey.to_be_bytes()))
@@ -1,11 +1,14 @@
-use std::{collections::hash_map, mem::size_of, sync::Arc};
+use std::{collections::hash_map, sync::Arc};
 
 use ruma::{
     api::client::error::ErrorKind, CanonicalJsonObject, EventId, OwnedUserId, RoomId, UserId,
 };
 use tracing::error;
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, PduEvent, Result};
+use crate::{
+    database::{abstraction::PaginatedScan, KeyValueDatabase},
+    service, services, utils, Error, PduEvent, Result,
+};
 
 use service::rooms::timeline::PduCount;
 
@@ -42,7 +45,7 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
         Ok(self
             .eventid_pduid
             .get(event_id.as_bytes())?
-            .map(|pdu_id| pdu_count(&pdu_id))
+            .map(|pdu_id| PduCount::from_pdu_id(&pdu_id))
             .transpose()?)
     }
 
@@ -232,14 +235,15 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
         room_id: &RoomId,
         until: PduCount,
     ) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>> {
-        let (prefix, current) = count_to_id(&room_id, until, 1, true)?;
+        let (prefix, current) = count_to_id(&room_id, until)?;
 
         let user_id = user_id.to_owned();
 
         Ok(Box::new(
-            self.pduid_pdu
-                .iter_from(&current, true)
-                .take_while(move |(k, _)| k.starts_with(&prefix))
+            PaginatedScan::new(prefix)
+                .after(current)
+                .backwards()
+                .iter(&*self.pduid_pdu)?
                 .map(move |(pdu_id, v)| {
                     let mut pdu = serde_json::from_slice::<PduEvent>(&v)
                         .map_err(|_| Error::bad_database("PDU in db is invalid."))?;
@@ -247,7 +251,7 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
                         pdu.remove_transaction_id()?;
                     }
                     pdu.add_age()?;
-                    let count = pdu_count(&pdu_id)?;
+                    let count = PduCount::from_pdu_id(&pdu_id)?;
                     Ok((count, pdu))
                 }),
         ))
@@ -259,14 +263,14 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
         room_id: &RoomId,
         from: PduCount,
     ) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>> {
-        let (prefix, current) = count_to_id(&room_id, from, 1, false)?;
+        let (prefix, current) = count_to_id(&room_id, from)?;
 
         let user_id = user_id.to_owned();
 
         Ok(Box::new(
-            self.pduid_pdu
-                .iter_from(&current, false)
-                .take_while(move |(k, _)| k.starts_with(&prefix))
+            PaginatedScan::new(prefix)
+                .after(current)
+                .iter(&*self.pduid_pdu)?
                 .map(move |(pdu_id, v)| {
                     let mut pdu = serde_json::from_slice::<PduEvent>(&v)
                         .map_err(|_| Error::bad_database("PDU in db is invalid."))?;
@@ -274,12 +278,29 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
                         pdu.remove_transaction_id()?;
                     }
                     pdu.add_age()?;
-                    let count = pdu_count(&pdu_id)?;
+                    let count = PduCount::from_pdu_id(&pdu_id)?;
                     Ok((count, pdu))
                 }),
         ))
     }
 
+    fn retention_watermark(&self, room_id: &RoomId) -> Result<Option<PduCount>> {
+        self.roomid_retentionwatermark
+            .get(room_id.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .ok()
+                    .and_then(|count| PduCount::try_from_string(&count).ok())
+                    .ok_or_else(|| Error::bad_database("Invalid retention watermark in db."))
+            })
+            .transpose()
+    }
+
+    fn set_retention_watermark(&self, room_id: &RoomId, count: PduCount) -> Result<()> {
+        self.roomid_retentionwatermark
+            .insert(room_id.as_bytes(), count.stringify().as_bytes())
+    }
+
     fn increment_notification_counts(
         &self,
         room_id: &RoomId,
@@ -310,26 +331,8 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
 }
 
 /// Returns the `count` of this pdu's id.
-fn pdu_count(pdu_id: &[u8]) -> Result<PduCount> {
-    let last_u64 = utils::u64_from_bytes(&pdu_id[pdu_id.len() - size_of::<u64>()..])
-        .map_err(|_| Error::bad_database("PDU has invalid count bytes."))?;
-    let second_last_u64 = utils::u64_from_bytes(
-        &pdu_id[pdu_id.len() - 2 * size_of::<u64>()..pdu_id.len() - size_of::<u64>()],
-    );
-
-    if matches!(second_last_u64, Ok(0)) {
-        Ok(PduCount::Backfilled(u64::MAX - last_u64))
-    } else {
-        Ok(PduCount::Normal(last_u64))
-    }
-}
-
-fn count_to_id(
-    room_id: &RoomId,
-    count: PduCount,
-    offset: u64,
-    subtract: bool,
-) -> Result<(Vec<u8>, Vec<u8>)> {
+/// Returns the shortroomid prefix of the room and the pdu id of `count`.
+fn count_to_id(room_id: &RoomId, count: PduCount) -> Result<(Vec<u8>, Vec<u8>)> {
     let prefix = services()
         .rooms
         .short
@@ -338,27 +341,11 @@ fn count_to_id(
         .to_be_bytes()
         .to_vec();
     let mut pdu_id = prefix.clone();
-    // +1 so we don't send the base event
     let count_raw = match count {
-        PduCount::Normal(x) => {
-            if subtract {
-                x - offset
-            } else {
-                x + offset
-            }
-        }
+        PduCount::Normal(x) => x,
         PduCount::Backfilled(x) => {
             pdu_id.extend_from_slice(&0_u64.to_be_bytes());
-            let num = u64::MAX - x;
-            if subtract {
-                if num > 0 {
-                    num - offset
-                } else {
-                    num
-                }
-            } else {
-                num + offset
-            }
+            u64::MAX - x
         }
     };
     pdu_id.extend_from_slice(&count_raw.to_be_bytes());
@@ -16,10 +16,19 @@ impl service::rooms::user::Data for KeyValueDatabase {
         self.userroomid_highlightcount
             .insert(&userroom_id, &0_u64.to_be_bytes())?;
 
-        self.roomuserid_lastnotificationread.insert(
-            &roomuser_id,
-            &services().globals.next_count()?.to_be_bytes(),
-        )?;
+        // Concurrent resets must not move the read marker backwards
+        let count = services().globals.next_count()?.to_be_bytes();
+        let mut current = self.roomuserid_lastnotificationread.get(&roomuser_id)?;
+        while current.as_deref() < Some(&count[..]) {
+            match self.roomuserid_lastnotificationread.compare_and_swap(
+                &roomuser_id,
+                current.as_deref(),
+                Some(&count[..]),
+            )? {
+                Ok(()) => break,
+                Err(e) => current = e.current,
+            }
+        }
 
         Ok(())
     }
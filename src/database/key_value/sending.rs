@@ -1,10 +1,10 @@
-use ruma::{ServerName, UserId};
+use ruma::{OwnedServerName, ServerName, UserId};
 
 use crate::{
     database::KeyValueDatabase,
     service::{
         self,
-        sending::{OutgoingKind, SendingEventType},
+        sending::{Backoff, OutgoingKind, SendingEventType},
     },
     services, utils, Error, Result,
 };
@@ -123,6 +123,30 @@ impl service::sending::Data for KeyValueDatabase {
                     .map_err(|_| Error::bad_database("Invalid u64 in servername_educount."))
             })
     }
+
+    fn set_backoff(&self, server_name: &ServerName, backoff: Backoff) -> Result<()> {
+        self.servername_backoff
+            .insert(server_name.as_bytes(), &backoff.to_bytes())
+    }
+
+    fn remove_backoff(&self, server_name: &ServerName) -> Result<()> {
+        self.servername_backoff.remove(server_name.as_bytes())
+    }
+
+    fn backoffs<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedServerName, Backoff)>> + 'a> {
+        Box::new(self.servername_backoff.iter().map(|(key, value)| {
+            let server_name = utils::string_from_bytes(&key)
+                .ok()
+                .and_then(|s| ServerName::parse(s).ok())
+                .ok_or_else(|| {
+                    Error::corruption("servername_backoff", &key, "invalid server name")
+                })?;
+            let backoff = Backoff::from_bytes(&value)
+                .ok_or_else(|| Error::corruption("servername_backoff", &key, "invalid backoff"))?;
+
+            Ok((server_name, backoff))
+        }))
+    }
 }
 
 #[tracing::instrument(skip(key))]
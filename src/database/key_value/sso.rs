@@ -0,0 +1,29 @@
+use ruma::{OwnedUserId, UserId};
+
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+
+impl service::sso::Data for KeyValueDatabase {
+    fn subject_user(&self, issuer: &str, subject: &str) -> Result<Option<OwnedUserId>> {
+        let mut key = issuer.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(subject.as_bytes());
+
+        self.oidcsubject_userid
+            .get(&key)?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in oidcsubject_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in oidcsubject_userid is invalid."))
+            })
+            .transpose()
+    }
+
+    fn set_subject_user(&self, issuer: &str, subject: &str, user_id: &UserId) -> Result<()> {
+        let mut key = issuer.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(subject.as_bytes());
+
+        self.oidcsubject_userid.insert(&key, user_id.as_bytes())
+    }
+}
@@ -0,0 +1,109 @@
+use ruma::{
+    thirdparty::{Medium, ThirdPartyIdentifier, ThirdPartyIdentifierInit},
+    MilliSecondsSinceUnixEpoch, OwnedUserId, UInt, UserId,
+};
+
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+
+impl service::threepid::Data for KeyValueDatabase {
+    fn add_threepid(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+        validated_at: u64,
+    ) -> Result<()> {
+        let mut threepid = medium.as_str().as_bytes().to_vec();
+        threepid.push(0xff);
+        threepid.extend_from_slice(address.as_bytes());
+
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&threepid);
+
+        let mut timestamps = validated_at.to_be_bytes().to_vec();
+        timestamps.extend_from_slice(&utils::millis_since_unix_epoch().to_be_bytes());
+
+        self.userthreepid_timestamps.insert(&key, &timestamps)?;
+        self.threepid_userid.insert(&threepid, user_id.as_bytes())
+    }
+
+    fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+        let mut threepid = medium.as_str().as_bytes().to_vec();
+        threepid.push(0xff);
+        threepid.extend_from_slice(address.as_bytes());
+
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&threepid);
+
+        self.userthreepid_timestamps.remove(&key)?;
+        self.threepid_userid.remove(&threepid)
+    }
+
+    fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<ThirdPartyIdentifier>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Box::new(
+            self.userthreepid_timestamps
+                .scan_prefix(prefix.clone())
+                .map(move |(key, timestamps)| {
+                    let mut parts = key[prefix.len()..].splitn(2, |&b| b == 0xff);
+
+                    let medium = utils::string_from_bytes(
+                        parts
+                            .next()
+                            .ok_or_else(|| Error::bad_database("Invalid userthreepid key."))?,
+                    )
+                    .map_err(|_| Error::bad_database("Medium in userthreepid is invalid."))?;
+
+                    let address = utils::string_from_bytes(
+                        parts
+                            .next()
+                            .ok_or_else(|| Error::bad_database("Invalid userthreepid key."))?,
+                    )
+                    .map_err(|_| Error::bad_database("Address in userthreepid is invalid."))?;
+
+                    if timestamps.len() != 16 {
+                        return Err(Error::bad_database("Invalid threepid timestamps."));
+                    }
+
+                    let timestamp = |bytes: &[u8]| {
+                        utils::u64_from_bytes(bytes)
+                            .ok()
+                            .and_then(UInt::new)
+                            .map(MilliSecondsSinceUnixEpoch)
+                            .ok_or_else(|| Error::bad_database("Invalid threepid timestamp."))
+                    };
+
+                    Ok(ThirdPartyIdentifierInit {
+                        address,
+                        medium: Medium::from(medium),
+                        validated_at: timestamp(&timestamps[..8])?,
+                        added_at: timestamp(&timestamps[8..])?,
+                    }
+                    .into())
+                }),
+        )
+    }
+
+    fn threepid_user(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>> {
+        let mut threepid = medium.as_str().as_bytes().to_vec();
+        threepid.push(0xff);
+        threepid.extend_from_slice(address.as_bytes());
+
+        self.threepid_userid
+            .get(&threepid)?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in threepid_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in threepid_userid is invalid."))
+            })
+            .transpose()
+    }
+}
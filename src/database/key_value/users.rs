@@ -11,7 +11,7 @@ use ruma::{
 use tracing::warn;
 
 use crate::{
-    database::KeyValueDatabase,
+    database::{abstraction, KeyValueDatabase},
     service::{self, users::clean_signatures},
     services, utils, Error, Result,
 };
@@ -118,6 +118,12 @@ impl service::users::Data for KeyValueDatabase {
         }
     }
 
+    /// Stores an already calculated password hash
+    fn set_password_hash(&self, user_id: &UserId, password_hash: &str) -> Result<()> {
+        self.userid_password
+            .insert(user_id.as_bytes(), password_hash.as_bytes())
+    }
+
     /// Returns the displayname of a user on this homeserver.
     fn displayname(&self, user_id: &UserId) -> Result<Option<String>> {
         self.userid_displayname
@@ -241,11 +247,10 @@ impl service::users::Data for KeyValueDatabase {
         let mut prefix = userdeviceid.clone();
         prefix.push(0xff);
 
-        for (key, _) in self.todeviceid_events.scan_prefix(prefix) {
-            self.todeviceid_events.remove(&key)?;
-        }
+        self.todeviceid_events.remove_prefix(&prefix)?;
 
-        // TODO: Remove onetimekeys
+        // Remove onetimekeys, nobody can claim them anymore
+        self.onetimekeyid_onetimekeys.remove_prefix(&prefix)?;
 
         self.userid_devicelistversion
             .increment(user_id.as_bytes())?;
@@ -769,10 +774,12 @@ impl service::users::Data for KeyValueDatabase {
         event_type: &str,
         content: serde_json::Value,
     ) -> Result<()> {
-        let mut key = target_user_id.as_bytes().to_vec();
-        key.push(0xff);
-        key.extend_from_slice(target_device_id.as_bytes());
-        key.push(0xff);
+        let mut prefix = target_user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(target_device_id.as_bytes());
+        prefix.push(0xff);
+
+        let mut key = prefix.clone();
         key.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
 
         let mut json = serde_json::Map::new();
@@ -784,6 +791,16 @@ impl service::users::Data for KeyValueDatabase {
 
         self.todeviceid_events.insert(&key, &value)?;
 
+        if let Some(max) = services().globals.max_to_device_events_per_device() {
+            let dropped = abstraction::trim_prefix(&*self.todeviceid_events, &prefix, max)?;
+            if dropped > 0 {
+                warn!(
+                    "Dropped {} old to-device events of {} {}, it has more than {} queued",
+                    dropped, target_user_id, target_device_id, max
+                );
+            }
+        }
+
         Ok(())
     }
 
@@ -867,7 +884,34 @@ impl service::users::Data for KeyValueDatabase {
         Ok(())
     }
 
-    /// Get device metadata.
+    fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: String,
+        ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        // The device might have been removed in the meantime
+        let mut device: Device = match self.userdeviceid_metadata.get(&userdeviceid)? {
+            Some(bytes) => serde_json::from_slice(&bytes).map_err(|_| {
+                Error::bad_database("Metadata in userdeviceid_metadata is invalid.")
+            })?,
+            None => return Ok(()),
+        };
+
+        device.last_seen_ip = Some(ip);
+        device.last_seen_ts = Some(ts);
+
+        self.userdeviceid_metadata.insert(
+            &userdeviceid,
+            &serde_json::to_vec(&device).expect("Device::to_string always works"),
+        )
+    }
+
     fn get_device_metadata(
         &self,
         user_id: &UserId,
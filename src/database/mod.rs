@@ -2,11 +2,12 @@ pub mod abstraction;
 pub mod key_value;
 
 use crate::{
-    service::rooms::timeline::PduCount, services, utils, Config, Error, PduEvent, Result, Services,
-    SERVICES,
+    service::rooms::{auth_chain::AuthChainCache, timeline::PduCount},
+    services,
+    utils::{self, tasks},
+    Config, Error, PduEvent, Result, Services, SERVICES,
 };
 use abstraction::{KeyValueDatabaseEngine, KvTree};
-use directories::ProjectDirs;
 use lru_cache::LruCache;
 use ruma::{
     events::{
@@ -79,6 +80,7 @@ pub struct KeyValueDatabase {
     pub(super) pduid_pdu: Arc<dyn KvTree>, // PduId = ShortRoomId + Count
     pub(super) eventid_pduid: Arc<dyn KvTree>,
     pub(super) roomid_pduleaves: Arc<dyn KvTree>,
+    pub(super) roomid_retentionwatermark: Arc<dyn KvTree>, // RetentionWatermark = PduCount
     pub(super) alias_roomid: Arc<dyn KvTree>,
     pub(super) aliasid_alias: Arc<dyn KvTree>, // AliasId = RoomId + Count
     pub(super) publicroomids: Arc<dyn KvTree>,
@@ -98,10 +100,13 @@ pub struct KeyValueDatabase {
     pub(super) roomuseroncejoinedids: Arc<dyn KvTree>,
     pub(super) userroomid_invitestate: Arc<dyn KvTree>, // InviteState = Vec<Raw<Pdu>>
     pub(super) roomuserid_invitecount: Arc<dyn KvTree>, // InviteCount = Count
+    pub(super) userroomid_knockstate: Arc<dyn KvTree>,  // KnockState = Vec<Raw<Pdu>>
+    pub(super) roomuserid_knockcount: Arc<dyn KvTree>,  // KnockCount = Count
     pub(super) userroomid_leftstate: Arc<dyn KvTree>,
     pub(super) roomuserid_leftcount: Arc<dyn KvTree>,
 
     pub(super) disabledroomids: Arc<dyn KvTree>, // Rooms where incoming federation handling is disabled
+    pub(super) roomid_replacementroomid: Arc<dyn KvTree>, // Rooms that were upgraded
 
     pub(super) lazyloadedids: Arc<dyn KvTree>, // LazyLoadedIds = UserId + DeviceId + RoomId + LazyLoadedUserId
 
@@ -131,6 +136,8 @@ pub struct KeyValueDatabase {
     /// RoomId + EventId -> outlier PDU.
     /// Any pdu that has passed the steps 1-8 in the incoming event /federation/send/txn.
     pub(super) eventid_outlierpdu: Arc<dyn KvTree>,
+    /// EventId -> time the outlier was stored, in milliseconds since the unix epoch.
+    pub(super) eventid_outlierinserted: Arc<dyn KvTree>,
     pub(super) softfailedeventids: Arc<dyn KvTree>,
 
     /// ShortEventId + ShortEventId -> ().
@@ -144,6 +151,7 @@ pub struct KeyValueDatabase {
 
     //pub media: media::Media,
     pub(super) mediaid_file: Arc<dyn KvTree>, // MediaId = MXC + WidthHeight + ContentDisposition + ContentType
+    pub(super) thumbnailid_created: Arc<dyn KvTree>, // ThumbnailId = MediaId of a thumbnail, Created = Timestamp in ms
     //pub key_backups: key_backups::KeyBackups,
     pub(super) backupid_algorithm: Arc<dyn KvTree>, // BackupId = UserId + Version(Count)
     pub(super) backupid_etag: Arc<dyn KvTree>,      // BackupId = UserId + Version(Count)
@@ -151,8 +159,12 @@ pub struct KeyValueDatabase {
 
     //pub transaction_ids: transaction_ids::TransactionIds,
     pub(super) userdevicetxnid_response: Arc<dyn KvTree>, // Response can be empty (/sendToDevice) or the event id (/send)
+
+    //pub reports: reports::Reports,
+    pub(super) reportid_report: Arc<dyn KvTree>, // ReportId = Count
     //pub sending: sending::Sending,
     pub(super) servername_educount: Arc<dyn KvTree>, // EduCount: Count of last EDU sync
+    pub(super) servername_backoff: Arc<dyn KvTree>,  // Backoff = Tries + LastFailure
     pub(super) servernameevent_data: Arc<dyn KvTree>, // ServernameEvent = (+ / $)SenderKey / ServerName / UserId + PduId / Id (for edus), Data = EDU content
     pub(super) servercurrentevent_data: Arc<dyn KvTree>, // ServerCurrentEvents = (+ / $)ServerName / UserId + PduId / Id (for edus), Data = EDU content
 
@@ -162,10 +174,17 @@ pub struct KeyValueDatabase {
     //pub pusher: pusher::PushData,
     pub(super) senderkey_pusher: Arc<dyn KvTree>,
 
+    //pub sso: sso::Sso,
+    pub(super) oidcsubject_userid: Arc<dyn KvTree>, // OidcSubject = Issuer + Subject
+
+    //pub threepid: threepid::Threepid,
+    pub(super) userthreepid_timestamps: Arc<dyn KvTree>, // UserThreepid = UserId + Medium + Address, Timestamps = ValidatedAt + AddedAt
+    pub(super) threepid_userid: Arc<dyn KvTree>,         // Threepid = Medium + Address
+
     pub(super) cached_registrations: Arc<RwLock<HashMap<String, serde_yaml::Value>>>,
     pub(super) pdu_cache: Mutex<LruCache<OwnedEventId, Arc<PduEvent>>>,
     pub(super) shorteventid_cache: Mutex<LruCache<u64, Arc<EventId>>>,
-    pub(super) auth_chain_cache: Mutex<LruCache<Vec<u64>, Arc<HashSet<u64>>>>,
+    pub(super) auth_chain_cache: Mutex<AuthChainCache>,
     pub(super) eventidshort_cache: Mutex<LruCache<OwnedEventId, u64>>,
     pub(super) statekeyshort_cache: Mutex<LruCache<(StateEventType, String), u64>>,
     pub(super) shortstatekey_cache: Mutex<LruCache<u64, (StateEventType, String)>>,
@@ -176,17 +195,54 @@ pub struct KeyValueDatabase {
 
 impl KeyValueDatabase {
     /// Tries to remove the old database but ignores all errors.
-    pub fn try_remove(server_name: &str) -> Result<()> {
-        let mut path = ProjectDirs::from("xyz", "koesters", "conduit")
-            .ok_or_else(|| Error::bad_config("The OS didn't return a valid home directory path."))?
-            .data_dir()
-            .to_path_buf();
-        path.push(server_name);
-        let _ = remove_dir_all(path);
+    /// Removes the database at the configured `database_path`.
+    pub fn try_remove(config: &Config) -> Result<()> {
+        let _ = remove_dir_all(&config.database_path);
 
         Ok(())
     }
 
+    /// Makes sure `database_path` is a directory, creating it if its parent exists.
+    fn check_database_path(path: &Path) -> Result<()> {
+        if path.as_os_str().is_empty() {
+            return Err(Error::bad_config("database_path must not be empty."));
+        }
+
+        if !path.is_absolute() {
+            warn!(
+                "database_path {} is relative, so it depends on the working directory. Using an absolute path is recommended.",
+                path.display()
+            );
+        }
+
+        if path.exists() {
+            if !path.is_dir() {
+                return Err(Error::bad_config(
+                    "database_path exists, but is not a directory.",
+                ));
+            }
+            return Ok(());
+        }
+
+        if path
+            .parent()
+            .is_some_and(|parent| !parent.as_os_str().is_empty() && !parent.is_dir())
+        {
+            error!(
+                "The parent directory of database_path {} does not exist",
+                path.display()
+            );
+            return Err(Error::bad_config(
+                "The parent directory of database_path does not exist. Please create it or fix database_path.",
+            ));
+        }
+
+        fs::create_dir(path).map_err(|e| {
+            error!("Failed to create database folder {}: {}", path.display(), e);
+            Error::bad_config("Database folder doesn't exist and couldn't be created (e.g. due to missing permissions). Please create the database folder yourself.")
+        })
+    }
+
     fn check_db_setup(config: &Config) -> Result<()> {
         let path = Path::new(&config.database_path);
 
@@ -236,11 +292,14 @@ impl KeyValueDatabase {
 
     /// Load an existing database or create a new one.
     pub async fn load_or_create(config: Config) -> Result<()> {
+        Self::check_database_path(Path::new(&config.database_path))?;
         Self::check_db_setup(&config)?;
 
-        if !Path::new(&config.database_path).exists() {
-            std::fs::create_dir_all(&config.database_path)
-                .map_err(|_| Error::BadConfig("Database folder doesn't exists and couldn't be created (e.g. due to missing permissions). Please create the database folder yourself."))?;
+        #[cfg(not(unix))]
+        if config.unix_socket_path.is_some() {
+            return Err(Error::bad_config(
+                "unix_socket_path is only supported on unix platforms.",
+            ));
         }
 
         let builder: Arc<dyn KeyValueDatabaseEngine> = match &*config.database_backend {
@@ -275,6 +334,13 @@ impl KeyValueDatabase {
             error!(?config.max_request_size, "Max request size is less than 1KB. Please increase it.");
         }
 
+        if config
+            .max_federation_request_size
+            .map_or(false, |size| size < 1024)
+        {
+            error!(?config.max_federation_request_size, "Max federation request size is less than 1KB. Please increase it.");
+        }
+
         let db_raw = Box::new(Self {
             _db: builder.clone(),
             userid_password: builder.open_tree("userid_password")?,
@@ -308,6 +374,7 @@ impl KeyValueDatabase {
             pduid_pdu: builder.open_tree("pduid_pdu")?,
             eventid_pduid: builder.open_tree("eventid_pduid")?,
             roomid_pduleaves: builder.open_tree("roomid_pduleaves")?,
+            roomid_retentionwatermark: builder.open_tree("roomid_retentionwatermark")?,
 
             alias_roomid: builder.open_tree("alias_roomid")?,
             aliasid_alias: builder.open_tree("aliasid_alias")?,
@@ -326,10 +393,13 @@ impl KeyValueDatabase {
             roomuseroncejoinedids: builder.open_tree("roomuseroncejoinedids")?,
             userroomid_invitestate: builder.open_tree("userroomid_invitestate")?,
             roomuserid_invitecount: builder.open_tree("roomuserid_invitecount")?,
+            userroomid_knockstate: builder.open_tree("userroomid_knockstate")?,
+            roomuserid_knockcount: builder.open_tree("roomuserid_knockcount")?,
             userroomid_leftstate: builder.open_tree("userroomid_leftstate")?,
             roomuserid_leftcount: builder.open_tree("roomuserid_leftcount")?,
 
             disabledroomids: builder.open_tree("disabledroomids")?,
+            roomid_replacementroomid: builder.open_tree("roomid_replacementroomid")?,
 
             lazyloadedids: builder.open_tree("lazyloadedids")?,
 
@@ -353,6 +423,7 @@ impl KeyValueDatabase {
             statehash_shortstatehash: builder.open_tree("statehash_shortstatehash")?,
 
             eventid_outlierpdu: builder.open_tree("eventid_outlierpdu")?,
+            eventid_outlierinserted: builder.open_tree("eventid_outlierinserted")?,
             softfailedeventids: builder.open_tree("softfailedeventids")?,
 
             tofrom_relation: builder.open_tree("tofrom_relation")?,
@@ -360,15 +431,21 @@ impl KeyValueDatabase {
             roomuserdataid_accountdata: builder.open_tree("roomuserdataid_accountdata")?,
             roomusertype_roomuserdataid: builder.open_tree("roomusertype_roomuserdataid")?,
             mediaid_file: builder.open_tree("mediaid_file")?,
+            thumbnailid_created: builder.open_tree("thumbnailid_created")?,
             backupid_algorithm: builder.open_tree("backupid_algorithm")?,
             backupid_etag: builder.open_tree("backupid_etag")?,
             backupkeyid_backup: builder.open_tree("backupkeyid_backup")?,
             userdevicetxnid_response: builder.open_tree("userdevicetxnid_response")?,
+            reportid_report: builder.open_tree("reportid_report")?,
             servername_educount: builder.open_tree("servername_educount")?,
+            servername_backoff: builder.open_tree("servername_backoff")?,
             servernameevent_data: builder.open_tree("servernameevent_data")?,
             servercurrentevent_data: builder.open_tree("servercurrentevent_data")?,
             id_appserviceregistrations: builder.open_tree("id_appserviceregistrations")?,
             senderkey_pusher: builder.open_tree("senderkey_pusher")?,
+            oidcsubject_userid: builder.open_tree("oidcsubject_userid")?,
+            userthreepid_timestamps: builder.open_tree("userthreepid_timestamps")?,
+            threepid_userid: builder.open_tree("threepid_userid")?,
             global: builder.open_tree("global")?,
             server_signingkeys: builder.open_tree("server_signingkeys")?,
 
@@ -379,8 +456,9 @@ impl KeyValueDatabase {
                     .try_into()
                     .expect("pdu cache capacity fits into usize"),
             )),
-            auth_chain_cache: Mutex::new(LruCache::new(
-                (100_000.0 * config.conduit_cache_capacity_modifier) as usize,
+            auth_chain_cache: Mutex::new(AuthChainCache::new(
+                (config.auth_chain_cache_max_bytes as f64 * config.conduit_cache_capacity_modifier)
+                    as usize,
             )),
             shorteventid_cache: Mutex::new(LruCache::new(
                 (100_000.0 * config.conduit_cache_capacity_modifier) as usize,
@@ -428,6 +506,21 @@ impl KeyValueDatabase {
         let latest_database_version = 13;
 
         if services().users.count()? > 0 {
+            let database_version = services().globals.database_version()?;
+            if database_version < latest_database_version {
+                let config = &services().globals.config;
+                warn!(
+                    "The database will be migrated from version {} to {}, this cannot be undone. Make sure you have a backup of {}.",
+                    database_version, latest_database_version, config.database_path
+                );
+                check_migration_confirmation(
+                    database_version,
+                    latest_database_version,
+                    config.require_migration_confirmation,
+                    config.auto_migrate,
+                )?;
+            }
+
             // MIGRATIONS
             if services().globals.database_version()? < 1 {
                 for (roomserverid, _) in db.roomserverids.iter() {
@@ -950,6 +1043,16 @@ impl KeyValueDatabase {
                 services().globals.config.database_backend,
                 latest_database_version
             );
+
+            if services().admin.get_admin_room()?.is_none() {
+                error!(
+                    "The admin room alias {} does not point to a room on this server",
+                    services().globals.admin_room_alias()
+                );
+                return Err(Error::bad_config(
+                    "admin_room_alias does not point to an existing room.",
+                ));
+            }
         } else {
             services()
                 .globals
@@ -989,6 +1092,20 @@ impl KeyValueDatabase {
         services().sending.start_handler();
 
         Self::start_cleanup_task().await;
+        if services().globals.config.optimize_state_second_interval > 0 {
+            Self::start_optimize_state_task();
+        }
+        if services().globals.config.retention_second_interval > 0 {
+            Self::start_retention_task();
+        }
+        let warmup_rooms = services().globals.config.auth_chain_cache_warmup_rooms;
+        if warmup_rooms > 0 {
+            tasks::spawn("auth chain warmup", async move {
+                if let Err(e) = services().rooms.auth_chain.warmup(warmup_rooms).await {
+                    warn!("Failed to warm up the auth chain cache: {}", e);
+                }
+            });
+        }
         if services().globals.allow_check_for_updates() {
             Self::start_check_for_updates_task();
         }
@@ -1009,11 +1126,14 @@ impl KeyValueDatabase {
 
     #[tracing::instrument]
     pub fn start_check_for_updates_task() {
-        tokio::spawn(async move {
+        tasks::spawn("check for updates", async move {
             let timer_interval = Duration::from_secs(60 * 60);
             let mut i = interval(timer_interval);
             loop {
-                i.tick().await;
+                tokio::select! {
+                    _ = i.tick() => {}
+                    _ = services().globals.tasks.shutdown_requested() => break,
+                }
                 let _ = Self::try_handle_updates().await;
             }
         });
@@ -1061,6 +1181,135 @@ impl KeyValueDatabase {
         Ok(())
     }
 
+    /// Periodically rebuilds the state diff layers of all rooms, see
+    /// `state_compressor::Service::optimize_room_state`, and compacts their referenced events.
+    #[tracing::instrument]
+    pub fn start_optimize_state_task() {
+        let timer_interval =
+            Duration::from_secs(services().globals.config.optimize_state_second_interval as u64);
+
+        tasks::spawn("optimize state", async move {
+            let mut i = interval(timer_interval);
+            // The first tick completes immediately
+            i.tick().await;
+
+            loop {
+                tokio::select! {
+                    _ = i.tick() => {}
+                    _ = services().globals.tasks.shutdown_requested() => break,
+                }
+
+                for room_id in services()
+                    .rooms
+                    .metadata
+                    .iter_ids()
+                    .filter_map(|r| r.ok())
+                    .collect::<Vec<_>>()
+                {
+                    match services()
+                        .rooms
+                        .state_compressor
+                        .optimize_room_state(&room_id)
+                        .await
+                    {
+                        Ok((before, after)) => {
+                            debug!(%room_id, before, after, "Optimized room state layers")
+                        }
+                        Err(e) => warn!("Failed to optimize state of {}: {}", room_id, e),
+                    }
+
+                    if let Some(keep_depth) = services().globals.config.referenced_events_keep_depth
+                    {
+                        match services()
+                            .rooms
+                            .pdu_metadata
+                            .compact_referenced_events(&room_id, keep_depth)
+                        {
+                            Ok(0) => {}
+                            Ok(count) => debug!(%room_id, count, "Compacted referenced events"),
+                            Err(e) => {
+                                warn!("Failed to compact referenced events of {}: {}", room_id, e)
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Periodically strips the content of messages that are older than the retention policy of
+    /// their room allows, see `timeline::Service::purge_expired_events`.
+    #[tracing::instrument]
+    pub fn start_retention_task() {
+        let timer_interval =
+            Duration::from_secs(services().globals.config.retention_second_interval as u64);
+
+        tasks::spawn("retention", async move {
+            let mut i = interval(timer_interval);
+
+            loop {
+                tokio::select! {
+                    _ = i.tick() => {}
+                    _ = services().globals.tasks.shutdown_requested() => break,
+                }
+
+                for room_id in services()
+                    .rooms
+                    .metadata
+                    .iter_ids()
+                    .filter_map(|r| r.ok())
+                    .collect::<Vec<_>>()
+                {
+                    let max_lifetime =
+                        match services().rooms.state_accessor.message_retention(&room_id) {
+                            Ok(Some(max_lifetime)) => max_lifetime,
+                            Ok(None) => continue,
+                            Err(e) => {
+                                warn!("Failed to get retention policy of {}: {}", room_id, e);
+                                continue;
+                            }
+                        };
+
+                    match services()
+                        .rooms
+                        .timeline
+                        .purge_expired_events(&room_id, max_lifetime)
+                    {
+                        Ok(0) => {}
+                        Ok(count) => debug!(%room_id, count, "Purged expired events"),
+                        Err(e) => warn!("Failed to purge expired events in {}: {}", room_id, e),
+                    }
+
+                    tokio::task::yield_now().await;
+                }
+
+                if let Some(days) = services().globals.config.thumbnail_retention_days {
+                    match services()
+                        .media
+                        .evict_thumbnails_older_than(Duration::from_secs(u64::from(days) * 86400))
+                        .await
+                    {
+                        Ok(0) => {}
+                        Ok(count) => debug!(count, "Evicted old thumbnails"),
+                        Err(e) => warn!("Failed to evict old thumbnails: {}", e),
+                    }
+                }
+
+                if let Some(days) = services().globals.config.outlier_retention_days {
+                    match services()
+                        .rooms
+                        .outlier
+                        .purge_stale_outliers(Duration::from_secs(u64::from(days) * 86400))
+                    {
+                        Ok(0) => {}
+                        Ok(count) => debug!(count, "Purged stale outliers"),
+                        Err(e) => warn!("Failed to purge stale outliers: {}", e),
+                    }
+                }
+            }
+        });
+    }
+
     #[tracing::instrument]
     pub async fn start_cleanup_task() {
         #[cfg(unix)]
@@ -1071,7 +1320,7 @@ impl KeyValueDatabase {
         let timer_interval =
             Duration::from_secs(services().globals.config.cleanup_second_interval as u64);
 
-        tokio::spawn(async move {
+        tasks::spawn("cleanup", async move {
             let mut i = interval(timer_interval);
             #[cfg(unix)]
             let mut s = signal(SignalKind::hangup()).unwrap();
@@ -1085,12 +1334,15 @@ impl KeyValueDatabase {
                     _ = s.recv() => {
                         debug!("cleanup: Received SIGHUP");
                     }
+                    _ = services().globals.tasks.shutdown_requested() => break,
                 };
                 #[cfg(not(unix))]
-                {
-                    i.tick().await;
-                    debug!("cleanup: Timer ticked")
-                }
+                tokio::select! {
+                    _ = i.tick() => {
+                        debug!("cleanup: Timer ticked")
+                    }
+                    _ = services().globals.tasks.shutdown_requested() => break,
+                };
 
                 let start = Instant::now();
                 if let Err(e) = services().globals.cleanup() {
@@ -1130,3 +1382,63 @@ fn set_emergency_access() -> Result<bool> {
 
     res
 }
+
+/// Refuses to migrate the database if migrations have to be confirmed with `auto_migrate`
+fn check_migration_confirmation(
+    database_version: u64,
+    latest_database_version: u64,
+    require_confirmation: bool,
+    auto_migrate: bool,
+) -> Result<()> {
+    if !require_confirmation || auto_migrate {
+        return Ok(());
+    }
+
+    for version in database_version..latest_database_version {
+        warn!("Pending migration: {} -> {}", version, version + 1);
+    }
+    error!("Not migrating the database without confirmation, set auto_migrate = true to continue");
+
+    Err(Error::bad_config(
+        "Database migrations have to be confirmed with auto_migrate.",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn database_path_with_missing_parent() {
+        let dir = std::env::temp_dir().join(format!("conduit-test-{}", utils::random_string(8)));
+
+        assert!(matches!(
+            KeyValueDatabase::check_database_path(&dir.join("missing").join("db")),
+            Err(Error::BadConfig(_))
+        ));
+        assert!(!dir.exists());
+
+        KeyValueDatabase::check_database_path(&dir).unwrap();
+        assert!(dir.is_dir());
+        KeyValueDatabase::check_database_path(&dir).unwrap();
+
+        let file = dir.join("file");
+        fs::File::create(&file).unwrap();
+        assert!(matches!(
+            KeyValueDatabase::check_database_path(&file),
+            Err(Error::BadConfig(_))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrations_need_confirmation() {
+        assert!(matches!(
+            check_migration_confirmation(11, 13, true, false),
+            Err(Error::BadConfig(_))
+        ));
+        assert!(check_migration_confirmation(11, 13, true, true).is_ok());
+        assert!(check_migration_confirmation(11, 13, false, false).is_ok());
+    }
+}
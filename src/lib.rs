@@ -16,10 +16,15 @@ mod utils;
 use std::sync::RwLock;
 
 pub use api::ruma_wrapper::{Ruma, RumaResponse};
-pub use config::Config;
+pub use config::{
+    Config, DestinationOverride, DeviceLimitAction, FederationCategory, ListenAddr, SearchTokenizer,
+};
 pub use database::KeyValueDatabase;
 pub use service::{pdu::PduEvent, Services};
-pub use utils::error::{Error, Result};
+pub use utils::{
+    access_log_line,
+    error::{Error, Result},
+};
 
 pub static SERVICES: RwLock<Option<&'static Services>> = RwLock::new(None);
 
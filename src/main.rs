@@ -8,22 +8,32 @@
 #![allow(clippy::suspicious_else_formatting)]
 #![deny(clippy::dbg_macro)]
 
-use std::{future::Future, io, net::SocketAddr, sync::atomic, time::Duration};
+#[cfg(unix)]
+use std::{fs, os::unix::fs::FileTypeExt, path::Path};
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{atomic, Arc},
+    time::{Duration, Instant},
+};
 
 use axum::{
-    extract::{DefaultBodyLimit, FromRequestParts, MatchedPath},
+    extract::{BodyStream, DefaultBodyLimit, FromRequestParts, MatchedPath},
     response::IntoResponse,
-    routing::{get, on, MethodFilter},
+    routing::{get, on, post, MethodFilter},
     Router,
 };
-use axum_server::{bind, bind_rustls, tls_rustls::RustlsConfig, Handle as ServerHandle};
+use axum_server::{
+    bind, bind_rustls, tls_rustls::RustlsConfig, Handle as ServerHandle, HttpConfig,
+};
 use conduit::api::{client_server, server_server};
 use figment::{
     providers::{Env, Format, Toml},
     Figment,
 };
 use http::{
-    header::{self, HeaderName},
+    header::{self, HeaderName, HeaderValue},
     Method, StatusCode, Uri,
 };
 use ruma::api::{
@@ -33,9 +43,15 @@ use ruma::api::{
     },
     IncomingRequest,
 };
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::signal;
 use tower::ServiceBuilder;
 use tower_http::{
+    compression::{
+        predicate::{NotForContentType, SizeAbove},
+        CompressionLayer, Predicate,
+    },
     cors::{self, CorsLayer},
     trace::TraceLayer,
     ServiceBuilderExt as _,
@@ -146,19 +162,50 @@ async fn main() {
     info!("Starting server");
     run_server().await.unwrap();
 
+    info!("Waiting for background tasks to stop");
+    services()
+        .globals
+        .tasks
+        .shutdown(Duration::from_secs(10))
+        .await;
+
     if config.allow_jaeger {
         opentelemetry::global::shutdown_tracer_provider();
     }
 }
 
+/// Compresses responses above `min_size` bytes with gzip or brotli, depending on the
+/// `Accept-Encoding` of the request. Media that is already compressed is left alone.
+fn compression_layer(min_size: u16) -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new()
+        .no_deflate()
+        .no_zstd()
+        .compress_when(
+            SizeAbove::new(min_size)
+                .and(NotForContentType::GRPC)
+                .and(NotForContentType::IMAGES)
+                .and(NotForContentType::const_new("video/"))
+                .and(NotForContentType::const_new("audio/")),
+        )
+}
+
 async fn run_server() -> io::Result<()> {
     let config = &services().globals.config;
-    let addr = SocketAddr::from((config.address, config.port));
 
     let x_requested_with = HeaderName::from_static("x-requested-with");
 
     let middlewares = ServiceBuilder::new()
         .sensitive_headers([header::AUTHORIZATION])
+        .option_layer(
+            config
+                .response_compression
+                .then(|| compression_layer(config.response_compression_min_size)),
+        )
+        .option_layer(
+            config
+                .access_log
+                .then(|| axum::middleware::from_fn(access_log)),
+        )
         .layer(axum::middleware::from_fn(spawn_task))
         .layer(
             TraceLayer::new_for_http().make_span_with(|request: &http::Request<_>| {
@@ -172,6 +219,7 @@ async fn run_server() -> io::Result<()> {
             }),
         )
         .layer(axum::middleware::from_fn(unrecognized_method))
+        .layer(axum::middleware::from_fn(media_download_headers))
         .layer(
             CorsLayer::new()
                 .allow_origin(cors::Any)
@@ -192,21 +240,42 @@ async fn run_server() -> io::Result<()> {
                 .max_age(Duration::from_secs(86400)),
         )
         .layer(DefaultBodyLimit::max(
+            // The ruma extractor enforces the client and federation limits separately
             config
                 .max_request_size
+                .max(services().globals.max_federation_request_size())
                 .try_into()
                 .expect("failed to convert max request size"),
         ));
 
-    let app = routes().layer(middlewares).into_make_service();
+    let app = routes().layer(middlewares);
     let handle = ServerHandle::new();
 
+    let addr = match config.listen_addr() {
+        ListenAddr::Tcp(addr) => addr,
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => return serve_unix(&path, app, handle).await,
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => unreachable!("unix_socket_path is rejected when loading the config"),
+    };
+
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+
     tokio::spawn(shutdown_signal(handle.clone()));
 
     match &config.tls {
         Some(tls) => {
             let conf = RustlsConfig::from_pem_file(&tls.certs, &tls.key).await?;
-            let server = bind_rustls(addr, conf).handle(handle).serve(app);
+            if !config.http2 {
+                // Don't let clients negotiate HTTP/2
+                let mut server_config = (*conf.get_inner()).clone();
+                server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+                conf.reload_from_config(Arc::new(server_config));
+            }
+            let server = bind_rustls(addr, conf)
+                .handle(handle)
+                .http_config(http_config(config))
+                .serve(app);
 
             #[cfg(feature = "systemd")]
             let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
@@ -214,7 +283,10 @@ async fn run_server() -> io::Result<()> {
             server.await?
         }
         None => {
-            let server = bind(addr).handle(handle).serve(app);
+            let server = bind(addr)
+                .handle(handle)
+                .http_config(http_config(config))
+                .serve(app);
 
             #[cfg(feature = "systemd")]
             let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
@@ -226,6 +298,80 @@ async fn run_server() -> io::Result<()> {
     Ok(())
 }
 
+/// The keep-alive and HTTP/2 settings of the server.
+fn http_config(config: &Config) -> HttpConfig {
+    let mut http_config = HttpConfig::new();
+    http_config
+        .http1_keep_alive(config.http1_keep_alive)
+        .http1_only(!config.http2);
+
+    if config.http2_keep_alive_interval > 0 {
+        http_config
+            .http2_keep_alive_interval(Some(Duration::from_secs(config.http2_keep_alive_interval)))
+            .http2_keep_alive_timeout(Duration::from_secs(config.http2_keep_alive_timeout));
+    }
+
+    http_config.build()
+}
+
+/// Serves `app` on the unix socket at `path` until the server shuts down.
+///
+/// Requests have no peer address then, so the address of the client is only known from the
+/// `X-Forwarded-For` header of the reverse proxy in front of the socket.
+#[cfg(unix)]
+async fn serve_unix(path: &Path, app: Router, handle: ServerHandle) -> io::Result<()> {
+    let config = &services().globals.config;
+
+    if config.tls.is_some() {
+        warn!("TLS is not used when listening on a unix socket");
+    }
+
+    let mut server = bind_unix(path)?
+        .http1_keepalive(config.http1_keep_alive)
+        .http1_only(!config.http2);
+    if config.http2_keep_alive_interval > 0 {
+        server = server
+            .http2_keep_alive_interval(Duration::from_secs(config.http2_keep_alive_interval))
+            .http2_keep_alive_timeout(Duration::from_secs(config.http2_keep_alive_timeout));
+    }
+
+    let server = server
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal(handle));
+
+    #[cfg(feature = "systemd")]
+    let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
+
+    server
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+/// Listens on the unix socket at `path`, replacing a stale socket left over from a previous run.
+#[cfg(unix)]
+fn bind_unix(
+    path: &Path,
+) -> io::Result<
+    hyper::server::Builder<
+        impl hyper::server::accept::Accept<Conn = UnixStream, Error = io::Error>,
+    >,
+> {
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    info!("Listening on {}", path.display());
+
+    Ok(hyper::Server::builder(hyper::server::accept::poll_fn(
+        move |cx| {
+            listener
+                .poll_accept(cx)
+                .map(|result| Some(result.map(|(stream, _)| stream)))
+        },
+    )))
+}
+
 async fn spawn_task<B: Send + 'static>(
     req: axum::http::Request<B>,
     next: axum::middleware::Next<B>,
@@ -233,9 +379,49 @@ async fn spawn_task<B: Send + 'static>(
     if services().globals.shutdown.load(atomic::Ordering::Relaxed) {
         return Err(StatusCode::SERVICE_UNAVAILABLE);
     }
-    tokio::spawn(next.run(req))
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    Ok(catch_panic(req.method().clone(), req.uri().clone(), next.run(req)).await)
+}
+
+/// Runs a request handler in its own task, so that a panic in it only fails this request. The
+/// panic is logged and answered with a 500.
+async fn catch_panic(
+    method: Method,
+    uri: Uri,
+    handler: impl Future<Output = axum::response::Response> + Send + 'static,
+) -> axum::response::Response {
+    match tokio::spawn(handler).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Request handler for {method} {uri} panicked: {e}");
+            RumaResponse(UiaaResponse::MatrixError(RumaError {
+                body: ErrorBody::Standard {
+                    kind: ErrorKind::Unknown,
+                    message: "Internal server error.".to_owned(),
+                },
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            }))
+            .into_response()
+        }
+    }
+}
+
+async fn access_log<B>(
+    req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let start = Instant::now();
+
+    let response = next.run(req).await;
+
+    info!(
+        target: "conduit::access",
+        "{}",
+        access_log_line(&method, &uri, response.status(), start.elapsed())
+    );
+
+    response
 }
 
 async fn unrecognized_method<B>(
@@ -259,6 +445,92 @@ async fn unrecognized_method<B>(
     Ok(inner)
 }
 
+/// Adds the headers of [`media_security_headers`] to media downloads.
+async fn media_download_headers<B>(
+    req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let path = req.uri().path();
+    let is_download = path.starts_with("/_matrix/media/")
+        && (path.contains("/download/") || path.contains("/thumbnail/"));
+
+    let mut response = next.run(req).await;
+    if is_download {
+        media_security_headers(
+            response.headers_mut(),
+            &services().globals.config.media_content_security_policy,
+        );
+    }
+
+    response
+}
+
+/// Keeps uploaded files from running scripts on our origin when they are opened directly:
+/// - The Content-Security-Policy is set to `csp`, SVGs are always sandboxed
+/// - Browsers must not guess another content type
+/// - Types that browsers render as documents are downloaded instead of shown
+fn media_security_headers(headers: &mut http::HeaderMap, csp: &str) {
+    const DOCUMENT_TYPES: &[&str] = &[
+        "text/html",
+        "application/xhtml+xml",
+        "text/xml",
+        "application/xml",
+        "text/javascript",
+        "application/javascript",
+    ];
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|essence| essence.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    // SVGs can contain scripts, so they are sandboxed even if the policy doesn't do that
+    let sandboxed = csp.split(';').any(|directive| {
+        directive
+            .split_whitespace()
+            .next()
+            .is_some_and(|name| name.eq_ignore_ascii_case("sandbox"))
+    });
+    let csp = match (
+        content_type == "image/svg+xml" && !sandboxed,
+        csp.is_empty(),
+    ) {
+        (true, true) => "sandbox".to_owned(),
+        (true, false) => format!("sandbox; {csp}"),
+        (false, _) => csp.to_owned(),
+    };
+    if let Ok(csp) = HeaderValue::from_str(&csp) {
+        if !csp.is_empty() {
+            headers.insert(header::CONTENT_SECURITY_POLICY, csp);
+        }
+    }
+
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+
+    if DOCUMENT_TYPES.contains(&content_type.as_str()) {
+        // Keep the parameters, like the filename
+        let parameters = headers
+            .get(header::CONTENT_DISPOSITION)
+            .and_then(|disposition| disposition.to_str().ok())
+            .and_then(|disposition| disposition.split_once(|c| c == ';' || c == ':'))
+            .map(|(_, parameters)| parameters.trim().to_owned())
+            .filter(|parameters| !parameters.is_empty());
+
+        let disposition = match parameters {
+            Some(parameters) => format!("attachment; {parameters}"),
+            None => "attachment".to_owned(),
+        };
+        if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+            headers.insert(header::CONTENT_DISPOSITION, disposition);
+        }
+    }
+}
+
 fn routes() -> Router {
     Router::new()
         .ruma_route(client_server::get_supported_versions_route)
@@ -266,6 +538,16 @@ fn routes() -> Router {
         .ruma_route(client_server::register_route)
         .ruma_route(client_server::get_login_types_route)
         .ruma_route(client_server::login_route)
+        .route(
+            "/_synapse/admin/v1/register",
+            get(client_server::shared_secret_register_nonce_route)
+                .post(client_server::shared_secret_register_route),
+        )
+        .ruma_route(client_server::sso_login_route)
+        .route(
+            "/_conduit/client/oidc/callback",
+            get(client_server::sso_callback_route),
+        )
         .ruma_route(client_server::whoami_route)
         .ruma_route(client_server::logout_route)
         .ruma_route(client_server::logout_all_route)
@@ -273,6 +555,12 @@ fn routes() -> Router {
         .ruma_route(client_server::deactivate_route)
         .ruma_route(client_server::third_party_route)
         .ruma_route(client_server::request_3pid_management_token_via_email_route)
+        .route(
+            "/_conduit/client/3pid/email/validate",
+            get(client_server::validate_3pid_email_route),
+        )
+        .ruma_route(client_server::add_3pid_route)
+        .ruma_route(client_server::delete_3pid_route)
         .ruma_route(client_server::request_3pid_management_token_via_msisdn_route)
         .ruma_route(client_server::get_capabilities_route)
         .ruma_route(client_server::get_pushrules_all_route)
@@ -321,11 +609,13 @@ fn routes() -> Router {
         .ruma_route(client_server::create_room_route)
         .ruma_route(client_server::redact_event_route)
         .ruma_route(client_server::report_event_route)
+        .ruma_route(client_server::appservice_ping_route)
         .ruma_route(client_server::create_alias_route)
         .ruma_route(client_server::delete_alias_route)
         .ruma_route(client_server::get_alias_route)
         .ruma_route(client_server::join_room_by_id_route)
         .ruma_route(client_server::join_room_by_id_or_alias_route)
+        .ruma_route(client_server::knock_room_route)
         .ruma_route(client_server::joined_members_route)
         .ruma_route(client_server::leave_room_route)
         .ruma_route(client_server::forget_room_route)
@@ -376,7 +666,15 @@ fn routes() -> Router {
         .ruma_route(client_server::turn_server_route)
         .ruma_route(client_server::send_event_to_device_route)
         .ruma_route(client_server::get_media_config_route)
-        .ruma_route(client_server::create_content_route)
+        // Uploads are streamed to disk, so they don't go through the Ruma extractor
+        .route(
+            "/_matrix/media/r0/upload",
+            post(client_server::create_content_route::<BodyStream, axum::Error>),
+        )
+        .route(
+            "/_matrix/media/v3/upload",
+            post(client_server::create_content_route::<BodyStream, axum::Error>),
+        )
         .ruma_route(client_server::get_content_route)
         .ruma_route(client_server::get_content_as_filename_route)
         .ruma_route(client_server::get_content_thumbnail_route)
@@ -421,6 +719,8 @@ fn routes() -> Router {
         .ruma_route(server_server::create_join_event_template_route)
         .ruma_route(server_server::create_join_event_v1_route)
         .ruma_route(server_server::create_join_event_v2_route)
+        .ruma_route(server_server::create_knock_event_template_route)
+        .ruma_route(server_server::create_knock_event_v1_route)
         .ruma_route(server_server::create_invite_route)
         .ruma_route(server_server::get_devices_route)
         .ruma_route(server_server::get_room_information_route)
@@ -435,6 +735,10 @@ fn routes() -> Router {
             "/_matrix/client/v3/rooms/:room_id/initialSync",
             get(initial_sync),
         )
+        .route(
+            "/_conduit/version",
+            get(client_server::conduit_version_route),
+        )
         .route("/", get(it_works))
         .fallback(not_found)
 }
@@ -585,3 +889,165 @@ fn maximize_fd_limit() -> Result<(), nix::errno::Errno> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn handler_panic_is_a_500() {
+        async fn panics() -> &'static str {
+            panic!("handler panicked")
+        }
+
+        let app = Router::new()
+            .route("/panic", get(panics))
+            .route("/", get(it_works))
+            .layer(axum::middleware::from_fn(
+                |req: http::Request<Body>, next: axum::middleware::Next<Body>| async move {
+                    catch_panic(req.method().clone(), req.uri().clone(), next.run(req)).await
+                },
+            ));
+
+        let response = app
+            .clone()
+            .oneshot(http::Request::get("/panic").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let response = app
+            .oneshot(http::Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_compressed() {
+        let app = Router::new()
+            .route("/large", get(|| async { "x".repeat(4096) }))
+            .route("/small", get(|| async { "x" }))
+            .layer(compression_layer(1024));
+
+        let request = |path: &str, accept_encoding: Option<&str>| {
+            let mut request = http::Request::get(path);
+            if let Some(accept_encoding) = accept_encoding {
+                request = request.header(header::ACCEPT_ENCODING, accept_encoding);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        for encoding in ["gzip", "br"] {
+            let response = app
+                .clone()
+                .oneshot(request("/large", Some(encoding)))
+                .await
+                .unwrap();
+            assert_eq!(response.headers()[header::CONTENT_ENCODING], encoding);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert!(body.len() < 4096);
+        }
+
+        let response = app.clone().oneshot(request("/large", None)).await.unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.len(), 4096);
+
+        let response = app.oneshot(request("/small", Some("gzip"))).await.unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn media_downloads_are_sandboxed() {
+        let csp = "sandbox; default-src 'none'";
+        let headers = |content_type: &str, disposition: Option<&str>, csp: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            if let Some(disposition) = disposition {
+                headers.insert(header::CONTENT_DISPOSITION, disposition.parse().unwrap());
+            }
+            media_security_headers(&mut headers, csp);
+            headers
+        };
+
+        let png = headers("image/png", Some("inline; filename=cat.png"), csp);
+        assert_eq!(png[header::CONTENT_SECURITY_POLICY], csp);
+        assert_eq!(png[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(png[header::CONTENT_DISPOSITION], "inline; filename=cat.png");
+
+        let html = headers(
+            "text/html; charset=utf-8",
+            Some("inline; filename=page.html"),
+            csp,
+        );
+        assert_eq!(html[header::CONTENT_SECURITY_POLICY], csp);
+        assert_eq!(
+            html[header::CONTENT_DISPOSITION],
+            "attachment; filename=page.html"
+        );
+        let html = headers("application/xhtml+xml", None, csp);
+        assert_eq!(html[header::CONTENT_DISPOSITION], "attachment");
+
+        // An empty policy disables the header, but SVGs stay sandboxed
+        let png = headers("image/png", None, "");
+        assert!(png.get(header::CONTENT_SECURITY_POLICY).is_none());
+        assert_eq!(png[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        let svg = headers("image/svg+xml", None, "");
+        assert_eq!(svg[header::CONTENT_SECURITY_POLICY], "sandbox");
+
+        // So do they with a policy that doesn't sandbox
+        let svg = headers("image/svg+xml", None, "default-src 'none'");
+        assert_eq!(
+            svg[header::CONTENT_SECURITY_POLICY],
+            "sandbox; default-src 'none'"
+        );
+        let svg = headers("image/svg+xml", None, csp);
+        assert_eq!(svg[header::CONTENT_SECURITY_POLICY], csp);
+        let png = headers("image/png", None, "default-src 'none'");
+        assert_eq!(png[header::CONTENT_SECURITY_POLICY], "default-src 'none'");
+    }
+
+    #[tokio::test]
+    async fn http_settings_are_used() {
+        async fn serve(config: &str) -> String {
+            let config: Config = Figment::new()
+                .merge(Toml::string(&format!(
+                    r#"
+                        server_name = "example.com"
+                        database_path = "/tmp/conduit"
+                        {config}
+                    "#
+                )))
+                .extract()
+                .unwrap();
+
+            let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+            let url = format!("http://{}/", listener.local_addr().unwrap());
+            tokio::spawn(
+                axum_server::from_tcp(listener)
+                    .http_config(http_config(&config))
+                    .serve(Router::new().route("/", get(it_works)).into_make_service()),
+            );
+
+            url
+        }
+
+        let h2c = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+
+        let url = serve("").await;
+        let response = reqwest::get(&url).await.unwrap();
+        assert!(response.headers().get(header::CONNECTION).is_none());
+        assert!(h2c.get(&url).send().await.is_ok());
+
+        let url = serve("http1_keep_alive = false\nhttp2 = false").await;
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.headers()[header::CONNECTION], "close");
+        assert!(h2c.get(&url).send().await.is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use std::{
     collections::BTreeMap,
     convert::{TryFrom, TryInto},
-    sync::{Arc, RwLock},
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc, RwLock},
     time::Instant,
 };
 
@@ -23,25 +24,29 @@ use ruma::{
         },
         TimelineEventType,
     },
-    EventId, OwnedRoomAliasId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
+    EventId, OwnedRoomId, RoomId, RoomVersionId, ServerName, UserId,
 };
+use serde::Deserialize;
 use serde_json::value::to_raw_value;
 use tokio::sync::{mpsc, Mutex, MutexGuard};
 
 use crate::{
     api::client_server::{leave_all_rooms, AUTO_GEN_PASSWORD_LENGTH},
     services,
-    utils::{self, HtmlEscape},
+    utils::{self, tasks, HtmlEscape},
     Error, PduEvent, Result,
 };
 
 use super::pdu::PduBuilder;
 
+/// How many timeline index entries `check-integrity` looks at on backends without their own check
+const INTEGRITY_CHECK_SAMPLE_SIZE: usize = 10_000;
+
 #[cfg_attr(test, derive(Debug))]
 #[derive(Parser)]
 #[command(name = "@conduit:server.name:", version = env!("CARGO_PKG_VERSION"))]
 enum AdminCommand {
-    #[command(verbatim_doc_comment)]
+    #[command(verbatim_doc_comment, alias = "appservice-register")]
     /// Register an appservice using its registration YAML
     ///
     /// This command needs a YAML generated by an appservice (such as a bridge),
@@ -59,6 +64,7 @@ enum AdminCommand {
     /// Unregister an appservice using its ID
     ///
     /// You can find the ID using the `list-appservices` command.
+    #[command(alias = "appservice-unregister")]
     UnregisterAppservice {
         /// The appservice to unregister
         appservice_identifier: String,
@@ -145,6 +151,45 @@ enum AdminCommand {
     /// Show configuration values
     ShowConfig,
 
+    /// Change the message of the day that clients can show, or remove it without a message
+    Motd {
+        /// The new message of the day
+        message: Vec<String>,
+    },
+
+    /// List the reported events that were not resolved yet
+    #[command(alias = "reports")]
+    ListReports,
+
+    /// Remove a report after dealing with it
+    ResolveReport {
+        /// The ID shown by `list-reports`
+        report_id: u64,
+    },
+
+    #[command(verbatim_doc_comment)]
+    /// Send an event into a room as the server user
+    ///
+    /// The event needs a `type` and `content`, state events also need a
+    /// `state_key`. The server user has to be allowed to send the event.
+    ///
+    /// [commandbody]
+    /// # ```
+    /// # { "type": "m.room.message", "content": { "msgtype": "m.text", "body": "Hi" } }
+    /// # ```
+    SendEvent {
+        /// The room to send the event into
+        room_id: Box<RoomId>,
+    },
+
+    /// Show the running version, database backend and enabled features
+    Version,
+
+    /// Check the database for corruption without changing it
+    ///
+    /// SQLite runs its own integrity check, other backends check a sample of the timeline index.
+    CheckIntegrity,
+
     /// Reset user password
     ResetPassword {
         /// Username of the user for whom the password should be reset
@@ -159,11 +204,56 @@ enum AdminCommand {
         password: Option<String>,
     },
 
+    /// Create users from a file with a `localpart,password_hash` line for each user
+    ///
+    /// The password hashes have to be Argon2 hashes, they are stored as they are.
+    /// Users that already exist are skipped.
+    ImportUsers {
+        /// Path of the file on the server
+        path: PathBuf,
+    },
+
     /// Disables incoming federation handling for a room.
     DisableRoom { room_id: Box<RoomId> },
     /// Enables incoming federation handling for a room again.
     EnableRoom { room_id: Box<RoomId> },
 
+    /// Rebuild the state diff layers of a room so its state can be loaded faster
+    OptimizeState { room_id: Box<RoomId> },
+
+    /// Replace the current state of a room with the state another server reports for it
+    ///
+    /// The state events are fetched and checked like any other events over federation. Only use
+    /// this when the state of the room diverged from the rest of the federation.
+    ResyncRoom {
+        room_id: Box<RoomId>,
+        /// The server to fetch the state from, it has to be in the room
+        server_name: Box<ServerName>,
+        #[arg(long)]
+        /// Actually replace the state
+        confirm: bool,
+    },
+
+    /// Rebuild the full-text search index from the message events in the timeline
+    ///
+    /// Runs in the background and reports its progress here. Without a room, all rooms are
+    /// reindexed.
+    ReindexSearch {
+        room_id: Option<Box<RoomId>>,
+        #[arg(long)]
+        /// Stop the running reindex
+        stop: bool,
+    },
+
+    /// Show how we currently reach a server over federation
+    Resolve { server_name: Box<ServerName> },
+
+    /// Forget the cached destination of a server, so it gets resolved again
+    FlushDestination { server_name: Box<ServerName> },
+
+    /// Forget all cached destinations
+    FlushDestinations,
+
     /// Verify json signatures
     /// [commandbody]
     /// # ```
@@ -201,7 +291,7 @@ impl Service {
 
     pub fn start_handler(self: &Arc<Self>) {
         let self2 = Arc::clone(self);
-        tokio::spawn(async move {
+        tasks::spawn("admin handler", async move {
             self2.handler().await;
         });
     }
@@ -214,15 +304,8 @@ impl Service {
         let conduit_user = UserId::parse(format!("@conduit:{}", services().globals.server_name()))
             .expect("@conduit:server_name is valid");
 
-        let conduit_room = services()
-            .rooms
-            .alias
-            .resolve_local_alias(
-                format!("#admins:{}", services().globals.server_name())
-                    .as_str()
-                    .try_into()
-                    .expect("#admins:server_name is a valid room alias"),
-            )
+        let conduit_room = self
+            .get_admin_room()
             .expect("Database data for admin room alias must be valid")
             .expect("Admin room must exist");
 
@@ -269,10 +352,19 @@ impl Service {
 
                     drop(state_lock);
                 }
+                _ = services().globals.tasks.shutdown_requested() => break,
             }
         }
     }
 
+    /// Returns the room that the `admin_room_alias` points to. Its members are the server admins.
+    pub(crate) fn get_admin_room(&self) -> Result<Option<OwnedRoomId>> {
+        services()
+            .rooms
+            .alias
+            .resolve_local_alias(&services().globals.admin_room_alias())
+    }
+
     pub fn process_message(&self, room_message: String) {
         self.sender
             .send(AdminRoomEvent::ProcessMessage(room_message))
@@ -484,27 +576,25 @@ impl Service {
                 {
                     let string = body[1..body.len() - 1].join("\n");
                     match serde_json::from_str(&string) {
-                        Ok(value) => {
-                            match ruma::signatures::reference_hash(&value, &RoomVersionId::V6) {
-                                Ok(hash) => {
-                                    let event_id = EventId::parse(format!("${hash}"));
-
-                                    match serde_json::from_value::<PduEvent>(
-                                        serde_json::to_value(value).expect("value is json"),
-                                    ) {
-                                        Ok(pdu) => RoomMessageEventContent::text_plain(format!(
-                                            "EventId: {event_id:?}\n{pdu:#?}"
-                                        )),
-                                        Err(e) => RoomMessageEventContent::text_plain(format!(
-                                            "EventId: {event_id:?}\nCould not parse event: {e}"
-                                        )),
-                                    }
+                        Ok(value) => match utils::reference_hash(&value, &RoomVersionId::V6) {
+                            Ok(hash) => {
+                                let event_id = EventId::parse(format!("${hash}"));
+
+                                match serde_json::from_value::<PduEvent>(
+                                    serde_json::to_value(value).expect("value is json"),
+                                ) {
+                                    Ok(pdu) => RoomMessageEventContent::text_plain(format!(
+                                        "EventId: {event_id:?}\n{pdu:#?}"
+                                    )),
+                                    Err(e) => RoomMessageEventContent::text_plain(format!(
+                                        "EventId: {event_id:?}\nCould not parse event: {e}"
+                                    )),
                                 }
-                                Err(e) => RoomMessageEventContent::text_plain(format!(
-                                    "Could not parse PDU JSON: {e:?}"
-                                )),
                             }
-                        }
+                            Err(e) => RoomMessageEventContent::text_plain(format!(
+                                "Could not parse PDU JSON: {e:?}"
+                            )),
+                        },
                         Err(e) => RoomMessageEventContent::text_plain(format!(
                             "Invalid json in command body: {e}"
                         )),
@@ -573,6 +663,130 @@ impl Service {
                 // Construct and send the response
                 RoomMessageEventContent::text_plain(format!("{}", services().globals.config))
             }
+            AdminCommand::Motd { message } => {
+                if message.is_empty() {
+                    services().globals.set_motd(None);
+                    RoomMessageEventContent::text_plain("Removed the message of the day.")
+                } else {
+                    services().globals.set_motd(Some(message.join(" ")));
+                    RoomMessageEventContent::text_plain("Changed the message of the day.")
+                }
+            }
+            AdminCommand::ListReports => {
+                let reports = services().reports.pending()?;
+                if reports.is_empty() {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "There are no open reports.",
+                    ));
+                }
+
+                let mut message = format!("Open reports ({}):", reports.len());
+                for (report_id, report) in reports {
+                    message.push_str(&format!(
+                        "\n{report_id}: {} reported {} (sent by {}) in {} with score {:?}: {}",
+                        report.reporter,
+                        report.event_id,
+                        report.sender,
+                        report.room_id,
+                        report.score,
+                        report.reason.as_deref().unwrap_or("no reason given")
+                    ));
+                }
+
+                RoomMessageEventContent::text_plain(message)
+            }
+            AdminCommand::ResolveReport { report_id } => {
+                if services().reports.resolve(report_id)? {
+                    RoomMessageEventContent::text_plain(format!("Report {report_id} resolved."))
+                } else {
+                    RoomMessageEventContent::text_plain(format!("Report {report_id} not found."))
+                }
+            }
+            AdminCommand::SendEvent { room_id } => {
+                if body.len() > 2
+                    && body[0].trim().starts_with("```")
+                    && body.last().unwrap().trim() == "```"
+                {
+                    let string = body[1..body.len() - 1].join("\n");
+                    let pdu_builder = match parse_raw_event(&string) {
+                        Ok(pdu_builder) => pdu_builder,
+                        Err(e) => {
+                            return Ok(RoomMessageEventContent::text_plain(format!(
+                                "Invalid event in command body: {e}"
+                            )))
+                        }
+                    };
+
+                    if !services().rooms.metadata.exists(&room_id)? {
+                        return Ok(RoomMessageEventContent::text_plain("Room does not exist."));
+                    }
+
+                    let conduit_user =
+                        UserId::parse_with_server_name("conduit", services().globals.server_name())
+                            .expect("@conduit:server_name is valid");
+
+                    let mutex_state = Arc::clone(
+                        services()
+                            .globals
+                            .roomid_mutex_state
+                            .write()
+                            .unwrap()
+                            .entry(room_id.clone().into())
+                            .or_default(),
+                    );
+                    let state_lock = mutex_state.lock().await;
+
+                    let event_id = services().rooms.timeline.build_and_append_pdu(
+                        pdu_builder,
+                        &conduit_user,
+                        &room_id,
+                        &state_lock,
+                    )?;
+
+                    RoomMessageEventContent::text_plain(format!("Sent event {event_id}."))
+                } else {
+                    RoomMessageEventContent::text_plain(
+                        "Expected code block in command body. Add --help for details.",
+                    )
+                }
+            }
+            AdminCommand::CheckIntegrity => {
+                self.send_message(RoomMessageEventContent::text_plain(
+                    "Checking database integrity, this might take a while...",
+                ));
+
+                // The check reads the whole database, which would block the runtime
+                let problems = tokio::task::spawn_blocking(|| {
+                    services()
+                        .globals
+                        .db
+                        .check_integrity(INTEGRITY_CHECK_SAMPLE_SIZE)
+                })
+                .await
+                .map_err(|_| Error::bad_database("The database integrity check panicked."))??;
+
+                if problems.is_empty() {
+                    RoomMessageEventContent::text_plain("Database integrity check passed: OK")
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Database integrity check found {} problems:\n{}",
+                        problems.len(),
+                        problems.join("\n")
+                    ))
+                }
+            }
+            AdminCommand::Version => {
+                let info = serde_json::to_string_pretty(&services().globals.server_info())
+                    .expect("json can be serialized");
+
+                RoomMessageEventContent::text_html(
+                    format!("```json\n{info}\n```"),
+                    format!(
+                        "<pre><code class=\"language-json\">{}\n</code></pre>\n",
+                        HtmlEscape(&info)
+                    ),
+                )
+            }
             AdminCommand::ResetPassword { username } => {
                 let user_id = match UserId::parse_with_server_name(
                     username.as_str().to_lowercase(),
@@ -641,33 +855,7 @@ impl Service {
                 }
                 // Create user
                 services().users.create(&user_id, Some(password.as_str()))?;
-
-                // Default to pretty displayname
-                let mut displayname = user_id.localpart().to_owned();
-
-                // If enabled append lightning bolt to display name (default true)
-                if services().globals.enable_lightning_bolt() {
-                    displayname.push_str(" ⚡️");
-                }
-
-                services()
-                    .users
-                    .set_displayname(&user_id, Some(displayname))?;
-
-                // Initial account data
-                services().account_data.update(
-                    None,
-                    &user_id,
-                    ruma::events::GlobalAccountDataEventType::PushRules
-                        .to_string()
-                        .into(),
-                    &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
-                        content: ruma::events::push_rules::PushRulesEventContent {
-                            global: ruma::push::Ruleset::server_default(&user_id),
-                        },
-                    })
-                    .expect("to json value always works"),
-                )?;
+                initialize_user(&user_id)?;
 
                 // we dont add a device since we're not the user, just the creator
 
@@ -676,6 +864,74 @@ impl Service {
                     "Created user with user_id: {user_id} and password: {password}"
                 ))
             }
+            AdminCommand::ImportUsers { path } => {
+                let content = match tokio::fs::read_to_string(&path).await {
+                    Ok(content) => content,
+                    Err(e) => {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "Failed to read {}: {e}",
+                            path.display()
+                        )))
+                    }
+                };
+
+                let mut created = Vec::new();
+                let mut skipped = Vec::new();
+                let mut failed = Vec::new();
+
+                for line in parse_user_import(&content) {
+                    let (line_number, localpart, password_hash) = match line {
+                        Ok(line) => line,
+                        Err(e) => {
+                            failed.push(e);
+                            continue;
+                        }
+                    };
+
+                    let user_id = match UserId::parse_with_server_name(
+                        localpart.to_lowercase(),
+                        services().globals.server_name(),
+                    ) {
+                        Ok(user_id) if !user_id.is_historical() => user_id,
+                        _ => {
+                            failed
+                                .push(format!("line {line_number}: invalid username {localpart}"));
+                            continue;
+                        }
+                    };
+
+                    if services().users.exists(&user_id)? {
+                        skipped.push(user_id.to_string());
+                        continue;
+                    }
+
+                    if let Err(e) = services().users.create_with_hash(&user_id, password_hash) {
+                        failed.push(format!("line {line_number}: {user_id}: {e}"));
+                        continue;
+                    }
+                    initialize_user(&user_id)?;
+
+                    created.push(user_id.to_string());
+                }
+
+                let mut message = format!("Created {} users.", created.len());
+                if !skipped.is_empty() {
+                    message.push_str(&format!(
+                        "\nSkipped {} existing users: {}",
+                        skipped.len(),
+                        skipped.join(", ")
+                    ));
+                }
+                if !failed.is_empty() {
+                    message.push_str(&format!(
+                        "\nFailed to import {} lines:\n{}",
+                        failed.len(),
+                        failed.join("\n")
+                    ));
+                }
+
+                RoomMessageEventContent::text_plain(message)
+            }
             AdminCommand::DisableRoom { room_id } => {
                 services().rooms.metadata.disable_room(&room_id, true)?;
                 RoomMessageEventContent::text_plain("Room disabled.")
@@ -768,6 +1024,128 @@ impl Service {
                     )
                 }
             }
+            AdminCommand::OptimizeState { room_id } => {
+                let (before, after) = services()
+                    .rooms
+                    .state_compressor
+                    .optimize_room_state(&room_id)
+                    .await?;
+                RoomMessageEventContent::text_plain(format!(
+                    "Optimized state of {room_id}: {before} layers before, {after} layers after."
+                ))
+            }
+            AdminCommand::ResyncRoom {
+                room_id,
+                server_name,
+                confirm,
+            } => {
+                if &*server_name == services().globals.server_name() {
+                    RoomMessageEventContent::text_plain("Can't resync a room from this server.")
+                } else if !services()
+                    .rooms
+                    .state_cache
+                    .server_in_room(&server_name, &room_id)?
+                {
+                    RoomMessageEventContent::text_plain(format!(
+                        "{server_name} is not in {room_id}."
+                    ))
+                } else if !confirm {
+                    RoomMessageEventContent::text_plain(format!(
+                        "This replaces the current state of {room_id} with the state {server_name} reports. Run the command again with --confirm to continue."
+                    ))
+                } else {
+                    match services()
+                        .rooms
+                        .event_handler
+                        .resync_room_state(&server_name, &room_id)
+                        .await
+                    {
+                        Ok(state_count) => RoomMessageEventContent::text_plain(format!(
+                            "Resynced {room_id} from {server_name}, the room has {state_count} state events now."
+                        )),
+                        Err(e) => RoomMessageEventContent::text_plain(format!(
+                            "Failed to resync {room_id} from {server_name}: {e}"
+                        )),
+                    }
+                }
+            }
+            AdminCommand::ReindexSearch { room_id, stop } => {
+                let search = &services().rooms.search;
+                if stop {
+                    if search.reindexing.load(Ordering::Relaxed) {
+                        search.stop_reindexing.store(true, Ordering::Relaxed);
+                        RoomMessageEventContent::text_plain("Stopping the search reindex...")
+                    } else {
+                        RoomMessageEventContent::text_plain("No search reindex is running.")
+                    }
+                } else if search.reindexing.swap(true, Ordering::SeqCst) {
+                    RoomMessageEventContent::text_plain(
+                        "A search reindex is already running, stop it with `reindex-search --stop`.",
+                    )
+                } else {
+                    search.stop_reindexing.store(false, Ordering::Relaxed);
+
+                    let rooms: Vec<OwnedRoomId> = match room_id {
+                        Some(room_id) => vec![room_id.into()],
+                        None => services()
+                            .rooms
+                            .metadata
+                            .iter_ids()
+                            .filter_map(|r| r.ok())
+                            .collect(),
+                    };
+                    let room_count = rooms.len();
+
+                    tokio::task::spawn_blocking(move || {
+                        let result = reindex_search(&rooms);
+                        services()
+                            .rooms
+                            .search
+                            .reindexing
+                            .store(false, Ordering::SeqCst);
+
+                        services()
+                            .admin
+                            .send_message(RoomMessageEventContent::text_plain(match result {
+                                Ok(Some(messages)) => format!(
+                                    "Finished the search reindex: {messages} messages in {} rooms.",
+                                    rooms.len()
+                                ),
+                                Ok(None) => "Stopped the search reindex.".to_owned(),
+                                Err(e) => format!("The search reindex failed: {e}"),
+                            }));
+                    });
+
+                    RoomMessageEventContent::text_plain(format!(
+                        "Reindexing {room_count} rooms in the background, stop it with `reindex-search --stop`."
+                    ))
+                }
+            }
+            AdminCommand::Resolve { server_name } => {
+                match services().globals.cached_destination(&server_name) {
+                    Some((actual_destination, host)) => RoomMessageEventContent::text_plain(
+                        format!("{server_name} is reached at {actual_destination:?} (host {host})"),
+                    ),
+                    None => RoomMessageEventContent::text_plain(format!(
+                        "No cached destination for {server_name}."
+                    )),
+                }
+            }
+            AdminCommand::FlushDestination { server_name } => {
+                if services().globals.flush_destination(&server_name) {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Flushed cached destination for {server_name}."
+                    ))
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "No cached destination for {server_name}."
+                    ))
+                }
+            }
+            AdminCommand::FlushDestinations => {
+                let count = services().globals.flush_destinations();
+                RoomMessageEventContent::text_plain(format!("Flushed {count} cached destinations."))
+            }
             AdminCommand::SignJson => {
                 if body.len() > 2 && body[0].trim() == "```" && body.last().unwrap().trim() == "```"
                 {
@@ -1076,9 +1454,7 @@ impl Service {
         )?;
 
         // 6. Room alias
-        let alias: OwnedRoomAliasId = format!("#admins:{}", services().globals.server_name())
-            .try_into()
-            .expect("#admins:server_name is a valid alias name");
+        let alias = services().globals.admin_room_alias();
 
         services().rooms.timeline.build_and_append_pdu(
             PduBuilder {
@@ -1110,15 +1486,7 @@ impl Service {
         user_id: &UserId,
         displayname: String,
     ) -> Result<()> {
-        let admin_room_alias: Box<RoomAliasId> =
-            format!("#admins:{}", services().globals.server_name())
-                .try_into()
-                .expect("#admins:server_name is a valid alias name");
-        let room_id = services()
-            .rooms
-            .alias
-            .resolve_local_alias(&admin_room_alias)?
-            .expect("Admin room must exist");
+        let room_id = self.get_admin_room()?.expect("Admin room must exist");
 
         let mutex_state = Arc::clone(
             services()
@@ -1226,9 +1594,169 @@ impl Service {
     }
 }
 
+/// Whether a message is an admin command: it has to be sent to the admin room and be addressed to
+/// the server user, but must not come from the server user itself unless the emergency password
+/// is set so that admins can log in as it.
+pub(crate) fn is_admin_command(
+    body: &str,
+    sender: &UserId,
+    room_id: &RoomId,
+    admin_room: Option<&RoomId>,
+    server_user: &UserId,
+    emergency_password_set: bool,
+) -> bool {
+    let from_conduit = sender == server_user && !emergency_password_set;
+    let server_user = server_user.as_str();
+
+    let to_conduit = body.starts_with(&format!("{server_user}: "))
+        || body.starts_with(&format!("{server_user} "))
+        || body == format!("{server_user}:")
+        || body == server_user;
+
+    to_conduit && !from_conduit && admin_room == Some(room_id)
+}
+
+/// Sets the default displayname and push rules of a newly created user.
+pub(crate) fn initialize_user(user_id: &UserId) -> Result<()> {
+    // Default to pretty displayname
+    let mut displayname = user_id.localpart().to_owned();
+
+    // If enabled append lightning bolt to display name (default true)
+    if services().globals.enable_lightning_bolt() {
+        displayname.push_str(" ⚡️");
+    }
+
+    services()
+        .users
+        .set_displayname(user_id, Some(displayname))?;
+
+    // Initial account data
+    services().account_data.update(
+        None,
+        user_id,
+        ruma::events::GlobalAccountDataEventType::PushRules
+            .to_string()
+            .into(),
+        &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+            content: ruma::events::push_rules::PushRulesEventContent {
+                global: services().globals.default_push_rules(user_id),
+            },
+        })
+        .expect("to json value always works"),
+    )?;
+
+    Ok(())
+}
+
+/// Parses the `localpart,password_hash` lines of `import-users` into the line number, localpart
+/// and hash. Empty lines and lines starting with `#` are ignored.
+fn parse_user_import(
+    content: &str,
+) -> impl Iterator<Item = std::result::Result<(usize, &str, &str), String>> + '_ {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| match line.split_once(',') {
+            Some((localpart, password_hash))
+                if !localpart.trim().is_empty() && !password_hash.trim().is_empty() =>
+            {
+                Ok((line_number, localpart.trim(), password_hash.trim()))
+            }
+            _ => Err(format!(
+                "line {line_number}: expected localpart,password_hash"
+            )),
+        })
+}
+
+/// Rebuilds the search index of the rooms, sending progress to the admin room. Returns the number
+/// of indexed messages or `None` if the reindex was stopped.
+fn reindex_search(rooms: &[OwnedRoomId]) -> Result<Option<usize>> {
+    const PROGRESS_INTERVAL: usize = 100;
+
+    let server_user = UserId::parse_with_server_name("conduit", services().globals.server_name())
+        .expect("@conduit:server_name is valid");
+
+    let mut indexed = 0;
+    for (i, room_id) in rooms.iter().enumerate() {
+        if services().globals.shutdown.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        let Some(shortroomid) = services().rooms.short.get_shortroomid(room_id)? else {
+            continue;
+        };
+
+        let messages = services()
+            .rooms
+            .timeline
+            .all_pdus(&server_user, room_id)?
+            .filter_map(|pdu| pdu.and_then(|(_, pdu)| indexable_message(&pdu)).transpose());
+
+        match services()
+            .rooms
+            .search
+            .reindex_room(shortroomid, messages)?
+        {
+            Some(count) => indexed += count,
+            None => return Ok(None),
+        }
+
+        if (i + 1) % PROGRESS_INTERVAL == 0 {
+            services()
+                .admin
+                .send_message(RoomMessageEventContent::text_plain(format!(
+                    "Search reindex: {} of {} rooms done, {indexed} messages so far.",
+                    i + 1,
+                    rooms.len()
+                )));
+        }
+    }
+
+    Ok(Some(indexed))
+}
+
+/// The PDU ID and body of a message event that belongs in the search index.
+fn indexable_message(pdu: &PduEvent) -> Result<Option<(Vec<u8>, String)>> {
+    #[derive(Deserialize)]
+    struct ExtractBody {
+        body: Option<String>,
+    }
+
+    if pdu.kind != TimelineEventType::RoomMessage {
+        return Ok(None);
+    }
+
+    let Some(body) = serde_json::from_str::<ExtractBody>(pdu.content.get())
+        .ok()
+        .and_then(|content| content.body)
+    else {
+        return Ok(None);
+    };
+
+    let pdu_id = services()
+        .rooms
+        .timeline
+        .get_pdu_id(&pdu.event_id)?
+        .ok_or_else(|| Error::bad_database("Timeline PDU has no PDU ID."))?;
+
+    Ok(Some((pdu_id, body)))
+}
+
+/// Parses the event of `send-event`, which has the same fields as the PDU builder.
+fn parse_raw_event(json: &str) -> serde_json::Result<PduBuilder> {
+    serde_json::from_str(json)
+}
+
 #[cfg(test)]
 mod test {
+    use std::net::Ipv4Addr;
+
+    use ruma::{room_id, user_id};
+
     use super::*;
+    use crate::{api::server_server::FedDest, utils::testing};
 
     #[test]
     fn get_help_short() {
@@ -1255,4 +1783,220 @@ mod test {
         assert!(error.contains("Commands:"));
         assert!(error.contains("Options:"));
     }
+
+    #[test]
+    fn parse_flush_destination() {
+        let command = AdminCommand::try_parse_from([
+            "argv[0] doesn't matter",
+            "flush-destination",
+            "example.com",
+        ])
+        .unwrap();
+
+        assert!(matches!(
+            command,
+            AdminCommand::FlushDestination { server_name } if server_name.as_str() == "example.com"
+        ));
+    }
+
+    #[test]
+    fn admin_commands_only_from_admin_room() {
+        let admins = room_id!("!admins:example.com");
+        let other = room_id!("!other:example.com");
+        let conduit = user_id!("@conduit:example.com");
+        let alice = user_id!("@alice:example.com");
+        let command = "@conduit:example.com: list-local-users";
+
+        assert!(is_admin_command(
+            command,
+            alice,
+            admins,
+            Some(admins),
+            conduit,
+            false
+        ));
+        assert!(is_admin_command(
+            "@conduit:example.com",
+            alice,
+            admins,
+            Some(admins),
+            conduit,
+            false
+        ));
+
+        // Other rooms, or no admin room at all
+        assert!(!is_admin_command(
+            command,
+            alice,
+            other,
+            Some(admins),
+            conduit,
+            false
+        ));
+        assert!(!is_admin_command(
+            command, alice, admins, None, conduit, false
+        ));
+
+        // Messages that are not addressed to the server user
+        assert!(!is_admin_command(
+            "list-local-users",
+            alice,
+            admins,
+            Some(admins),
+            conduit,
+            false
+        ));
+        assert!(!is_admin_command(
+            "@conduit:example.community: list-local-users",
+            alice,
+            admins,
+            Some(admins),
+            conduit,
+            false
+        ));
+
+        // The server user's own messages, unless an admin logged in as it
+        assert!(!is_admin_command(
+            command,
+            conduit,
+            admins,
+            Some(admins),
+            conduit,
+            false
+        ));
+        assert!(is_admin_command(
+            command,
+            conduit,
+            admins,
+            Some(admins),
+            conduit,
+            true
+        ));
+    }
+
+    #[test]
+    fn parse_send_event() {
+        let command = AdminCommand::try_parse_from([
+            "argv[0] doesn't matter",
+            "send-event",
+            "!room:example.com",
+        ])
+        .unwrap();
+        assert!(matches!(
+            command,
+            AdminCommand::SendEvent { room_id } if room_id.as_str() == "!room:example.com"
+        ));
+
+        let pdu_builder = parse_raw_event(
+            r#"{ "type": "m.room.message", "content": { "msgtype": "m.text", "body": "Hi" } }"#,
+        )
+        .unwrap();
+        assert_eq!(pdu_builder.event_type, TimelineEventType::RoomMessage);
+        assert_eq!(pdu_builder.state_key, None);
+
+        let content: RoomMessageEventContent =
+            serde_json::from_str(pdu_builder.content.get()).unwrap();
+        assert_eq!(content.msgtype.body(), "Hi");
+
+        // Malformed JSON and events without a type are rejected
+        assert!(parse_raw_event(r#"{ "type": "m.room.message", "#).is_err());
+        assert!(parse_raw_event(r#"{ "content": {} }"#).is_err());
+    }
+
+    #[test]
+    fn import_users_keeps_password_hashes() {
+        let alice_hash = utils::calculate_password_hash("alice's password").unwrap();
+        let bob_hash = utils::calculate_password_hash("bob's password").unwrap();
+        let content = format!("# migrated users\nalice,{alice_hash}\n\nbob,{bob_hash}\nbroken\n");
+
+        let lines: Vec<_> = parse_user_import(&content).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[2],
+            Err("line 5: expected localpart,password_hash".to_owned())
+        );
+
+        let (_, localpart, hash) = lines[0].clone().unwrap();
+        assert_eq!(localpart, "alice");
+        assert!(utils::is_password_hash(hash));
+        assert!(argon2::verify_encoded(hash, b"alice's password").unwrap());
+        assert!(!argon2::verify_encoded(hash, b"bob's password").unwrap());
+
+        let (line_number, localpart, hash) = lines[1].clone().unwrap();
+        assert_eq!((line_number, localpart), (4, "bob"));
+        assert!(argon2::verify_encoded(hash, b"bob's password").unwrap());
+
+        assert!(!utils::is_password_hash("not a hash"));
+    }
+
+    #[tokio::test]
+    async fn flushed_destinations_are_forgotten() {
+        let services = testing::services();
+        let cache = |name: &str| {
+            let server_name = ServerName::parse(format!(
+                "{name}.{}.example.org",
+                utils::random_string(8).to_lowercase()
+            ))
+            .unwrap();
+            let host = format!("matrix.{server_name}");
+            services
+                .globals
+                .actual_destination_cache
+                .write()
+                .unwrap()
+                .insert(
+                    server_name.clone(),
+                    (
+                        FedDest::Named(host.clone(), ":8448".to_owned()),
+                        host.clone(),
+                    ),
+                );
+            services
+                .globals
+                .tls_name_override
+                .write()
+                .unwrap()
+                .insert(host.clone(), (vec![Ipv4Addr::LOCALHOST.into()], 8448));
+            (server_name, host)
+        };
+        let (first, first_host) = cache("first");
+        let (second, second_host) = cache("second");
+
+        let run = |args: &[&str]| {
+            let command =
+                AdminCommand::try_parse_from(["argv[0] doesn't matter"].iter().chain(args))
+                    .unwrap();
+            services.admin.process_admin_command(command, Vec::new())
+        };
+        let reply = |content: RoomMessageEventContent| content.msgtype.body().to_owned();
+
+        assert!(reply(run(&["resolve", first.as_str()]).await.unwrap()).contains(&first_host));
+
+        // Only the flushed server is resolved again
+        assert_eq!(
+            reply(run(&["flush-destination", first.as_str()]).await.unwrap()),
+            format!("Flushed cached destination for {first}.")
+        );
+        assert!(services.globals.cached_destination(&first).is_none());
+        assert!(!services
+            .globals
+            .tls_name_override
+            .read()
+            .unwrap()
+            .contains_key(&first_host));
+        assert!(services.globals.cached_destination(&second).is_some());
+        assert_eq!(
+            reply(run(&["resolve", first.as_str()]).await.unwrap()),
+            format!("No cached destination for {first}.")
+        );
+
+        assert!(reply(run(&["flush-destinations"]).await.unwrap()).starts_with("Flushed "));
+        assert!(services.globals.cached_destination(&second).is_none());
+        assert!(!services
+            .globals
+            .tls_name_override
+            .read()
+            .unwrap()
+            .contains_key(&second_host));
+    }
 }
@@ -2,16 +2,115 @@ mod data;
 
 pub use data::Data;
 
-use crate::Result;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use regex::Regex;
+use ruma::{
+    api::{
+        appservice::{Namespace, Registration},
+        client::error::ErrorKind,
+    },
+    events::TimelineEventType,
+    OwnedRoomAliasId, ServerName, UserId,
+};
+use tracing::warn;
+
+use crate::{Error, PduEvent, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
+    registrations: RwLock<BTreeMap<String, Arc<RegistrationInfo>>>,
+}
+
+/// A registration that was parsed and whose namespace regexes were compiled when it was
+/// registered.
+pub struct RegistrationInfo {
+    pub registration: Registration,
+    pub yaml: serde_yaml::Value,
+    users: Vec<Regex>,
+    aliases: Vec<Regex>,
+    rooms: Vec<Regex>,
+}
+
+impl RegistrationInfo {
+    /// Parses a registration, failing if a required field is missing or a namespace regex does
+    /// not compile.
+    pub fn parse(yaml: serde_yaml::Value) -> Result<Self> {
+        let registration: Registration = serde_yaml::from_value(yaml.clone()).map_err(|e| {
+            warn!("Invalid appservice registration: {e}");
+            Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Registration is invalid, it needs id, url, as_token, hs_token, sender_localpart and namespaces.",
+            )
+        })?;
+
+        let regexes = |namespaces: &[Namespace]| {
+            namespaces
+                .iter()
+                .map(|namespace| Regex::new(&namespace.regex))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| {
+                    warn!(
+                        "Invalid regex in appservice registration {}: {e}",
+                        registration.id
+                    );
+                    Error::BadRequest(
+                        ErrorKind::InvalidParam,
+                        "Registration contains a namespace regex that is not valid.",
+                    )
+                })
+        };
+        let namespaces = &registration.namespaces;
+        let users = regexes(&namespaces.users)?;
+        let aliases = regexes(&namespaces.aliases)?;
+        let rooms = regexes(&namespaces.rooms)?;
+
+        Ok(Self {
+            registration,
+            yaml,
+            users,
+            aliases,
+            rooms,
+        })
+    }
 }
 
 impl Service {
+    /// Loads the stored registrations. Invalid registrations, which could be stored before they
+    /// were validated, are ignored.
+    pub fn build(db: &'static dyn Data) -> Result<Self> {
+        let registrations = db
+            .all()?
+            .into_iter()
+            .filter_map(|(id, yaml)| match RegistrationInfo::parse(yaml) {
+                Ok(info) => Some((id, Arc::new(info))),
+                Err(e) => {
+                    warn!("Ignoring invalid appservice registration {id}: {e}");
+                    None
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            db,
+            registrations: RwLock::new(registrations),
+        })
+    }
+
     /// Registers an appservice and returns the ID to the caller
+    ///
+    /// The registration is validated first, an existing registration with the same ID is replaced.
     pub fn register_appservice(&self, yaml: serde_yaml::Value) -> Result<String> {
-        self.db.register_appservice(yaml)
+        let info = validate_registration(yaml, &self.registrations())?;
+        let id = self.db.register_appservice(info.yaml.clone())?;
+        self.registrations
+            .write()
+            .unwrap()
+            .insert(id.clone(), Arc::new(info));
+        Ok(id)
     }
 
     /// Remove an appservice registration
@@ -20,7 +119,9 @@ impl Service {
     ///
     /// * `service_name` - the name you send to register the service previously
     pub fn unregister_appservice(&self, service_name: &str) -> Result<()> {
-        self.db.unregister_appservice(service_name)
+        self.db.unregister_appservice(service_name)?;
+        self.registrations.write().unwrap().remove(service_name);
+        Ok(())
     }
 
     pub fn get_registration(&self, id: &str) -> Result<Option<serde_yaml::Value>> {
@@ -34,4 +135,337 @@ impl Service {
     pub fn all(&self) -> Result<Vec<(String, serde_yaml::Value)>> {
         self.db.all()
     }
+
+    /// The valid registrations, parsed when they were registered.
+    pub fn registrations(&self) -> Vec<Arc<RegistrationInfo>> {
+        self.registrations
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Parses a registration and checks that no other appservice uses the same tokens.
+fn validate_registration(
+    yaml: serde_yaml::Value,
+    existing: &[Arc<RegistrationInfo>],
+) -> Result<RegistrationInfo> {
+    let info = RegistrationInfo::parse(yaml)?;
+    let registration = &info.registration;
+
+    let token_in_use = existing
+        .iter()
+        .map(|other| &other.registration)
+        .filter(|other| other.id != registration.id)
+        .any(|other| {
+            other.as_token == registration.as_token || other.hs_token == registration.hs_token
+        });
+    if token_in_use {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Another appservice already uses this as_token or hs_token.",
+        ));
+    }
+
+    Ok(info)
+}
+
+/// Whether an event has to be pushed to an appservice: it is sent by or targets one of the
+/// appservice's users (including its sender), or its room matches one of the room or alias
+/// namespaces.
+///
+/// `room_aliases` is only called if the room is not matched otherwise.
+pub fn is_interested(
+    info: &RegistrationInfo,
+    pdu: &PduEvent,
+    server_name: &ServerName,
+    room_aliases: impl FnOnce() -> Vec<OwnedRoomAliasId>,
+) -> bool {
+    let target = (pdu.kind == TimelineEventType::RoomMember)
+        .then_some(pdu.state_key.as_deref())
+        .flatten();
+
+    let sender_user =
+        UserId::parse_with_server_name(info.registration.sender_localpart.as_str(), server_name)
+            .ok();
+    if target.is_some_and(|target| sender_user.is_some_and(|user| user.as_str() == target)) {
+        return true;
+    }
+
+    if info.users.iter().any(|users| {
+        users.is_match(pdu.sender.as_str()) || target.is_some_and(|target| users.is_match(target))
+    }) {
+        return true;
+    }
+
+    if info
+        .rooms
+        .iter()
+        .any(|rooms| rooms.is_match(pdu.room_id.as_str()))
+    {
+        return true;
+    }
+
+    !info.aliases.is_empty()
+        && room_aliases().iter().any(|alias| {
+            info.aliases
+                .iter()
+                .any(|aliases| aliases.is_match(alias.as_str()))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, TcpListener},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use axum::{routing::put, Json, Router};
+    use ruma::{room_alias_id, server_name};
+    use serde_json::json;
+
+    use super::{is_interested, validate_registration, RegistrationInfo};
+    use crate::{
+        utils::{self, testing},
+        PduEvent,
+    };
+
+    fn registration(id: &str, as_token: &str, user_regex: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(&format!(
+            r#"
+id: {id}
+url: "http://localhost:29318"
+as_token: {as_token}
+hs_token: hs_{as_token}
+sender_localpart: {id}bot
+namespaces:
+  users:
+  - exclusive: true
+    regex: '{user_regex}'
+  aliases: []
+  rooms: []
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn valid_registration() {
+        let yaml = registration("telegram", "secret", "@telegram_.*:example\\.com");
+        assert!(validate_registration(yaml.clone(), &[]).is_ok());
+
+        // Registering it again replaces it
+        let existing = [Arc::new(RegistrationInfo::parse(yaml.clone()).unwrap())];
+        assert!(validate_registration(yaml, &existing).is_ok());
+    }
+
+    #[test]
+    fn invalid_registrations() {
+        let mut missing_token = registration("telegram", "secret", "@telegram_.*");
+        missing_token.as_mapping_mut().unwrap().remove("as_token");
+        assert!(validate_registration(missing_token, &[]).is_err());
+
+        let invalid_regex = registration("telegram", "secret", "@telegram_(.*");
+        assert!(validate_registration(invalid_regex, &[]).is_err());
+
+        let existing = [Arc::new(
+            RegistrationInfo::parse(registration("whatsapp", "secret", "@whatsapp_.*")).unwrap(),
+        )];
+        let duplicate_token = registration("telegram", "secret", "@telegram_.*");
+        assert!(validate_registration(duplicate_token, &existing).is_err());
+    }
+
+    fn pdu(sender: &str, room_id: &str, kind: &str, state_key: Option<&str>) -> PduEvent {
+        testing::pdu(json!({
+            "room_id": room_id,
+            "sender": sender,
+            "type": kind,
+            "state_key": state_key,
+        }))
+    }
+
+    #[test]
+    fn interest_in_events() {
+        let mut yaml = registration("telegram", "secret", "@telegram_.*:example\\.com");
+        yaml["namespaces"]["rooms"] =
+            serde_yaml::from_str("[{ exclusive: false, regex: '!bridged.*:example\\.com' }]")
+                .unwrap();
+        yaml["namespaces"]["aliases"] =
+            serde_yaml::from_str("[{ exclusive: true, regex: '#telegram_.*:example\\.com' }]")
+                .unwrap();
+        let registration = RegistrationInfo::parse(yaml).unwrap();
+        let server_name = server_name!("example.com");
+        let no_aliases = Vec::new;
+
+        // Room namespace
+        let in_room = pdu(
+            "@alice:example.com",
+            "!bridged1:example.com",
+            "m.room.message",
+            None,
+        );
+        assert!(is_interested(
+            &registration,
+            &in_room,
+            server_name,
+            no_aliases
+        ));
+
+        // User namespace, as sender and as membership target
+        let from_user = pdu(
+            "@telegram_1:example.com",
+            "!other:example.com",
+            "m.room.message",
+            None,
+        );
+        assert!(is_interested(
+            &registration,
+            &from_user,
+            server_name,
+            no_aliases
+        ));
+        let invite = pdu(
+            "@alice:example.com",
+            "!other:example.com",
+            "m.room.member",
+            Some("@telegram_1:example.com"),
+        );
+        assert!(is_interested(
+            &registration,
+            &invite,
+            server_name,
+            no_aliases
+        ));
+        let invite_bot = pdu(
+            "@alice:example.com",
+            "!other:example.com",
+            "m.room.member",
+            Some("@telegrambot:example.com"),
+        );
+        assert!(is_interested(
+            &registration,
+            &invite_bot,
+            server_name,
+            no_aliases
+        ));
+
+        // Alias namespace
+        let unrelated = pdu(
+            "@alice:example.com",
+            "!other:example.com",
+            "m.room.message",
+            None,
+        );
+        assert!(is_interested(
+            &registration,
+            &unrelated,
+            server_name,
+            || vec![room_alias_id!("#telegram_chat:example.com").to_owned()]
+        ));
+
+        // Nothing matches
+        assert!(!is_interested(
+            &registration,
+            &unrelated,
+            server_name,
+            || vec![room_alias_id!("#other:example.com").to_owned()]
+        ));
+        let not_a_member_event = pdu(
+            "@alice:example.com",
+            "!other:example.com",
+            "m.room.topic",
+            Some("@telegram_1:example.com"),
+        );
+        assert!(!is_interested(
+            &registration,
+            &not_a_member_event,
+            server_name,
+            no_aliases
+        ));
+    }
+
+    #[tokio::test]
+    async fn events_are_pushed_once() {
+        let services = testing::services();
+        let user = testing::create_user();
+        let room_id = testing::create_room(&user).await;
+
+        // An appservice that stores the IDs of the events pushed to it
+        let received = Arc::new(Mutex::new(Vec::<String>::new()));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().route(
+            "/_matrix/app/v1/transactions/:txn_id",
+            put({
+                let received = Arc::clone(&received);
+                move |Json(body): Json<serde_json::Value>| {
+                    let received = Arc::clone(&received);
+                    async move {
+                        let events = body["events"].as_array().cloned().unwrap_or_default();
+                        received.lock().unwrap().extend(
+                            events.iter().filter_map(|event| {
+                                event["event_id"].as_str().map(ToOwned::to_owned)
+                            }),
+                        );
+                        Json(json!({}))
+                    }
+                }
+            }),
+        );
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        // The appservice is both in the room through its users and interested in the room itself
+        let id = utils::random_string(8).to_lowercase();
+        let mut yaml = registration(
+            &id,
+            &utils::random_string(16),
+            &regex::escape(user.as_str()),
+        );
+        yaml["url"] = url.into();
+        yaml["namespaces"]["rooms"] = serde_yaml::from_str(&format!(
+            "[{{ exclusive: false, regex: '{}' }}]",
+            regex::escape(room_id.as_str())
+        ))
+        .unwrap();
+        services.appservice.register_appservice(yaml).unwrap();
+
+        // Invalid registrations are rejected instead of being ignored for every event
+        let mut invalid = registration(&id, &utils::random_string(16), "@(.*");
+        invalid["url"] = "http://localhost".into();
+        assert!(services.appservice.register_appservice(invalid).is_err());
+        assert!(services
+            .appservice
+            .registrations()
+            .iter()
+            .any(|info| info.registration.id == id && info.users.len() == 1));
+
+        let event_id = testing::send_message(&room_id, &user, "hello").await;
+        let pushed = || {
+            received
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|pushed| pushed.as_str() == event_id.as_str())
+                .count()
+        };
+        for _ in 0..100 {
+            if pushed() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        // Give a second push some time to arrive
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(pushed(), 1);
+
+        services.appservice.unregister_appservice(&id).unwrap();
+    }
 }
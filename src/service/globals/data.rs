@@ -12,11 +12,20 @@ use crate::Result;
 #[async_trait]
 pub trait Data: Send + Sync {
     fn next_count(&self) -> Result<u64>;
+    /// Increases the counter by `amount` at once and returns the new value.
+    fn reserve_counts(&self, amount: u64) -> Result<u64>;
     fn current_count(&self) -> Result<u64>;
     fn last_check_for_updates_id(&self) -> Result<u64>;
     fn update_check_for_updates_id(&self, id: u64) -> Result<()>;
     async fn watch(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()>;
+    async fn watch_account_data(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()>;
     fn cleanup(&self) -> Result<()>;
+    /// Makes sure all writes so far survive a crash.
+    fn persist(&self) -> Result<()>;
+    fn database_backend(&self) -> &'static str;
+    /// Returns the problems found in the database, checking at most `sample_size` entries if the
+    /// backend has no integrity check of its own.
+    fn check_integrity(&self, sample_size: usize) -> Result<Vec<String>>;
     fn memory_usage(&self) -> String;
     fn clear_caches(&self, amount: u32);
     fn load_keypair(&self) -> Result<Ed25519KeyPair>;
@@ -1,28 +1,34 @@
 mod data;
 pub use data::Data;
 use ruma::{
-    serde::Base64, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedServerName,
-    OwnedServerSigningKeyId, OwnedUserId,
+    serde::Base64, OwnedDeviceId, OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedServerName,
+    OwnedServerSigningKeyId, OwnedUserId, RoomAliasId,
 };
 
 use crate::api::server_server::FedDest;
 
-use crate::{services, Config, Error, Result};
+use crate::{
+    config::OidcConfig,
+    services,
+    utils::{self, tasks::TaskManager},
+    Config, DestinationOverride, DeviceLimitAction, Error, Result, SearchTokenizer,
+};
 use ruma::{
     api::{
-        client::sync::sync_events,
+        client::{session::get_login_types::v3::LoginType, sync::sync_events},
         federation::discovery::{ServerSigningKeys, VerifyKey},
     },
+    events::room::history_visibility::HistoryVisibility,
+    push::Ruleset,
     DeviceId, RoomVersionId, ServerName, UserId,
 };
 use std::{
     collections::{BTreeMap, HashMap},
-    fs,
     future::Future,
     net::{IpAddr, SocketAddr},
     path::PathBuf,
     sync::{
-        atomic::{self, AtomicBool},
+        atomic::{self, AtomicBool, AtomicU64, AtomicUsize},
         Arc, Mutex, RwLock,
     },
     time::{Duration, Instant},
@@ -51,19 +57,31 @@ pub struct Service {
     dns_resolver: TokioAsyncResolver,
     jwt_decoding_key: Option<jsonwebtoken::DecodingKey>,
     federation_client: reqwest::Client,
+    well_known_client: reqwest::Client,
     default_client: reqwest::Client,
     pub stable_room_versions: Vec<RoomVersionId>,
     pub unstable_room_versions: Vec<RoomVersionId>,
     pub bad_event_ratelimiter: Arc<RwLock<HashMap<OwnedEventId, RateLimitState>>>,
     pub bad_signature_ratelimiter: Arc<RwLock<HashMap<Vec<String>, RateLimitState>>>,
     pub servername_ratelimiter: Arc<RwLock<HashMap<OwnedServerName, Arc<Semaphore>>>>,
+    pub servername_txn_ratelimiter: RwLock<HashMap<OwnedServerName, Arc<Semaphore>>>,
+    pub federation_retry_after: RwLock<HashMap<OwnedServerName, Instant>>, // set from 429 responses
+    pub signing_keys_fetched: RwLock<HashMap<OwnedServerName, Instant>>,
+    pub registration_nonces: Mutex<HashMap<String, Instant>>, // for shared secret registration
+    pub message_rate_limiter: Option<utils::RateLimiter<(OwnedUserId, OwnedRoomId)>>,
+    pub room_join_rate_limiter: Option<utils::RateLimiter<OwnedRoomId>>,
     pub sync_receivers: RwLock<HashMap<(OwnedUserId, OwnedDeviceId), SyncHandle>>,
+    pub sync_connections: ConnectionCounter,
     pub roomid_mutex_insert: RwLock<HashMap<OwnedRoomId, Arc<Mutex<()>>>>,
     pub roomid_mutex_state: RwLock<HashMap<OwnedRoomId, Arc<TokioMutex<()>>>>,
     pub roomid_mutex_federation: RwLock<HashMap<OwnedRoomId, Arc<TokioMutex<()>>>>, // this lock will be held longer
     pub roomid_federationhandletime: RwLock<HashMap<OwnedRoomId, (OwnedEventId, Instant)>>,
     pub stateres_mutex: Arc<Mutex<()>>,
     pub rotate: RotationHandler,
+    pub tasks: TaskManager,
+    count_allocator: Option<CountAllocator>,
+    persisted_count: AtomicU64,
+    motd: RwLock<Option<String>>,
 
     pub shutdown: AtomicBool,
 }
@@ -98,8 +116,93 @@ impl Default for RotationHandler {
     }
 }
 
+/// Counts the open connections of each user, see `max_sync_connections_per_user`.
+#[derive(Default)]
+pub struct ConnectionCounter {
+    connections: Mutex<HashMap<OwnedUserId, Arc<AtomicUsize>>>,
+}
+
+impl ConnectionCounter {
+    /// Counts a new connection of the user until the returned guard is dropped. Returns `None` if
+    /// the user already has `max` connections.
+    pub fn acquire(&self, user_id: &UserId, max: Option<usize>) -> Option<ConnectionGuard> {
+        let counter = Arc::clone(
+            self.connections
+                .lock()
+                .unwrap()
+                .entry(user_id.to_owned())
+                .or_default(),
+        );
+
+        let previous = counter.fetch_add(1, atomic::Ordering::SeqCst);
+        if max.is_some_and(|max| previous >= max) {
+            counter.fetch_sub(1, atomic::Ordering::SeqCst);
+            return None;
+        }
+
+        Some(ConnectionGuard(counter))
+    }
+}
+
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+}
+
+/// Hands out counts from blocks that are reserved in the database at once, see
+/// `count_allocation_block`.
+struct CountAllocator {
+    block_size: u64,
+    /// The last count that was handed out and the end of the reserved block
+    block: Mutex<Option<(u64, u64)>>,
+}
+
+impl CountAllocator {
+    fn new(block_size: u64) -> Self {
+        Self {
+            block_size,
+            block: Mutex::new(None),
+        }
+    }
+
+    /// Returns the next count, reserving a new block with `reserve` if the current one is used up.
+    /// `reserve` increases the stored counter by the given amount and returns its new value.
+    fn next(&self, reserve: impl FnOnce(u64) -> Result<u64>) -> Result<u64> {
+        let mut block = self.block.lock().unwrap();
+
+        let (count, end) = match *block {
+            Some((last, end)) if last < end => (last + 1, end),
+            _ => {
+                let end = reserve(self.block_size)?;
+                (end - self.block_size + 1, end)
+            }
+        };
+        *block = Some((count, end));
+
+        Ok(count)
+    }
+
+    /// The last count that was handed out, if any.
+    fn current(&self) -> Option<u64> {
+        self.block.lock().unwrap().map(|(last, _)| last)
+    }
+}
+
 impl Service {
     pub fn load(db: &'static dyn Data, config: Config) -> Result<Self> {
+        if config
+            .admin_room_alias
+            .as_ref()
+            .is_some_and(|alias| alias.server_name() != &*config.server_name)
+        {
+            return Err(Error::bad_config(
+                "admin_room_alias has to be an alias on this server.",
+            ));
+        }
+
         let keypair = db.load_keypair();
 
         let keypair = match keypair {
@@ -119,8 +222,12 @@ impl Service {
             .map(|secret| jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()));
 
         let default_client = reqwest_client_builder(&config)?.build()?;
+        let well_known_client = federation_client_builder(&config)?
+            .redirect(redirect_policy(config.federation_max_redirects, true))
+            .build()?;
         let name_override = Arc::clone(&tls_name_override);
-        let federation_client = reqwest_client_builder(&config)?
+        let federation_client = federation_client_builder(&config)?
+            .redirect(redirect_policy(config.federation_max_redirects, false))
             .resolve_fn(move |domain| {
                 let read_guard = name_override.read().unwrap();
                 let (override_name, port) = read_guard.get(&domain)?;
@@ -140,6 +247,20 @@ impl Service {
         // Experimental, partially supported room versions
         let unstable_room_versions = vec![RoomVersionId::V3, RoomVersionId::V4, RoomVersionId::V5];
 
+        let message_rate_limiter = config
+            .message_rate_limit
+            .as_ref()
+            .map(utils::RateLimiter::new);
+        let room_join_rate_limiter = config
+            .room_join_rate_limit
+            .as_ref()
+            .map(utils::RateLimiter::new);
+
+        let motd = RwLock::new(config.server_notice_motd.clone());
+
+        let count_allocator = (config.count_allocation_block > 1)
+            .then(|| CountAllocator::new(config.count_allocation_block));
+
         let mut s = Self {
             db,
             config,
@@ -154,6 +275,7 @@ impl Service {
             actual_destination_cache: Arc::new(RwLock::new(WellKnownMap::new())),
             tls_name_override,
             federation_client,
+            well_known_client,
             default_client,
             jwt_decoding_key,
             stable_room_versions,
@@ -161,17 +283,38 @@ impl Service {
             bad_event_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             bad_signature_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             servername_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
+            servername_txn_ratelimiter: RwLock::new(HashMap::new()),
+            federation_retry_after: RwLock::new(HashMap::new()),
+            signing_keys_fetched: RwLock::new(HashMap::new()),
+            registration_nonces: Mutex::new(HashMap::new()),
+            message_rate_limiter,
+            room_join_rate_limiter,
             roomid_mutex_state: RwLock::new(HashMap::new()),
             roomid_mutex_insert: RwLock::new(HashMap::new()),
             roomid_mutex_federation: RwLock::new(HashMap::new()),
             roomid_federationhandletime: RwLock::new(HashMap::new()),
             stateres_mutex: Arc::new(Mutex::new(())),
             sync_receivers: RwLock::new(HashMap::new()),
+            sync_connections: ConnectionCounter::default(),
             rotate: RotationHandler::new(),
+            tasks: TaskManager::default(),
+            count_allocator,
+            persisted_count: AtomicU64::new(0),
+            motd,
             shutdown: AtomicBool::new(false),
         };
 
-        fs::create_dir_all(s.get_media_folder())?;
+        let media_folder = s.get_media_folder();
+        if let Err(e) = utils::ensure_writable_dir(&media_folder) {
+            error!(
+                "Media directory {} is not writable: {}",
+                media_folder.display(),
+                e
+            );
+            return Err(Error::bad_config(
+                "The media directory does not exist and cannot be created, or is not writable.",
+            ));
+        }
 
         if !s
             .supported_room_versions()
@@ -195,20 +338,37 @@ impl Service {
         self.default_client.clone()
     }
 
-    /// Returns a client used for resolving .well-knowns
+    /// Returns a client used for federation requests
     pub fn federation_client(&self) -> reqwest::Client {
         // Client is cheap to clone (Arc wrapper) and avoids lifetime issues
         self.federation_client.clone()
     }
 
+    /// Returns a client used for resolving .well-knowns, which only follows redirects on the
+    /// same host
+    pub fn well_known_client(&self) -> reqwest::Client {
+        // Client is cheap to clone (Arc wrapper) and avoids lifetime issues
+        self.well_known_client.clone()
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn next_count(&self) -> Result<u64> {
-        self.db.next_count()
+        match &self.count_allocator {
+            Some(allocator) => allocator.next(|amount| self.db.reserve_counts(amount)),
+            None => self.db.next_count(),
+        }
     }
 
     #[tracing::instrument(skip(self))]
     pub fn current_count(&self) -> Result<u64> {
-        self.db.current_count()
+        match self
+            .count_allocator
+            .as_ref()
+            .and_then(CountAllocator::current)
+        {
+            Some(count) => Ok(count),
+            None => self.db.current_count(),
+        }
     }
 
     #[tracing::instrument(skip(self))]
@@ -225,22 +385,116 @@ impl Service {
         self.db.watch(user_id, device_id).await
     }
 
+    /// Like `watch`, but only wakes up for account data and to-device events.
+    pub async fn watch_account_data(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        self.db.watch_account_data(user_id, device_id).await
+    }
+
     pub fn cleanup(&self) -> Result<()> {
         self.db.cleanup()
     }
 
+    /// Makes sure all writes so far survive a crash, see `persisted_count`.
+    pub fn persist(&self) -> Result<()> {
+        let count = self.current_count()?;
+        self.db.persist()?;
+        self.persisted_count
+            .fetch_max(count, atomic::Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Writes up to this count were explicitly persisted. Later writes are on disk after the next
+    /// cleanup at the latest.
+    pub fn persisted_count(&self) -> u64 {
+        self.persisted_count.load(atomic::Ordering::SeqCst)
+    }
+
     pub fn server_name(&self) -> &ServerName {
         self.config.server_name.as_ref()
     }
 
+    /// The alias of the admin room, `#admins:server_name` unless configured otherwise.
+    pub fn admin_room_alias(&self) -> OwnedRoomAliasId {
+        self.config.admin_room_alias.clone().unwrap_or_else(|| {
+            RoomAliasId::parse(format!("#admins:{}", self.server_name()))
+                .expect("#admins:server_name is a valid room alias")
+        })
+    }
+
     pub fn max_request_size(&self) -> u32 {
         self.config.max_request_size
     }
 
+    /// Maximum body size for `/_matrix/federation/*` requests. Falls back to
+    /// `max_request_size` if not configured.
+    pub fn max_federation_request_size(&self) -> u32 {
+        self.config
+            .max_federation_request_size
+            .unwrap_or(self.config.max_request_size)
+    }
+
+    pub fn sync_max_since_gap(&self) -> Option<u64> {
+        self.config.sync_max_since_gap
+    }
+
+    pub fn media_max_decode_pixels(&self) -> u64 {
+        self.config.media_max_decode_pixels
+    }
+
     pub fn max_fetch_prev_events(&self) -> u16 {
         self.config.max_fetch_prev_events
     }
 
+    pub fn max_concurrent_transactions_per_origin(&self) -> usize {
+        self.config.max_concurrent_transactions_per_origin.get()
+    }
+
+    pub fn max_event_bytes(&self) -> usize {
+        self.config.max_event_bytes
+    }
+
+    pub fn max_state_events_per_room(&self) -> Option<usize> {
+        self.config.max_state_events_per_room
+    }
+
+    pub fn max_devices_per_user(&self) -> Option<usize> {
+        self.config.max_devices_per_user
+    }
+
+    pub fn max_to_device_events_per_device(&self) -> Option<usize> {
+        self.config.max_to_device_events_per_device
+    }
+
+    pub fn max_rooms_per_user(&self) -> Option<usize> {
+        self.config.max_rooms_per_user
+    }
+
+    pub fn max_joined_rooms_per_user(&self) -> Option<usize> {
+        self.config.max_joined_rooms_per_user
+    }
+
+    pub fn max_sync_connections_per_user(&self) -> Option<usize> {
+        self.config.max_sync_connections_per_user
+    }
+
+    pub fn device_limit_action(&self) -> DeviceLimitAction {
+        self.config.device_limit_action
+    }
+
+    pub fn durable_event_types(&self) -> &[String] {
+        &self.config.durable_event_types
+    }
+
+    pub fn search_tokenizer(&self) -> SearchTokenizer {
+        self.config.search_tokenizer
+    }
+
+    /// The number of events, rooms, etc. to return for a requested `limit`, capped by
+    /// `max_fetch_limit`.
+    pub fn fetch_limit(&self, requested: Option<u64>, default: u64) -> usize {
+        utils::clamp_limit(requested, default, self.config.max_fetch_limit)
+    }
+
     pub fn allow_registration(&self) -> bool {
         self.config.allow_registration
     }
@@ -253,10 +507,38 @@ impl Service {
         self.config.allow_federation
     }
 
+    pub fn allow_profile_lookup_over_federation(&self) -> bool {
+        self.config.allow_profile_lookup_over_federation
+    }
+
+    pub fn user_directory_search_all_users(&self) -> bool {
+        self.config.user_directory_search_all_users
+    }
+
+    pub fn notify_admins_of_reports(&self) -> bool {
+        self.config.notify_admins_of_reports
+    }
+
+    /// The push rules of new users: the server defaults and the configured `push_rules`.
+    pub fn default_push_rules(&self, user_id: &UserId) -> Ruleset {
+        self.config
+            .push_rules
+            .apply(Ruleset::server_default(user_id))
+    }
+
     pub fn allow_room_creation(&self) -> bool {
         self.config.allow_room_creation
     }
 
+    /// The current message of the day, `server_notice_motd` until it is changed by an admin.
+    pub fn motd(&self) -> Option<String> {
+        self.motd.read().unwrap().clone()
+    }
+
+    pub fn set_motd(&self, motd: Option<String>) {
+        *self.motd.write().unwrap() = motd;
+    }
+
     pub fn allow_unstable_room_versions(&self) -> bool {
         self.config.allow_unstable_room_versions
     }
@@ -265,6 +547,10 @@ impl Service {
         self.config.default_room_version.clone()
     }
 
+    pub fn default_history_visibility(&self) -> &HistoryVisibility {
+        &self.config.default_history_visibility
+    }
+
     pub fn enable_lightning_bolt(&self) -> bool {
         self.config.enable_lightning_bolt
     }
@@ -277,6 +563,53 @@ impl Service {
         &self.config.trusted_servers
     }
 
+    pub fn cached_destination(&self, server_name: &ServerName) -> Option<(FedDest, String)> {
+        self.actual_destination_cache
+            .read()
+            .unwrap()
+            .get(server_name)
+            .cloned()
+    }
+
+    /// Forgets how to reach a server, so the next request resolves it again. Returns false if
+    /// nothing was cached.
+    pub fn flush_destination(&self, server_name: &ServerName) -> bool {
+        let removed = self
+            .actual_destination_cache
+            .write()
+            .unwrap()
+            .remove(server_name);
+
+        if let Some((actual_destination, _)) = &removed {
+            self.tls_name_override
+                .write()
+                .unwrap()
+                .remove(&actual_destination.hostname());
+        }
+
+        removed.is_some()
+    }
+
+    /// Forgets all cached destinations, returns how many there were.
+    pub fn flush_destinations(&self) -> usize {
+        let mut cache = self.actual_destination_cache.write().unwrap();
+        let count = cache.len();
+        cache.clear();
+        self.tls_name_override.write().unwrap().clear();
+
+        count
+    }
+
+    pub fn trusted_proxies(&self) -> &[IpAddr] {
+        &self.config.trusted_proxies
+    }
+
+    pub fn destination_override(&self, server_name: &ServerName) -> Option<&DestinationOverride> {
+        self.config
+            .federation_destination_overrides
+            .get(server_name)
+    }
+
     pub fn dns_resolver(&self) -> &TokioAsyncResolver {
         &self.dns_resolver
     }
@@ -285,6 +618,15 @@ impl Service {
         self.jwt_decoding_key.as_ref()
     }
 
+    pub fn oidc(&self) -> Option<&OidcConfig> {
+        self.config.oidc.as_ref()
+    }
+
+    /// The login types users can currently choose from.
+    pub fn login_flows(&self) -> Vec<LoginType> {
+        login_flows(&self.config)
+    }
+
     pub fn turn_password(&self) -> &String {
         &self.config.turn_password
     }
@@ -301,6 +643,10 @@ impl Service {
         &self.config.turn_username
     }
 
+    pub fn registration_shared_secret(&self) -> Option<&str> {
+        self.config.registration_shared_secret.as_deref()
+    }
+
     pub fn turn_secret(&self) -> &String {
         &self.config.turn_secret
     }
@@ -327,9 +673,29 @@ impl Service {
         origin: &ServerName,
         new_keys: ServerSigningKeys,
     ) -> Result<BTreeMap<OwnedServerSigningKeyId, VerifyKey>> {
+        self.signing_keys_fetched
+            .write()
+            .unwrap()
+            .insert(origin.to_owned(), Instant::now());
+
         self.db.add_signing_key(origin, new_keys)
     }
 
+    /// Whether the signing keys of a server should be fetched again, even if they are still valid,
+    /// so revoked keys are noticed. Keys we haven't fetched since startup always count as stale.
+    pub fn signing_keys_need_refresh(&self, origin: &ServerName) -> bool {
+        origin != self.server_name()
+            && signing_keys_stale(
+                self.signing_keys_fetched
+                    .read()
+                    .unwrap()
+                    .get(origin)
+                    .copied(),
+                Instant::now(),
+                Duration::from_secs(self.config.signing_key_refresh_interval_secs),
+            )
+    }
+
     /// This returns an empty `Ok(BTreeMap<..>)` when there are no keys found for the server.
     pub fn signing_keys_for(
         &self,
@@ -350,6 +716,19 @@ impl Service {
         Ok(keys)
     }
 
+    /// The version of Conduit, the database backend in use and which major features are enabled.
+    pub fn server_info(&self) -> serde_json::Value {
+        serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "database_backend": self.db.database_backend(),
+            "features": {
+                "federation": self.allow_federation(),
+                "registration": self.allow_registration(),
+                "encryption": self.allow_encryption(),
+            },
+        })
+    }
+
     pub fn database_version(&self) -> Result<u64> {
         self.db.database_version()
     }
@@ -385,9 +764,28 @@ impl Service {
     }
 }
 
+fn login_flows(config: &Config) -> Vec<LoginType> {
+    let mut flows = vec![
+        LoginType::Password(Default::default()),
+        LoginType::ApplicationService(Default::default()),
+    ];
+
+    if config.oidc.is_some() {
+        flows.push(LoginType::Sso(Default::default()));
+    }
+
+    // JWTs and the tokens from SSO logins are both sent as m.login.token
+    if config.jwt_secret.is_some() || config.oidc.is_some() {
+        flows.push(LoginType::Token(Default::default()));
+    }
+
+    flows
+}
+
 fn reqwest_client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
     let mut reqwest_client_builder = reqwest::Client::builder()
         .pool_max_idle_per_host(0)
+        .user_agent(&config.user_agent)
         .connect_timeout(Duration::from_secs(30))
         .timeout(Duration::from_secs(60 * 3));
 
@@ -397,3 +795,294 @@ fn reqwest_client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
 
     Ok(reqwest_client_builder)
 }
+
+fn federation_client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
+    Ok(reqwest_client_builder(config)?.local_address(config.federation_bind_address))
+}
+
+/// Follows at most `max_redirects` redirects and never the same URL twice. With `same_origin`,
+/// redirects to another scheme, host or port are rejected too.
+fn redirect_policy(max_redirects: usize, same_origin: bool) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        let previous = attempt.previous();
+        if previous.len() > max_redirects {
+            attempt.error("too many redirects")
+        } else if previous.contains(attempt.url()) {
+            attempt.error("redirect loop")
+        } else if same_origin
+            && previous
+                .first()
+                .is_some_and(|first| first.origin() != attempt.url().origin())
+        {
+            attempt.error("redirect to another origin")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+fn signing_keys_stale(fetched: Option<Instant>, now: Instant, refresh_interval: Duration) -> bool {
+    fetched.map_or(true, |fetched| {
+        now.duration_since(fetched) >= refresh_interval
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::api::client::session::get_login_types::v3::LoginType;
+    use serde_json::json;
+
+    use std::time::{Duration, Instant};
+
+    use super::{
+        federation_client_builder, login_flows, redirect_policy, reqwest_client_builder,
+        signing_keys_stale, ConnectionCounter, CountAllocator,
+    };
+    use crate::Config;
+
+    fn config(jwt_secret: Option<&str>) -> Config {
+        serde_json::from_value(json!({
+            "server_name": "example.com",
+            "database_path": "/tmp/conduit",
+            "jwt_secret": jwt_secret,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn token_login_requires_jwt_secret() {
+        assert!(!login_flows(&config(None))
+            .iter()
+            .any(|flow| matches!(flow, LoginType::Token(_))));
+        assert!(login_flows(&config(Some("secret")))
+            .iter()
+            .any(|flow| matches!(flow, LoginType::Token(_))));
+    }
+
+    #[test]
+    fn password_login_is_always_offered() {
+        assert!(login_flows(&config(None))
+            .iter()
+            .any(|flow| matches!(flow, LoginType::Password(_))));
+    }
+
+    #[test]
+    fn signing_keys_are_refetched_after_refresh_interval() {
+        let interval = Duration::from_secs(3600);
+        let fetched = Instant::now();
+
+        assert!(signing_keys_stale(None, fetched, interval));
+        assert!(!signing_keys_stale(
+            Some(fetched),
+            fetched + Duration::from_secs(60),
+            interval
+        ));
+        assert!(signing_keys_stale(
+            Some(fetched),
+            fetched + Duration::from_secs(3600),
+            interval
+        ));
+    }
+
+    /// Starts a server that redirects `/loop` to itself, `/hops/n` to `/hops/n-1` and
+    /// `/elsewhere` to the same server using `localhost` instead of its IP, and returns its URL
+    fn redirecting_server() -> String {
+        use axum::{
+            extract::Path,
+            response::{IntoResponse, Redirect},
+            routing::get,
+            Router,
+        };
+        use std::net::{Ipv4Addr, TcpListener};
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let other_origin = url.replace("127.0.0.1", "localhost");
+
+        let app = Router::new()
+            .route("/loop", get(|| async { Redirect::temporary("/loop") }))
+            .route(
+                "/hops/:n",
+                get(|Path(n): Path<u32>| async move {
+                    if n == 0 {
+                        "done".into_response()
+                    } else {
+                        Redirect::temporary(&format!("/hops/{}", n - 1)).into_response()
+                    }
+                }),
+            )
+            .route(
+                "/elsewhere",
+                get(move || async move { Redirect::temporary(&format!("{other_origin}/hops/0")) }),
+            );
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        url
+    }
+
+    #[tokio::test]
+    async fn federation_requests_use_bind_address() {
+        use axum::{extract::ConnectInfo, routing::get, Router};
+        use std::net::{SocketAddr, TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.ip().to_string() }),
+        );
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>()),
+        );
+
+        let mut config = config(None);
+        config.federation_bind_address = Some("127.0.0.1".parse().unwrap());
+        let client = federation_client_builder(&config).unwrap().build().unwrap();
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "127.0.0.1");
+
+        // An address that doesn't belong to this host can't be bound
+        config.federation_bind_address = Some("192.0.2.1".parse().unwrap());
+        let client = federation_client_builder(&config).unwrap().build().unwrap();
+        assert!(client.get(&url).send().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn redirects_are_limited() {
+        let url = redirecting_server();
+        let client = reqwest::Client::builder()
+            .redirect(redirect_policy(3, false))
+            .build()
+            .unwrap();
+
+        let response = client.get(format!("{url}/hops/3")).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "done");
+
+        let error = client
+            .get(format!("{url}/hops/4"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(error.is_redirect());
+
+        let error = client.get(format!("{url}/loop")).send().await.unwrap_err();
+        assert!(error.is_redirect());
+
+        let response = client.get(format!("{url}/elsewhere")).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "done");
+
+        // Like for .well-known lookups
+        let client = reqwest::Client::builder()
+            .redirect(redirect_policy(3, true))
+            .build()
+            .unwrap();
+
+        let response = client.get(format!("{url}/hops/2")).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "done");
+
+        let error = client
+            .get(format!("{url}/elsewhere"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(error.is_redirect());
+    }
+
+    #[test]
+    fn count_blocks_are_monotonic_across_restarts() {
+        let counter = std::cell::Cell::new(7_u64);
+        let reserve = |amount: u64| -> crate::Result<u64> {
+            counter.set(counter.get() + amount);
+            Ok(counter.get())
+        };
+
+        let allocator = CountAllocator::new(4);
+        assert_eq!(allocator.current(), None);
+
+        let counts: Vec<_> = (0..6).map(|_| allocator.next(reserve).unwrap()).collect();
+        assert_eq!(counts, [8, 9, 10, 11, 12, 13]);
+        assert_eq!(allocator.current(), Some(13));
+        // Two blocks were reserved, 14 and 15 are still unused
+        assert_eq!(counter.get(), 15);
+
+        // After a restart the unused rest of the last block is skipped
+        let restarted = CountAllocator::new(4);
+        let after: Vec<_> = (0..3).map(|_| restarted.next(reserve).unwrap()).collect();
+        assert_eq!(after, [16, 17, 18]);
+        assert!(after[0] > *counts.last().unwrap());
+        assert_eq!(counter.get(), 19);
+    }
+
+    #[test]
+    fn sync_connections_are_limited() {
+        let alice = ruma::user_id!("@alice:example.com");
+        let bob = ruma::user_id!("@bob:example.com");
+        let counter = ConnectionCounter::default();
+
+        let mut syncs: Vec<_> = (0..3)
+            .map(|_| counter.acquire(alice, Some(3)).unwrap())
+            .collect();
+        assert!(counter.acquire(alice, Some(3)).is_none());
+        // Other users have their own limit
+        assert!(counter.acquire(bob, Some(3)).is_some());
+
+        // A finished sync makes room for a new one
+        syncs.pop();
+        syncs.push(counter.acquire(alice, Some(3)).unwrap());
+        assert!(counter.acquire(alice, Some(3)).is_none());
+
+        assert!(counter.acquire(alice, None).is_some());
+    }
+
+    #[tokio::test]
+    async fn requests_send_the_user_agent() {
+        use axum::{http::HeaderMap, routing::get, Router};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let app = Router::new().route(
+            "/",
+            get(|headers: HeaderMap| async move {
+                headers[reqwest::header::USER_AGENT]
+                    .to_str()
+                    .unwrap()
+                    .to_owned()
+            }),
+        );
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        async fn user_agent(config: &Config, url: &str) -> String {
+            reqwest_client_builder(config)
+                .unwrap()
+                .build()
+                .unwrap()
+                .get(url)
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap()
+        }
+
+        let mut config = config(None);
+        assert_eq!(
+            user_agent(&config, &url).await,
+            format!("Conduit/{}", env!("CARGO_PKG_VERSION"))
+        );
+
+        config.user_agent = "Example/1.0".to_owned();
+        assert_eq!(user_agent(&config, &url).await, "Example/1.0");
+    }
+}
@@ -1,6 +1,16 @@
 use crate::Result;
 
 pub trait Data: Send + Sync {
+    /// Returns the key `create_file_metadata` stores the metadata under, without storing it.
+    fn file_metadata_key(
+        &self,
+        mxc: &str,
+        width: u32,
+        height: u32,
+        content_disposition: Option<&str>,
+        content_type: Option<&str>,
+    ) -> Vec<u8>;
+
     fn create_file_metadata(
         &self,
         mxc: String,
@@ -10,6 +20,15 @@ pub trait Data: Send + Sync {
         content_type: Option<&str>,
     ) -> Result<Vec<u8>>;
 
+    /// Removes the metadata of a file, for thumbnails also the creation time.
+    fn remove_file_metadata(&self, key: &[u8]) -> Result<()>;
+
+    /// Remembers when a thumbnail was created, so it can be evicted later.
+    fn set_thumbnail_created(&self, key: &[u8], created: u64) -> Result<()>;
+
+    /// Returns the metadata key and creation time of all thumbnails.
+    fn thumbnails<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(Vec<u8>, u64)>> + 'a>;
+
     /// Returns content_disposition, content_type and the metadata key.
     fn search_file_metadata(
         &self,
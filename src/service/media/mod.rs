@@ -1,15 +1,26 @@
 mod data;
-use std::io::Cursor;
+use std::{
+    fmt,
+    io::{self, Cursor},
+    path::Path,
+    time::Duration,
+};
 
 pub use data::Data;
 
-use crate::{services, Result};
+use crate::{services, utils, Error, Result};
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
 use image::imageops::FilterType;
+use ring::digest;
+use ruma::api::client::error::ErrorKind;
 
 use tokio::{
-    fs::File,
+    fs::{self, File},
     io::{AsyncReadExt, AsyncWriteExt, BufReader},
 };
+use tracing::{debug, error, warn};
 
 pub struct FileMeta {
     pub content_disposition: Option<String>,
@@ -36,8 +47,93 @@ impl Service {
             .create_file_metadata(mxc, 0, 0, content_disposition, content_type)?;
 
         let path = services().globals.get_media_file(&key);
-        let mut f = File::create(path).await?;
-        f.write_all(file).await?;
+        write_media_file(&path, file).await?;
+        Ok(())
+    }
+
+    /// Uploads a file from a stream of chunks.
+    ///
+    /// Every chunk is written to disk as soon as it arrives, so the file is never buffered in
+    /// memory as a whole. The chunks go to a temporary file, which is only moved into place and
+    /// made visible through its metadata once it is complete. Nothing is stored if the stream
+    /// fails or exceeds `max_size` bytes.
+    pub async fn create_from_stream<S, E>(
+        &self,
+        mxc: String,
+        content_disposition: Option<&str>,
+        content_type: Option<&str>,
+        mut stream: S,
+        max_size: u64,
+    ) -> Result<()>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: fmt::Display,
+    {
+        // Media keys are URL-safe base64, so they can't start with a dot
+        let temp_path = services()
+            .globals
+            .get_media_folder()
+            .join(format!(".upload-{}", utils::random_string(16)));
+
+        let result = async {
+            let mut f = File::create(&temp_path)
+                .await
+                .map_err(|e| media_storage_error(&temp_path, e))?;
+            let mut hasher = digest::Context::new(&digest::SHA256);
+            let mut size = 0_u64;
+
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|e| {
+                    warn!("Failed to read upload body: {}", e);
+                    Error::BadRequest(ErrorKind::Unknown, "Failed to read request body.")
+                })?;
+
+                size += chunk.len() as u64;
+                if size > max_size {
+                    return Err(Error::BadRequest(ErrorKind::TooLarge, "File is too large."));
+                }
+
+                hasher.update(&chunk);
+                f.write_all(&chunk)
+                    .await
+                    .map_err(|e| media_storage_error(&temp_path, e))?;
+            }
+
+            f.flush()
+                .await
+                .map_err(|e| media_storage_error(&temp_path, e))?;
+
+            Ok::<_, Error>((size, hasher.finish()))
+        }
+        .await;
+
+        let (size, hash) = match result {
+            Ok(uploaded) => uploaded,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path).await;
+                return Err(e);
+            }
+        };
+
+        // Width, Height = 0 if it's not a thumbnail
+        let key = self
+            .db
+            .file_metadata_key(&mxc, 0, 0, content_disposition, content_type);
+        let path = services().globals.get_media_file(&key);
+        if let Err(e) = fs::rename(&temp_path, &path).await {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(media_storage_error(&path, e));
+        }
+
+        self.db
+            .create_file_metadata(mxc.clone(), 0, 0, content_disposition, content_type)?;
+
+        debug!(
+            %mxc,
+            size,
+            sha256 = %general_purpose::STANDARD_NO_PAD.encode(hash),
+            "Stored uploaded file"
+        );
         Ok(())
     }
 
@@ -57,8 +153,7 @@ impl Service {
                 .create_file_metadata(mxc, width, height, content_disposition, content_type)?;
 
         let path = services().globals.get_media_file(&key);
-        let mut f = File::create(path).await?;
-        f.write_all(file).await?;
+        write_media_file(&path, file).await?;
 
         Ok(())
     }
@@ -138,7 +233,7 @@ impl Service {
             let mut file = Vec::new();
             File::open(path).await?.read_to_end(&mut file).await?;
 
-            if let Ok(image) = image::load_from_memory(&file) {
+            if let Some(image) = decode_image(&file, services().globals.media_max_decode_pixels()) {
                 let original_width = image.width();
                 let original_height = image.height();
                 if width > original_width || height > original_height {
@@ -205,8 +300,9 @@ impl Service {
                 )?;
 
                 let path = services().globals.get_media_file(&thumbnail_key);
-                let mut f = File::create(path).await?;
-                f.write_all(&thumbnail_bytes).await?;
+                write_media_file(&path, &thumbnail_bytes).await?;
+                self.db
+                    .set_thumbnail_created(&thumbnail_key, utils::millis_since_unix_epoch())?;
 
                 Ok(Some(FileMeta {
                     content_disposition,
@@ -225,4 +321,251 @@ impl Service {
             Ok(None)
         }
     }
+
+    /// Deletes thumbnails that were created longer than `age` ago and returns how many there
+    /// were. They are generated again when requested, original files are kept.
+    pub async fn evict_thumbnails_older_than(&self, age: Duration) -> Result<usize> {
+        let keys = self.remove_thumbnail_metadata(utils::millis_since_unix_epoch(), age)?;
+
+        for key in &keys {
+            let path = services().globals.get_media_file(key);
+            if let Err(e) = fs::remove_file(&path).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to delete thumbnail {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        Ok(keys.len())
+    }
+
+    /// Removes the metadata of thumbnails older than `age` and returns their keys
+    fn remove_thumbnail_metadata(&self, now: u64, age: Duration) -> Result<Vec<Vec<u8>>> {
+        let cutoff = now.saturating_sub(age.as_millis() as u64);
+
+        let keys: Vec<_> = self
+            .db
+            .thumbnails()
+            .filter_map(|r| r.ok())
+            .filter(|(_, created)| *created < cutoff)
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in &keys {
+            self.db.remove_file_metadata(key)?;
+        }
+
+        Ok(keys)
+    }
+}
+
+/// Decodes an image for thumbnailing. Images with more than `max_pixels` pixels are not decoded,
+/// so a small file that claims to be huge can't make the decoder allocate lots of memory.
+fn decode_image(file: &[u8], max_pixels: u64) -> Option<image::DynamicImage> {
+    let reader = || {
+        image::io::Reader::new(Cursor::new(file))
+            .with_guessed_format()
+            .ok()
+    };
+
+    let (width, height) = reader()?.into_dimensions().ok()?;
+    if u64::from(width) * u64::from(height) > max_pixels {
+        debug!("Not creating thumbnail for {width}x{height} image");
+        return None;
+    }
+
+    let mut reader = reader()?;
+    let mut limits = image::io::Limits::default();
+    // Enough for 4 channels of 32 bits
+    limits.max_alloc = Some(max_pixels.saturating_mul(16));
+    reader.limits(limits);
+
+    reader.decode().ok()
+}
+
+async fn write_media_file(path: &Path, content: &[u8]) -> Result<()> {
+    let mut f = File::create(path)
+        .await
+        .map_err(|e| media_storage_error(path, e))?;
+    f.write_all(content)
+        .await
+        .map_err(|e| media_storage_error(path, e))
+}
+
+/// Logs which media file could not be written. The IO error itself is hidden from the client,
+/// which can't do anything about it.
+fn media_storage_error(path: &Path, e: io::Error) -> Error {
+    error!("Failed to write media file {}: {}", path.display(), e);
+    Error::IoError { source: e }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
+    use crate::utils::testing;
+
+    #[test]
+    fn evicting_thumbnails_keeps_originals() {
+        let service = &testing::services().media;
+        let mxc = format!("mxc://example.com/{}", utils::random_string(16));
+        let day = 24 * 60 * 60 * 1000;
+
+        service
+            .db
+            .create_file_metadata(mxc.clone(), 0, 0, None, Some("image/png"))
+            .unwrap();
+        let old = service
+            .db
+            .create_file_metadata(mxc.clone(), 32, 32, None, Some("image/png"))
+            .unwrap();
+        service.db.set_thumbnail_created(&old, day).unwrap();
+        let new = service
+            .db
+            .create_file_metadata(mxc.clone(), 96, 96, None, Some("image/png"))
+            .unwrap();
+        service.db.set_thumbnail_created(&new, 10 * day).unwrap();
+
+        let evicted = service
+            .remove_thumbnail_metadata(10 * day, Duration::from_millis(5 * day))
+            .unwrap();
+        assert_eq!(evicted, vec![old]);
+
+        assert!(service.db.search_file_metadata(mxc.clone(), 0, 0).is_ok());
+        assert!(service
+            .db
+            .search_file_metadata(mxc.clone(), 32, 32)
+            .is_err());
+        assert!(service.db.search_file_metadata(mxc, 96, 96).is_ok());
+
+        let thumbnails: Vec<_> = service.db.thumbnails().map(|r| r.unwrap().0).collect();
+        assert!(!thumbnails.contains(&old));
+        assert!(thumbnails.contains(&new));
+    }
+
+    fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        // CRC-32 as used by PNG
+        let mut crc = !0_u32;
+        for byte in kind.iter().chain(data) {
+            crc ^= u32::from(*byte);
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&(!crc).to_be_bytes());
+        chunk
+    }
+
+    #[test]
+    fn huge_images_are_not_decoded() {
+        // A few bytes that claim to be a 60000x60000 RGBA image
+        let mut header = 60_000_u32.to_be_bytes().to_vec();
+        header.extend_from_slice(&60_000_u32.to_be_bytes());
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        let mut huge = b"\x89PNG\r\n\x1a\n".to_vec();
+        huge.extend(png_chunk(b"IHDR", &header));
+        huge.extend(png_chunk(b"IDAT", &[0x78, 0x9c, 0x03, 0x00]));
+        huge.extend(png_chunk(b"IEND", &[]));
+
+        assert_eq!(
+            image::io::Reader::new(Cursor::new(&huge))
+                .with_guessed_format()
+                .unwrap()
+                .into_dimensions()
+                .unwrap(),
+            (60_000, 60_000)
+        );
+        assert!(decode_image(&huge, 8192 * 8192).is_none());
+
+        let mut small = Vec::new();
+        image::DynamicImage::new_rgb8(2, 2)
+            .write_to(&mut Cursor::new(&mut small), image::ImageOutputFormat::Png)
+            .unwrap();
+        assert!(decode_image(&small, 4).is_some());
+        assert!(decode_image(&small, 3).is_none());
+    }
+
+    fn upload(
+        chunks: Vec<Result<&'static [u8], &'static str>>,
+    ) -> impl Stream<Item = Result<Bytes, &'static str>> + Unpin {
+        stream::iter(
+            chunks
+                .into_iter()
+                .map(|chunk| chunk.map(Bytes::from_static)),
+        )
+    }
+
+    #[tokio::test]
+    async fn uploads_are_only_stored_once_complete() {
+        let services = testing::services();
+        let mxc = || format!("mxc://example.com/{}", utils::random_string(16));
+
+        let complete = mxc();
+        services
+            .media
+            .create_from_stream(
+                complete.clone(),
+                None,
+                Some("text/plain"),
+                upload(vec![Ok(b"hello "), Ok(b"world")]),
+                100,
+            )
+            .await
+            .unwrap();
+        let file = services.media.get(complete).await.unwrap().unwrap();
+        assert_eq!(file.file, b"hello world");
+        assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+
+        // Failed uploads leave neither metadata nor files behind
+        let too_large = mxc();
+        assert!(services
+            .media
+            .create_from_stream(
+                too_large.clone(),
+                None,
+                None,
+                upload(vec![Ok(b"hello "), Ok(b"world")]),
+                8,
+            )
+            .await
+            .is_err());
+        assert!(services.media.get(too_large).await.unwrap().is_none());
+
+        let broken = mxc();
+        assert!(services
+            .media
+            .create_from_stream(
+                broken.clone(),
+                None,
+                None,
+                upload(vec![Ok(b"hello"), Err("connection reset")]),
+                100,
+            )
+            .await
+            .is_err());
+        assert!(services.media.get(broken).await.unwrap().is_none());
+
+        let temporary_files = std::fs::read_dir(services.globals.get_media_folder())
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(".upload-")
+            })
+            .count();
+        assert_eq!(temporary_files, 0);
+    }
 }
@@ -1,6 +1,7 @@
 use std::{
     collections::{BTreeMap, HashMap},
-    sync::{Arc, Mutex},
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::Duration,
 };
 
 use lru_cache::LruCache;
@@ -15,8 +16,11 @@ pub mod key_backups;
 pub mod media;
 pub mod pdu;
 pub mod pusher;
+pub mod reports;
 pub mod rooms;
 pub mod sending;
+pub mod sso;
+pub mod threepid;
 pub mod transaction_ids;
 pub mod uiaa;
 pub mod users;
@@ -24,6 +28,7 @@ pub mod users;
 pub struct Services {
     pub appservice: appservice::Service,
     pub pusher: pusher::Service,
+    pub reports: reports::Service,
     pub rooms: rooms::Service,
     pub transaction_ids: transaction_ids::Service,
     pub uiaa: uiaa::Service,
@@ -34,12 +39,15 @@ pub struct Services {
     pub key_backups: key_backups::Service,
     pub media: media::Service,
     pub sending: Arc<sending::Service>,
+    pub sso: sso::Service,
+    pub threepid: threepid::Service,
 }
 
 impl Services {
     pub fn build<
         D: appservice::Data
             + pusher::Data
+            + reports::Data
             + rooms::Data
             + transaction_ids::Data
             + uiaa::Data
@@ -49,22 +57,25 @@ impl Services {
             + key_backups::Data
             + media::Data
             + sending::Data
+            + sso::Data
+            + threepid::Data
             + 'static,
     >(
         db: &'static D,
         config: Config,
     ) -> Result<Self> {
         Ok(Self {
-            appservice: appservice::Service { db },
+            appservice: appservice::Service::build(db)?,
             pusher: pusher::Service { db },
+            reports: reports::Service { db },
             rooms: rooms::Service {
                 alias: rooms::alias::Service { db },
                 auth_chain: rooms::auth_chain::Service { db },
                 directory: rooms::directory::Service { db },
                 edus: rooms::edus::Service {
-                    presence: rooms::edus::presence::Service { db },
+                    presence: rooms::edus::presence::Service::build(db, &config),
                     read_receipt: rooms::edus::read_receipt::Service { db },
-                    typing: rooms::edus::typing::Service { db },
+                    typing: rooms::edus::typing::Service::build(db, &config),
                 },
                 event_handler: rooms::event_handler::Service,
                 lazy_loading: rooms::lazy_loading::Service {
@@ -74,7 +85,11 @@ impl Services {
                 metadata: rooms::metadata::Service { db },
                 outlier: rooms::outlier::Service { db },
                 pdu_metadata: rooms::pdu_metadata::Service { db },
-                search: rooms::search::Service { db },
+                search: rooms::search::Service {
+                    db,
+                    reindexing: AtomicBool::new(false),
+                    stop_reindexing: AtomicBool::new(false),
+                },
                 short: rooms::short::Service { db },
                 state: rooms::state::Service { db },
                 state_accessor: rooms::state_accessor::Service {
@@ -92,6 +107,9 @@ impl Services {
                     stateinfo_cache: Mutex::new(LruCache::new(
                         (100.0 * config.conduit_cache_capacity_modifier) as usize,
                     )),
+                    full_state_cache: Mutex::new(LruCache::new(
+                        config.state_cache_capacity as usize,
+                    )),
                 },
                 timeline: rooms::timeline::Service {
                     db,
@@ -108,12 +126,26 @@ impl Services {
             users: users::Service {
                 db,
                 connections: Mutex::new(BTreeMap::new()),
+                device_last_seen: Mutex::new(HashMap::new()),
+                remote_profile_cache: users::ProfileCache::new(
+                    (1000.0 * config.conduit_cache_capacity_modifier) as usize,
+                    Duration::from_secs(config.remote_profile_cache_ttl_secs),
+                ),
+                profile_last_updated: Mutex::new(HashMap::new()),
+                profile_update_interval: Duration::from_secs(config.profile_update_interval_secs),
             },
             account_data: account_data::Service { db },
             admin: admin::Service::build(),
             key_backups: key_backups::Service { db },
             media: media::Service { db },
             sending: sending::Service::build(db, &config),
+            sso: sso::Service {
+                db,
+                provider: Mutex::new(None),
+                pending_logins: Mutex::new(HashMap::new()),
+                login_tokens: Mutex::new(HashMap::new()),
+            },
+            threepid: threepid::Service::build(db, &config)?,
 
             globals: globals::Service::load(db, config)?,
         })
@@ -147,6 +179,13 @@ impl Services {
             .lock()
             .unwrap()
             .len();
+        let full_state_cache = self
+            .rooms
+            .state_compressor
+            .full_state_cache
+            .lock()
+            .unwrap()
+            .len();
         let lasttimelinecount_cache = self
             .rooms
             .timeline
@@ -161,6 +200,7 @@ impl Services {
             .lock()
             .unwrap()
             .len();
+        let remote_profile_cache = self.users.remote_profile_cache.len();
 
         format!(
             "\
@@ -168,8 +208,10 @@ lazy_load_waiting: {lazy_load_waiting}
 server_visibility_cache: {server_visibility_cache}
 user_visibility_cache: {user_visibility_cache}
 stateinfo_cache: {stateinfo_cache}
+full_state_cache: {full_state_cache}
 lasttimelinecount_cache: {lasttimelinecount_cache}
-roomid_spacechunk_cache: {roomid_spacechunk_cache}\
+roomid_spacechunk_cache: {roomid_spacechunk_cache}
+remote_profile_cache: {remote_profile_cache}\
             "
         )
     }
@@ -205,6 +247,12 @@ roomid_spacechunk_cache: {roomid_spacechunk_cache}\
                 .lock()
                 .unwrap()
                 .clear();
+            self.rooms
+                .state_compressor
+                .full_state_cache
+                .lock()
+                .unwrap()
+                .clear();
         }
         if amount > 4 {
             self.rooms
@@ -222,5 +270,8 @@ roomid_spacechunk_cache: {roomid_spacechunk_cache}\
                 .unwrap()
                 .clear();
         }
+        if amount > 6 {
+            self.users.remote_profile_cache.clear();
+        }
     }
 }
@@ -1,4 +1,4 @@
-use crate::Error;
+use crate::{utils, Error};
 use ruma::{
     events::{
         room::member::RoomMemberEventContent, space::child::HierarchySpaceChildEvent,
@@ -50,6 +50,18 @@ pub struct PduEvent {
 impl PduEvent {
     #[tracing::instrument(skip(self))]
     pub fn redact(&mut self, reason: &PduEvent) -> crate::Result<()> {
+        self.strip_content()?;
+
+        self.unsigned = Some(to_raw_value(&json!({
+            "redacted_because": serde_json::to_value(reason).expect("to_value(PduEvent) always works")
+        })).expect("to string always works"));
+
+        Ok(())
+    }
+
+    /// Removes everything from the content that the redaction algorithm doesn't keep, as well as
+    /// the unsigned data.
+    pub fn strip_content(&mut self) -> crate::Result<()> {
         self.unsigned = None;
 
         let allowed: &[&str] = match self.kind {
@@ -82,10 +94,6 @@ impl PduEvent {
             }
         }
 
-        self.unsigned = Some(to_raw_value(&json!({
-            "redacted_because": serde_json::to_value(reason).expect("to_value(PduEvent) always works")
-        })).expect("to string always works"));
-
         self.content = to_raw_value(&new_content).expect("to string always works");
 
         Ok(())
@@ -409,11 +417,11 @@ pub(crate) fn gen_event_id_canonical_json(
     let event_id = format!(
         "${}",
         // Anything higher than version3 behaves the same
-        ruma::signatures::reference_hash(&value, room_version_id)
-            .expect("ruma can calculate reference hashes")
+        utils::reference_hash(&value, room_version_id)
+            .map_err(|_| Error::BadServerResponse("Invalid PDU in server response"))?
     )
     .try_into()
-    .expect("ruma's reference hashes are valid event ids");
+    .expect("reference hashes are valid event ids");
 
     Ok((event_id, value))
 }
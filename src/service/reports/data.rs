@@ -0,0 +1,14 @@
+use crate::Result;
+
+use super::Report;
+
+pub trait Data: Send + Sync {
+    /// Stores a new report and returns its ID.
+    fn add_report(&self, report: &Report) -> Result<u64>;
+
+    /// Returns all reports that were not resolved yet, oldest first.
+    fn pending_reports<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(u64, Report)>> + 'a>;
+
+    /// Removes a report. Returns false if there was no report with this ID.
+    fn remove_report(&self, report_id: u64) -> Result<bool>;
+}
@@ -0,0 +1,96 @@
+mod data;
+
+pub use data::Data;
+use ruma::{Int, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// An event that a user reported to the server admins.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Report {
+    pub reporter: OwnedUserId,
+    pub room_id: OwnedRoomId,
+    pub event_id: OwnedEventId,
+    pub sender: OwnedUserId,
+    pub score: Option<Int>,
+    pub reason: Option<String>,
+    pub received_ts: MilliSecondsSinceUnixEpoch,
+}
+
+pub struct Service {
+    pub db: &'static dyn Data,
+}
+
+impl Service {
+    /// Stores the report until an admin resolves it and returns its ID.
+    pub fn file(&self, report: &Report) -> Result<u64> {
+        self.db.add_report(report)
+    }
+
+    /// Returns all reports that were not resolved yet, oldest first.
+    pub fn pending(&self) -> Result<Vec<(u64, Report)>> {
+        self.db.pending_reports().collect()
+    }
+
+    /// Removes a report once it was dealt with. Returns false if there was no such report.
+    pub fn resolve(&self, report_id: u64) -> Result<bool> {
+        self.db.remove_report(report_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{event_id, int, uint, user_id, RoomId};
+
+    use super::*;
+    use crate::utils::testing;
+
+    fn report(room_id: &RoomId, reason: &str) -> Report {
+        Report {
+            reporter: user_id!("@alice:example.com").to_owned(),
+            room_id: room_id.to_owned(),
+            event_id: event_id!("$event").to_owned(),
+            sender: user_id!("@mallory:example.com").to_owned(),
+            score: Some(int!(-100)),
+            reason: Some(reason.to_owned()),
+            received_ts: MilliSecondsSinceUnixEpoch(uint!(1_700_000_000_000)),
+        }
+    }
+
+    #[test]
+    fn filed_reports_are_listed_until_resolved() {
+        let services = testing::services();
+        let service = &services.reports;
+        let room_id = &RoomId::new(services.globals.server_name());
+        // Other tests may file reports as well
+        let pending = || -> Vec<_> {
+            service
+                .pending()
+                .unwrap()
+                .into_iter()
+                .filter(|(_, report)| report.room_id == *room_id)
+                .collect()
+        };
+        assert!(pending().is_empty());
+
+        let spam = service.file(&report(room_id, "spam")).unwrap();
+        let abuse = service.file(&report(room_id, "abuse")).unwrap();
+
+        assert_eq!(
+            pending(),
+            [
+                (spam, report(room_id, "spam")),
+                (abuse, report(room_id, "abuse"))
+            ]
+        );
+
+        assert!(service.resolve(spam).unwrap());
+        assert!(!service.resolve(spam).unwrap());
+
+        let pending = pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0, abuse);
+        assert_eq!(pending[0].1.reason.as_deref(), Some("abuse"));
+    }
+}
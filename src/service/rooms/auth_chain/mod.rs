@@ -1,14 +1,16 @@
 mod data;
 use std::{
-    collections::{BTreeSet, HashSet},
+    collections::{BTreeSet, HashSet, VecDeque},
+    mem::size_of,
     sync::Arc,
 };
 
 pub use data::Data;
+use lru_cache::LruCache;
 use ruma::{api::client::error::ErrorKind, EventId, RoomId};
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 
-use crate::{services, Error, Result};
+use crate::{services, Error, PduEvent, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -124,38 +126,299 @@ impl Service {
             .filter_map(move |sid| services().rooms.short.get_eventid_from_short(sid).ok()))
     }
 
+    /// Computes the auth chains of the current state of the `rooms` rooms with the most joined
+    /// members, so that the first federation requests after a restart hit the cache.
+    #[tracing::instrument(skip(self))]
+    pub async fn warmup(&self, rooms: usize) -> Result<()> {
+        let mut candidates = Vec::new();
+        for room_id in services().rooms.metadata.iter_ids() {
+            let room_id = room_id?;
+            let joined = services()
+                .rooms
+                .state_cache
+                .room_joined_count(&room_id)?
+                .unwrap_or(0);
+            candidates.push((joined, room_id));
+        }
+        candidates.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+        for (_, room_id) in candidates.into_iter().take(rooms) {
+            let shortstatehash = match services().rooms.state.get_room_shortstatehash(&room_id)? {
+                Some(shortstatehash) => shortstatehash,
+                None => continue,
+            };
+            let state_ids = services()
+                .rooms
+                .state_accessor
+                .state_full_ids(shortstatehash)
+                .await?
+                .into_values()
+                .collect();
+
+            let chain_length = self.get_auth_chain(&room_id, state_ids).await?.count();
+            debug!(%room_id, chain_length, "Warmed up auth chain");
+        }
+
+        info!("Finished warming up the auth chain cache");
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self, event_id))]
     fn get_auth_chain_inner(&self, room_id: &RoomId, event_id: &EventId) -> Result<HashSet<u64>> {
-        let mut todo = vec![Arc::from(event_id)];
-        let mut found = HashSet::new();
-
-        while let Some(event_id) = todo.pop() {
-            match services().rooms.timeline.get_pdu(&event_id) {
-                Ok(Some(pdu)) => {
-                    if pdu.room_id != room_id {
-                        return Err(Error::BadRequest(ErrorKind::Forbidden, "Evil event in db"));
-                    }
-                    for auth_event in &pdu.auth_events {
-                        let sauthevent = services()
-                            .rooms
-                            .short
-                            .get_or_create_shorteventid(auth_event)?;
-
-                        if !found.contains(&sauthevent) {
-                            found.insert(sauthevent);
-                            todo.push(auth_event.clone());
-                        }
-                    }
+        collect_auth_chain(
+            room_id,
+            event_id,
+            services().globals.config.max_auth_chain_depth,
+            |event_id| services().rooms.timeline.get_pdu(event_id),
+        )?
+        .iter()
+        .map(|auth_event| {
+            services()
+                .rooms
+                .short
+                .get_or_create_shorteventid(auth_event)
+        })
+        .collect()
+    }
+}
+
+/// Walks the auth events of `event_id` and returns all events that were found. Every event in the
+/// chain has to belong to `room_id` and be reachable in at most `max_depth` steps.
+fn collect_auth_chain(
+    room_id: &RoomId,
+    event_id: &EventId,
+    max_depth: usize,
+    get_pdu: impl Fn(&EventId) -> Result<Option<Arc<PduEvent>>>,
+) -> Result<HashSet<Arc<EventId>>> {
+    // Breadth-first, so every event is reached on its shortest path
+    let mut todo = VecDeque::from([(Arc::from(event_id), 0)]);
+    let mut found = HashSet::new();
+
+    while let Some((event_id, depth)) = todo.pop_front() {
+        match get_pdu(&event_id) {
+            Ok(Some(pdu)) => {
+                if pdu.room_id != room_id {
+                    return Err(Error::BadRequest(ErrorKind::Forbidden, "Evil event in db"));
                 }
-                Ok(None) => {
-                    warn!(?event_id, "Could not find pdu mentioned in auth events");
+                if !pdu.auth_events.is_empty() && depth >= max_depth {
+                    warn!(?event_id, max_depth, "Auth chain is too deep");
+                    return Err(Error::BadRequest(
+                        ErrorKind::Forbidden,
+                        "Auth chain is too deep.",
+                    ));
                 }
-                Err(error) => {
-                    error!(?event_id, ?error, "Could not load event in auth chain");
+                for auth_event in &pdu.auth_events {
+                    if found.insert(auth_event.clone()) {
+                        todo.push_back((auth_event.clone(), depth + 1));
+                    }
                 }
             }
+            Ok(None) => {
+                warn!(?event_id, "Could not find pdu mentioned in auth events");
+            }
+            Err(error) => {
+                error!(?event_id, ?error, "Could not load event in auth chain");
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// An LRU cache for auth chains that is capped by the approximate number of bytes the cached
+/// chains take up instead of by the number of entries, because chain sizes vary wildly.
+pub struct AuthChainCache {
+    cache: LruCache<Vec<u64>, Arc<HashSet<u64>>>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl AuthChainCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            cache: LruCache::new(usize::MAX),
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn entry_size(key: &[u64], auth_chain: &HashSet<u64>) -> usize {
+        (key.len() + auth_chain.len()) * size_of::<u64>()
+    }
+
+    pub fn get(&mut self, key: &[u64]) -> Option<Arc<HashSet<u64>>> {
+        self.cache.get_mut(key).map(Arc::clone)
+    }
+
+    pub fn insert(&mut self, key: Vec<u64>, auth_chain: Arc<HashSet<u64>>) {
+        let size = Self::entry_size(&key, &auth_chain);
+        if size > self.max_bytes {
+            // Caching this would evict everything else
+            return;
+        }
+
+        let key_len = key.len();
+        if let Some(old) = self.cache.insert(key, auth_chain) {
+            self.bytes -= (key_len + old.len()) * size_of::<u64>();
+        }
+        self.bytes += size;
+
+        while self.bytes > self.max_bytes {
+            match self.cache.remove_lru() {
+                Some((key, auth_chain)) => self.bytes -= Self::entry_size(&key, &auth_chain),
+                None => break,
+            }
         }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Approximate number of bytes used by the cached auth chains.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ruma::{event_id, room_id};
+    use serde_json::json;
+
+    use super::*;
+    use crate::utils::testing;
+
+    fn chain(start: u64, len: u64) -> Arc<HashSet<u64>> {
+        Arc::new((start..start + len).collect())
+    }
+
+    #[test]
+    fn evicts_to_stay_under_byte_cap() {
+        let mut cache = AuthChainCache::new(10_000);
+
+        for i in 0..10 {
+            cache.insert(vec![i], chain(i * 1000, 400));
+            assert!(cache.bytes() <= 10_000);
+        }
+
+        // Each entry takes up (1 + 400) * 8 bytes, so only three fit
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get(&[0]).is_none());
+        assert!(cache.get(&[9]).is_some());
+    }
+
+    #[test]
+    fn replacing_entry_updates_size() {
+        let mut cache = AuthChainCache::new(10_000);
+
+        cache.insert(vec![1], chain(0, 100));
+        cache.insert(vec![1], chain(0, 10));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.bytes(), 11 * size_of::<u64>());
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn skips_chains_larger_than_cap() {
+        let mut cache = AuthChainCache::new(1_000);
+
+        cache.insert(vec![1], chain(0, 10));
+        cache.insert(vec![2], chain(0, 1_000));
+        assert!(cache.get(&[1]).is_some());
+        assert!(cache.get(&[2]).is_none());
+    }
+
+    #[test]
+    fn collects_seeded_auth_chain() {
+        let pdu = |event_id: &str, room_id: &str, auth_events: &[&str]| {
+            let pdu: PduEvent = testing::pdu(json!({
+                "event_id": event_id,
+                "room_id": room_id,
+                "auth_events": auth_events,
+            }));
+            (pdu.event_id.clone(), Arc::new(pdu))
+        };
+        let room = "!room:example.com";
+        let pdus: HashMap<_, _> = [
+            pdu("$create", room, &[]),
+            pdu("$power", room, &["$create", "$member"]),
+            pdu("$member", room, &["$create"]),
+            pdu("$message", room, &["$create", "$power", "$member"]),
+            pdu("$other", "!other:example.com", &[]),
+            pdu("$evil", room, &["$create", "$other"]),
+        ]
+        .into_iter()
+        .collect();
+        let get_pdu = |event_id: &EventId| Ok(pdus.get(event_id).cloned());
+
+        let chain = collect_auth_chain(
+            room_id!("!room:example.com"),
+            event_id!("$message"),
+            10,
+            get_pdu,
+        )
+        .unwrap();
+        let expected: HashSet<Arc<EventId>> = ["$create", "$power", "$member"]
+            .into_iter()
+            .map(|id| Arc::from(<&EventId>::try_from(id).unwrap()))
+            .collect();
+        assert_eq!(chain, expected);
+
+        assert!(collect_auth_chain(
+            room_id!("!room:example.com"),
+            event_id!("$evil"),
+            10,
+            get_pdu
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn deep_auth_chains_are_rejected() {
+        // Every event is authorized by the one before it
+        let pdus: HashMap<_, _> = (0..100)
+            .map(|i| {
+                let auth_events: Vec<_> = (i > 0)
+                    .then(|| format!("$e{}", i - 1))
+                    .into_iter()
+                    .collect();
+                let pdu: PduEvent = testing::pdu(json!({
+                    "event_id": format!("$e{i}"),
+                    "type": "m.room.member",
+                    "state_key": "@alice:example.com",
+                    "content": { "membership": "join" },
+                    "depth": i + 1,
+                    "auth_events": auth_events,
+                }));
+                (pdu.event_id.clone(), Arc::new(pdu))
+            })
+            .collect();
+        let get_pdu = |event_id: &EventId| Ok(pdus.get(event_id).cloned());
+        let room_id = room_id!("!room:example.com");
+
+        let chain = collect_auth_chain(room_id, event_id!("$e99"), 99, get_pdu).unwrap();
+        assert_eq!(chain.len(), 99);
 
-        Ok(found)
+        assert!(matches!(
+            collect_auth_chain(room_id, event_id!("$e99"), 98, get_pdu),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+        // Only the part of the chain that is walked counts
+        assert!(collect_auth_chain(room_id, event_id!("$e50"), 50, get_pdu).is_ok());
     }
 }
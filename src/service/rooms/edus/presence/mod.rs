@@ -4,13 +4,27 @@ use std::collections::HashMap;
 pub use data::Data;
 use ruma::{events::presence::PresenceEvent, OwnedUserId, RoomId, UserId};
 
-use crate::Result;
+use crate::{Config, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
+
+    /// With `allow_presence = false`, updates are dropped and nothing is ever returned.
+    pub allow_presence: bool,
+
+    /// Whether presence updates of local users are sent to other servers.
+    pub federate_presence: bool,
 }
 
 impl Service {
+    pub fn build(db: &'static dyn Data, config: &Config) -> Self {
+        Self {
+            db,
+            allow_presence: config.allow_presence,
+            federate_presence: config.allow_federation && config.federate_presence,
+        }
+    }
+
     /// Adds a presence event which will be saved until a new event replaces it.
     ///
     /// Note: This method takes a RoomId because presence updates are always bound to rooms to
@@ -21,11 +35,19 @@ impl Service {
         room_id: &RoomId,
         presence: PresenceEvent,
     ) -> Result<()> {
+        if !self.allow_presence {
+            return Ok(());
+        }
+
         self.db.update_presence(user_id, room_id, presence)
     }
 
     /// Resets the presence timeout, so the user will stay in their current presence state.
     pub fn ping_presence(&self, user_id: &UserId) -> Result<()> {
+        if !self.allow_presence {
+            return Ok(());
+        }
+
         self.db.ping_presence(user_id)
     }
 
@@ -34,6 +56,10 @@ impl Service {
         user_id: &UserId,
         room_id: &RoomId,
     ) -> Result<Option<PresenceEvent>> {
+        if !self.allow_presence {
+            return Ok(None);
+        }
+
         let last_update = match self.db.last_presence_update(user_id)? {
             Some(last) => last,
             None => return Ok(None),
@@ -117,6 +143,72 @@ impl Service {
         room_id: &RoomId,
         since: u64,
     ) -> Result<HashMap<OwnedUserId, PresenceEvent>> {
+        if !self.allow_presence {
+            return Ok(HashMap::new());
+        }
+
         self.db.presence_since(room_id, since)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+    use ruma::{events::presence::PresenceEventContent, presence::PresenceState};
+
+    fn service(allow_presence: bool) -> Service {
+        let config = testing::config_with(|config| config.allow_presence = allow_presence);
+        Service::build(testing::services().rooms.edus.presence.db, &config)
+    }
+
+    fn update(service: &Service, user_id: &UserId, room_id: &RoomId) {
+        service
+            .update_presence(
+                user_id,
+                room_id,
+                PresenceEvent {
+                    content: PresenceEventContent {
+                        avatar_url: None,
+                        currently_active: None,
+                        displayname: None,
+                        last_active_ago: None,
+                        presence: PresenceState::Online,
+                        status_msg: None,
+                    },
+                    sender: user_id.to_owned(),
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn disabled_presence_is_not_stored() {
+        let service = service(false);
+        let user_id = &testing::create_user();
+        let room_id = &RoomId::new(testing::services().globals.server_name());
+
+        update(&service, user_id, room_id);
+
+        assert!(service.db.last_presence_update(user_id).unwrap().is_none());
+        assert!(service.db.presence_since(room_id, 0).unwrap().is_empty());
+        assert!(service.presence_since(room_id, 0).unwrap().is_empty());
+        assert!(service
+            .get_last_presence_event(user_id, room_id)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn enabled_presence_is_returned() {
+        let service = service(true);
+        let user_id = &testing::create_user();
+        let room_id = &RoomId::new(testing::services().globals.server_name());
+
+        update(&service, user_id, room_id);
+
+        let updates = service.presence_since(room_id, 0).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert!(updates.contains_key(user_id));
+    }
+}
@@ -1,15 +1,29 @@
 mod data;
 
 pub use data::Data;
-use ruma::{events::SyncEphemeralRoomEvent, RoomId, UserId};
+use ruma::{
+    api::federation::transactions::edu::{Edu, TypingContent},
+    events::SyncEphemeralRoomEvent,
+    RoomId, UserId,
+};
 
-use crate::Result;
+use crate::{services, Config, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
+
+    /// Whether typing updates of local users are sent to other servers.
+    pub federate_typing: bool,
 }
 
 impl Service {
+    pub fn build(db: &'static dyn Data, config: &Config) -> Self {
+        Self {
+            db,
+            federate_typing: config.allow_federation && config.federate_typing,
+        }
+    }
+
     /// Sets a user as typing until the timeout timestamp is reached or roomtyping_remove is
     /// called.
     pub fn typing_add(&self, user_id: &UserId, room_id: &RoomId, timeout: u64) -> Result<()> {
@@ -33,6 +47,40 @@ impl Service {
         self.db.last_typing_update(room_id)
     }
 
+    /// Returns the EDU that tells other servers that a local user started or stopped typing, or
+    /// `None` if typing updates are not federated.
+    pub fn federation_edu(&self, user_id: &UserId, room_id: &RoomId, typing: bool) -> Option<Edu> {
+        self.federate_typing.then(|| {
+            Edu::Typing(TypingContent::new(
+                room_id.to_owned(),
+                user_id.to_owned(),
+                typing,
+            ))
+        })
+    }
+
+    /// Sends the typing state of a local user to the other servers in the room.
+    pub fn federate(&self, user_id: &UserId, room_id: &RoomId, typing: bool) -> Result<()> {
+        let Some(edu) = self.federation_edu(user_id, room_id, typing) else {
+            return Ok(());
+        };
+        let serialized = serde_json::to_vec(&edu).expect("json can be serialized");
+
+        for server in services()
+            .rooms
+            .state_cache
+            .room_servers(room_id)
+            .filter_map(|r| r.ok())
+            .filter(|server| &**server != services().globals.server_name())
+        {
+            services()
+                .sending
+                .send_reliable_edu(&server, serialized.clone(), 0)?;
+        }
+
+        Ok(())
+    }
+
     /// Returns a new typing EDU.
     pub fn typings_all(
         &self,
@@ -47,3 +95,54 @@ impl Service {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    fn service(federate_typing: bool) -> Service {
+        let config = testing::config_with(|config| {
+            config.allow_federation = true;
+            config.federate_typing = federate_typing;
+        });
+        Service::build(testing::services().rooms.edus.typing.db, &config)
+    }
+
+    #[test]
+    fn typing_is_not_federated_when_disabled() {
+        let service = service(false);
+        let user_id = &testing::create_user();
+        let room_id = &RoomId::new(testing::services().globals.server_name());
+
+        service.typing_add(user_id, room_id, u64::MAX).unwrap();
+
+        // Local clients still see the typing notification
+        assert!(service
+            .typings_all(room_id)
+            .unwrap()
+            .content
+            .user_ids
+            .contains(&user_id.to_owned()));
+        assert!(service.federation_edu(user_id, room_id, true).is_none());
+        assert!(service.federation_edu(user_id, room_id, false).is_none());
+        // Nothing is queued, so the sending service is never touched
+        service.federate(user_id, room_id, true).unwrap();
+    }
+
+    #[test]
+    fn typing_is_federated_when_enabled() {
+        let service = service(true);
+        let user_id = &testing::create_user();
+        let room_id = &RoomId::new(testing::services().globals.server_name());
+
+        match service.federation_edu(user_id, room_id, true) {
+            Some(Edu::Typing(content)) => {
+                assert_eq!(content.room_id, room_id.to_owned());
+                assert_eq!(content.user_id, user_id.to_owned());
+                assert!(content.typing);
+            }
+            _ => panic!("expected a typing EDU"),
+        }
+    }
+}
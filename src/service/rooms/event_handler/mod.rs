@@ -36,13 +36,73 @@ use ruma::{
 use serde_json::value::RawValue as RawJsonValue;
 use tracing::{debug, error, info, trace, warn};
 
-use crate::{service::*, services, Error, PduEvent, Result};
+use crate::{service::*, services, utils, Error, PduEvent, Result};
 
 use super::state_compressor::CompressedStateEvent;
 
 pub struct Service;
 
 impl Service {
+    /// Rejects events that are larger than `max_event_bytes` and state events that would add a new
+    /// entry to the state of a room that already has `max_state_events_per_room` entries.
+    fn check_pdu_limits(
+        &self,
+        origin: &ServerName,
+        event_id: &EventId,
+        room_id: &RoomId,
+        value: &CanonicalJsonObject,
+    ) -> Result<()> {
+        if let Err(e) = check_event_size(value, services().globals.max_event_bytes()) {
+            warn!("Rejecting event {} from {}: {}", event_id, origin, e);
+            return Err(e);
+        }
+
+        let max_state_events = match services().globals.max_state_events_per_room() {
+            Some(max_state_events) => max_state_events,
+            None => return Ok(()),
+        };
+
+        let (event_type, state_key) = match (value.get("type"), value.get("state_key")) {
+            (
+                Some(CanonicalJsonValue::String(event_type)),
+                Some(CanonicalJsonValue::String(state_key)),
+            ) => (StateEventType::from(event_type.as_str()), state_key),
+            _ => return Ok(()),
+        };
+
+        if services()
+            .rooms
+            .state_accessor
+            .room_state_get_id(room_id, &event_type, state_key)?
+            .is_some()
+        {
+            // Replacing an existing entry does not grow the state
+            return Ok(());
+        }
+
+        let state_events = match services().rooms.state.get_room_shortstatehash(room_id)? {
+            Some(shortstatehash) => services()
+                .rooms
+                .state_compressor
+                .full_state(shortstatehash)?
+                .len(),
+            None => 0,
+        };
+
+        if state_events >= max_state_events {
+            warn!(
+                "Rejecting state event {} from {}: {} already has {} state events",
+                event_id, origin, room_id, state_events
+            );
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Room has too many state events.",
+            ));
+        }
+
+        Ok(())
+    }
+
     /// When receiving an event one needs to:
     /// 0. Check the server is in the room
     /// 1. Skip the PDU if we already know about it
@@ -97,6 +157,8 @@ impl Service {
             return Ok(Some(pdu_id.to_vec()));
         }
 
+        self.check_pdu_limits(origin, event_id, room_id, &value)?;
+
         let create_event = services()
             .rooms
             .state_accessor
@@ -314,7 +376,12 @@ impl Service {
                 }
                 Ok(ruma::signatures::Verified::Signatures) => {
                     // Redact
-                    warn!("Calculated hash does not match: {}", event_id);
+                    warn!(
+                        "Calculated hash {} does not match {:?}: {}",
+                        utils::content_hash(&value),
+                        value.get("hashes"),
+                        event_id
+                    );
                     match ruma::canonical_json::redact(value, room_version_id, None) {
                         Ok(obj) => obj,
                         Err(_) => {
@@ -667,43 +734,16 @@ impl Service {
                         )
                         .await;
 
-                    let mut state: HashMap<_, Arc<EventId>> = HashMap::new();
-                    for (pdu, _) in state_vec {
-                        let state_key = pdu.state_key.clone().ok_or_else(|| {
-                            Error::bad_database("Found non-state pdu in state events.")
-                        })?;
-
-                        let shortstatekey = services().rooms.short.get_or_create_shortstatekey(
-                            &pdu.kind.to_string().into(),
-                            &state_key,
-                        )?;
-
-                        match state.entry(shortstatekey) {
-                            hash_map::Entry::Vacant(v) => {
-                                v.insert(Arc::from(&*pdu.event_id));
-                            }
-                            hash_map::Entry::Occupied(_) => return Err(
-                                Error::bad_database("State event's type and state_key combination exists multiple times."),
-                            ),
-                        }
-                    }
-
-                    // The original create event must still be in the state
-                    let create_shortstatekey = services()
-                        .rooms
-                        .short
-                        .get_shortstatekey(&StateEventType::RoomCreate, "")?
-                        .expect("Room exists");
-
-                    if state.get(&create_shortstatekey).map(|id| id.as_ref())
-                        != Some(&create_event.event_id)
-                    {
-                        return Err(Error::bad_database(
-                            "Incoming event refers to wrong create event.",
-                        ));
-                    }
-
-                    state_at_incoming_event = Some(state);
+                    state_at_incoming_event = Some(state_from_pdus(
+                        state_vec.iter().map(|(pdu, _)| &**pdu),
+                        &create_event.event_id,
+                        |event_type, state_key| {
+                            services()
+                                .rooms
+                                .short
+                                .get_or_create_shortstatekey(event_type, state_key)
+                        },
+                    )?);
                 }
                 Err(e) => {
                     warn!("Fetching state for event failed: {}", e);
@@ -1315,11 +1355,11 @@ impl Service {
 
         let event_id = format!(
             "${}",
-            ruma::signatures::reference_hash(&value, room_version)
-                .expect("ruma can calculate reference hashes")
+            utils::reference_hash(&value, room_version)
+                .map_err(|_| Error::BadServerResponse("Invalid PDU in server response"))?
         );
-        let event_id = <&EventId>::try_from(event_id.as_str())
-            .expect("ruma's reference hashes are valid event ids");
+        let event_id =
+            <&EventId>::try_from(event_id.as_str()).expect("reference hashes are valid event ids");
 
         if let Some((time, tries)) = services()
             .globals
@@ -1381,6 +1421,9 @@ impl Service {
             if !contains_all_ids(&result) {
                 trace!("Signing key not loaded for {}", origin);
                 servers.insert(origin.to_owned(), BTreeMap::new());
+            } else if services().globals.signing_keys_need_refresh(origin) {
+                trace!("Signing keys of {} are due for a refresh", origin);
+                servers.insert(origin.to_owned(), BTreeMap::new());
             }
 
             pub_key_map.insert(origin.to_string(), result);
@@ -1619,7 +1662,7 @@ impl Service {
             .map(|(k, v)| (k.to_string(), v.key))
             .collect();
 
-        if contains_all_ids(&result) {
+        if contains_all_ids(&result) && !services().globals.signing_keys_need_refresh(origin) {
             return Ok(result);
         }
 
@@ -1702,6 +1745,12 @@ impl Service {
 
         drop(permit);
 
+        if contains_all_ids(&result) {
+            // The refresh failed, but the cached keys are still valid
+            debug!("Using cached signing keys for {}", origin);
+            return Ok(result);
+        }
+
         back_off(signature_ids);
 
         warn!("Failed to find public key for server: {}", origin);
@@ -1710,6 +1759,126 @@ impl Service {
         ))
     }
 
+    /// Replaces the current state of a room with the state `origin` reports at one of our forward
+    /// extremities. The state events are checked like any other outlier, nothing is replaced if
+    /// one of them can't be fetched or fails the checks. The fetched state is then resolved
+    /// against the current state, see [`Self::replace_room_state`]. Returns the number of state
+    /// events.
+    #[tracing::instrument(skip(self))]
+    pub async fn resync_room_state(&self, origin: &ServerName, room_id: &RoomId) -> Result<usize> {
+        let create_event = services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomCreate, "")?
+            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Room not found."))?;
+        let room_version_id = services().rooms.state.get_room_version(room_id)?;
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let extremity = services()
+            .rooms
+            .state
+            .get_forward_extremities(room_id)?
+            .into_iter()
+            .find_map(|event_id| services().rooms.timeline.get_pdu(&event_id).ok().flatten())
+            .ok_or_else(|| Error::bad_database("Room has no forward extremities."))?;
+
+        let res = services()
+            .sending
+            .send_federation_request(
+                origin,
+                get_room_state_ids::v1::Request {
+                    room_id: room_id.to_owned(),
+                    event_id: (*extremity.event_id).to_owned(),
+                },
+            )
+            .await?;
+
+        let pub_key_map = RwLock::new(BTreeMap::new());
+        let state_vec = self
+            .fetch_and_handle_outliers(
+                origin,
+                &res.pdu_ids
+                    .iter()
+                    .map(|x| Arc::from(&**x))
+                    .collect::<Vec<_>>(),
+                &create_event,
+                room_id,
+                &room_version_id,
+                &pub_key_map,
+            )
+            .await;
+
+        if state_vec.len() != res.pdu_ids.len() {
+            return Err(Error::BadServerResponse(
+                "Not all state events could be fetched and validated.",
+            ));
+        }
+
+        // /state_ids returns the state before the event
+        let mut pdus: Vec<_> = state_vec.iter().map(|(pdu, _)| &**pdu).collect();
+        if extremity.state_key.is_some() {
+            pdus.retain(|pdu| {
+                (&pdu.kind, &pdu.state_key) != (&extremity.kind, &extremity.state_key)
+            });
+            pdus.push(&extremity);
+        }
+
+        let state = state_from_pdus(pdus, &create_event.event_id, |event_type, state_key| {
+            services()
+                .rooms
+                .short
+                .get_or_create_shortstatekey(event_type, state_key)
+        })?;
+
+        let state_count = self
+            .replace_room_state(room_id, &room_version_id, state, &state_lock)
+            .await?;
+
+        info!("Resynced the state of {} from {}", room_id, origin);
+
+        Ok(state_count)
+    }
+
+    /// Resolves `incoming_state` against the current state of the room and makes the result the
+    /// current state. State resolution runs the auth checks, so incoming events that aren't
+    /// allowed don't make it into the room, and the incoming state can't roll back newer local
+    /// state. Returns the number of state events.
+    async fn replace_room_state(
+        &self,
+        room_id: &RoomId,
+        room_version_id: &RoomVersionId,
+        incoming_state: HashMap<u64, Arc<EventId>>,
+        state_lock: &tokio::sync::MutexGuard<'_, ()>,
+    ) -> Result<usize> {
+        let new_room_state = self
+            .resolve_state(room_id, room_version_id, incoming_state)
+            .await?;
+        let state_count = new_room_state.len();
+
+        let (sstatehash, new, removed) = services()
+            .rooms
+            .state_compressor
+            .save_state(room_id, new_room_state)?;
+
+        services()
+            .rooms
+            .state
+            .force_state(room_id, sstatehash, new, removed, state_lock)
+            .await?;
+
+        Ok(state_count)
+    }
+
     fn check_room_id(&self, room_id: &RoomId, pdu: &PduEvent) -> Result<()> {
         if pdu.room_id != room_id {
             warn!("Found event from room {} in room {}", pdu.room_id, room_id);
@@ -1721,3 +1890,254 @@ impl Service {
         Ok(())
     }
 }
+
+/// Maps state events to their shortstatekeys. Every type and state key combination may only appear
+/// once and the create event of the room has to be part of the state.
+fn state_from_pdus<'a>(
+    pdus: impl IntoIterator<Item = &'a PduEvent>,
+    create_event_id: &EventId,
+    mut get_shortstatekey: impl FnMut(&StateEventType, &str) -> Result<u64>,
+) -> Result<HashMap<u64, Arc<EventId>>> {
+    let mut state = HashMap::new();
+    for pdu in pdus {
+        let state_key = pdu
+            .state_key
+            .as_deref()
+            .ok_or_else(|| Error::bad_database("Found non-state pdu in state events."))?;
+
+        let shortstatekey = get_shortstatekey(&pdu.kind.to_string().into(), state_key)?;
+
+        match state.entry(shortstatekey) {
+            hash_map::Entry::Vacant(v) => {
+                v.insert(Arc::clone(&pdu.event_id));
+            }
+            hash_map::Entry::Occupied(_) => {
+                return Err(Error::bad_database(
+                    "State event's type and state_key combination exists multiple times.",
+                ))
+            }
+        }
+    }
+
+    // The original create event must still be in the state
+    let create_shortstatekey = get_shortstatekey(&StateEventType::RoomCreate, "")?;
+    if state.get(&create_shortstatekey).map(|id| id.as_ref()) != Some(create_event_id) {
+        return Err(Error::bad_database(
+            "Incoming event refers to wrong create event.",
+        ));
+    }
+
+    Ok(state)
+}
+
+/// Checks that the canonical JSON of an event is at most `max_bytes` long.
+fn check_event_size(value: &CanonicalJsonObject, max_bytes: usize) -> Result<()> {
+    let size = serde_json::to_vec(value)
+        .expect("canonical json can be serialized")
+        .len();
+
+    if size > max_bytes {
+        return Err(Error::BadRequest(
+            ErrorKind::TooLarge,
+            "Event is too large.",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use ruma::{event_id, events::StateEventType, CanonicalJsonObject};
+    use serde_json::json;
+
+    use super::{check_event_size, state_from_pdus};
+    use crate::{utils::testing, PduEvent};
+
+    fn message(body: &str) -> CanonicalJsonObject {
+        serde_json::from_value(json!({
+            "type": "m.room.message",
+            "sender": "@alice:example.com",
+            "content": { "msgtype": "m.text", "body": body },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn normal_event_passes() {
+        assert!(check_event_size(&message("hello"), 65_536).is_ok());
+    }
+
+    #[test]
+    fn oversized_event_is_rejected() {
+        assert!(check_event_size(&message(&"a".repeat(70_000)), 65_536).is_err());
+    }
+
+    fn state_event(event_id: &str, kind: &str, state_key: &str) -> PduEvent {
+        testing::pdu(json!({
+            "event_id": event_id,
+            "type": kind,
+            "state_key": state_key,
+        }))
+    }
+
+    #[test]
+    fn resynced_state_replaces_local_state() {
+        let mut shortstatekeys = HashMap::new();
+        let mut get_shortstatekey =
+            |event_type: &StateEventType, state_key: &str| -> crate::Result<u64> {
+                let next = shortstatekeys.len() as u64;
+                Ok(*shortstatekeys
+                    .entry((event_type.clone(), state_key.to_owned()))
+                    .or_insert(next))
+            };
+
+        let create = state_event("$create", "m.room.create", "");
+        let local = [
+            create.clone(),
+            state_event("$name", "m.room.name", ""),
+            state_event("$alice", "m.room.member", "@alice:example.com"),
+        ];
+        let remote = [
+            create.clone(),
+            state_event("$name2", "m.room.name", ""),
+            state_event("$alice", "m.room.member", "@alice:example.com"),
+            state_event("$bob", "m.room.member", "@bob:example.com"),
+        ];
+
+        let local_state =
+            state_from_pdus(&local, &create.event_id, &mut get_shortstatekey).unwrap();
+        let remote_state =
+            state_from_pdus(&remote, &create.event_id, &mut get_shortstatekey).unwrap();
+
+        let name = get_shortstatekey(&StateEventType::RoomName, "").unwrap();
+        assert_eq!(&*local_state[&name], event_id!("$name"));
+        assert_eq!(&*remote_state[&name], event_id!("$name2"));
+        assert_eq!(remote_state.len(), 4);
+
+        // The state has to belong to the same room and can't contain duplicates
+        assert!(state_from_pdus(&remote[1..], &create.event_id, &mut get_shortstatekey).is_err());
+        let duplicated = [
+            create.clone(),
+            state_event("$name", "m.room.name", ""),
+            state_event("$name2", "m.room.name", ""),
+        ];
+        assert!(state_from_pdus(&duplicated, &create.event_id, &mut get_shortstatekey).is_err());
+    }
+
+    #[tokio::test]
+    async fn resync_resolves_against_current_state() {
+        use ruma::events::TimelineEventType;
+
+        use crate::utils::testing;
+
+        let services = testing::services();
+        let alice = testing::create_user();
+        let bob = testing::create_user();
+        let room_id = testing::create_room(&alice).await;
+        let room_version_id = services.rooms.state.get_room_version(&room_id).unwrap();
+
+        let set_name = |name: &'static str| {
+            testing::send(
+                &room_id,
+                &alice,
+                TimelineEventType::RoomName,
+                json!({ "name": name }),
+                Some(""),
+            )
+        };
+        let current_state = || async {
+            let shortstatehash = services
+                .rooms
+                .state
+                .get_room_shortstatehash(&room_id)
+                .unwrap()
+                .unwrap();
+            services
+                .rooms
+                .state_accessor
+                .state_full_ids(shortstatehash)
+                .await
+                .unwrap()
+        };
+        let state_get = |event_type: StateEventType, state_key: &str| {
+            services
+                .rooms
+                .state_accessor
+                .room_state_get(&room_id, &event_type, state_key)
+                .unwrap()
+                .map(|pdu| pdu.event_id.clone())
+        };
+
+        let name_a = set_name("A").await.unwrap();
+        let state_a = current_state().await;
+        testing::send(
+            &room_id,
+            &bob,
+            TimelineEventType::RoomMember,
+            json!({ "membership": "join" }),
+            Some(bob.as_str()),
+        )
+        .await
+        .unwrap();
+        let name_b = set_name("B").await.unwrap();
+        let state_b = current_state().await;
+
+        let mutex_state = Arc::clone(
+            services
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        // Pretend the room missed bob's join and the second name
+        let compressed = state_a
+            .iter()
+            .map(|(shortstatekey, event_id)| {
+                services
+                    .rooms
+                    .state_compressor
+                    .compress_state_event(*shortstatekey, event_id)
+            })
+            .collect::<crate::Result<_>>()
+            .unwrap();
+        let (sstatehash, new, removed) = services
+            .rooms
+            .state_compressor
+            .save_state(&room_id, Arc::new(compressed))
+            .unwrap();
+        services
+            .rooms
+            .state
+            .force_state(&room_id, sstatehash, new, removed, &state_lock)
+            .await
+            .unwrap();
+        assert_eq!(state_get(StateEventType::RoomName, ""), Some(name_a));
+        assert_eq!(state_get(StateEventType::RoomMember, bob.as_str()), None);
+
+        let state_count = services
+            .rooms
+            .event_handler
+            .replace_room_state(&room_id, &room_version_id, state_b.clone(), &state_lock)
+            .await
+            .unwrap();
+        assert_eq!(state_count, state_b.len());
+        assert_eq!(current_state().await, state_b);
+
+        // Older state doesn't roll the room back
+        services
+            .rooms
+            .event_handler
+            .replace_room_state(&room_id, &room_version_id, state_a, &state_lock)
+            .await
+            .unwrap();
+        assert_eq!(state_get(StateEventType::RoomName, ""), Some(name_b));
+        assert!(state_get(StateEventType::RoomMember, bob.as_str()).is_some());
+    }
+}
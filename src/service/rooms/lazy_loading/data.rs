@@ -24,4 +24,6 @@ pub trait Data: Send + Sync {
         device_id: &DeviceId,
         room_id: &RoomId,
     ) -> Result<()>;
+
+    fn lazy_load_forget_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()>;
 }
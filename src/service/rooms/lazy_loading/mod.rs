@@ -31,6 +31,36 @@ impl Service {
             .lazy_load_was_sent_before(user_id, device_id, room_id, ll_user)
     }
 
+    /// Returns the senders whose member events have to be sent along with their events, which are
+    /// all of them with `send_redundant`, and otherwise only those this device has not seen yet.
+    #[tracing::instrument(skip(self, senders))]
+    pub fn lazy_load_missing_members<'a>(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+        senders: impl IntoIterator<Item = &'a UserId>,
+        send_redundant: bool,
+    ) -> Result<HashSet<OwnedUserId>> {
+        let mut missing = HashSet::new();
+
+        for sender in senders {
+            if missing.contains(sender) {
+                continue;
+            }
+
+            if send_redundant
+                || !self
+                    .db
+                    .lazy_load_was_sent_before(user_id, device_id, room_id, sender)?
+            {
+                missing.insert(sender.to_owned());
+            }
+        }
+
+        Ok(missing)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn lazy_load_mark_sent(
         &self,
@@ -87,4 +117,100 @@ impl Service {
     ) -> Result<()> {
         self.db.lazy_load_reset(user_id, device_id, room_id)
     }
+
+    /// Forgets which members were sent to a device in any room, e.g. because it was deleted.
+    #[tracing::instrument(skip(self))]
+    pub fn lazy_load_forget_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        self.lazy_load_waiting
+            .lock()
+            .unwrap()
+            .retain(|(user, device, _, _), _| user != user_id || device != device_id);
+
+        self.db.lazy_load_forget_device(user_id, device_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+    use ruma::{device_id, user_id};
+
+    #[test]
+    fn second_sync_omits_sent_members() {
+        let service = &testing::services().rooms.lazy_loading;
+        let user = &testing::create_user();
+        let device = device_id!("PHONE");
+        let room = &RoomId::new(testing::services().globals.server_name());
+        let bob = user_id!("@bob:example.com");
+        let carol = user_id!("@carol:example.com");
+
+        // First sync: nothing was sent before
+        let first = service
+            .lazy_load_missing_members(user, device, room, [bob, bob], false)
+            .unwrap();
+        assert_eq!(first, HashSet::from([bob.to_owned()]));
+        service.lazy_load_mark_sent(user, device, room, first, PduCount::Normal(5));
+
+        // Members only count as sent once the client syncs with the returned token
+        service
+            .lazy_load_confirm_delivery(user, device, room, PduCount::Normal(5))
+            .unwrap();
+
+        let second = service
+            .lazy_load_missing_members(user, device, room, [bob, carol], false)
+            .unwrap();
+        assert_eq!(second, HashSet::from([carol.to_owned()]));
+
+        let redundant = service
+            .lazy_load_missing_members(user, device, room, [bob, carol], true)
+            .unwrap();
+        assert_eq!(redundant.len(), 2);
+
+        // Other devices have their own view
+        let other_device = service
+            .lazy_load_missing_members(user, device_id!("LAPTOP"), room, [bob], false)
+            .unwrap();
+        assert_eq!(other_device.len(), 1);
+    }
+
+    #[test]
+    fn unconfirmed_and_forgotten_members_are_resent() {
+        let service = &testing::services().rooms.lazy_loading;
+        let user = &testing::create_user();
+        let device = device_id!("PHONE");
+        let room = &RoomId::new(testing::services().globals.server_name());
+        let bob = user_id!("@bob:example.com");
+
+        service.lazy_load_mark_sent(
+            user,
+            device,
+            room,
+            HashSet::from([bob.to_owned()]),
+            PduCount::Normal(5),
+        );
+        // The client retried with an older token, so the response was never received
+        service
+            .lazy_load_confirm_delivery(user, device, room, PduCount::Normal(3))
+            .unwrap();
+        assert_eq!(
+            service
+                .lazy_load_missing_members(user, device, room, [bob], false)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        service
+            .lazy_load_confirm_delivery(user, device, room, PduCount::Normal(5))
+            .unwrap();
+        service.lazy_load_forget_device(user, device).unwrap();
+        assert_eq!(
+            service
+                .lazy_load_missing_members(user, device, room, [bob], false)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
 }
@@ -6,4 +6,6 @@ pub trait Data: Send + Sync {
     fn iter_ids<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
     fn is_disabled(&self, room_id: &RoomId) -> Result<bool>;
     fn disable_room(&self, room_id: &RoomId, disabled: bool) -> Result<()>;
+    fn set_replacement_room(&self, room_id: &RoomId, replacement_room: &RoomId) -> Result<()>;
+    fn replacement_room(&self, room_id: &RoomId) -> Result<Option<OwnedRoomId>>;
 }
@@ -27,4 +27,14 @@ impl Service {
     pub fn disable_room(&self, room_id: &RoomId, disabled: bool) -> Result<()> {
         self.db.disable_room(room_id, disabled)
     }
+
+    /// Remembers that the room was upgraded to `replacement_room`.
+    pub fn set_replacement_room(&self, room_id: &RoomId, replacement_room: &RoomId) -> Result<()> {
+        self.db.set_replacement_room(room_id, replacement_room)
+    }
+
+    /// The room this room was upgraded to by a local user, if any.
+    pub fn replacement_room(&self, room_id: &RoomId) -> Result<Option<OwnedRoomId>> {
+        self.db.replacement_room(room_id)
+    }
 }
@@ -1,4 +1,4 @@
-use ruma::{CanonicalJsonObject, EventId};
+use ruma::{CanonicalJsonObject, EventId, OwnedEventId};
 
 use crate::{PduEvent, Result};
 
@@ -6,4 +6,11 @@ pub trait Data: Send + Sync {
     fn get_outlier_pdu_json(&self, event_id: &EventId) -> Result<Option<CanonicalJsonObject>>;
     fn get_outlier_pdu(&self, event_id: &EventId) -> Result<Option<PduEvent>>;
     fn add_pdu_outlier(&self, event_id: &EventId, pdu: &CanonicalJsonObject) -> Result<()>;
+    /// Returns all outliers and when they were stored, if that is known.
+    fn all_outlier_pdus<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedEventId, PduEvent, Option<u64>)>> + 'a>;
+    /// Sets when the outlier was stored, for outliers from before this was recorded.
+    fn set_outlier_inserted(&self, event_id: &EventId, inserted: u64) -> Result<()>;
+    fn remove_pdu_outlier(&self, event_id: &EventId) -> Result<()>;
 }
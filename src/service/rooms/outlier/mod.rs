@@ -1,9 +1,10 @@
 mod data;
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
 pub use data::Data;
 use ruma::{CanonicalJsonObject, EventId};
 
-use crate::{PduEvent, Result};
+use crate::{services, utils, PduEvent, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -25,4 +26,129 @@ impl Service {
     pub fn add_pdu_outlier(&self, event_id: &EventId, pdu: &CanonicalJsonObject) -> Result<()> {
         self.db.add_pdu_outlier(event_id, pdu)
     }
+
+    /// Removes outliers that were stored more than `max_age` ago and never became part of a
+    /// timeline, a room's state or an auth chain. Outliers that are still referenced by other
+    /// outliers are kept, they may be needed once those are resolved.
+    ///
+    /// The age is based on when this server stored the outlier, because the `origin_server_ts`
+    /// is chosen by the sender.
+    #[tracing::instrument(skip(self))]
+    pub fn purge_stale_outliers(&self, max_age: Duration) -> Result<usize> {
+        let cutoff = utils::millis_since_unix_epoch()
+            .saturating_sub(max_age.as_millis().try_into().unwrap_or(u64::MAX));
+
+        self.purge_outliers_before(cutoff, |event_id| {
+            Ok(services().rooms.timeline.get_pdu_id(event_id)?.is_some()
+                || services().rooms.short.get_shorteventid(event_id)?.is_some())
+        })
+    }
+
+    fn purge_outliers_before(
+        &self,
+        cutoff: u64,
+        is_used: impl Fn(&EventId) -> Result<bool>,
+    ) -> Result<usize> {
+        let mut stale = Vec::new();
+        let mut referenced = HashSet::<Arc<EventId>>::new();
+
+        for outlier in self.db.all_outlier_pdus() {
+            let (event_id, pdu, inserted) = outlier?;
+
+            // Outliers from before insertion times were recorded start aging now
+            let inserted = match inserted {
+                Some(inserted) => inserted,
+                None => {
+                    let now = utils::millis_since_unix_epoch();
+                    self.db.set_outlier_inserted(&event_id, now)?;
+                    now
+                }
+            };
+
+            if inserted < cutoff && !is_used(&event_id)? {
+                stale.push(event_id);
+            } else {
+                referenced.extend(pdu.auth_events);
+                referenced.extend(pdu.prev_events);
+            }
+        }
+
+        let mut purged = 0;
+        for event_id in stale {
+            if !referenced.contains(&*event_id) {
+                self.db.remove_pdu_outlier(&event_id)?;
+                purged += 1;
+            }
+        }
+
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+    use ruma::OwnedEventId;
+    use serde_json::json;
+
+    fn outlier(event_id: &EventId, auth_events: &[&OwnedEventId]) -> CanonicalJsonObject {
+        // An ancient origin_server_ts, which must not make the outlier look stale
+        testing::pdu(json!({
+            "event_id": event_id,
+            "origin_server_ts": 1,
+            "content": { "body": "hi", "msgtype": "m.text" },
+            "auth_events": auth_events,
+        }))
+    }
+
+    #[test]
+    fn stale_unused_outliers_are_purged() {
+        let outliers = &testing::services().rooms.outlier;
+        let event_id = |name: &str| -> OwnedEventId {
+            format!("${name}-{}:example.com", utils::random_string(8))
+                .try_into()
+                .unwrap()
+        };
+        let stale = event_id("stale");
+        let referenced = event_id("referenced");
+        let in_state = event_id("in_state");
+        let recent = event_id("recent");
+        // Outliers of other tests count as used, so they are kept
+        let is_used = |event_id: &EventId| -> Result<bool> {
+            Ok(![&stale, &referenced, &in_state, &recent]
+                .iter()
+                .any(|id| id.as_str() == event_id.as_str())
+                || event_id.as_str() == in_state.as_str())
+        };
+
+        for id in [&stale, &referenced, &in_state] {
+            outliers.add_pdu_outlier(id, &outlier(id, &[])).unwrap();
+        }
+
+        // The outliers were just stored, so they are not stale yet
+        assert_eq!(
+            outliers
+                .purge_outliers_before(utils::millis_since_unix_epoch() - 60_000, is_used)
+                .unwrap(),
+            0
+        );
+
+        let cutoff = utils::millis_since_unix_epoch() + 1;
+        outliers
+            .add_pdu_outlier(&recent, &outlier(&recent, &[&referenced]))
+            .unwrap();
+        outliers
+            .db
+            .set_outlier_inserted(&recent, cutoff + 60_000)
+            .unwrap();
+
+        let purged = outliers.purge_outliers_before(cutoff, is_used).unwrap();
+        assert_eq!(purged, 1);
+
+        assert!(outliers.get_pdu_outlier(&stale).unwrap().is_none());
+        for id in [&referenced, &in_state, &recent] {
+            assert!(outliers.get_pdu_outlier(id).unwrap().is_some());
+        }
+    }
 }
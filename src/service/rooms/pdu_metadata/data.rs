@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use crate::{service::rooms::timeline::PduCount, PduEvent, Result};
-use ruma::{EventId, RoomId, UserId};
+use ruma::{EventId, OwnedEventId, RoomId, UserId};
 
 pub trait Data: Send + Sync {
     fn add_relation(&self, from: u64, to: u64) -> Result<()>;
@@ -14,6 +14,12 @@ pub trait Data: Send + Sync {
     ) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>>;
     fn mark_as_referenced(&self, room_id: &RoomId, event_ids: &[Arc<EventId>]) -> Result<()>;
     fn is_event_referenced(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool>;
+    /// All events of the room that were marked as referenced.
+    fn referenced_events<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> Box<dyn Iterator<Item = Result<OwnedEventId>> + 'a>;
+    fn unmark_as_referenced(&self, room_id: &RoomId, event_id: &EventId) -> Result<()>;
     fn mark_event_soft_failed(&self, event_id: &EventId) -> Result<()>;
     fn is_event_soft_failed(&self, event_id: &EventId) -> Result<bool>;
 }
@@ -1,11 +1,11 @@
 mod data;
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
 pub use data::Data;
 use ruma::{
     api::client::relations::get_relating_events,
     events::{relation::RelationType, TimelineEventType},
-    EventId, RoomId, UserId,
+    EventId, RoomId, UInt, UserId,
 };
 use serde::Deserialize;
 
@@ -177,6 +177,57 @@ impl Service {
         self.db.is_event_referenced(room_id, event_id)
     }
 
+    /// Forgets that events more than `keep_depth` below the newest forward extremity were
+    /// referenced, so `referencedevents` does not grow forever.
+    ///
+    /// References are only checked for forward extremities and events that far back are not
+    /// expected to become one again. Current forward extremities and events we don't have are
+    /// always kept.
+    #[tracing::instrument(skip(self))]
+    pub fn compact_referenced_events(&self, room_id: &RoomId, keep_depth: u64) -> Result<usize> {
+        let extremities = services().rooms.state.get_forward_extremities(room_id)?;
+
+        self.compact_references(room_id, &extremities, keep_depth, |event_id| {
+            Ok(services()
+                .rooms
+                .timeline
+                .get_pdu(event_id)?
+                .map(|pdu| pdu.depth))
+        })
+    }
+
+    fn compact_references(
+        &self,
+        room_id: &RoomId,
+        extremities: &HashSet<Arc<EventId>>,
+        keep_depth: u64,
+        depth: impl Fn(&EventId) -> Result<Option<UInt>>,
+    ) -> Result<usize> {
+        let mut max_depth = UInt::MIN;
+        for extremity in extremities {
+            max_depth = max_depth.max(depth(extremity)?.unwrap_or_default());
+        }
+
+        let Some(min_depth) = u64::from(max_depth).checked_sub(keep_depth) else {
+            return Ok(0);
+        };
+
+        let mut compacted = 0;
+        for event_id in self.db.referenced_events(room_id).collect::<Vec<_>>() {
+            let event_id = event_id?;
+            if extremities.contains(&*event_id) {
+                continue;
+            }
+
+            if depth(&event_id)?.is_some_and(|depth| u64::from(depth) < min_depth) {
+                self.db.unmark_as_referenced(room_id, &event_id)?;
+                compacted += 1;
+            }
+        }
+
+        Ok(compacted)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn mark_event_soft_failed(&self, event_id: &EventId) -> Result<()> {
         self.db.mark_event_soft_failed(event_id)
@@ -187,3 +238,63 @@ impl Service {
         self.db.is_event_soft_failed(event_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+    use ruma::event_id;
+
+    #[test]
+    fn compaction_keeps_recent_references_and_extremities() {
+        let services = testing::services();
+        let service = &services.rooms.pdu_metadata;
+        let room_id = &RoomId::new(services.globals.server_name());
+        let other_room = &RoomId::new(services.globals.server_name());
+
+        // $e1 <- $e2 <- ... <- $e100, and $e100 is also referenced by an event we soft failed
+        let event_ids: Vec<Arc<EventId>> = (1..=100)
+            .map(|i| Arc::from(EventId::parse(format!("$e{i}")).unwrap()))
+            .collect();
+        service.mark_as_referenced(room_id, &event_ids).unwrap();
+        service.mark_as_referenced(other_room, &event_ids).unwrap();
+        let unknown: Arc<EventId> = event_id!("$unknown").into();
+        service
+            .mark_as_referenced(room_id, &[Arc::clone(&unknown)])
+            .unwrap();
+
+        // An old event that became a forward extremity again
+        let extremities = HashSet::from([Arc::clone(&event_ids[99]), Arc::clone(&event_ids[4])]);
+
+        let depth = |event_id: &EventId| {
+            Ok(event_id
+                .as_str()
+                .strip_prefix("$e")
+                .map(|i| UInt::from(i.parse::<u32>().unwrap())))
+        };
+
+        let compacted = service
+            .compact_references(room_id, &extremities, 10, depth)
+            .unwrap();
+        assert_eq!(compacted, 88);
+
+        for (i, event_id) in event_ids.iter().enumerate() {
+            let depth = i + 1;
+            assert_eq!(
+                service.is_event_referenced(room_id, event_id).unwrap(),
+                depth >= 90 || depth == 5,
+                "{event_id}"
+            );
+            assert!(service.is_event_referenced(other_room, event_id).unwrap());
+        }
+        assert!(service.is_event_referenced(room_id, &unknown).unwrap());
+
+        // Not enough events yet
+        assert_eq!(
+            service
+                .compact_references(other_room, &extremities, 1000, depth)
+                .unwrap(),
+            0
+        );
+    }
+}
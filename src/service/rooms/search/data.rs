@@ -4,6 +4,11 @@ use ruma::RoomId;
 pub trait Data: Send + Sync {
     fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()>;
 
+    fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()>;
+
+    /// Removes all index entries of a room.
+    fn clear_index(&self, shortroomid: u64) -> Result<()>;
+
     fn search_pdus<'a>(
         &'a self,
         room_id: &RoomId,
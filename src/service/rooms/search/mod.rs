@@ -1,12 +1,18 @@
 mod data;
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
 pub use data::Data;
 
-use crate::Result;
+use crate::{Result, SearchTokenizer};
 use ruma::RoomId;
 
 pub struct Service {
     pub db: &'static dyn Data,
+    /// Whether a `reindex-search` admin command is running
+    pub reindexing: AtomicBool,
+    /// Set to ask a running reindex to stop
+    pub stop_reindexing: AtomicBool,
 }
 
 impl Service {
@@ -15,6 +21,13 @@ impl Service {
         self.db.index_pdu(shortroomid, pdu_id, message_body)
     }
 
+    /// Removes a message body that was indexed with `index_pdu`, even if the `search_tokenizer`
+    /// changed since then.
+    #[tracing::instrument(skip(self))]
+    pub fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
+        self.db.deindex_pdu(shortroomid, pdu_id, message_body)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn search_pdus<'a>(
         &'a self,
@@ -23,4 +36,214 @@ impl Service {
     ) -> Result<Option<(impl Iterator<Item = Vec<u8>> + 'a, Vec<String>)>> {
         self.db.search_pdus(room_id, search_string)
     }
+
+    /// Clears the search index of a room and indexes the given `(pdu_id, message body)` pairs again.
+    ///
+    /// Returns the number of indexed messages, or `None` if the reindex was stopped with
+    /// `stop_reindexing` before it was done. The index of the room is incomplete then.
+    #[tracing::instrument(skip(self, messages))]
+    pub fn reindex_room(
+        &self,
+        shortroomid: u64,
+        messages: impl Iterator<Item = Result<(Vec<u8>, String)>>,
+    ) -> Result<Option<usize>> {
+        self.db.clear_index(shortroomid)?;
+
+        let mut indexed = 0;
+        for message in messages {
+            if self.stop_reindexing.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+
+            let (pdu_id, body) = message?;
+            self.db.index_pdu(shortroomid, &pdu_id, &body)?;
+            indexed += 1;
+        }
+
+        Ok(Some(indexed))
+    }
+}
+
+/// Splits a message body or search term into the lowercase words that are stored in the index.
+///
+/// Indexing and searching have to use the same tokenizer, otherwise words are not found.
+pub fn tokenize(tokenizer: SearchTokenizer, text: &str) -> Vec<String> {
+    let unicode_words = || {
+        text.split_terminator(|c: char| !c.is_alphanumeric())
+            .filter(|s| !s.is_empty())
+            .map(str::to_lowercase)
+    };
+
+    match tokenizer {
+        SearchTokenizer::Whitespace => text.split_whitespace().map(str::to_lowercase).collect(),
+        SearchTokenizer::UnicodeWords => unicode_words().collect(),
+        SearchTokenizer::Ngram => unicode_words().flat_map(|word| ngrams(&word)).collect(),
+    }
+}
+
+/// The overlapping pairs of characters in a word, or the word itself if it is shorter.
+fn ngrams(word: &str) -> Vec<String> {
+    const N: usize = 2;
+
+    let chars: Vec<_> = word.chars().collect();
+    if chars.len() <= N {
+        return vec![word.to_owned()];
+    }
+
+    chars
+        .windows(N)
+        .map(|window| window.iter().collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use ruma::RoomId;
+
+    use super::*;
+    use crate::utils::testing;
+
+    fn pdu_id(shortroomid: u64, count: u64) -> Vec<u8> {
+        let mut pdu_id = shortroomid.to_be_bytes().to_vec();
+        pdu_id.extend_from_slice(&count.to_be_bytes());
+        pdu_id
+    }
+
+    fn messages(shortroomid: u64) -> Vec<Result<(Vec<u8>, String)>> {
+        vec![
+            Ok((pdu_id(shortroomid, 1), "Hello, World!".to_owned())),
+            Ok((pdu_id(shortroomid, 2), "hello there".to_owned())),
+            Ok((pdu_id(shortroomid, 3), "Lunch at noon?".to_owned())),
+        ]
+    }
+
+    fn search(service: &Service, room_id: &RoomId, term: &str) -> BTreeSet<Vec<u8>> {
+        service
+            .search_pdus(room_id, term)
+            .unwrap()
+            .map(|(pdu_ids, _)| pdu_ids.collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn reindexing_restores_search_tokens() {
+        let services = testing::services();
+        // Stopping the reindex must not affect the other tests
+        let service = Service {
+            db: services.rooms.search.db,
+            reindexing: AtomicBool::new(false),
+            stop_reindexing: AtomicBool::new(false),
+        };
+
+        let room_id = &RoomId::new(services.globals.server_name());
+        let other_room = &RoomId::new(services.globals.server_name());
+        let shortroomid = services
+            .rooms
+            .short
+            .get_or_create_shortroomid(room_id)
+            .unwrap();
+        let other_shortroomid = services
+            .rooms
+            .short
+            .get_or_create_shortroomid(other_room)
+            .unwrap();
+
+        for message in messages(shortroomid) {
+            let (pdu_id, body) = message.unwrap();
+            service.index_pdu(shortroomid, &pdu_id, &body).unwrap();
+        }
+        service
+            .index_pdu(
+                other_shortroomid,
+                &pdu_id(other_shortroomid, 9),
+                "hello other room",
+            )
+            .unwrap();
+
+        // Inconsistent entries: a stale token and a missing message
+        service
+            .index_pdu(shortroomid, &pdu_id(shortroomid, 7), "stale")
+            .unwrap();
+        service
+            .deindex_pdu(shortroomid, &pdu_id(shortroomid, 3), "Lunch at noon?")
+            .unwrap();
+        assert!(search(&service, room_id, "noon").is_empty());
+
+        assert_eq!(
+            service
+                .reindex_room(shortroomid, messages(shortroomid).into_iter())
+                .unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            search(&service, room_id, "HELLO"),
+            BTreeSet::from([pdu_id(shortroomid, 1), pdu_id(shortroomid, 2)])
+        );
+        assert_eq!(
+            search(&service, room_id, "lunch noon"),
+            BTreeSet::from([pdu_id(shortroomid, 3)])
+        );
+        assert!(search(&service, room_id, "stale").is_empty());
+        assert_eq!(
+            search(&service, other_room, "hello"),
+            BTreeSet::from([pdu_id(other_shortroomid, 9)])
+        );
+
+        service.stop_reindexing.store(true, Ordering::Relaxed);
+        assert_eq!(
+            service
+                .reindex_room(shortroomid, messages(shortroomid).into_iter())
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn tokenizers() {
+        let text = "Hello, wörld! e-mail";
+        assert_eq!(
+            tokenize(SearchTokenizer::UnicodeWords, text),
+            ["hello", "wörld", "e", "mail"]
+        );
+        assert_eq!(
+            tokenize(SearchTokenizer::Whitespace, text),
+            ["hello,", "wörld!", "e-mail"]
+        );
+        assert_eq!(
+            tokenize(SearchTokenizer::Ngram, text),
+            ["he", "el", "ll", "lo", "wö", "ör", "rl", "ld", "e", "ma", "ai", "il"]
+        );
+    }
+
+    #[test]
+    fn cjk_is_searchable_with_ngrams() {
+        let message = "東京都に住んでいます。";
+        let message_tokens = tokenize(SearchTokenizer::Ngram, message);
+        assert_eq!(
+            message_tokens,
+            [
+                "東京", "京都", "都に", "に住", "住ん", "んで", "でい", "いま", "ます"
+            ]
+        );
+
+        // Every token of a search term that appears in the message is in the index
+        for term in ["東京", "京都", "東京都", "住んでいます"] {
+            let term_tokens = tokenize(SearchTokenizer::Ngram, term);
+            assert!(!term_tokens.is_empty());
+            assert!(
+                term_tokens.iter().all(|t| message_tokens.contains(t)),
+                "{term} should be found"
+            );
+        }
+        assert!(!tokenize(SearchTokenizer::Ngram, "大阪")
+            .iter()
+            .all(|t| message_tokens.contains(t)));
+
+        // Without ngrams the whole sentence is one word
+        let word_tokens = tokenize(SearchTokenizer::UnicodeWords, message);
+        assert_eq!(word_tokens, ["東京都に住んでいます"]);
+        assert!(!word_tokens.contains(&"京都".to_owned()));
+    }
 }
@@ -6,6 +6,8 @@ use ruma::{events::StateEventType, EventId, RoomId};
 pub trait Data: Send + Sync {
     fn get_or_create_shorteventid(&self, event_id: &EventId) -> Result<u64>;
 
+    fn get_shorteventid(&self, event_id: &EventId) -> Result<Option<u64>>;
+
     fn get_shortstatekey(
         &self,
         event_type: &StateEventType,
@@ -15,6 +15,11 @@ impl Service {
         self.db.get_or_create_shorteventid(event_id)
     }
 
+    /// The short ID of an event that is part of a room's state or an auth chain we know of.
+    pub fn get_shorteventid(&self, event_id: &EventId) -> Result<Option<u64>> {
+        self.db.get_shorteventid(event_id)
+    }
+
     pub fn get_shortstatekey(
         &self,
         event_type: &StateEventType,
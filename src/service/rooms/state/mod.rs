@@ -401,10 +401,7 @@ impl Service {
         let full_state = services()
             .rooms
             .state_compressor
-            .load_shortstatehash_info(shortstatehash)?
-            .pop()
-            .expect("there is always one layer")
-            .1;
+            .full_state(shortstatehash)?;
 
         Ok(full_state
             .iter()
@@ -2,22 +2,28 @@ mod data;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 pub use data::Data;
 use lru_cache::LruCache;
 use ruma::{
+    api::client::error::ErrorKind,
     events::{
         room::{
             avatar::RoomAvatarEventContent,
             history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
+            join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
             member::{MembershipState, RoomMemberEventContent},
             name::RoomNameEventContent,
+            power_levels::RoomPowerLevelsEventContent,
         },
-        StateEventType,
+        StateEventType, TimelineEventType,
     },
-    EventId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
+    state_res::RoomVersion,
+    EventId, Int, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
+use serde::Deserialize;
 use tracing::error;
 
 use crate::{services, Error, PduEvent, Result};
@@ -192,22 +198,11 @@ impl Service {
                     })
             })?;
 
-        let visibility = match history_visibility {
-            HistoryVisibility::WorldReadable => true,
-            HistoryVisibility::Shared => currently_member,
-            HistoryVisibility::Invited => {
-                // Allow if any member on requesting server was AT LEAST invited, else deny
-                self.user_was_invited(shortstatehash, &user_id)
-            }
-            HistoryVisibility::Joined => {
-                // Allow if any member on requested server was joined, else deny
-                self.user_was_joined(shortstatehash, &user_id)
-            }
-            _ => {
-                error!("Unknown history visibility {history_visibility}");
-                false
-            }
-        };
+        let membership = self
+            .user_membership(shortstatehash, user_id)
+            .unwrap_or(MembershipState::Leave);
+
+        let visibility = history_visible(&history_visibility, &membership, currently_member);
 
         self.user_visibility_cache
             .lock()
@@ -309,4 +304,579 @@ impl Service {
                     .map_err(|_| Error::bad_database("Invalid room member event in database."))
             })
     }
+
+    /// Returns the join rule of the room, if it has a join rules event.
+    pub fn get_join_rule(&self, room_id: &RoomId) -> Result<Option<JoinRule>> {
+        self.room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+            .map_or(Ok(None), |s| {
+                serde_json::from_str(s.content.get())
+                    .map(|c: RoomJoinRulesEventContent| Some(c.join_rule))
+                    .map_err(|_| Error::bad_database("Invalid room join rules event in database."))
+            })
+    }
+
+    /// How long messages in the room are kept: the `max_lifetime` of its `m.room.retention` event
+    /// or otherwise `default_message_retention_days`. `None` means forever.
+    pub fn message_retention(&self, room_id: &RoomId) -> Result<Option<Duration>> {
+        let room_policy = self
+            .room_state_get(room_id, &"m.room.retention".into(), "")?
+            .and_then(|s| retention_max_lifetime(s.content.get()));
+
+        Ok(room_policy.or_else(|| {
+            services()
+                .globals
+                .config
+                .default_message_retention_days
+                .map(|days| Duration::from_secs(u64::from(days) * 24 * 60 * 60))
+        }))
+    }
+
+    /// Whether users are allowed to knock on the room, based on its version and join rule.
+    pub fn allows_knocking(&self, room_id: &RoomId) -> Result<bool> {
+        let room_version = services().rooms.state.get_room_version(room_id)?;
+        if !RoomVersion::new(&room_version).map_or(false, |v| v.allow_knocking) {
+            return Ok(false);
+        }
+
+        Ok(self
+            .get_join_rule(room_id)?
+            .map_or(false, |join_rule| join_rule_allows_knock(&join_rule)))
+    }
+
+    /// Finds a local user that can authorise the join of `user_id` to a restricted room. Returns
+    /// `None` if the room is not restricted or the user does not need an authorisation because they
+    /// are invited or already joined.
+    pub fn restricted_join_authoriser(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<OwnedUserId>> {
+        let allowed_rooms = match self.restricted_join_allowed_rooms(room_id)? {
+            Some(allowed_rooms) => allowed_rooms,
+            None => return Ok(None),
+        };
+
+        if services().rooms.state_cache.is_joined(user_id, room_id)?
+            || services().rooms.state_cache.is_invited(user_id, room_id)?
+        {
+            return Ok(None);
+        }
+
+        let (local_members, invite_level) = self.local_members_with_levels(room_id)?;
+
+        join_authoriser(
+            &allowed_rooms,
+            |allowed_room| {
+                services()
+                    .rooms
+                    .state_cache
+                    .is_joined(user_id, allowed_room)
+                    .unwrap_or(false)
+            },
+            local_members.into_iter(),
+            invite_level,
+        )
+        .map(Some)
+    }
+
+    /// Checks that the local user `authoriser` may authorise the join of `user_id` to a restricted
+    /// room, before this server signs such a join. The auth rules can't check this because the
+    /// membership in the allowed rooms is only known to the authorising server.
+    pub fn check_join_authoriser(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        authoriser: &UserId,
+    ) -> Result<()> {
+        let allowed_rooms =
+            self.restricted_join_allowed_rooms(room_id)?
+                .ok_or(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Room does not restrict joins.",
+                ))?;
+        let (local_members, invite_level) = self.local_members_with_levels(room_id)?;
+
+        join_authoriser(
+            &allowed_rooms,
+            |allowed_room| {
+                services()
+                    .rooms
+                    .state_cache
+                    .is_joined(user_id, allowed_room)
+                    .unwrap_or(false)
+            },
+            local_members
+                .into_iter()
+                .filter(|(member, _)| member == authoriser),
+            invite_level,
+        )?;
+
+        Ok(())
+    }
+
+    /// The rooms whose members may join the room, or `None` if the room doesn't restrict joins.
+    fn restricted_join_allowed_rooms(&self, room_id: &RoomId) -> Result<Option<Vec<OwnedRoomId>>> {
+        let room_version = services().rooms.state.get_room_version(room_id)?;
+        if !RoomVersion::new(&room_version).map_or(false, |v| v.restricted_join_rules) {
+            return Ok(None);
+        }
+
+        Ok(match self.get_join_rule(room_id)? {
+            Some(JoinRule::Restricted(restricted))
+            | Some(JoinRule::KnockRestricted(restricted)) => Some(
+                restricted
+                    .allow
+                    .into_iter()
+                    .filter_map(|rule| match rule {
+                        AllowRule::RoomMembership(membership) => Some(membership.room_id),
+                        _ => None,
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        })
+    }
+
+    /// The local members of the room with their power levels, and the level needed to invite.
+    fn local_members_with_levels(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<(Vec<(OwnedUserId, Int)>, Int)> {
+        let power_levels = self.power_levels(room_id)?.unwrap_or_default();
+
+        let local_members = services()
+            .rooms
+            .state_cache
+            .room_members(room_id)
+            .filter_map(|r| r.ok())
+            .filter(|member| member.server_name() == services().globals.server_name())
+            .map(|member| {
+                let level = user_power_level(&power_levels, &member);
+                (member, level)
+            })
+            .collect();
+
+        Ok((local_members, power_levels.invite))
+    }
+
+    /// The current power levels of the room, or `None` if it has no power levels event.
+    fn power_levels(&self, room_id: &RoomId) -> Result<Option<RoomPowerLevelsEventContent>> {
+        self.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+            .map(|event| {
+                serde_json::from_str(event.content.get())
+                    .map_err(|_| Error::bad_database("Invalid power levels event in database."))
+            })
+            .transpose()
+    }
+
+    /// Whether the power levels of the room allow the user to send an event of this type. Pass a
+    /// `state_key` for state events. Membership changes are checked by
+    /// [`Self::user_can_set_membership`].
+    ///
+    /// This is checked before building events to give a clear error, the auth rules still apply.
+    pub fn user_can_send(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        event_type: &TimelineEventType,
+        state_key: Option<&str>,
+    ) -> Result<bool> {
+        Ok(self.power_levels(room_id)?.map_or(true, |power_levels| {
+            power_levels_allow_event(&power_levels, user_id, event_type, state_key)
+        }))
+    }
+
+    /// Whether the power levels of the room allow `sender` to change the membership of `target`
+    /// to `membership`.
+    pub fn user_can_set_membership(
+        &self,
+        sender: &UserId,
+        room_id: &RoomId,
+        target: &UserId,
+        membership: &MembershipState,
+    ) -> Result<bool> {
+        Ok(self.power_levels(room_id)?.map_or(true, |power_levels| {
+            power_levels_allow_membership(&power_levels, sender, target, membership)
+        }))
+    }
+}
+
+fn user_power_level(power_levels: &RoomPowerLevelsEventContent, user_id: &UserId) -> Int {
+    power_levels
+        .users
+        .get(user_id)
+        .copied()
+        .unwrap_or(power_levels.users_default)
+}
+
+/// Compares the user's power level with the level required for the event type in `events`, or
+/// `state_default` and `events_default`. State keys that are user IDs belong to that user.
+/// Membership events depend on their content, see [`power_levels_allow_membership`].
+pub(crate) fn power_levels_allow_event(
+    power_levels: &RoomPowerLevelsEventContent,
+    user_id: &UserId,
+    event_type: &TimelineEventType,
+    state_key: Option<&str>,
+) -> bool {
+    if *event_type == TimelineEventType::RoomMember {
+        return true;
+    }
+
+    if state_key
+        .is_some_and(|state_key| state_key.starts_with('@') && state_key != user_id.as_str())
+    {
+        return false;
+    }
+
+    let required = power_levels
+        .events
+        .get(event_type)
+        .copied()
+        .unwrap_or(if state_key.is_some() {
+            power_levels.state_default
+        } else {
+            power_levels.events_default
+        });
+
+    user_power_level(power_levels, user_id) >= required
+}
+
+/// Invites need the `invite` level, kicks and bans need the `kick` or `ban` level and a higher
+/// level than the target. Users can't join others and can always leave themselves.
+fn power_levels_allow_membership(
+    power_levels: &RoomPowerLevelsEventContent,
+    sender: &UserId,
+    target: &UserId,
+    membership: &MembershipState,
+) -> bool {
+    let sender_level = user_power_level(power_levels, sender);
+    let target_level = user_power_level(power_levels, target);
+
+    match membership {
+        MembershipState::Invite => sender_level >= power_levels.invite,
+        MembershipState::Leave if sender == target => true,
+        MembershipState::Leave => sender_level >= power_levels.kick && sender_level > target_level,
+        MembershipState::Ban => sender_level >= power_levels.ban && sender_level > target_level,
+        MembershipState::Join | MembershipState::Knock => sender == target,
+        _ => false,
+    }
+}
+
+/// Picks the user with the highest power level that is allowed to invite to a restricted room, if
+/// the joining user is a member of one of the `allowed_rooms`.
+fn join_authoriser(
+    allowed_rooms: &[OwnedRoomId],
+    is_member: impl Fn(&RoomId) -> bool,
+    local_members: impl Iterator<Item = (OwnedUserId, Int)>,
+    invite_level: Int,
+) -> Result<OwnedUserId> {
+    if !allowed_rooms.iter().any(|room_id| is_member(room_id)) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "User is not a member of any room that allows joining this room.",
+        ));
+    }
+
+    local_members
+        .filter(|(_, level)| *level >= invite_level)
+        .max_by_key(|(_, level)| *level)
+        .map(|(user_id, _)| user_id)
+        .ok_or(Error::BadRequest(
+            ErrorKind::UnableToGrantJoin,
+            "No user on this server can authorise the join.",
+        ))
+}
+
+/// Reads the `max_lifetime` in milliseconds from the content of an `m.room.retention` event.
+fn retention_max_lifetime(content: &str) -> Option<Duration> {
+    #[derive(Deserialize)]
+    struct RetentionEventContent {
+        max_lifetime: Option<u64>,
+    }
+
+    serde_json::from_str::<RetentionEventContent>(content)
+        .ok()?
+        .max_lifetime
+        .map(Duration::from_millis)
+}
+
+fn join_rule_allows_knock(join_rule: &JoinRule) -> bool {
+    matches!(join_rule, JoinRule::Knock | JoinRule::KnockRestricted(_))
+}
+
+/// Whether a user can see an event under the given history visibility, following the algorithm
+/// from the spec: members at the time of the event can always see it, `shared` history is
+/// visible to current members and `invited` history to users that were invited at the time.
+fn history_visible(
+    history_visibility: &HistoryVisibility,
+    membership_at_event: &MembershipState,
+    currently_joined: bool,
+) -> bool {
+    match history_visibility {
+        HistoryVisibility::WorldReadable => true,
+        _ if *membership_at_event == MembershipState::Join => true,
+        HistoryVisibility::Shared => currently_joined,
+        HistoryVisibility::Invited => *membership_at_event == MembershipState::Invite,
+        HistoryVisibility::Joined => false,
+        _ => {
+            error!("Unknown history visibility {history_visibility}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{
+        events::room::join_rules::{JoinRule, Restricted},
+        int, room_id, user_id, OwnedRoomId,
+    };
+
+    use super::{
+        history_visible, join_authoriser, join_rule_allows_knock, power_levels_allow_event,
+        power_levels_allow_membership, retention_max_lifetime, Duration, HistoryVisibility,
+        MembershipState, RoomPowerLevelsEventContent, TimelineEventType,
+    };
+
+    #[test]
+    fn retention_policies() {
+        assert_eq!(
+            retention_max_lifetime(r#"{"max_lifetime": 86400000}"#),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+        assert_eq!(retention_max_lifetime(r#"{"min_lifetime": 1000}"#), None);
+        assert_eq!(retention_max_lifetime(r#"{"max_lifetime": "soon"}"#), None);
+    }
+
+    #[test]
+    fn knock_rooms_allow_knocking() {
+        assert!(join_rule_allows_knock(&JoinRule::Knock));
+        assert!(join_rule_allows_knock(&JoinRule::KnockRestricted(
+            Restricted::new(Vec::new())
+        )));
+    }
+
+    #[test]
+    fn invite_only_rooms_reject_knocking() {
+        assert!(!join_rule_allows_knock(&JoinRule::Invite));
+        assert!(!join_rule_allows_knock(&JoinRule::Public));
+        assert!(!join_rule_allows_knock(&JoinRule::Restricted(
+            Restricted::new(Vec::new())
+        )));
+    }
+
+    #[test]
+    fn restricted_join_allowed_via_membership() {
+        let allowed_rooms: Vec<OwnedRoomId> = vec![room_id!("!space:example.com").to_owned()];
+        let local_members = vec![
+            (user_id!("@mod:example.com").to_owned(), int!(50)),
+            (user_id!("@admin:example.com").to_owned(), int!(100)),
+            (user_id!("@user:example.com").to_owned(), int!(0)),
+        ];
+
+        let authoriser = join_authoriser(
+            &allowed_rooms,
+            |room_id| room_id == room_id!("!space:example.com"),
+            local_members.into_iter(),
+            int!(50),
+        )
+        .unwrap();
+        assert_eq!(authoriser, user_id!("@admin:example.com").to_owned());
+    }
+
+    #[test]
+    fn restricted_join_denied_without_membership() {
+        let allowed_rooms: Vec<OwnedRoomId> = vec![room_id!("!space:example.com").to_owned()];
+        let local_members = vec![(user_id!("@admin:example.com").to_owned(), int!(100))];
+
+        assert!(join_authoriser(
+            &allowed_rooms,
+            |_| false,
+            local_members.into_iter(),
+            int!(50)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn restricted_join_needs_user_that_can_invite() {
+        let allowed_rooms: Vec<OwnedRoomId> = vec![room_id!("!space:example.com").to_owned()];
+        let local_members = vec![(user_id!("@user:example.com").to_owned(), int!(0))];
+
+        assert!(join_authoriser(
+            &allowed_rooms,
+            |_| true,
+            local_members.into_iter(),
+            int!(50)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn history_visibility_settings() {
+        use HistoryVisibility::*;
+        use MembershipState::{Invite, Join, Leave};
+
+        // (visibility, membership at the event, currently joined, visible)
+        let cases = [
+            (WorldReadable, Leave, false, true),
+            (WorldReadable, Join, true, true),
+            (Shared, Join, false, true),
+            (Shared, Leave, true, true),
+            (Shared, Leave, false, false),
+            (Shared, Invite, false, false),
+            (Invited, Invite, false, true),
+            (Invited, Join, false, true),
+            (Invited, Leave, true, false),
+            (Joined, Join, false, true),
+            (Joined, Invite, true, false),
+            (Joined, Leave, true, false),
+        ];
+
+        for (visibility, membership, currently_joined, expected) in cases {
+            assert_eq!(
+                history_visible(&visibility, &membership, currently_joined),
+                expected,
+                "{visibility} with membership {membership} (currently joined: {currently_joined})"
+            );
+        }
+    }
+
+    #[test]
+    fn state_events_need_power() {
+        let alice = user_id!("@alice:example.com");
+        let bob = user_id!("@bob:example.com");
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.users.insert(alice.to_owned(), int!(100));
+        power_levels
+            .events
+            .insert(TimelineEventType::RoomTopic, int!(0));
+
+        // state_default is 50
+        assert!(power_levels_allow_event(
+            &power_levels,
+            alice,
+            &TimelineEventType::RoomName,
+            Some("")
+        ));
+        assert!(!power_levels_allow_event(
+            &power_levels,
+            bob,
+            &TimelineEventType::RoomName,
+            Some("")
+        ));
+        assert!(power_levels_allow_event(
+            &power_levels,
+            bob,
+            &TimelineEventType::RoomTopic,
+            Some("")
+        ));
+        assert!(power_levels_allow_event(
+            &power_levels,
+            bob,
+            &TimelineEventType::RoomMessage,
+            None
+        ));
+
+        // State keys of other users
+        assert!(!power_levels_allow_event(
+            &power_levels,
+            alice,
+            &"org.example.status".into(),
+            Some("@bob:example.com")
+        ));
+    }
+
+    #[test]
+    fn membership_changes_need_power() {
+        let alice = user_id!("@alice:example.com");
+        let bob = user_id!("@bob:example.com");
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.users.insert(alice.to_owned(), int!(100));
+
+        assert!(power_levels_allow_membership(
+            &power_levels,
+            alice,
+            bob,
+            &MembershipState::Ban
+        ));
+        assert!(!power_levels_allow_membership(
+            &power_levels,
+            bob,
+            alice,
+            &MembershipState::Leave
+        ));
+        assert!(power_levels_allow_membership(
+            &power_levels,
+            bob,
+            bob,
+            &MembershipState::Leave
+        ));
+        // invite defaults to 0
+        assert!(power_levels_allow_membership(
+            &power_levels,
+            bob,
+            alice,
+            &MembershipState::Invite
+        ));
+        assert!(!power_levels_allow_membership(
+            &power_levels,
+            alice,
+            bob,
+            &MembershipState::Join
+        ));
+    }
+
+    #[tokio::test]
+    async fn join_authorisations_are_checked() {
+        use crate::utils::testing;
+
+        let services = testing::services();
+        let admin = testing::create_user();
+        let bystander = testing::create_user();
+        let allowed_room = testing::create_room(&admin).await;
+        let restricted_room = testing::create_room(&admin).await;
+        testing::send(
+            &restricted_room,
+            &admin,
+            TimelineEventType::RoomJoinRules,
+            serde_json::json!({
+                "join_rule": "restricted",
+                "allow": [{ "type": "m.room_membership", "room_id": allowed_room }],
+            }),
+            Some(""),
+        )
+        .await
+        .unwrap();
+        let state_accessor = &services.rooms.state_accessor;
+
+        // Nobody from another server is in the allowed room
+        let mallory = user_id!("@mallory:remote.test");
+        assert!(state_accessor
+            .check_join_authoriser(&restricted_room, mallory, &admin)
+            .is_err());
+
+        let member = testing::create_user();
+        testing::send(
+            &allowed_room,
+            &member,
+            TimelineEventType::RoomMember,
+            serde_json::json!({ "membership": "join" }),
+            Some(member.as_str()),
+        )
+        .await
+        .unwrap();
+        assert!(state_accessor
+            .check_join_authoriser(&restricted_room, &member, &admin)
+            .is_ok());
+
+        // The authoriser has to be a member of the restricted room
+        assert!(state_accessor
+            .check_join_authoriser(&restricted_room, &member, &bystander)
+            .is_err());
+        // Rooms that don't restrict joins are never authorised
+        assert!(state_accessor
+            .check_join_authoriser(&allowed_room, &member, &admin)
+            .is_err());
+    }
 }
@@ -16,6 +16,12 @@ pub trait Data: Send + Sync {
         room_id: &RoomId,
         last_state: Option<Vec<Raw<AnyStrippedStateEvent>>>,
     ) -> Result<()>;
+    fn mark_as_knocked(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        last_state: Option<Vec<Raw<AnyStrippedStateEvent>>>,
+    ) -> Result<()>;
     fn mark_as_left(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
 
     fn update_joined_count(&self, room_id: &RoomId) -> Result<()>;
@@ -69,6 +75,8 @@ pub trait Data: Send + Sync {
 
     fn get_invite_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>>;
 
+    fn get_knock_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>>;
+
     fn get_left_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>>;
 
     /// Returns an iterator over all rooms this user joined.
@@ -83,12 +91,24 @@ pub trait Data: Send + Sync {
         user_id: &UserId,
     ) -> Box<dyn Iterator<Item = Result<(OwnedRoomId, Vec<Raw<AnyStrippedStateEvent>>)>> + 'a>;
 
+    /// Returns an iterator over all rooms a user knocked on.
+    fn rooms_knocked<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedRoomId, Vec<Raw<AnyStrippedStateEvent>>)>> + 'a>;
+
     fn invite_state(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
     ) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>>;
 
+    fn knock_state(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>>;
+
     fn left_state(
         &self,
         user_id: &UserId,
@@ -107,5 +127,7 @@ pub trait Data: Send + Sync {
 
     fn is_invited(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool>;
 
+    fn is_knocked(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool>;
+
     fn is_left(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool>;
 }
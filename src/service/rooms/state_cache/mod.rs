@@ -178,6 +178,9 @@ impl Service {
 
                 self.db.mark_as_invited(user_id, room_id, last_state)?;
             }
+            MembershipState::Knock => {
+                self.db.mark_as_knocked(user_id, room_id, last_state)?;
+            }
             MembershipState::Leave | MembershipState::Ban => {
                 self.db.mark_as_left(user_id, room_id)?;
             }
@@ -281,6 +284,11 @@ impl Service {
         self.db.get_invite_count(room_id, user_id)
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn get_knock_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
+        self.db.get_knock_count(room_id, user_id)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn get_left_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
         self.db.get_left_count(room_id, user_id)
@@ -304,6 +312,15 @@ impl Service {
         self.db.rooms_invited(user_id)
     }
 
+    /// Returns an iterator over all rooms a user knocked on.
+    #[tracing::instrument(skip(self))]
+    pub fn rooms_knocked<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<(OwnedRoomId, Vec<Raw<AnyStrippedStateEvent>>)>> + 'a {
+        self.db.rooms_knocked(user_id)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn invite_state(
         &self,
@@ -313,6 +330,15 @@ impl Service {
         self.db.invite_state(user_id, room_id)
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn knock_state(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>> {
+        self.db.knock_state(user_id, room_id)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn left_state(
         &self,
@@ -346,6 +372,11 @@ impl Service {
         self.db.is_invited(user_id, room_id)
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn is_knocked(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
+        self.db.is_knocked(user_id, room_id)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn is_left(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
         self.db.is_left(user_id, room_id)
@@ -27,6 +27,10 @@ pub struct Service {
             )>,
         >,
     >,
+
+    /// The full state of a shortstatehash. These never change, so they don't need to be
+    /// invalidated.
+    pub full_state_cache: Mutex<LruCache<u64, Arc<HashSet<CompressedStateEvent>>>>,
 }
 
 pub type CompressedStateEvent = [u8; 2 * size_of::<u64>()];
@@ -45,46 +49,80 @@ impl Service {
             Arc<HashSet<CompressedStateEvent>>, // removed
         )>,
     > {
-        if let Some(r) = self
-            .stateinfo_cache
-            .lock()
-            .unwrap()
-            .get_mut(&shortstatehash)
-        {
-            return Ok(r.clone());
-        }
+        // Walk up the parents until a cached layer or the root layer is found
+        let mut layers = Vec::new();
+        let mut current = shortstatehash;
+        let mut response = loop {
+            if let Some(r) = self.stateinfo_cache.lock().unwrap().get_mut(&current) {
+                break r.clone();
+            }
 
-        let StateDiff {
-            parent,
-            added,
-            removed,
-        } = self.db.get_statediff(shortstatehash)?;
+            let diff = self.db.get_statediff(current)?;
+            let parent = diff.parent;
+            layers.push((current, diff));
 
-        if let Some(parent) = parent {
-            let mut response = self.load_shortstatehash_info(parent)?;
-            let mut state = (*response.last().unwrap().1).clone();
-            state.extend(added.iter().copied());
-            let removed = (*removed).clone();
-            for r in &removed {
-                state.remove(r);
+            match parent {
+                Some(parent) => current = parent,
+                None => break Vec::new(),
             }
+        };
 
-            response.push((shortstatehash, Arc::new(state), added, Arc::new(removed)));
+        // Then apply the diffs from the oldest layer on, caching every layer on the way
+        while let Some((
+            shortstatehash,
+            StateDiff {
+                parent,
+                added,
+                removed,
+            },
+        )) = layers.pop()
+        {
+            if parent.is_some() {
+                let mut state = (*response.last().unwrap().1).clone();
+                state.extend(added.iter().copied());
+                for r in removed.iter() {
+                    state.remove(r);
+                }
 
-            self.stateinfo_cache
-                .lock()
-                .unwrap()
-                .insert(shortstatehash, response.clone());
+                response.push((shortstatehash, Arc::new(state), added, removed));
+            } else {
+                response = vec![(shortstatehash, added.clone(), added, removed)];
+            }
 
-            Ok(response)
-        } else {
-            let response = vec![(shortstatehash, added.clone(), added, removed)];
             self.stateinfo_cache
                 .lock()
                 .unwrap()
                 .insert(shortstatehash, response.clone());
-            Ok(response)
         }
+
+        Ok(response)
+    }
+
+    /// Returns the full state of the shortstatehash, without building the stack of diffs again if
+    /// it was used recently.
+    #[tracing::instrument(skip(self))]
+    pub fn full_state(&self, shortstatehash: u64) -> Result<Arc<HashSet<CompressedStateEvent>>> {
+        if let Some(full_state) = self
+            .full_state_cache
+            .lock()
+            .unwrap()
+            .get_mut(&shortstatehash)
+        {
+            return Ok(Arc::clone(full_state));
+        }
+
+        let full_state = self
+            .load_shortstatehash_info(shortstatehash)?
+            .pop()
+            .expect("there is always one layer")
+            .1;
+
+        self.full_state_cache
+            .lock()
+            .unwrap()
+            .insert(shortstatehash, Arc::clone(&full_state));
+
+        Ok(full_state)
     }
 
     pub fn compress_state_event(
@@ -252,6 +290,51 @@ impl Service {
         Ok(())
     }
 
+    /// Rewrites the diff layers of the current room state so that every layer is stored as the
+    /// smallest diff to one of the layers below it. The full state of every layer stays exactly the
+    /// same, so states built on top of these layers are not affected.
+    ///
+    /// Returns the number of layers before and after.
+    #[tracing::instrument(skip(self))]
+    pub async fn optimize_room_state(&self, room_id: &RoomId) -> Result<(usize, usize)> {
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let _state_lock = mutex_state.lock().await;
+
+        let shortstatehash = match services().rooms.state.get_room_shortstatehash(room_id)? {
+            Some(shortstatehash) => shortstatehash,
+            None => return Ok((0, 0)),
+        };
+
+        let layers = self.load_shortstatehash_info(shortstatehash)?;
+        let full_states: Vec<_> = layers.iter().map(|layer| Arc::clone(&layer.1)).collect();
+
+        for (layer, (parent, added, removed)) in layers.iter().zip(rebalance_layers(&full_states)) {
+            self.db.save_statediff(
+                layer.0,
+                StateDiff {
+                    parent: parent.map(|i| layers[i].0),
+                    added: Arc::new(added),
+                    removed: Arc::new(removed),
+                },
+            )?;
+        }
+
+        // Cached stacks may still contain the old layers
+        self.stateinfo_cache.lock().unwrap().clear();
+
+        let after = self.load_shortstatehash_info(shortstatehash)?.len();
+
+        Ok((layers.len(), after))
+    }
+
     /// Returns the new shortstatehash, and the state diff from the previous room state
     pub fn save_state(
         &self,
@@ -318,3 +401,247 @@ impl Service {
         Ok((new_shortstatehash, statediffnew, statediffremoved))
     }
 }
+
+/// Picks a parent for every layer of a state stack (ordered from the bottom layer to the top) so
+/// that the layer is stored as the smallest possible diff to a layer below it, or as full state if
+/// that is smaller. Returns the index of the parent layer, the added and the removed events for
+/// each layer.
+#[allow(clippy::type_complexity)]
+fn rebalance_layers(
+    full_states: &[Arc<HashSet<CompressedStateEvent>>],
+) -> Vec<(
+    Option<usize>,
+    HashSet<CompressedStateEvent>,
+    HashSet<CompressedStateEvent>,
+)> {
+    full_states
+        .iter()
+        .enumerate()
+        .map(|(i, state)| {
+            let mut parent = None;
+            let mut smallest = state.len();
+            for (j, other) in full_states[..i].iter().enumerate() {
+                let size = state.symmetric_difference(other).count();
+                if size < smallest {
+                    parent = Some(j);
+                    smallest = size;
+                }
+            }
+
+            match parent {
+                Some(j) => (
+                    parent,
+                    state.difference(&full_states[j]).copied().collect(),
+                    full_states[j].difference(state).copied().collect(),
+                ),
+                None => (None, (**state).clone(), HashSet::new()),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    fn event(shortstatekey: u64, shorteventid: u64) -> CompressedStateEvent {
+        let mut v = shortstatekey.to_be_bytes().to_vec();
+        v.extend_from_slice(&shorteventid.to_be_bytes());
+        v.try_into().unwrap()
+    }
+
+    fn resolve(
+        plan: &[(
+            Option<usize>,
+            HashSet<CompressedStateEvent>,
+            HashSet<CompressedStateEvent>,
+        )],
+        i: usize,
+    ) -> HashSet<CompressedStateEvent> {
+        let (parent, added, removed) = &plan[i];
+        let mut state = parent.map_or_else(HashSet::new, |p| resolve(plan, p));
+        state.extend(added.iter().copied());
+        for r in removed {
+            state.remove(r);
+        }
+        state
+    }
+
+    #[test]
+    fn rebalancing_preserves_state() {
+        let base: HashSet<_> = (0..50).map(|k| event(k, k)).collect();
+
+        // A member changes their profile back and forth, then the room name changes
+        let mut second = base.clone();
+        second.remove(&event(10, 10));
+        second.insert(event(10, 100));
+        let third = base.clone();
+        let mut fourth = third.clone();
+        fourth.remove(&event(20, 20));
+        fourth.insert(event(20, 101));
+        fourth.insert(event(60, 102));
+
+        let full_states: Vec<_> = [base, second, third, fourth]
+            .into_iter()
+            .map(Arc::new)
+            .collect();
+        let plan = rebalance_layers(&full_states);
+
+        for (i, state) in full_states.iter().enumerate() {
+            assert_eq!(&resolve(&plan, i), &**state);
+        }
+
+        // The third state is identical to the base, so it becomes an empty diff on top of it
+        assert_eq!(plan[2].0, Some(0));
+        assert!(plan[2].1.is_empty() && plan[2].2.is_empty());
+        assert_eq!(plan[3].0, Some(0));
+    }
+
+    #[test]
+    fn rebalancing_keeps_small_states_full() {
+        let full_states = vec![
+            Arc::new([event(1, 1)].into_iter().collect()),
+            Arc::new([event(2, 2)].into_iter().collect()),
+        ];
+        let plan = rebalance_layers(&full_states);
+
+        assert_eq!(plan[1].0, None);
+        assert_eq!(resolve(&plan, 1), *full_states[1]);
+    }
+
+    /// The recursive implementation `load_shortstatehash_info` had before, without caching.
+    fn load_recursive(
+        db: &dyn Data,
+        shortstatehash: u64,
+    ) -> Vec<(
+        u64,
+        Arc<HashSet<CompressedStateEvent>>,
+        Arc<HashSet<CompressedStateEvent>>,
+        Arc<HashSet<CompressedStateEvent>>,
+    )> {
+        let StateDiff {
+            parent,
+            added,
+            removed,
+        } = db.get_statediff(shortstatehash).unwrap();
+
+        if let Some(parent) = parent {
+            let mut response = load_recursive(db, parent);
+            let mut state = (*response.last().unwrap().1).clone();
+            state.extend(added.iter().copied());
+            for r in removed.iter() {
+                state.remove(r);
+            }
+            response.push((shortstatehash, Arc::new(state), added, removed));
+            response
+        } else {
+            vec![(shortstatehash, added.clone(), added, removed)]
+        }
+    }
+
+    #[test]
+    fn iterative_loading_matches_recursive() {
+        let services = testing::services();
+        let db = services.rooms.state_compressor.db;
+        // Starts with empty caches
+        let service = Service {
+            db,
+            stateinfo_cache: Mutex::new(LruCache::new(100)),
+            full_state_cache: Mutex::new(LruCache::new(100)),
+        };
+
+        // Unused shortstatehashes, `shortstatehashes[i]` is the layer `i`
+        let shortstatehashes: Vec<u64> = (0..=20)
+            .map(|_| services.globals.next_count().unwrap())
+            .collect();
+
+        // A chain of 20 layers that each replace one event and add another one
+        let set = |events: &[CompressedStateEvent]| Arc::new(events.iter().copied().collect());
+        db.save_statediff(
+            shortstatehashes[1],
+            StateDiff {
+                parent: None,
+                added: set(&[event(1, 1), event(2, 2)]),
+                removed: set(&[]),
+            },
+        )
+        .unwrap();
+        for i in 2..=20 {
+            db.save_statediff(
+                shortstatehashes[i as usize],
+                StateDiff {
+                    parent: Some(shortstatehashes[i as usize - 1]),
+                    added: set(&[event(1, i * 10), event(i + 10, i)]),
+                    removed: set(&[event(1, if i == 2 { 1 } else { (i - 1) * 10 })]),
+                },
+            )
+            .unwrap();
+        }
+
+        // A layer in the middle is cached first, so later loads start from there
+        assert_eq!(
+            service
+                .load_shortstatehash_info(shortstatehashes[10])
+                .unwrap(),
+            load_recursive(db, shortstatehashes[10])
+        );
+        for shortstatehash in [20, 15, 1, 10].map(|i| shortstatehashes[i]) {
+            assert_eq!(
+                service.load_shortstatehash_info(shortstatehash).unwrap(),
+                load_recursive(db, shortstatehash)
+            );
+        }
+
+        let info = service
+            .load_shortstatehash_info(shortstatehashes[20])
+            .unwrap();
+        assert_eq!(info.len(), 20);
+        let full_state = &info.last().unwrap().1;
+        assert_eq!(full_state.len(), 21);
+        assert!(full_state.contains(&event(1, 200)));
+    }
+
+    #[tokio::test]
+    async fn full_state_is_cached() {
+        let services = testing::services();
+        let user = testing::create_user();
+        let room_id = testing::create_room(&user).await;
+        let shortstatehash = services
+            .rooms
+            .state
+            .get_room_shortstatehash(&room_id)
+            .unwrap()
+            .unwrap();
+
+        let compressor = &services.rooms.state_compressor;
+        compressor
+            .full_state_cache
+            .lock()
+            .unwrap()
+            .remove(&shortstatehash);
+
+        let full_state = compressor.full_state(shortstatehash).unwrap();
+        assert_eq!(
+            full_state,
+            compressor
+                .load_shortstatehash_info(shortstatehash)
+                .unwrap()
+                .pop()
+                .unwrap()
+                .1
+        );
+        assert_eq!(full_state.len(), 4);
+
+        // The second lookup gets the same state from the cache
+        assert!(compressor
+            .full_state_cache
+            .lock()
+            .unwrap()
+            .contains_key(&shortstatehash));
+        assert!(Arc::ptr_eq(
+            &full_state,
+            &compressor.full_state(shortstatehash).unwrap()
+        ));
+    }
+}
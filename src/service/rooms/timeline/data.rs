@@ -82,6 +82,11 @@ pub trait Data: Send + Sync {
         from: PduCount,
     ) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>>;
 
+    /// Returns the count up to which the retention policy of the room was applied.
+    fn retention_watermark(&self, room_id: &RoomId) -> Result<Option<PduCount>>;
+
+    fn set_retention_watermark(&self, room_id: &RoomId, count: PduCount) -> Result<()>;
+
     fn increment_notification_counts(
         &self,
         room_id: &RoomId,
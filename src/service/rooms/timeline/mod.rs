@@ -7,11 +7,12 @@ use std::{
 
 use std::{
     collections::HashSet,
+    mem::size_of,
     sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
 pub use data::Data;
-use regex::Regex;
 use ruma::{
     api::{client::error::ErrorKind, federation},
     canonical_json::to_canonical_value,
@@ -28,7 +29,7 @@ use ruma::{
     state_res,
     state_res::{Event, RoomVersion},
     uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, OwnedRoomId,
-    OwnedServerName, RoomAliasId, RoomId, ServerName, UserId,
+    OwnedServerName, RoomId, ServerName, UserId,
 };
 use serde::Deserialize;
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
@@ -37,12 +38,18 @@ use tracing::{error, info, warn};
 
 use crate::{
     api::server_server,
-    service::pdu::{EventHash, PduBuilder},
+    service::{
+        admin, appservice,
+        pdu::{EventHash, PduBuilder},
+    },
     services, utils, Error, PduEvent, Result,
 };
 
 use super::state_compressor::CompressedStateEvent;
 
+/// How many expired events `purge_expired_events` loads into memory at once.
+const PURGE_BATCH_SIZE: usize = 100;
+
 #[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
 pub enum PduCount {
     Backfilled(u64),
@@ -72,6 +79,22 @@ impl PduCount {
             PduCount::Normal(x) => x.to_string(),
         }
     }
+
+    /// Reads the count from the end of a pdu id, which is the shortroomid followed by the count.
+    /// Backfilled events have a zero between the two.
+    pub fn from_pdu_id(pdu_id: &[u8]) -> Result<Self> {
+        let last_u64 = utils::u64_from_bytes(&pdu_id[pdu_id.len() - size_of::<u64>()..])
+            .map_err(|_| Error::bad_database("PDU has invalid count bytes."))?;
+        let second_last_u64 = utils::u64_from_bytes(
+            &pdu_id[pdu_id.len() - 2 * size_of::<u64>()..pdu_id.len() - size_of::<u64>()],
+        );
+
+        if matches!(second_last_u64, Ok(0)) {
+            Ok(PduCount::Backfilled(u64::MAX - last_u64))
+        } else {
+            Ok(PduCount::Normal(last_u64))
+        }
+    }
 }
 
 impl PartialOrd for PduCount {
@@ -90,9 +113,11 @@ impl Ord for PduCount {
         }
     }
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::testing;
 
     #[test]
     fn comparisons() {
@@ -101,6 +126,147 @@ mod tests {
         assert!(PduCount::Normal(1) > PduCount::Backfilled(1));
         assert!(PduCount::Backfilled(1) < PduCount::Normal(1));
     }
+
+    #[test]
+    fn only_message_content_can_expire() {
+        let mut message: PduEvent = testing::pdu(serde_json::json!({
+            "content": { "msgtype": "m.text", "body": "secret" },
+        }));
+        let name: PduEvent = testing::pdu(serde_json::json!({
+            "type": "m.room.name",
+            "state_key": "",
+            "content": { "name": "Room" },
+        }));
+        let member: PduEvent = testing::pdu(serde_json::json!({
+            "type": "m.room.member",
+            "state_key": "@alice:example.com",
+            "content": { "membership": "join", "displayname": "Alice" },
+        }));
+
+        assert!(Service::can_expire(&message));
+        assert!(!Service::can_expire(&name));
+        assert!(!Service::can_expire(&member));
+
+        message.strip_content().unwrap();
+        assert_eq!(message.content.get(), "{}");
+
+        // Stripped events are not rewritten again
+        assert!(!Service::can_expire(&message));
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_purged_but_state_remains() {
+        let services = testing::services();
+        let timeline = &services.rooms.timeline;
+        let user = testing::create_user();
+        let room_id = testing::create_room(&user).await;
+        let word = utils::random_string(12).to_lowercase();
+
+        let old = testing::send_message(&room_id, &user, &format!("secret {word}")).await;
+        let name = testing::send(
+            &room_id,
+            &user,
+            TimelineEventType::RoomName,
+            serde_json::json!({ "name": "Room" }),
+            Some(""),
+        )
+        .await
+        .unwrap();
+        let search = || {
+            services
+                .rooms
+                .search
+                .search_pdus(&room_id, &word)
+                .unwrap()
+                .map_or(0, |(results, _)| results.count())
+        };
+        assert_eq!(search(), 1);
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(
+            timeline
+                .purge_expired_events(&room_id, Duration::ZERO)
+                .unwrap(),
+            1
+        );
+
+        let old = timeline.get_pdu(&old).unwrap().unwrap();
+        assert_eq!(old.content.get(), "{}");
+        assert_eq!(search(), 0);
+        let name_event = timeline.get_pdu(&name).unwrap().unwrap();
+        assert!(name_event.content.get().contains("Room"));
+        let state_name = services
+            .rooms
+            .state_accessor
+            .room_state_get(&room_id, &StateEventType::RoomName, "")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state_name.event_id, name);
+
+        // Messages are only purged once they are old enough, and the watermark keeps already
+        // purged ones from being visited again
+        let new = testing::send_message(&room_id, &user, "not yet").await;
+        assert_eq!(
+            timeline
+                .purge_expired_events(&room_id, Duration::from_secs(3600))
+                .unwrap(),
+            0
+        );
+        let new = timeline.get_pdu(&new).unwrap().unwrap();
+        assert!(new.content.get().contains("not yet"));
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_purged_in_batches() {
+        let services = testing::services();
+        let user = testing::create_user();
+        let room_id = testing::create_room(&user).await;
+        for i in 0..=PURGE_BATCH_SIZE {
+            testing::send_message(&room_id, &user, &format!("message {i}")).await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(
+            services
+                .rooms
+                .timeline
+                .purge_expired_events(&room_id, Duration::ZERO)
+                .unwrap(),
+            PURGE_BATCH_SIZE + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn durable_events_are_persisted() {
+        let services = testing::services();
+        let user = testing::create_user();
+        let room_id = testing::create_room(&user).await;
+        let count = |event_id: &EventId| match services
+            .rooms
+            .timeline
+            .get_pdu_count(event_id)
+            .unwrap()
+            .unwrap()
+        {
+            PduCount::Normal(count) => count,
+            PduCount::Backfilled(_) => unreachable!("the event was sent locally"),
+        };
+
+        // The test server only treats org.example.durable as durable
+        let message = testing::send_message(&room_id, &user, "hello").await;
+        assert!(services.globals.persisted_count() < count(&message));
+
+        let durable = testing::send(
+            &room_id,
+            &user,
+            "org.example.durable".into(),
+            serde_json::json!({}),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(services.globals.persisted_count() >= count(&durable));
+    }
 }
 
 pub struct Service {
@@ -413,7 +579,7 @@ impl Service {
                         .map_err(|_| Error::bad_database("Invalid content in pdu."))?;
 
                     let invite_state = match content.membership {
-                        MembershipState::Invite => {
+                        MembershipState::Invite | MembershipState::Knock => {
                             let state = services().rooms.state.calculate_invite_state(pdu)?;
                             Some(state)
                         }
@@ -447,25 +613,19 @@ impl Service {
                         .search
                         .index_pdu(shortroomid, &pdu_id, &body)?;
 
-                    let admin_room = services().rooms.alias.resolve_local_alias(
-                        <&RoomAliasId>::try_from(
-                            format!("#admins:{}", services().globals.server_name()).as_str(),
-                        )
-                        .expect("#admins:server_name is a valid room alias"),
-                    )?;
-                    let server_user = format!("@conduit:{}", services().globals.server_name());
-
-                    let to_conduit = body.starts_with(&format!("{server_user}: "))
-                        || body.starts_with(&format!("{server_user} "))
-                        || body == format!("{server_user}:")
-                        || body == format!("{server_user}");
-
-                    // This will evaluate to false if the emergency password is set up so that
-                    // the administrator can execute commands as conduit
-                    let from_conduit = pdu.sender == server_user
-                        && services().globals.emergency_password().is_none();
+                    let admin_room = services().admin.get_admin_room()?;
+                    let server_user =
+                        UserId::parse_with_server_name("conduit", services().globals.server_name())
+                            .expect("@conduit:server_name is valid");
 
-                    if to_conduit && !from_conduit && admin_room.as_ref() == Some(&pdu.room_id) {
+                    if admin::is_admin_command(
+                        &body,
+                        &pdu.sender,
+                        &pdu.room_id,
+                        admin_room.as_deref(),
+                        &server_user,
+                        services().globals.emergency_password().is_some(),
+                    ) {
                         services().admin.process_message(body);
                     }
                 }
@@ -529,93 +689,41 @@ impl Service {
             }
         }
 
-        for appservice in services().appservice.all()? {
+        // Important events are written to disk right away instead of with the next cleanup
+        if services()
+            .globals
+            .durable_event_types()
+            .iter()
+            .any(|event_type| *event_type == pdu.kind.to_string())
+        {
+            services().globals.persist()?;
+        }
+
+        // Each event is pushed at most once per appservice, even if it is both in the room and
+        // interested in it
+        for appservice in services().appservice.registrations() {
+            let id = &appservice.registration.id;
             if services()
                 .rooms
                 .state_cache
-                .appservice_in_room(&pdu.room_id, &appservice)?
+                .appservice_in_room(&pdu.room_id, &(id.clone(), appservice.yaml.clone()))?
+                || appservice::is_interested(
+                    &appservice,
+                    pdu,
+                    services().globals.server_name(),
+                    || {
+                        services()
+                            .rooms
+                            .alias
+                            .local_aliases_for_room(&pdu.room_id)
+                            .filter_map(|r| r.ok())
+                            .collect()
+                    },
+                )
             {
                 services()
                     .sending
-                    .send_pdu_appservice(appservice.0, pdu_id.clone())?;
-                continue;
-            }
-
-            // If the RoomMember event has a non-empty state_key, it is targeted at someone.
-            // If it is our appservice user, we send this PDU to it.
-            if pdu.kind == TimelineEventType::RoomMember {
-                if let Some(state_key_uid) = &pdu
-                    .state_key
-                    .as_ref()
-                    .and_then(|state_key| UserId::parse(state_key.as_str()).ok())
-                {
-                    if let Some(appservice_uid) = appservice
-                        .1
-                        .get("sender_localpart")
-                        .and_then(|string| string.as_str())
-                        .and_then(|string| {
-                            UserId::parse_with_server_name(string, services().globals.server_name())
-                                .ok()
-                        })
-                    {
-                        if state_key_uid == &appservice_uid {
-                            services()
-                                .sending
-                                .send_pdu_appservice(appservice.0, pdu_id.clone())?;
-                            continue;
-                        }
-                    }
-                }
-            }
-
-            if let Some(namespaces) = appservice.1.get("namespaces") {
-                let users = namespaces
-                    .get("users")
-                    .and_then(|users| users.as_sequence())
-                    .map_or_else(Vec::new, |users| {
-                        users
-                            .iter()
-                            .filter_map(|users| Regex::new(users.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-                let aliases = namespaces
-                    .get("aliases")
-                    .and_then(|aliases| aliases.as_sequence())
-                    .map_or_else(Vec::new, |aliases| {
-                        aliases
-                            .iter()
-                            .filter_map(|aliases| Regex::new(aliases.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-                let rooms = namespaces
-                    .get("rooms")
-                    .and_then(|rooms| rooms.as_sequence());
-
-                let matching_users = |users: &Regex| {
-                    users.is_match(pdu.sender.as_str())
-                        || pdu.kind == TimelineEventType::RoomMember
-                            && pdu
-                                .state_key
-                                .as_ref()
-                                .map_or(false, |state_key| users.is_match(state_key))
-                };
-                let matching_aliases = |aliases: &Regex| {
-                    services()
-                        .rooms
-                        .alias
-                        .local_aliases_for_room(&pdu.room_id)
-                        .filter_map(|r| r.ok())
-                        .any(|room_alias| aliases.is_match(room_alias.as_str()))
-                };
-
-                if aliases.iter().any(matching_aliases)
-                    || rooms.map_or(false, |rooms| rooms.contains(&pdu.room_id.as_str().into()))
-                    || users.iter().any(matching_users)
-                {
-                    services()
-                        .sending
-                        .send_pdu_appservice(appservice.0, pdu_id.clone())?;
-                }
+                    .send_pdu_appservice(id.clone(), pdu_id.clone())?;
             }
         }
 
@@ -787,10 +895,10 @@ impl Service {
         // Generate event id
         pdu.event_id = EventId::parse_arc(format!(
             "${}",
-            ruma::signatures::reference_hash(&pdu_json, &room_version_id)
-                .expect("ruma can calculate reference hashes")
+            utils::reference_hash(&pdu_json, &room_version_id)
+                .expect("reference hashes can be calculated for events we created")
         ))
-        .expect("ruma's reference hashes are valid event ids");
+        .expect("reference hashes are valid event ids");
 
         pdu_json.insert(
             "event_id".to_owned(),
@@ -819,12 +927,7 @@ impl Service {
         let (pdu, pdu_json) =
             self.create_hash_and_sign_event(pdu_builder, sender, room_id, state_lock)?;
 
-        let admin_room = services().rooms.alias.resolve_local_alias(
-            <&RoomAliasId>::try_from(
-                format!("#admins:{}", services().globals.server_name()).as_str(),
-            )
-            .expect("#admins:server_name is a valid room alias"),
-        )?;
+        let admin_room = services().admin.get_admin_room()?;
         if admin_room.filter(|v| v == room_id).is_some() {
             match pdu.event_type() {
                 TimelineEventType::RoomEncryption => {
@@ -1045,6 +1148,108 @@ impl Service {
         Ok(())
     }
 
+    /// Strips the content of the message events in a room that are older than `max_lifetime` and
+    /// removes them from the search index. The events themselves stay in the DAG and state events
+    /// are never touched, so the room state stays intact.
+    ///
+    /// Events are visited in timeline order, starting after the watermark of the last run. The
+    /// scan stops at the first event that was sent after the cutoff, but events claiming to be
+    /// from the future don't stop it, their timestamp can't be trusted. The watermark stays in
+    /// front of every event that hasn't expired yet. Expired events are stripped in batches of
+    /// `PURGE_BATCH_SIZE`, so a room with a long history is never loaded at once.
+    #[tracing::instrument(skip(self))]
+    pub fn purge_expired_events(&self, room_id: &RoomId, max_lifetime: Duration) -> Result<usize> {
+        let now = utils::millis_since_unix_epoch();
+        let cutoff = now.saturating_sub(max_lifetime.as_millis().try_into().unwrap_or(u64::MAX));
+
+        let shortroomid = services()
+            .rooms
+            .short
+            .get_shortroomid(room_id)?
+            .ok_or_else(|| Error::bad_database("Room to purge has no shortroomid."))?;
+
+        let watermark = self.db.retention_watermark(room_id)?;
+        let mut new_watermark = watermark;
+        // Whether an event from the future was skipped, the watermark can't move past it
+        let mut skipped = false;
+        let mut from = watermark.unwrap_or_else(PduCount::min);
+        let mut purged = 0;
+        loop {
+            let mut expired = Vec::new();
+            let mut done = true;
+            for pdu in self.pdus_after(user_id!("@doesntmatter:conduit.rs"), room_id, from)? {
+                let (count, pdu) = pdu?;
+                from = count;
+                let origin_server_ts = u64::from(pdu.origin_server_ts);
+
+                if origin_server_ts < cutoff {
+                    if !skipped {
+                        new_watermark = Some(count);
+                    }
+                    if Self::can_expire(&pdu) {
+                        expired.push(pdu);
+                        if expired.len() == PURGE_BATCH_SIZE {
+                            done = false;
+                            break;
+                        }
+                    }
+                } else if origin_server_ts <= now {
+                    break;
+                } else {
+                    skipped = true;
+                }
+            }
+
+            purged += expired.len();
+            for mut pdu in expired {
+                let pdu_id = self
+                    .get_pdu_id(&pdu.event_id)?
+                    .ok_or_else(|| Error::bad_database("Expired PDU has no PDU ID."))?;
+
+                #[derive(Deserialize)]
+                struct ExtractBody {
+                    body: Option<String>,
+                }
+
+                if let Some(body) = serde_json::from_str::<ExtractBody>(pdu.content.get())
+                    .ok()
+                    .and_then(|content| content.body)
+                {
+                    services()
+                        .rooms
+                        .search
+                        .deindex_pdu(shortroomid, &pdu_id, &body)?;
+                }
+
+                pdu.strip_content()?;
+                self.replace_pdu(
+                    &pdu_id,
+                    &utils::to_canonical_object(&pdu).expect("PDU is an object"),
+                    &pdu,
+                )?;
+            }
+
+            // The progress is saved after every batch, so an interrupted run doesn't start over
+            if let Some(count) = new_watermark {
+                if new_watermark != watermark {
+                    self.db.set_retention_watermark(room_id, count)?;
+                }
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        Ok(purged)
+    }
+
+    /// Whether the retention policy may strip an event: state events are kept and events that were
+    /// already stripped or redacted don't need to be rewritten.
+    fn can_expire(pdu: &PduEvent) -> bool {
+        pdu.state_key.is_none() && pdu.content.get() != "{}"
+    }
+
     #[tracing::instrument(skip(self, room_id))]
     pub async fn backfill_if_required(&self, room_id: &RoomId, from: PduCount) -> Result<()> {
         let first_pdu = self
@@ -1,8 +1,8 @@
-use ruma::ServerName;
+use ruma::{OwnedServerName, ServerName};
 
 use crate::Result;
 
-use super::{OutgoingKind, SendingEventType};
+use super::{Backoff, OutgoingKind, SendingEventType};
 
 pub trait Data: Send + Sync {
     fn active_requests<'a>(
@@ -26,4 +26,7 @@ pub trait Data: Send + Sync {
     fn mark_as_active(&self, events: &[(SendingEventType, Vec<u8>)]) -> Result<()>;
     fn set_latest_educount(&self, server_name: &ServerName, educount: u64) -> Result<()>;
     fn get_latest_educount(&self, server_name: &ServerName) -> Result<u64>;
+    fn set_backoff(&self, server_name: &ServerName, backoff: Backoff) -> Result<()>;
+    fn remove_backoff(&self, server_name: &ServerName) -> Result<()>;
+    fn backoffs<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedServerName, Backoff)>> + 'a>;
 }
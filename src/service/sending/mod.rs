@@ -5,14 +5,14 @@ pub use data::Data;
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     fmt::Debug,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{self, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
     api::{appservice_server, server_server},
     services,
-    utils::calculate_hash,
+    utils::{self, calculate_hash, tasks},
     Config, Error, PduEvent, Result,
 };
 use federation::transactions::send_transaction_message;
@@ -26,7 +26,8 @@ use ruma::{
         federation::{
             self,
             transactions::edu::{
-                DeviceListUpdateContent, Edu, ReceiptContent, ReceiptData, ReceiptMap,
+                DeviceListUpdateContent, Edu, PresenceContent, PresenceUpdate, ReceiptContent,
+                ReceiptData, ReceiptMap,
             },
         },
         OutgoingRequest,
@@ -40,7 +41,7 @@ use ruma::{
 };
 use tokio::{
     select,
-    sync::{mpsc, Mutex, Semaphore},
+    sync::{mpsc, Mutex, OwnedSemaphorePermit, Semaphore},
 };
 use tracing::{debug, error, warn};
 
@@ -90,8 +91,10 @@ pub struct Service {
 
     /// The state for a given state hash.
     pub(super) maximum_requests: Arc<Semaphore>,
+    destination_requests: DestinationPermits,
     pub sender: mpsc::UnboundedSender<(OutgoingKind, SendingEventType, Vec<u8>)>,
     receiver: Mutex<mpsc::UnboundedReceiver<(OutgoingKind, SendingEventType, Vec<u8>)>>,
+    persist_backoff: bool,
 }
 
 enum TransactionStatus {
@@ -100,6 +103,91 @@ enum TransactionStatus {
     Retrying(u32),        // number of times failed
 }
 
+/// Limits how many requests can be sent to one server at the same time, so that a slow server
+/// can't take up all of the `maximum_requests` permits. Acquired before the global permit.
+struct DestinationPermits {
+    max_per_destination: Option<usize>,
+    destinations: sync::Mutex<HashMap<OwnedServerName, Arc<Semaphore>>>,
+}
+
+impl DestinationPermits {
+    fn new(max_per_destination: Option<usize>) -> Self {
+        Self {
+            max_per_destination,
+            destinations: sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn acquire(&self, destination: &ServerName) -> Option<OwnedSemaphorePermit> {
+        let max = self.max_per_destination?;
+
+        let semaphore = {
+            let mut destinations = self.destinations.lock().unwrap();
+            // Forget destinations nobody is sending to
+            if destinations.len() > 1000 {
+                destinations.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+            }
+
+            Arc::clone(
+                destinations
+                    .entry(destination.to_owned())
+                    .or_insert_with(|| Arc::new(Semaphore::new(max))),
+            )
+        };
+
+        semaphore.acquire_owned().await.ok()
+    }
+}
+
+/// How long to wait before retrying a destination after `tries` failed attempts
+fn backoff_duration(tries: u32) -> Duration {
+    (Duration::from_secs(30) * tries * tries).min(Duration::from_secs(60 * 60 * 24))
+}
+
+/// The failure state of a destination, as stored in the database so that it survives restarts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    pub tries: u32,
+    pub last_failure: SystemTime,
+}
+
+impl Backoff {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let millis = self
+            .last_failure
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let mut bytes = self.tries.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&millis.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self {
+            tries: u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?),
+            last_failure: UNIX_EPOCH
+                + Duration::from_millis(utils::u64_from_bytes(bytes.get(4..)?).ok()?),
+        })
+    }
+
+    /// The transaction status to resume with, so that the remaining backoff is respected
+    fn resume(self, now: SystemTime) -> TransactionStatus {
+        let elapsed = now
+            .duration_since(self.last_failure)
+            .unwrap_or_default()
+            .min(backoff_duration(self.tries));
+
+        TransactionStatus::Failed(
+            self.tries,
+            Instant::now()
+                .checked_sub(elapsed)
+                .unwrap_or_else(Instant::now),
+        )
+    }
+}
+
 impl Service {
     pub fn build(db: &'static dyn Data, config: &Config) -> Arc<Self> {
         let (sender, receiver) = mpsc::unbounded_channel();
@@ -108,12 +196,14 @@ impl Service {
             sender,
             receiver: Mutex::new(receiver),
             maximum_requests: Arc::new(Semaphore::new(config.max_concurrent_requests as usize)),
+            destination_requests: DestinationPermits::new(config.federation_sender_fairness),
+            persist_backoff: config.persist_federation_backoff,
         })
     }
 
     pub fn start_handler(self: &Arc<Self>) {
         let self2 = Arc::clone(self);
-        tokio::spawn(async move {
+        tasks::spawn("sending handler", async move {
             self2.handler().await.unwrap();
         });
     }
@@ -125,6 +215,15 @@ impl Service {
 
         let mut current_transaction_status = HashMap::<OutgoingKind, TransactionStatus>::new();
 
+        // Keep backing off from destinations that were failing before the restart
+        if self.persist_backoff {
+            let now = SystemTime::now();
+            for (server_name, backoff) in self.db.backoffs().filter_map(|r| r.ok()) {
+                current_transaction_status
+                    .insert(OutgoingKind::Normal(server_name), backoff.resume(now));
+            }
+        }
+
         // Retry requests we could not finish yet
         let mut initial_transactions = HashMap::<OutgoingKind, Vec<SendingEventType>>::new();
 
@@ -146,6 +245,11 @@ impl Service {
         }
 
         for (outgoing_kind, events) in initial_transactions {
+            if current_transaction_status.contains_key(&outgoing_kind) {
+                // Retried with the next event once the backoff is over
+                continue;
+            }
+
             current_transaction_status.insert(outgoing_kind.clone(), TransactionStatus::Running);
             futures.push(Self::handle_events(outgoing_kind.clone(), events));
         }
@@ -157,6 +261,19 @@ impl Service {
                         Ok(outgoing_kind) => {
                             self.db.delete_all_active_requests_for(&outgoing_kind)?;
 
+                            if let OutgoingKind::Normal(server_name) = &outgoing_kind {
+                                services()
+                                    .globals
+                                    .federation_retry_after
+                                    .write()
+                                    .unwrap()
+                                    .remove(server_name);
+
+                                if self.persist_backoff {
+                                    self.db.remove_backoff(server_name)?;
+                                }
+                            }
+
                             // Find events that have been added since starting the last request
                             let new_events = self.db.queued_requests(&outgoing_kind).filter_map(|r| r.ok()).take(30).collect::<Vec<_>>();
 
@@ -175,7 +292,7 @@ impl Service {
                             }
                         }
                         Err((outgoing_kind, _)) => {
-                            current_transaction_status.entry(outgoing_kind).and_modify(|e| *e = match e {
+                            current_transaction_status.entry(outgoing_kind.clone()).and_modify(|e| *e = match e {
                                 TransactionStatus::Running => TransactionStatus::Failed(1, Instant::now()),
                                 TransactionStatus::Retrying(n) => TransactionStatus::Failed(*n+1, Instant::now()),
                                 TransactionStatus::Failed(_, _) => {
@@ -183,6 +300,12 @@ impl Service {
                                     return
                                 },
                             });
+
+                            if let (true, OutgoingKind::Normal(server_name), Some(TransactionStatus::Failed(tries, _))) =
+                                (self.persist_backoff, &outgoing_kind, current_transaction_status.get(&outgoing_kind))
+                            {
+                                self.db.set_backoff(server_name, Backoff { tries: *tries, last_failure: SystemTime::now() })?;
+                            }
                         }
                     };
                 },
@@ -195,6 +318,8 @@ impl Service {
                         futures.push(Self::handle_events(outgoing_kind, events));
                     }
                 }
+                // Unfinished requests are still active in the database and retried after a restart
+                _ = services().globals.tasks.shutdown_requested() => return Ok(()),
             }
         }
     }
@@ -209,6 +334,17 @@ impl Service {
         let mut retry = false;
         let mut allow = true;
 
+        let retry_after = match outgoing_kind {
+            OutgoingKind::Normal(server_name) => services()
+                .globals
+                .federation_retry_after
+                .read()
+                .unwrap()
+                .get(server_name)
+                .copied(),
+            _ => None,
+        };
+
         let entry = current_transaction_status.entry(outgoing_kind.clone());
 
         entry
@@ -218,12 +354,14 @@ impl Service {
                 }
                 TransactionStatus::Failed(tries, time) => {
                     // Fail if a request has failed recently (exponential backoff)
-                    let mut min_elapsed_duration = Duration::from_secs(30) * (*tries) * (*tries);
-                    if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
-                        min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
+                    let mut next_attempt = *time + backoff_duration(*tries);
+
+                    // The server told us when to come back, respect that if it's later
+                    if let Some(retry_after) = retry_after {
+                        next_attempt = next_attempt.max(retry_after);
                     }
 
-                    if time.elapsed() < min_elapsed_duration {
+                    if Instant::now() < next_attempt {
                         allow = false;
                     } else {
                         retry = true;
@@ -273,6 +411,10 @@ impl Service {
         let mut events = Vec::new();
         let mut max_edu_count = since;
         let mut device_list_changes = HashSet::new();
+        let mut presence_updates = HashMap::new();
+        // Everything up to here is covered if we look at all rooms
+        let current_count = services().globals.current_count()?;
+        let mut complete = true;
 
         'outer: for room_id in services().rooms.state_cache.server_rooms(server_name) {
             let room_id = room_id?;
@@ -285,6 +427,21 @@ impl Service {
                     .filter(|user_id| user_id.server_name() == services().globals.server_name()),
             );
 
+            // Look for presence updates of our users in this room
+            if services().rooms.edus.presence.federate_presence {
+                presence_updates.extend(
+                    services()
+                        .rooms
+                        .edus
+                        .presence
+                        .presence_since(&room_id, since)?
+                        .into_iter()
+                        .filter(|(user_id, _)| {
+                            user_id.server_name() == services().globals.server_name()
+                        }),
+                );
+            }
+
             // Look for read receipts in this room
             for r in services()
                 .rooms
@@ -345,6 +502,7 @@ impl Service {
                 events.push(serde_json::to_vec(&federation_event).expect("json can be serialized"));
 
                 if events.len() >= 20 {
+                    complete = false;
                     break 'outer;
                 }
             }
@@ -366,6 +524,32 @@ impl Service {
             events.push(serde_json::to_vec(&edu).expect("json can be serialized"));
         }
 
+        if !presence_updates.is_empty() {
+            let edu = Edu::Presence(PresenceContent::new(
+                presence_updates
+                    .into_values()
+                    .map(|presence| {
+                        let mut update = PresenceUpdate::new(
+                            presence.sender,
+                            presence.content.presence,
+                            presence.content.last_active_ago.unwrap_or_default(),
+                        );
+                        update.status_msg = presence.content.status_msg;
+                        update.currently_active =
+                            presence.content.currently_active.unwrap_or_default();
+                        update
+                    })
+                    .collect(),
+            ));
+
+            events.push(serde_json::to_vec(&edu).expect("json can be serialized"));
+        }
+
+        // Presence updates don't carry their count, so only skip past them if nothing was left out
+        if complete {
+            max_edu_count = max_edu_count.max(current_count);
+        }
+
         Ok((events, max_edu_count))
     }
 
@@ -632,6 +816,11 @@ impl Service {
                     }
                 }
 
+                let destination_permit = services()
+                    .sending
+                    .destination_requests
+                    .acquire(server)
+                    .await;
                 let permit = services().sending.maximum_requests.acquire().await;
 
                 let response = server_server::send_request(
@@ -666,6 +855,7 @@ impl Service {
                 .map_err(|e| (kind, e));
 
                 drop(permit);
+                drop(destination_permit);
 
                 response
             }
@@ -682,6 +872,7 @@ impl Service {
         T: Debug,
     {
         debug!("Waiting for permit");
+        let destination_permit = self.destination_requests.acquire(destination).await;
         let permit = self.maximum_requests.acquire().await;
         debug!("Got permit");
         let response = tokio::time::timeout(
@@ -694,6 +885,7 @@ impl Service {
             Error::BadServerResponse("Timeout waiting for server response")
         })?;
         drop(permit);
+        drop(destination_permit);
 
         response
     }
@@ -714,3 +906,132 @@ impl Service {
         response
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{self, testing};
+
+    #[test]
+    fn backoff_survives_restart() {
+        let failed_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let stored = Backoff {
+            tries: 3,
+            last_failure: failed_at,
+        }
+        .to_bytes();
+
+        // Restarted 10 seconds later
+        let backoff = Backoff::from_bytes(&stored).unwrap();
+        assert_eq!(backoff.tries, 3);
+        assert_eq!(backoff.last_failure, failed_at);
+
+        let TransactionStatus::Failed(tries, time) =
+            backoff.resume(failed_at + Duration::from_secs(10))
+        else {
+            panic!("backoff was not resumed");
+        };
+        assert_eq!(tries, 3);
+        let remaining = (time + backoff_duration(tries)).saturating_duration_since(Instant::now());
+        assert!(remaining > Duration::from_secs(250));
+        assert!(remaining <= Duration::from_secs(260));
+
+        // Restarted after the backoff ran out
+        let TransactionStatus::Failed(_, time) =
+            backoff.resume(failed_at + Duration::from_secs(3600))
+        else {
+            panic!("backoff was not resumed");
+        };
+        assert!(time + backoff_duration(3) <= Instant::now());
+
+        assert_eq!(Backoff::from_bytes(&stored[..6]), None);
+    }
+
+    #[test]
+    fn failed_transactions_are_retried_after_backing_off() {
+        let services = testing::services();
+        let server = ServerName::parse(format!(
+            "{}.example.org",
+            utils::random_string(8).to_lowercase()
+        ))
+        .unwrap();
+        let kind = OutgoingKind::Normal(server.clone());
+        let select = |status: &mut HashMap<OutgoingKind, TransactionStatus>| {
+            services
+                .sending
+                .select_events(&kind, Vec::new(), status)
+                .unwrap()
+                .is_some()
+        };
+        let mut status = HashMap::new();
+
+        // Still backing off from the last failure
+        status.insert(kind.clone(), TransactionStatus::Failed(2, Instant::now()));
+        assert!(!select(&mut status));
+        assert!(matches!(status[&kind], TransactionStatus::Failed(2, _)));
+
+        // Our backoff ran out, but the server asked us to wait longer
+        let failed_at = Instant::now() - backoff_duration(2) - Duration::from_secs(1);
+        status.insert(kind.clone(), TransactionStatus::Failed(2, failed_at));
+        services
+            .globals
+            .federation_retry_after
+            .write()
+            .unwrap()
+            .insert(server.clone(), Instant::now() + Duration::from_secs(3600));
+        assert!(!select(&mut status));
+
+        // Once both ran out, the transaction is retried, but only once at a time
+        services
+            .globals
+            .federation_retry_after
+            .write()
+            .unwrap()
+            .insert(server, Instant::now());
+        assert!(select(&mut status));
+        assert!(matches!(status[&kind], TransactionStatus::Retrying(2)));
+        assert!(!select(&mut status));
+    }
+
+    async fn acquire(
+        global: &Arc<Semaphore>,
+        destinations: &DestinationPermits,
+        server: &ServerName,
+    ) -> (Option<OwnedSemaphorePermit>, OwnedSemaphorePermit) {
+        let destination = destinations.acquire(server).await;
+        let global = Arc::clone(global).acquire_owned().await.unwrap();
+        (destination, global)
+    }
+
+    #[tokio::test]
+    async fn slow_destination_does_not_block_others() {
+        let slow = ServerName::parse("slow.example.com").unwrap();
+        let fast = ServerName::parse("fast.example.com").unwrap();
+        let timeout = Duration::from_millis(50);
+
+        // Without fairness, two requests to the slow server take up all permits
+        let global = Arc::new(Semaphore::new(2));
+        let unfair = DestinationPermits::new(None);
+        let _first = acquire(&global, &unfair, &slow).await;
+        let _second = acquire(&global, &unfair, &slow).await;
+        assert!(
+            tokio::time::timeout(timeout, acquire(&global, &unfair, &fast))
+                .await
+                .is_err()
+        );
+
+        let global = Arc::new(Semaphore::new(2));
+        let fair = DestinationPermits::new(Some(1));
+        let _first = acquire(&global, &fair, &slow).await;
+        assert!(
+            tokio::time::timeout(timeout, acquire(&global, &fair, &slow))
+                .await
+                .is_err()
+        );
+        assert!(
+            tokio::time::timeout(timeout, acquire(&global, &fair, &fast))
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,9 @@
+use crate::Result;
+use ruma::{OwnedUserId, UserId};
+
+pub trait Data: Send + Sync {
+    /// Returns the user that was registered for this subject of the identity provider.
+    fn subject_user(&self, issuer: &str, subject: &str) -> Result<Option<OwnedUserId>>;
+
+    fn set_subject_user(&self, issuer: &str, subject: &str, user_id: &UserId) -> Result<()>;
+}
@@ -0,0 +1,494 @@
+mod data;
+
+pub use data::Data;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use jsonwebtoken::{jwk::JwkSet, DecodingKey, Validation};
+use reqwest::Url;
+use ruma::{api::client::error::ErrorKind, OwnedUserId, UserId};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    api::client_server::{create_account, AUTO_GEN_PASSWORD_LENGTH, TOKEN_LENGTH},
+    config::OidcConfig,
+    services, utils, Error, Result,
+};
+
+/// How long users have to log in at the identity provider
+const PENDING_LOGIN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+/// How long clients have to exchange a login token for an access token
+const LOGIN_TOKEN_LIFETIME: Duration = Duration::from_secs(2 * 60);
+const STATE_LENGTH: usize = 32;
+
+/// A login that was sent to the identity provider, but hasn't come back yet
+pub struct PendingLogin {
+    redirect_url: Url,
+    nonce: String,
+    created: Instant,
+}
+
+/// The parts of the OpenID provider configuration we need
+#[derive(Deserialize)]
+pub struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: Url,
+    token_endpoint: Url,
+    jwks_uri: Url,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+}
+
+pub struct Service {
+    pub db: &'static dyn Data,
+
+    pub(crate) provider: Mutex<Option<Arc<ProviderMetadata>>>,
+    pub(crate) pending_logins: Mutex<HashMap<String, PendingLogin>>, // state -> login
+    pub(crate) login_tokens: Mutex<HashMap<String, (OwnedUserId, Instant)>>, // token -> user, created
+}
+
+impl Service {
+    /// Starts an SSO login and returns the url of the identity provider the user should be sent
+    /// to. Once they are done, the identity provider sends them to our callback.
+    pub async fn start_login(&self, redirect_url: &str) -> Result<Url> {
+        let config = oidc_config()?;
+        let redirect_url = Url::parse(redirect_url)
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid redirectUrl."))?;
+        if !redirect_url_allowed(&config.client_redirect_urls, &redirect_url) {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "This redirectUrl is not allowed on this server.",
+            ));
+        }
+        let provider = self.provider().await?;
+
+        let state = utils::random_string(STATE_LENGTH);
+        let nonce = utils::random_string(STATE_LENGTH);
+
+        let mut url = provider.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &config.client_id)
+            .append_pair("redirect_uri", config.redirect_url.as_str())
+            .append_pair("scope", &config.scopes.join(" "))
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce);
+
+        let mut pending_logins = self.pending_logins.lock().unwrap();
+        pending_logins.retain(|_, login| login.created.elapsed() < PENDING_LOGIN_LIFETIME);
+        pending_logins.insert(
+            state,
+            PendingLogin {
+                redirect_url,
+                nonce,
+                created: Instant::now(),
+            },
+        );
+
+        Ok(url)
+    }
+
+    /// Finishes an SSO login after the identity provider sent the user back to us. Users are
+    /// registered on their first login.
+    ///
+    /// Returns the url of the client, with a `loginToken` it can use for `m.login.token`.
+    pub async fn finish_login(&self, code: &str, state: &str) -> Result<Url> {
+        let config = oidc_config()?;
+        let pending = {
+            let mut pending_logins = self.pending_logins.lock().unwrap();
+            take_pending_login(&mut pending_logins, state, Instant::now())?
+        };
+        let provider = self.provider().await?;
+
+        let response = services()
+            .globals
+            .default_client()
+            .post(provider.token_endpoint.clone())
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", config.redirect_url.as_str()),
+                ("client_id", config.client_id.as_str()),
+                ("client_secret", config.client_secret.as_str()),
+            ])
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            warn!(
+                "Identity provider rejected code exchange: {}",
+                response.status()
+            );
+            return Err(Error::BadServerResponse(
+                "Identity provider rejected the login.",
+            ));
+        }
+
+        let token_response: TokenResponse = serde_json::from_slice(&response.bytes().await?)
+            .map_err(|_| {
+                Error::BadServerResponse("Invalid token response from identity provider.")
+            })?;
+
+        let claims =
+            verify_id_token(&provider, &config.client_id, &token_response.id_token).await?;
+
+        if claims.nonce.as_deref() != Some(pending.nonce.as_str()) {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "ID token nonce does not match.",
+            ));
+        }
+
+        let user_id = match self.db.subject_user(&provider.issuer, &claims.sub)? {
+            Some(user_id) => user_id,
+            None => {
+                let user_id =
+                    register_user(claims.preferred_username.as_deref(), &claims.sub).await?;
+                self.db
+                    .set_subject_user(&provider.issuer, &claims.sub, &user_id)?;
+                user_id
+            }
+        };
+
+        if services().users.is_deactivated(&user_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::UserDeactivated,
+                "The user has been deactivated",
+            ));
+        }
+
+        Ok(self.issue_login_token(user_id, pending.redirect_url))
+    }
+
+    /// Adds a new `loginToken` for the user to the url of the client.
+    fn issue_login_token(&self, user_id: OwnedUserId, mut redirect_url: Url) -> Url {
+        let login_token = utils::random_string(TOKEN_LENGTH);
+
+        let mut login_tokens = self.login_tokens.lock().unwrap();
+        login_tokens.retain(|_, (_, created)| created.elapsed() < LOGIN_TOKEN_LIFETIME);
+        login_tokens.insert(login_token.clone(), (user_id, Instant::now()));
+        drop(login_tokens);
+
+        redirect_url
+            .query_pairs_mut()
+            .append_pair("loginToken", &login_token);
+
+        redirect_url
+    }
+
+    /// Returns the user a login token from an SSO login belongs to. Tokens can only be used once.
+    pub fn take_login_token(&self, token: &str) -> Option<OwnedUserId> {
+        let (user_id, created) = self.login_tokens.lock().unwrap().remove(token)?;
+        (created.elapsed() < LOGIN_TOKEN_LIFETIME).then_some(user_id)
+    }
+
+    async fn provider(&self) -> Result<Arc<ProviderMetadata>> {
+        let cached = self.provider.lock().unwrap().clone();
+        if let Some(provider) = cached {
+            return Ok(provider);
+        }
+
+        let config = oidc_config()?;
+        let issuer = config.issuer.as_str().trim_end_matches('/');
+
+        let response = services()
+            .globals
+            .default_client()
+            .get(format!("{issuer}/.well-known/openid-configuration"))
+            .send()
+            .await?;
+
+        let provider: ProviderMetadata =
+            serde_json::from_slice(&response.bytes().await?).map_err(|_| {
+                Error::BadServerResponse("Invalid OpenID configuration from identity provider.")
+            })?;
+
+        if provider.issuer.trim_end_matches('/') != issuer {
+            return Err(Error::BadServerResponse(
+                "Issuer of the identity provider does not match the config.",
+            ));
+        }
+
+        let provider = Arc::new(provider);
+        *self.provider.lock().unwrap() = Some(Arc::clone(&provider));
+
+        Ok(provider)
+    }
+}
+
+fn oidc_config() -> Result<&'static OidcConfig> {
+    services().globals.oidc().ok_or(Error::BadRequest(
+        ErrorKind::Unrecognized,
+        "SSO login is not configured on this server.",
+    ))
+}
+
+/// Whether login tokens may be sent to `url`, which has to be on the same origin as one of the
+/// `allowed` urls and below its path.
+fn redirect_url_allowed(allowed: &[String], url: &Url) -> bool {
+    allowed
+        .iter()
+        .filter_map(|allowed| Url::parse(allowed).ok())
+        .any(|allowed| {
+            let path = allowed.path().trim_end_matches('/');
+            // Compared by hand because custom schemes of mobile apps have opaque origins
+            allowed.scheme() == url.scheme()
+                && allowed.host_str() == url.host_str()
+                && allowed.port_or_known_default() == url.port_or_known_default()
+                && (url.path() == path || url.path().starts_with(&format!("{path}/")))
+        })
+}
+
+fn take_pending_login(
+    pending_logins: &mut HashMap<String, PendingLogin>,
+    state: &str,
+    now: Instant,
+) -> Result<PendingLogin> {
+    let login = pending_logins.remove(state).ok_or(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "Unknown login state, please try again.",
+    ))?;
+
+    if now.duration_since(login.created) > PENDING_LOGIN_LIFETIME {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Login took too long, please try again.",
+        ));
+    }
+
+    Ok(login)
+}
+
+async fn verify_id_token(
+    provider: &ProviderMetadata,
+    client_id: &str,
+    id_token: &str,
+) -> Result<IdTokenClaims> {
+    let header = jsonwebtoken::decode_header(id_token)
+        .map_err(|_| Error::BadServerResponse("Invalid ID token from identity provider."))?;
+
+    let response = services()
+        .globals
+        .default_client()
+        .get(provider.jwks_uri.clone())
+        .send()
+        .await?;
+
+    let jwks: JwkSet = serde_json::from_slice(&response.bytes().await?)
+        .map_err(|_| Error::BadServerResponse("Invalid JWKS from identity provider."))?;
+
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    }
+    .ok_or(Error::BadServerResponse(
+        "Identity provider did not publish the key of the ID token.",
+    ))?;
+
+    let key = DecodingKey::from_jwk(jwk)
+        .map_err(|_| Error::BadServerResponse("Unsupported ID token key."))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&provider.issuer]);
+    validation.set_audience(&[client_id]);
+
+    jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map(|token| token.claims)
+        .map_err(|e| {
+            warn!("Invalid ID token: {e}");
+            Error::BadRequest(ErrorKind::Forbidden, "Invalid ID token.")
+        })
+}
+
+/// Turns the claims of an ID token into a localpart that is valid on this server.
+fn localpart_from_claims(preferred_username: Option<&str>, subject: &str) -> String {
+    let localpart: String = preferred_username
+        .filter(|username| !username.is_empty())
+        .unwrap_or(subject)
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '=' | '-' | '/'))
+        .collect();
+
+    if localpart.is_empty() {
+        "user".to_owned()
+    } else {
+        localpart
+    }
+}
+
+async fn register_user(preferred_username: Option<&str>, subject: &str) -> Result<OwnedUserId> {
+    let base = localpart_from_claims(preferred_username, subject);
+    let mut localpart = base.clone();
+
+    let user_id = loop {
+        match UserId::parse_with_server_name(localpart.as_str(), services().globals.server_name()) {
+            Ok(user_id) if !services().users.exists(&user_id)? => break user_id,
+            _ => localpart = format!("{base}-{}", utils::random_string(6).to_lowercase()),
+        }
+    };
+
+    // SSO users don't know their password, but an empty one would mean they are deactivated
+    create_account(
+        &user_id,
+        Some(utils::random_string(AUTO_GEN_PASSWORD_LENGTH).as_str()),
+        preferred_username,
+        true,
+    )
+    .await?;
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use reqwest::Url;
+
+    use super::{
+        localpart_from_claims, redirect_url_allowed, register_user, take_pending_login,
+        PendingLogin,
+    };
+    use crate::utils::testing;
+
+    fn pending_logins(created: Instant) -> HashMap<String, PendingLogin> {
+        let mut pending_logins = HashMap::new();
+        pending_logins.insert(
+            "state".to_owned(),
+            PendingLogin {
+                redirect_url: "https://client.example.com/".parse().unwrap(),
+                nonce: "nonce".to_owned(),
+                created,
+            },
+        );
+        pending_logins
+    }
+
+    #[test]
+    fn pending_login_is_taken_once() {
+        let now = Instant::now();
+        let mut pending_logins = pending_logins(now);
+
+        let login = take_pending_login(&mut pending_logins, "state", now).unwrap();
+        assert_eq!(login.nonce, "nonce");
+        assert!(take_pending_login(&mut pending_logins, "state", now).is_err());
+    }
+
+    #[test]
+    fn mismatched_state_is_rejected() {
+        let now = Instant::now();
+        let mut pending_logins = pending_logins(now);
+
+        assert!(take_pending_login(&mut pending_logins, "other", now).is_err());
+        // The real login can still finish
+        assert!(take_pending_login(&mut pending_logins, "state", now).is_ok());
+    }
+
+    #[test]
+    fn expired_login_is_rejected() {
+        let now = Instant::now();
+        let mut pending_logins = pending_logins(now);
+
+        assert!(take_pending_login(
+            &mut pending_logins,
+            "state",
+            now + Duration::from_secs(3600)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn localpart_uses_preferred_username() {
+        assert_eq!(localpart_from_claims(Some("Alice"), "1234"), "alice");
+        assert_eq!(localpart_from_claims(Some("bob@corp"), "1234"), "bobcorp");
+        assert_eq!(localpart_from_claims(None, "f00-b4r"), "f00-b4r");
+        assert_eq!(localpart_from_claims(Some(""), "@@"), "user");
+    }
+
+    #[test]
+    fn redirect_url_has_to_be_allowed() {
+        let allowed = vec![
+            "https://app.example.com/".to_owned(),
+            "https://example.com/client".to_owned(),
+            "im.example.app://login".to_owned(),
+            "not a url".to_owned(),
+        ];
+        let is_allowed = |url: &str| redirect_url_allowed(&allowed, &Url::parse(url).unwrap());
+
+        assert!(is_allowed("https://app.example.com/"));
+        assert!(is_allowed("https://app.example.com/#/login?x=1"));
+        assert!(is_allowed("https://example.com/client"));
+        assert!(is_allowed("https://example.com/client/login"));
+        assert!(is_allowed("im.example.app://login/done"));
+
+        assert!(!is_allowed("https://evil.example.org/"));
+        assert!(!is_allowed("http://app.example.com/"));
+        assert!(!is_allowed("https://app.example.com:8443/"));
+        assert!(!is_allowed("https://example.com/clientside"));
+        assert!(!is_allowed("https://example.com/"));
+        assert!(!redirect_url_allowed(
+            &[],
+            &Url::parse("https://app.example.com/").unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn callback_issues_login_token_for_new_user() {
+        let services = testing::services();
+        let now = Instant::now();
+        let state = "callback-state";
+        services.sso.pending_logins.lock().unwrap().insert(
+            state.to_owned(),
+            PendingLogin {
+                redirect_url: "https://client.example.com/".parse().unwrap(),
+                nonce: "nonce".to_owned(),
+                created: now,
+            },
+        );
+
+        let pending =
+            take_pending_login(&mut services.sso.pending_logins.lock().unwrap(), state, now)
+                .unwrap();
+        let user_id = register_user(Some("SSO-User"), "subject").await.unwrap();
+        assert!(user_id.localpart().starts_with("sso-user"));
+        assert!(services
+            .users
+            .displayname(&user_id)
+            .unwrap()
+            .unwrap()
+            .starts_with("SSO-User"));
+
+        let redirect_url = services
+            .sso
+            .issue_login_token(user_id.clone(), pending.redirect_url);
+        assert_eq!(redirect_url.host_str(), Some("client.example.com"));
+        let login_token = redirect_url
+            .query_pairs()
+            .find(|(key, _)| key == "loginToken")
+            .map(|(_, value)| value.into_owned())
+            .unwrap();
+
+        // The client exchanges the token with m.login.token, which only works once
+        assert_eq!(services.sso.take_login_token(&login_token), Some(user_id));
+        assert_eq!(services.sso.take_login_token(&login_token), None);
+    }
+}
@@ -0,0 +1,26 @@
+use crate::Result;
+use ruma::{
+    thirdparty::{Medium, ThirdPartyIdentifier},
+    OwnedUserId, UserId,
+};
+
+pub trait Data: Send + Sync {
+    fn add_threepid(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+        validated_at: u64,
+    ) -> Result<()>;
+
+    fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()>;
+
+    /// Returns all third party identifiers of this user.
+    fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<ThirdPartyIdentifier>> + 'a>;
+
+    /// Returns the user this third party identifier belongs to.
+    fn threepid_user(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>>;
+}
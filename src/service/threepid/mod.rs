@@ -0,0 +1,455 @@
+mod data;
+
+pub use data::Data;
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use ruma::{
+    api::client::error::ErrorKind,
+    thirdparty::{Medium, ThirdPartyIdentifier},
+    ClientSecret, OwnedClientSecret, OwnedSessionId, OwnedUserId, SessionId, UInt, UserId,
+};
+use tracing::{info, warn};
+
+use crate::{api::client_server::SESSION_ID_LENGTH, services, utils, Config, Error, Result};
+
+/// How long users have to click the link in the verification email
+const VALIDATION_SESSION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const VALIDATION_TOKEN_LENGTH: usize = 32;
+
+/// An email address we sent a verification email to
+pub struct ValidationSession {
+    client_secret: OwnedClientSecret,
+    address: String,
+    token: String,
+    send_attempt: UInt,
+    created: Instant,
+    validated_at: Option<u64>,
+}
+
+/// Delivers emails. This is the SMTP relay from the config, tests use a stub instead.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Message) -> std::result::Result<(), String>;
+}
+
+#[async_trait]
+impl Mailer for AsyncSmtpTransport<Tokio1Executor> {
+    async fn send(&self, email: Message) -> std::result::Result<(), String> {
+        AsyncTransport::send(self, email)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+pub struct Service {
+    pub db: &'static dyn Data,
+
+    mailer: Option<Box<dyn Mailer>>,
+    pub(crate) validation_sessions: Mutex<HashMap<OwnedSessionId, ValidationSession>>,
+}
+
+impl Service {
+    pub fn build(db: &'static dyn Data, config: &Config) -> Result<Self> {
+        let mailer = match &config.smtp {
+            Some(smtp) => {
+                let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)
+                    .map_err(|e| {
+                        warn!("Invalid SMTP host {}: {}", smtp.host, e);
+                        Error::bad_config("Invalid SMTP host.")
+                    })?
+                    .port(smtp.port);
+
+                if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+                    builder =
+                        builder.credentials(Credentials::new(username.clone(), password.clone()));
+                }
+
+                Some(Box::new(builder.build()) as Box<dyn Mailer>)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            db,
+            mailer,
+            validation_sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Sends a verification email to the address. Retries of the same request (same client
+    /// secret and send attempt) don't send another email.
+    pub async fn request_email_token(
+        &self,
+        client_secret: &ClientSecret,
+        address: &str,
+        send_attempt: UInt,
+    ) -> Result<OwnedSessionId> {
+        let (mailer, smtp) = match (&self.mailer, &services().globals.config.smtp) {
+            (Some(mailer), Some(smtp)) => (mailer, smtp),
+            _ => {
+                return Err(Error::BadRequest(
+                    ErrorKind::ThreepidDenied,
+                    "Email addresses are not supported on this server.",
+                ))
+            }
+        };
+
+        let address = address.trim().to_lowercase();
+        let to = address
+            .parse::<Mailbox>()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid email address."))?;
+
+        if self.db.threepid_user(&Medium::Email, &address)?.is_some() {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidInUse,
+                "Email address is already in use.",
+            ));
+        }
+
+        let (sid, token) = {
+            let mut sessions = self.validation_sessions.lock().unwrap();
+            sessions.retain(|_, session| session.created.elapsed() < VALIDATION_SESSION_LIFETIME);
+
+            let existing = sessions.iter_mut().find(|(_, session)| {
+                session.client_secret.as_str() == client_secret.as_str()
+                    && session.address == address
+            });
+
+            match existing {
+                Some((sid, session)) => {
+                    if send_attempt <= session.send_attempt {
+                        // The client is retrying, the email was already sent
+                        return Ok(sid.clone());
+                    }
+                    session.send_attempt = send_attempt;
+                    (sid.clone(), session.token.clone())
+                }
+                None => {
+                    let sid: OwnedSessionId =
+                        SessionId::parse(utils::random_string(SESSION_ID_LENGTH))
+                            .expect("random strings are valid session ids");
+                    let token = utils::random_string(VALIDATION_TOKEN_LENGTH);
+
+                    sessions.insert(
+                        sid.clone(),
+                        ValidationSession {
+                            client_secret: client_secret.to_owned(),
+                            address: address.clone(),
+                            token: token.clone(),
+                            send_attempt,
+                            created: Instant::now(),
+                            validated_at: None,
+                        },
+                    );
+                    (sid, token)
+                }
+            }
+        };
+
+        let mut link = smtp.base_url.clone();
+        link.set_path("/_conduit/client/3pid/email/validate");
+        link.query_pairs_mut()
+            .append_pair("sid", sid.as_str())
+            .append_pair("client_secret", client_secret.as_str())
+            .append_pair("token", &token);
+
+        let email = Message::builder()
+            .from(smtp.from.clone())
+            .to(to)
+            .subject(format!(
+                "Verify your email address on {}",
+                services().globals.server_name()
+            ))
+            .body(format!(
+                "Click the link below to add this email address to your Matrix account on {}.\n\n\
+                {link}\n\n\
+                If you didn't ask for this, you can ignore this email.",
+                services().globals.server_name()
+            ))
+            .map_err(|e| {
+                warn!("Failed to build verification email: {}", e);
+                Error::BadRequest(ErrorKind::InvalidParam, "Invalid email address.")
+            })?;
+
+        mailer.send(email).await.map_err(|e| {
+            warn!("Failed to send verification email to {}: {}", address, e);
+            Error::BadServerResponse("Failed to send verification email.")
+        })?;
+
+        Ok(sid)
+    }
+
+    /// Marks the email address of the session as validated, called when the user clicks the link
+    /// in the verification email.
+    pub fn validate_email(&self, sid: &SessionId, client_secret: &str, token: &str) -> Result<()> {
+        let mut sessions = self.validation_sessions.lock().unwrap();
+        let session = find_session(&mut sessions, sid, client_secret, Instant::now())?;
+
+        if session.token != token {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Invalid verification token.",
+            ));
+        }
+
+        session.validated_at = Some(utils::millis_since_unix_epoch());
+
+        Ok(())
+    }
+
+    /// Adds the validated email address of the session to the user's account.
+    pub fn add_threepid(
+        &self,
+        user_id: &UserId,
+        sid: &SessionId,
+        client_secret: &ClientSecret,
+    ) -> Result<()> {
+        let session = {
+            let mut sessions = self.validation_sessions.lock().unwrap();
+            let session = find_session(&mut sessions, sid, client_secret.as_str(), Instant::now())?;
+
+            if session.validated_at.is_none() {
+                return Err(Error::BadRequest(
+                    ErrorKind::ThreepidAuthFailed,
+                    "Email address has not been verified yet.",
+                ));
+            }
+
+            sessions
+                .remove(sid)
+                .expect("session exists, we just found it")
+        };
+
+        if self
+            .db
+            .threepid_user(&Medium::Email, &session.address)?
+            .is_some()
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidInUse,
+                "Email address is already in use.",
+            ));
+        }
+
+        self.db.add_threepid(
+            user_id,
+            &Medium::Email,
+            &session.address,
+            session
+                .validated_at
+                .expect("we checked that the session is validated"),
+        )?;
+
+        info!("User {} added email address {}", user_id, session.address);
+
+        Ok(())
+    }
+
+    pub fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+        if self.db.threepid_user(medium, address)?.as_deref() != Some(user_id) {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Third party identifier not found.",
+            ));
+        }
+
+        self.db.remove_threepid(user_id, medium, address)
+    }
+
+    /// Removes all third party identifiers of this user, used when deactivating their account.
+    pub fn remove_all_threepids(&self, user_id: &UserId) -> Result<()> {
+        for threepid in self.threepids(user_id).collect::<Vec<_>>() {
+            let threepid = threepid?;
+            self.db
+                .remove_threepid(user_id, &threepid.medium, &threepid.address)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<ThirdPartyIdentifier>> + 'a {
+        self.db.threepids(user_id)
+    }
+
+    pub fn threepid_user(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>> {
+        self.db.threepid_user(medium, address)
+    }
+}
+
+fn find_session<'a>(
+    sessions: &'a mut HashMap<OwnedSessionId, ValidationSession>,
+    sid: &SessionId,
+    client_secret: &str,
+    now: Instant,
+) -> Result<&'a mut ValidationSession> {
+    match sessions.get_mut(sid) {
+        Some(session)
+            if session.client_secret.as_str() == client_secret
+                && now.duration_since(session.created) < VALIDATION_SESSION_LIFETIME =>
+        {
+            Ok(session)
+        }
+        _ => Err(Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "Unknown or expired verification session.",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use async_trait::async_trait;
+    use lettre::Message;
+    use ruma::{thirdparty::Medium, uint, ClientSecret, OwnedSessionId, SessionId};
+
+    use super::{find_session, Mailer, Service, ValidationSession};
+    use crate::utils::testing;
+
+    /// Keeps the emails instead of sending them
+    #[derive(Clone, Default)]
+    struct StubMailer {
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Mailer for StubMailer {
+        async fn send(&self, email: Message) -> std::result::Result<(), String> {
+            self.sent
+                .lock()
+                .unwrap()
+                .push(String::from_utf8(email.formatted()).unwrap());
+            Ok(())
+        }
+    }
+
+    fn sessions(created: Instant) -> (OwnedSessionId, HashMap<OwnedSessionId, ValidationSession>) {
+        let sid = SessionId::parse("session").unwrap();
+        let mut sessions = HashMap::new();
+        sessions.insert(
+            sid.clone(),
+            ValidationSession {
+                client_secret: "secret".try_into().unwrap(),
+                address: "alice@example.com".to_owned(),
+                token: "token".to_owned(),
+                send_attempt: uint!(1),
+                created,
+                validated_at: None,
+            },
+        );
+        (sid, sessions)
+    }
+
+    #[test]
+    fn session_is_found_with_client_secret() {
+        let now = Instant::now();
+        let (sid, mut sessions) = sessions(now);
+
+        let session = find_session(&mut sessions, &sid, "secret", now).unwrap();
+        assert_eq!(session.address, "alice@example.com");
+    }
+
+    #[test]
+    fn wrong_client_secret_is_rejected() {
+        let now = Instant::now();
+        let (sid, mut sessions) = sessions(now);
+
+        assert!(find_session(&mut sessions, &sid, "other", now).is_err());
+    }
+
+    #[test]
+    fn expired_session_is_rejected() {
+        let now = Instant::now();
+        let (sid, mut sessions) = sessions(now);
+
+        assert!(find_session(
+            &mut sessions,
+            &sid,
+            "secret",
+            now + Duration::from_secs(7200)
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn email_is_verified_through_the_link() {
+        let services = testing::services();
+        let user = testing::create_user();
+        let mailer = StubMailer::default();
+        let threepid = Service {
+            db: services.threepid.db,
+            mailer: Some(Box::new(mailer.clone())),
+            validation_sessions: Default::default(),
+        };
+        let address = format!("{}@example.com", user.localpart());
+        let client_secret: &ClientSecret = "secret".try_into().unwrap();
+
+        let sid = threepid
+            .request_email_token(client_secret, &address.to_uppercase(), uint!(1))
+            .await
+            .unwrap();
+        // A retry of the same request doesn't send another email
+        assert_eq!(
+            threepid
+                .request_email_token(client_secret, &address, uint!(1))
+                .await
+                .unwrap(),
+            sid
+        );
+
+        let sent = mailer.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].contains(&format!("To: {address}")));
+        let link = sent[0]
+            .lines()
+            .find(|line| line.starts_with("https://matrix.example.com/"))
+            .unwrap();
+        let link = reqwest::Url::parse(link).unwrap();
+        let query = |key: &str| {
+            link.query_pairs()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        assert_eq!(link.path(), "/_conduit/client/3pid/email/validate");
+        assert_eq!(query("sid"), sid.as_str());
+
+        // The address can only be added after the link was clicked
+        assert!(threepid.add_threepid(&user, &sid, client_secret).is_err());
+        assert!(threepid
+            .validate_email(&sid, client_secret.as_str(), "wrong token")
+            .is_err());
+        threepid
+            .validate_email(&sid, &query("client_secret"), &query("token"))
+            .unwrap();
+        threepid.add_threepid(&user, &sid, client_secret).unwrap();
+
+        assert_eq!(
+            threepid.threepid_user(&Medium::Email, &address).unwrap(),
+            Some(user)
+        );
+        assert!(threepid
+            .request_email_token(client_secret, &address, uint!(2))
+            .await
+            .is_err());
+    }
+}
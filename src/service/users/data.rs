@@ -4,8 +4,8 @@ use ruma::{
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::AnyToDeviceEvent,
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri,
-    OwnedUserId, UInt, UserId,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
+    OwnedDeviceKeyId, OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
 use std::collections::BTreeMap;
 
@@ -36,6 +36,9 @@ pub trait Data: Send + Sync {
     /// Hash and set the user's password to the Argon2 hash
     fn set_password(&self, user_id: &UserId, password: Option<&str>) -> Result<()>;
 
+    /// Stores an already calculated password hash
+    fn set_password_hash(&self, user_id: &UserId, password_hash: &str) -> Result<()>;
+
     /// Returns the displayname of a user on this homeserver.
     fn displayname(&self, user_id: &UserId) -> Result<Option<String>>;
 
@@ -196,6 +199,16 @@ pub trait Data: Send + Sync {
         device: &Device,
     ) -> Result<()>;
 
+    /// Sets when and from where the device was last used. Unlike `update_device_metadata`, this
+    /// doesn't count as a device list change.
+    fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: String,
+        ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()>;
+
     /// Get device metadata.
     fn get_device_metadata(&self, user_id: &UserId, device_id: &DeviceId)
         -> Result<Option<Device>>;
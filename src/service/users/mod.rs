@@ -1,29 +1,39 @@
 mod data;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
+    future::Future,
     mem,
+    net::IpAddr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 pub use data::Data;
+use lru_cache::LruCache;
 use ruma::{
-    api::client::{
-        device::Device,
-        error::ErrorKind,
-        filter::FilterDefinition,
-        sync::sync_events::{
-            self,
-            v4::{ExtensionsConfig, SyncRequestList},
+    api::{
+        client::{
+            device::Device,
+            error::ErrorKind,
+            filter::FilterDefinition,
+            sync::sync_events::{
+                self,
+                v4::{ExtensionsConfig, SyncRequestList},
+            },
         },
+        federation::query::get_profile_information,
     },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::AnyToDeviceEvent,
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri,
-    OwnedRoomId, OwnedUserId, RoomAliasId, UInt, UserId,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
+    OwnedDeviceKeyId, OwnedMxcUri, OwnedRoomId, OwnedUserId, UInt, UserId,
 };
 
-use crate::{services, Error, Result};
+use crate::{services, utils, DeviceLimitAction, Error, Result};
+
+/// How often we write down that a device was used
+const DEVICE_LAST_SEEN_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct SlidingSyncCache {
     lists: BTreeMap<String, SyncRequestList>,
@@ -36,9 +46,94 @@ pub struct Service {
     pub db: &'static dyn Data,
     pub connections:
         Mutex<BTreeMap<(OwnedUserId, OwnedDeviceId, String), Arc<Mutex<SlidingSyncCache>>>>,
+    pub device_last_seen: Mutex<HashMap<(OwnedUserId, OwnedDeviceId), Instant>>,
+    pub remote_profile_cache: ProfileCache,
+    pub profile_last_updated: Mutex<HashMap<OwnedUserId, Instant>>,
+    pub profile_update_interval: Duration,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub displayname: Option<String>,
+    pub avatar_url: Option<OwnedMxcUri>,
+    pub blurhash: Option<String>,
+}
+
+/// Profiles of remote users, so they don't have to be fetched over federation every time.
+pub struct ProfileCache {
+    profiles: Mutex<LruCache<OwnedUserId, (Instant, Profile)>>,
+    ttl: Duration,
+}
+
+impl ProfileCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            profiles: Mutex::new(LruCache::new(capacity)),
+            ttl,
+        }
+    }
+
+    /// Returns the cached profile if it is younger than the TTL.
+    fn get(&self, user_id: &UserId, now: Instant) -> Option<Profile> {
+        match self.profiles.lock().unwrap().get_mut(user_id) {
+            Some((fetched, profile)) if now.duration_since(*fetched) < self.ttl => {
+                Some(profile.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the cached profile or fetches and caches it.
+    async fn get_or_fetch<F>(&self, user_id: &UserId, fetch: F) -> Result<Profile>
+    where
+        F: Future<Output = Result<Profile>>,
+    {
+        if let Some(profile) = self.get(user_id, Instant::now()) {
+            return Ok(profile);
+        }
+
+        let profile = fetch.await?;
+        self.profiles
+            .lock()
+            .unwrap()
+            .insert(user_id.to_owned(), (Instant::now(), profile.clone()));
+
+        Ok(profile)
+    }
+
+    pub fn len(&self) -> usize {
+        self.profiles.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.profiles.lock().unwrap().clear();
+    }
 }
 
 impl Service {
+    /// Rejects profile changes that come faster than `profile_update_interval_secs`, so joined
+    /// rooms are not spammed with membership events.
+    pub fn check_profile_update(&self, user_id: &UserId) -> Result<()> {
+        match profile_update_retry_after(
+            &mut self.profile_last_updated.lock().unwrap(),
+            user_id,
+            Instant::now(),
+            self.profile_update_interval,
+        ) {
+            None => Ok(()),
+            Some(retry_after) => Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: Some(retry_after),
+                },
+                "Profile was changed too recently.",
+            )),
+        }
+    }
+
     /// Check if a user has an account on this homeserver.
     pub fn exists(&self, user_id: &UserId) -> Result<bool> {
         self.db.exists(user_id)
@@ -238,14 +333,10 @@ impl Service {
 
     /// Check if a user is an admin
     pub fn is_admin(&self, user_id: &UserId) -> Result<bool> {
-        let admin_room_alias_id =
-            RoomAliasId::parse(format!("#admins:{}", services().globals.server_name()))
-                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid alias."))?;
-        let admin_room_id = services()
-            .rooms
-            .alias
-            .resolve_local_alias(&admin_room_alias_id)?
-            .unwrap();
+        let admin_room_id = match services().admin.get_admin_room()? {
+            Some(admin_room_id) => admin_room_id,
+            None => return Ok(false),
+        };
 
         services()
             .rooms
@@ -259,6 +350,19 @@ impl Service {
         Ok(())
     }
 
+    /// Create a new user account on this homeserver with an Argon2 hash calculated elsewhere, e.g.
+    /// by the homeserver the user is migrated from.
+    pub fn create_with_hash(&self, user_id: &UserId, password_hash: &str) -> Result<()> {
+        if !utils::is_password_hash(password_hash) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Password hash is not a valid Argon2 hash.",
+            ));
+        }
+
+        self.db.set_password_hash(user_id, password_hash)
+    }
+
     /// Returns the number of users registered on this server.
     pub fn count(&self) -> Result<usize> {
         self.db.count()
@@ -321,7 +425,58 @@ impl Service {
         self.db.set_blurhash(user_id, blurhash)
     }
 
+    /// Returns the profile of a local or remote user, or `None` if a local user does not exist.
+    ///
+    /// Remote profiles are cached for `remote_profile_cache_ttl_secs`. If `allow_remote` is false,
+    /// only cached remote profiles are returned.
+    pub async fn get_profile(
+        &self,
+        user_id: &UserId,
+        allow_remote: bool,
+    ) -> Result<Option<Profile>> {
+        if user_id.server_name() == services().globals.server_name() {
+            if !self.exists(user_id)? {
+                return Ok(None);
+            }
+
+            return Ok(Some(Profile {
+                displayname: self.displayname(user_id)?,
+                avatar_url: self.avatar_url(user_id)?,
+                blurhash: self.blurhash(user_id)?,
+            }));
+        }
+
+        if !allow_remote {
+            return Ok(self.remote_profile_cache.get(user_id, Instant::now()));
+        }
+
+        self.remote_profile_cache
+            .get_or_fetch(user_id, async {
+                let response = services()
+                    .sending
+                    .send_federation_request(
+                        user_id.server_name(),
+                        get_profile_information::v1::Request {
+                            user_id: user_id.to_owned(),
+                            field: None,
+                        },
+                    )
+                    .await?;
+
+                Ok(Profile {
+                    displayname: response.displayname,
+                    avatar_url: response.avatar_url,
+                    blurhash: response.blurhash,
+                })
+            })
+            .await
+            .map(Some)
+    }
+
     /// Adds a new device to a user.
+    ///
+    /// - Fails or removes the oldest devices if the user would have more than
+    /// `max_devices_per_user` devices
     pub fn create_device(
         &self,
         user_id: &UserId,
@@ -329,13 +484,33 @@ impl Service {
         token: &str,
         initial_device_display_name: Option<String>,
     ) -> Result<()> {
+        if let Some(max_devices) = services().globals.max_devices_per_user() {
+            let devices = self
+                .all_devices_metadata(user_id)
+                .filter_map(|r| r.ok())
+                .filter(|device| &*device.device_id != device_id)
+                .collect();
+
+            for device_id in devices_to_evict(
+                devices,
+                max_devices,
+                services().globals.device_limit_action(),
+            )? {
+                self.remove_device(user_id, &device_id)?;
+            }
+        }
+
         self.db
             .create_device(user_id, device_id, token, initial_device_display_name)
     }
 
     /// Removes a device from a user.
     pub fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
-        self.db.remove_device(user_id, device_id)
+        self.db.remove_device(user_id, device_id)?;
+        services()
+            .rooms
+            .lazy_loading
+            .lazy_load_forget_device(user_id, device_id)
     }
 
     /// Returns an iterator over all device ids of this user.
@@ -517,6 +692,32 @@ impl Service {
         self.db.remove_to_device_events(user_id, device_id, until)
     }
 
+    /// Remembers that the device was just used. To avoid a write for every request, this only
+    /// reaches the database once a minute per device.
+    pub fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: IpAddr,
+    ) -> Result<()> {
+        let due = last_seen_due(
+            &mut self.device_last_seen.lock().unwrap(),
+            (user_id.to_owned(), device_id.to_owned()),
+            Instant::now(),
+        );
+
+        if !due {
+            return Ok(());
+        }
+
+        self.db.update_device_last_seen(
+            user_id,
+            device_id,
+            ip.to_string(),
+            MilliSecondsSinceUnixEpoch::now(),
+        )
+    }
+
     pub fn update_device_metadata(
         &self,
         user_id: &UserId,
@@ -558,7 +759,8 @@ impl Service {
         // password without logging in should check if the account is deactivated.
         self.db.set_password(user_id, None)?;
 
-        // TODO: Unhook 3PID
+        services().threepid.remove_all_threepids(user_id)?;
+
         Ok(())
     }
 
@@ -603,3 +805,301 @@ pub fn clean_signatures<F: Fn(&UserId) -> bool>(
 
     Ok(())
 }
+
+fn last_seen_due(
+    device_last_seen: &mut HashMap<(OwnedUserId, OwnedDeviceId), Instant>,
+    device: (OwnedUserId, OwnedDeviceId),
+    now: Instant,
+) -> bool {
+    match device_last_seen.get(&device) {
+        Some(last_seen) if now.duration_since(*last_seen) < DEVICE_LAST_SEEN_INTERVAL => false,
+        _ => {
+            // Devices that weren't seen for an interval are due anyway. Forgetting them before the
+            // map would grow keeps it about as large as the number of recently active devices.
+            if device_last_seen.len() == device_last_seen.capacity() {
+                device_last_seen.retain(|_, last_seen| {
+                    now.duration_since(*last_seen) < DEVICE_LAST_SEEN_INTERVAL
+                });
+            }
+
+            device_last_seen.insert(device, now);
+            true
+        }
+    }
+}
+
+/// Returns the devices that have to be removed so there is room for a new one, starting with the
+/// device that was seen the longest time ago.
+fn devices_to_evict(
+    mut devices: Vec<Device>,
+    max_devices: usize,
+    action: DeviceLimitAction,
+) -> Result<Vec<OwnedDeviceId>> {
+    if devices.len() < max_devices {
+        return Ok(Vec::new());
+    }
+
+    match action {
+        DeviceLimitAction::Reject => Err(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: None,
+            },
+            "Too many devices, log out of another device first.",
+        )),
+        DeviceLimitAction::EvictOldest => {
+            devices.sort_by_key(|device| device.last_seen_ts);
+            let excess = devices.len() + 1 - max_devices.max(1);
+
+            Ok(devices
+                .into_iter()
+                .take(excess)
+                .map(|device| device.device_id)
+                .collect())
+        }
+    }
+}
+
+/// Returns how long the user has to wait before changing their profile again, or records the
+/// change if they don't have to wait.
+fn profile_update_retry_after(
+    profile_last_updated: &mut HashMap<OwnedUserId, Instant>,
+    user_id: &UserId,
+    now: Instant,
+    interval: Duration,
+) -> Option<Duration> {
+    if let Some(last_updated) = profile_last_updated.get(user_id) {
+        let elapsed = now.duration_since(*last_updated);
+        if elapsed < interval {
+            return Some(interval - elapsed);
+        }
+    }
+
+    profile_last_updated.insert(user_id.to_owned(), now);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use ruma::{
+        api::client::device::Device, device_id, serde::Raw, user_id, DeviceKeyId,
+        MilliSecondsSinceUnixEpoch,
+    };
+    use serde_json::{json, value::to_raw_value};
+
+    use super::{
+        devices_to_evict, last_seen_due, profile_update_retry_after, Profile, ProfileCache,
+    };
+    use crate::{
+        utils::{self, testing},
+        DeviceLimitAction, Result,
+    };
+
+    #[test]
+    fn last_seen_updates_are_throttled() {
+        let mut device_last_seen = HashMap::new();
+        let device = (
+            user_id!("@alice:example.com").to_owned(),
+            device_id!("ABCDEF").to_owned(),
+        );
+        let now = Instant::now();
+
+        assert!(last_seen_due(&mut device_last_seen, device.clone(), now));
+        assert!(!last_seen_due(
+            &mut device_last_seen,
+            device.clone(),
+            now + Duration::from_secs(10)
+        ));
+        assert!(last_seen_due(
+            &mut device_last_seen,
+            device,
+            now + Duration::from_secs(61)
+        ));
+    }
+
+    #[test]
+    fn inactive_devices_are_forgotten() {
+        let mut device_last_seen = HashMap::new();
+        let device = |i: usize| {
+            (
+                user_id!("@alice:example.com").to_owned(),
+                format!("DEVICE{i}").into(),
+            )
+        };
+        let now = Instant::now();
+
+        for i in 0..100 {
+            last_seen_due(&mut device_last_seen, device(i), now);
+        }
+
+        let later = now + Duration::from_secs(61);
+        let capacity = device_last_seen.capacity();
+        for i in 100..100 + capacity {
+            last_seen_due(&mut device_last_seen, device(i), later);
+        }
+
+        // The inactive devices made room for the new ones
+        assert_eq!(device_last_seen.len(), capacity);
+        assert!((0..100).all(|i| !device_last_seen.contains_key(&device(i))));
+    }
+
+    #[tokio::test]
+    async fn cached_profiles_are_not_refetched() {
+        let cache = ProfileCache::new(10, Duration::from_secs(60));
+        let user_id = user_id!("@alice:remote.test");
+        let fetches = std::cell::Cell::new(0);
+
+        let fetch = || async {
+            fetches.set(fetches.get() + 1);
+            Ok(Profile {
+                displayname: Some("Alice".to_owned()),
+                ..Default::default()
+            })
+        };
+
+        let first = cache.get_or_fetch(user_id, fetch()).await.unwrap();
+        let second = cache.get_or_fetch(user_id, fetch()).await.unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(fetches.get(), 1);
+
+        // Expired profiles are fetched again
+        assert!(cache
+            .get(user_id, Instant::now() + Duration::from_secs(61))
+            .is_none());
+    }
+
+    #[test]
+    fn rapid_profile_changes_are_rejected() {
+        let mut profile_last_updated = HashMap::new();
+        let user_id = user_id!("@alice:example.com");
+        let interval = Duration::from_secs(60);
+        let now = Instant::now();
+
+        assert_eq!(
+            profile_update_retry_after(&mut profile_last_updated, user_id, now, interval),
+            None
+        );
+        assert_eq!(
+            profile_update_retry_after(
+                &mut profile_last_updated,
+                user_id,
+                now + Duration::from_secs(20),
+                interval
+            ),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(
+            profile_update_retry_after(
+                &mut profile_last_updated,
+                user_id,
+                now + Duration::from_secs(60),
+                interval
+            ),
+            None
+        );
+
+        // Other users have their own cooldown
+        assert_eq!(
+            profile_update_retry_after(
+                &mut profile_last_updated,
+                user_id!("@bob:example.com"),
+                now + Duration::from_secs(61),
+                interval
+            ),
+            None
+        );
+    }
+
+    fn devices() -> Vec<Device> {
+        [("NEW", 300_u32), ("OLDEST", 100), ("OLD", 200)]
+            .into_iter()
+            .map(|(device_id, last_seen)| Device {
+                device_id: device_id.into(),
+                display_name: None,
+                last_seen_ip: None,
+                last_seen_ts: Some(MilliSecondsSinceUnixEpoch(last_seen.into())),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn device_limit_rejects_new_devices() {
+        assert!(devices_to_evict(devices(), 3, DeviceLimitAction::Reject).is_err());
+        assert!(devices_to_evict(devices(), 4, DeviceLimitAction::Reject)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn device_limit_evicts_oldest_devices() {
+        assert_eq!(
+            devices_to_evict(devices(), 3, DeviceLimitAction::EvictOldest).unwrap(),
+            [device_id!("OLDEST")]
+        );
+        assert_eq!(
+            devices_to_evict(devices(), 2, DeviceLimitAction::EvictOldest).unwrap(),
+            [device_id!("OLDEST"), device_id!("OLD")]
+        );
+    }
+
+    #[tokio::test]
+    async fn logging_out_drops_one_time_keys() {
+        let services = testing::services();
+        let user = testing::create_user();
+        let key_id = DeviceKeyId::parse("signed_curve25519:AAAAAQ").unwrap();
+        let key = Raw::from_json(to_raw_value(&json!({ "key": "abc" })).unwrap());
+
+        let devices = [
+            device_id!("FIRST"),
+            device_id!("SECOND"),
+            device_id!("THIRD"),
+        ];
+        for device_id in devices {
+            services
+                .users
+                .create_device(&user, device_id, &utils::random_string(32), None)
+                .unwrap();
+            services
+                .users
+                .add_one_time_key(&user, device_id, &key_id, &key)
+                .unwrap();
+        }
+
+        services.users.remove_device(&user, devices[0]).unwrap();
+        assert!(services
+            .users
+            .count_one_time_keys(&user, devices[0])
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            services
+                .users
+                .count_one_time_keys(&user, devices[1])
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Logging out all devices removes every device and its keys
+        let device_ids = services
+            .users
+            .all_device_ids(&user)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        for device_id in &device_ids {
+            services.users.remove_device(&user, device_id).unwrap();
+        }
+        assert_eq!(device_ids.len(), 2);
+        assert_eq!(services.users.all_device_ids(&user).count(), 0);
+        assert!(devices.iter().all(|device_id| services
+            .users
+            .count_one_time_keys(&user, device_id)
+            .unwrap()
+            .is_empty()));
+    }
+}
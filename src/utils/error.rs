@@ -68,6 +68,13 @@ pub enum Error {
     #[error("{0}")]
     /// Don't create this directly. Use Error::bad_database instead.
     BadDatabase(&'static str),
+    #[error("{reason}")]
+    /// Don't create this directly. Use Error::corruption instead.
+    Corruption {
+        tree: &'static str,
+        key: Vec<u8>,
+        reason: &'static str,
+    },
     #[error("uiaa")]
     Uiaa(UiaaInfo),
     #[error("{0}: {1}")]
@@ -88,6 +95,21 @@ impl Error {
         Self::BadDatabase(message)
     }
 
+    /// An entry in the database tree `tree` could not be parsed.
+    pub fn corruption(tree: &'static str, key: &[u8], reason: &'static str) -> Self {
+        error!(
+            tree,
+            key = %String::from_utf8_lossy(key),
+            "Corruption: {}",
+            reason
+        );
+        Self::Corruption {
+            tree,
+            key: key.to_vec(),
+            reason,
+        }
+    }
+
     pub fn bad_config(message: &'static str) -> Self {
         error!("BadConfig: {}", message);
         Self::BadConfig(message)
@@ -157,6 +179,7 @@ impl Error {
             Self::IoError { .. } => db_error,
             Self::BadConfig { .. } => db_error,
             Self::BadDatabase { .. } => db_error,
+            Self::Corruption { .. } => db_error,
             _ => self.to_string(),
         }
     }
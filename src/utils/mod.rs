@@ -1,16 +1,36 @@
 pub mod error;
+pub mod tasks;
+#[cfg(test)]
+pub mod testing;
 
 use argon2::{Config, Variant};
+use base64::{engine::general_purpose, Engine as _};
 use cmp::Ordering;
 use rand::prelude::*;
 use ring::digest;
-use ruma::{canonical_json::try_from_json_map, CanonicalJsonError, CanonicalJsonObject};
+use ruma::{
+    api::client::error::ErrorKind,
+    canonical_json::{redact, try_from_json_map, RedactionError},
+    CanonicalJsonError, CanonicalJsonObject, RoomVersionId,
+};
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor};
 use std::{
-    cmp, fmt,
+    cmp,
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    hash::Hash,
+    io,
+    net::IpAddr,
+    path::Path,
     str::FromStr,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use tracing::warn;
+
+use crate::{config::RateLimitConfig, Error};
+
 pub fn millis_since_unix_epoch() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -18,11 +38,13 @@ pub fn millis_since_unix_epoch() -> u64 {
         .as_millis() as u64
 }
 
+/// Increments a big endian u64. The result saturates at `u64::MAX` instead of wrapping around, so
+/// callers that need unique values have to check for it.
 pub fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
     let number = match old.map(|bytes| bytes.try_into()) {
         Some(Ok(bytes)) => {
             let number = u64::from_be_bytes(bytes);
-            number + 1
+            number.saturating_add(1)
         }
         _ => 1, // Start at one. since 0 should return the first event in the db
     };
@@ -70,6 +92,12 @@ pub fn calculate_password_hash(password: &str) -> Result<String, argon2::Error>
     argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &hashing_config)
 }
 
+/// Whether the string is an encoded Argon2 hash that passwords can be verified against
+pub fn is_password_hash(hash: &str) -> bool {
+    // Verification only fails with an error if the hash can't be decoded
+    argon2::verify_encoded(hash, b"").is_ok()
+}
+
 #[tracing::instrument(skip(keys))]
 pub fn calculate_hash(keys: &[&[u8]]) -> Vec<u8> {
     // We only hash the pdu's event ids, not the whole pdu
@@ -78,10 +106,14 @@ pub fn calculate_hash(keys: &[&[u8]]) -> Vec<u8> {
     hash.as_ref().to_owned()
 }
 
-pub fn common_elements(
-    mut iterators: impl Iterator<Item = impl Iterator<Item = Vec<u8>>>,
-    check_order: impl Fn(&[u8], &[u8]) -> Ordering,
-) -> Option<impl Iterator<Item = Vec<u8>>> {
+/// Returns an iterator over the elements that are contained in all of the given iterators.
+///
+/// Every iterator must be sorted according to `check_order`. Returns `None` if no iterators were
+/// given at all.
+pub fn common_elements<T>(
+    mut iterators: impl Iterator<Item = impl Iterator<Item = T>>,
+    check_order: impl Fn(&T, &T) -> Ordering,
+) -> Option<impl Iterator<Item = T>> {
     let first_iterator = iterators.next()?;
     let mut other_iterators = iterators.map(|i| i.peekable()).collect::<Vec<_>>();
 
@@ -89,8 +121,10 @@ pub fn common_elements(
         other_iterators.iter_mut().all(|it| {
             while let Some(element) = it.peek() {
                 match check_order(element, target) {
-                    Ordering::Greater => return false, // We went too far
-                    Ordering::Equal => return true,    // Element is in both iters
+                    // We went too far. Because all iterators are sorted, the target can't come
+                    // later and we keep the element for the next (bigger) target
+                    Ordering::Greater => return false,
+                    Ordering::Equal => return true, // Element is in both iters
                     Ordering::Less => {
                         // Keep searching
                         it.next();
@@ -118,6 +152,150 @@ pub fn to_canonical_object<T: serde::Serialize>(
     }
 }
 
+/// The SHA-256 of an event without `unsigned`, `signatures` and `hashes`, as unpadded base64.
+///
+/// This is what gets stored in the `hashes` of an event.
+pub fn content_hash(event: &CanonicalJsonObject) -> String {
+    let mut event = event.clone();
+    event.remove("unsigned");
+    event.remove("signatures");
+    event.remove("hashes");
+
+    let json = serde_json::to_vec(&event).expect("canonical json can be serialized");
+    general_purpose::STANDARD_NO_PAD.encode(digest::digest(&digest::SHA256, &json))
+}
+
+/// The SHA-256 of the redacted event without `unsigned` and `signatures`. Room versions 4 and
+/// later use URL-safe base64 for it, so it can be used as the event ID.
+pub fn reference_hash(
+    event: &CanonicalJsonObject,
+    room_version: &RoomVersionId,
+) -> Result<String, RedactionError> {
+    let mut event = redact(event.clone(), room_version, None)?;
+    event.remove("unsigned");
+    event.remove("signatures");
+
+    let json = serde_json::to_vec(&event).expect("canonical json can be serialized");
+    let hash = digest::digest(&digest::SHA256, &json);
+
+    Ok(match room_version {
+        RoomVersionId::V1 | RoomVersionId::V2 | RoomVersionId::V3 => {
+            general_purpose::STANDARD_NO_PAD.encode(hash)
+        }
+        _ => general_purpose::URL_SAFE_NO_PAD.encode(hash),
+    })
+}
+
+/// Largest integer allowed in canonical JSON, the smallest one is its negation
+const MAX_CANONICAL_INT: i64 = (1 << 53) - 1;
+
+/// Checks the raw JSON of an event for what converting it to canonical JSON silently accepts:
+/// duplicate keys, events larger than `max_bytes` and, from room version 6 on, floats and
+/// integers outside of the range that canonical JSON allows.
+///
+/// Invalid UTF-8 and unpaired surrogates are already rejected when parsing the JSON.
+pub fn validate_canonical_event(
+    json: &str,
+    room_version: &RoomVersionId,
+    max_bytes: usize,
+) -> crate::Result<()> {
+    if json.len() > max_bytes {
+        return Err(Error::BadRequest(
+            ErrorKind::TooLarge,
+            "Event is too large.",
+        ));
+    }
+
+    let strict_numbers = !matches!(
+        room_version,
+        RoomVersionId::V1
+            | RoomVersionId::V2
+            | RoomVersionId::V3
+            | RoomVersionId::V4
+            | RoomVersionId::V5
+    );
+
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    StrictJson { strict_numbers }
+        .deserialize(&mut deserializer)
+        .and_then(|()| deserializer.end())
+        .map_err(|e| {
+            warn!("Rejecting event that is not valid canonical JSON: {e}");
+            Error::BadRequest(ErrorKind::BadJson, "Event is not valid canonical JSON.")
+        })
+}
+
+/// Walks a JSON document without keeping it, failing on what canonical JSON doesn't allow.
+#[derive(Clone, Copy)]
+struct StrictJson {
+    strict_numbers: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for StrictJson {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for StrictJson {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("canonical JSON")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<(), E> {
+        if self.strict_numbers && !(-MAX_CANONICAL_INT..=MAX_CANONICAL_INT).contains(&v) {
+            return Err(E::custom(format!("integer {v} is out of range")));
+        }
+        Ok(())
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<(), E> {
+        if self.strict_numbers && v > MAX_CANONICAL_INT as u64 {
+            return Err(E::custom(format!("integer {v} is out of range")));
+        }
+        Ok(())
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<(), E> {
+        if self.strict_numbers {
+            return Err(E::custom(format!("{v} is not an integer")));
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while seq.next_element_seed(self)?.is_some() {}
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut keys = HashSet::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if !keys.insert(key) {
+                return Err(de::Error::custom("duplicate key"));
+            }
+            map.next_value_seed(self)?;
+        }
+        Ok(())
+    }
+}
+
 pub fn deserialize_from_str<
     'de,
     D: serde::de::Deserializer<'de>,
@@ -180,3 +358,519 @@ impl<'a> fmt::Display for HtmlEscape<'a> {
         Ok(())
     }
 }
+
+/// Finds the address of the client that sent a request. Proxies we trust add the address they
+/// got the request from to `X-Forwarded-For`, so we take the last address in there that isn't one
+/// of our proxies.
+pub fn client_ip(peer: IpAddr, forwarded_for: Option<&str>, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+
+    forwarded_client_ip(forwarded_for, trusted_proxies).unwrap_or(peer)
+}
+
+/// Finds the address of the client in `X-Forwarded-For` alone, for requests that have no peer
+/// address because they came in through a unix socket. Only a local reverse proxy can reach the
+/// socket, so the header is trusted like one from `trusted_proxies`.
+pub fn forwarded_client_ip(
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    forwarded_for
+        .into_iter()
+        .flat_map(|header| header.rsplit(','))
+        .map_while(|ip| ip.trim().parse::<IpAddr>().ok())
+        .find(|ip| !trusted_proxies.contains(ip))
+}
+
+/// Applies the server maximum to a limit requested by a client, using `default` if the client
+/// didn't ask for one.
+pub fn clamp_limit(requested: Option<u64>, default: u64, max: u64) -> usize {
+    requested
+        .unwrap_or(default)
+        .min(max)
+        .try_into()
+        .unwrap_or(usize::MAX)
+}
+
+/// Query parameters whose values must not end up in logs.
+const SECRET_QUERY_PARAMS: &[&str] = &["access_token", "client_secret", "loginToken", "token"];
+
+/// The path and query of `uri`, with the values of query parameters like `access_token` redacted.
+pub fn redacted_path_and_query(uri: &http::Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.path().to_owned();
+    };
+
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if SECRET_QUERY_PARAMS.contains(&key) => format!("{key}=<redacted>"),
+            _ => pair.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+
+    format!("{}?{}", uri.path(), query)
+}
+
+/// A line for the access log. Headers and bodies are never included.
+pub fn access_log_line(
+    method: &http::Method,
+    uri: &http::Uri,
+    status: http::StatusCode,
+    latency: Duration,
+) -> String {
+    format!(
+        "{method} {} {} {}ms",
+        redacted_path_and_query(uri),
+        status.as_u16(),
+        latency.as_millis()
+    )
+}
+
+/// Makes sure that `path` is a directory that files can be created in, creating it if necessary.
+pub fn ensure_writable_dir(path: &Path) -> io::Result<()> {
+    fs::create_dir_all(path)?;
+
+    let probe = path.join(format!(".conduit-write-test-{}", random_string(8)));
+    fs::File::create(&probe)?;
+    fs::remove_file(probe)
+}
+
+/// A token bucket for each key: every request takes a token, and tokens come back at
+/// `per_second` up to `burst`.
+pub struct RateLimiter<K> {
+    buckets: Mutex<HashMap<K, (f64, Instant)>>,
+    per_second: f64,
+    burst: f64,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    /// Buckets are cleaned up once there are this many of them
+    const PRUNE_THRESHOLD: usize = 10_000;
+
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            per_second: config.per_second,
+            burst: f64::from(config.burst.max(1)),
+        }
+    }
+
+    /// Takes a token, or returns how long it takes until the next token is available.
+    pub fn check(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= Self::PRUNE_THRESHOLD {
+            // Full buckets are the same as no bucket
+            buckets.retain(|_, (tokens, last)| {
+                *tokens + now.duration_since(*last).as_secs_f64() * self.per_second < self.burst
+            });
+        }
+
+        let (tokens, last) = buckets.entry(key).or_insert((self.burst, now));
+        *tokens =
+            (*tokens + now.duration_since(*last).as_secs_f64() * self.per_second).min(self.burst);
+        *last = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(self.retry_after(*tokens))
+        }
+    }
+
+    /// Like [`Self::check`], but doesn't take the token.
+    pub fn peek(&self, key: &K, now: Instant) -> Result<(), Duration> {
+        let tokens = self
+            .buckets
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(self.burst, |(tokens, last)| {
+                (tokens + now.duration_since(*last).as_secs_f64() * self.per_second).min(self.burst)
+            });
+
+        if tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(self.retry_after(tokens))
+        }
+    }
+
+    fn retry_after(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64((1.0 - tokens) / self.per_second.max(f64::MIN_POSITIVE))
+    }
+}
+
+/// Builds database keys out of fields that are separated by 0xff.
+///
+/// ```ignore
+/// let key = KeyBuilder::new().push_field(b"a").push_u64_be(1).build(); // a 0xff 00..01
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct KeyBuilder {
+    key: Vec<u8>,
+    fields: usize,
+}
+
+impl KeyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a field, separated from the previous one
+    pub fn push_field(mut self, field: &[u8]) -> Self {
+        if self.fields > 0 {
+            self.key.push(0xff);
+        }
+        self.key.extend_from_slice(field);
+        self.fields += 1;
+        self
+    }
+
+    pub fn push_u64_be(self, number: u64) -> Self {
+        self.push_field(&number.to_be_bytes())
+    }
+
+    /// Appends to the last field without a separator, for fixed size values that follow each
+    /// other directly
+    pub fn extend_field(mut self, bytes: &[u8]) -> Self {
+        self.key.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.key
+    }
+
+    /// The key with a trailing separator, to find all keys with more fields after these
+    pub fn prefix(mut self) -> Vec<u8> {
+        self.key.push(0xff);
+        self.key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn common(iterators: Vec<Vec<u64>>) -> Option<Vec<u64>> {
+        common_elements(iterators.into_iter().map(|i| i.into_iter()), |a, b| {
+            a.cmp(b)
+        })
+        .map(|it| it.collect())
+    }
+
+    #[test]
+    fn common_elements_no_iterators() {
+        assert_eq!(common(vec![]), None);
+    }
+
+    #[test]
+    fn common_elements_empty_iterators() {
+        assert_eq!(common(vec![vec![], vec![1, 2]]), Some(vec![]));
+        assert_eq!(common(vec![vec![1, 2], vec![]]), Some(vec![]));
+    }
+
+    #[test]
+    fn common_elements_single_iterator() {
+        assert_eq!(common(vec![vec![1, 2, 3]]), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn common_elements_disjoint() {
+        assert_eq!(common(vec![vec![1, 3, 5], vec![2, 4, 6]]), Some(vec![]));
+    }
+
+    #[test]
+    fn common_elements_full_overlap() {
+        assert_eq!(
+            common(vec![vec![1, 2, 3], vec![1, 2, 3], vec![1, 2, 3]]),
+            Some(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn common_elements_later_matches() {
+        // The other iterator overshoots the first target but must still match later ones
+        assert_eq!(
+            common(vec![vec![1, 4, 7, 9], vec![2, 4, 8, 9], vec![0, 4, 9]]),
+            Some(vec![4, 9])
+        );
+    }
+
+    #[test]
+    fn common_elements_reversed_order() {
+        let iterators = vec![vec![9u64, 7, 4, 1], vec![9, 8, 4, 2]];
+        let result: Vec<_> =
+            common_elements(iterators.into_iter().map(|i| i.into_iter()), |a, b| {
+                b.cmp(a)
+            })
+            .unwrap()
+            .collect();
+        assert_eq!(result, vec![9, 4]);
+    }
+
+    #[test]
+    fn client_ip_ignores_header_from_untrusted_peer() {
+        let peer = "203.0.113.5".parse().unwrap();
+        assert_eq!(client_ip(peer, Some("198.51.100.1"), &[]), peer);
+    }
+
+    #[test]
+    fn client_ip_skips_trusted_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(
+            client_ip(
+                proxy,
+                Some("192.0.2.7, 198.51.100.1, 10.0.0.2"),
+                &[proxy, other_proxy]
+            ),
+            "198.51.100.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn forwarded_client_ip_without_peer() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(
+            forwarded_client_ip(Some("192.0.2.7, 10.0.0.1"), &[proxy]),
+            Some("192.0.2.7".parse().unwrap())
+        );
+        assert_eq!(forwarded_client_ip(None, &[proxy]), None);
+        assert_eq!(forwarded_client_ip(Some("garbage"), &[]), None);
+    }
+
+    #[test]
+    fn client_ip_falls_back_to_peer() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(client_ip(proxy, None, &[proxy]), proxy);
+        assert_eq!(client_ip(proxy, Some("garbage"), &[proxy]), proxy);
+    }
+
+    #[test]
+    fn clamp_limit_to_max() {
+        assert_eq!(clamp_limit(Some(100_000), 10, 100), 100);
+        assert_eq!(clamp_limit(Some(20), 10, 100), 20);
+        assert_eq!(clamp_limit(None, 10, 100), 10);
+        assert_eq!(clamp_limit(None, 10, 5), 5);
+    }
+
+    #[test]
+    fn increment_saturates() {
+        let increment_u64 = |old: Option<u64>| {
+            let new = increment(old.map(u64::to_be_bytes).as_ref().map(|b| &b[..])).unwrap();
+            u64_from_bytes(&new).unwrap()
+        };
+
+        assert_eq!(increment_u64(None), 1);
+        assert_eq!(increment_u64(Some(41)), 42);
+        assert_eq!(increment_u64(Some(u64::MAX - 1)), u64::MAX);
+        assert_eq!(increment_u64(Some(u64::MAX)), u64::MAX);
+
+        // Values of the wrong length start over
+        assert_eq!(
+            u64_from_bytes(&increment(Some(&[1, 2])).unwrap()).unwrap(),
+            1
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_dir_is_not_writable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("conduit-test-{}", random_string(8)));
+        ensure_writable_dir(&dir).unwrap();
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+        let root = fs::File::create(dir.join("probe")).is_ok();
+        if !root {
+            assert!(ensure_writable_dir(&dir).is_err());
+            assert!(ensure_writable_dir(&dir.join("media")).is_err());
+        }
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Not even root can create a directory below a regular file
+        let file = std::env::temp_dir().join(format!("conduit-test-{}", random_string(8)));
+        fs::File::create(&file).unwrap();
+        assert!(ensure_writable_dir(&file.join("media")).is_err());
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn access_log_redacts_tokens() {
+        let uri: http::Uri =
+            "/_matrix/client/v3/sync?since=s72594&access_token=syt_secret_token&timeout=30000"
+                .parse()
+                .unwrap();
+
+        let line = access_log_line(
+            &http::Method::GET,
+            &uri,
+            http::StatusCode::OK,
+            Duration::from_millis(12),
+        );
+        assert!(!line.contains("syt_secret_token"));
+        assert_eq!(
+            line,
+            "GET /_matrix/client/v3/sync?since=s72594&access_token=<redacted>&timeout=30000 200 12ms"
+        );
+
+        let uri: http::Uri = "/_matrix/client/versions".parse().unwrap();
+        assert_eq!(redacted_path_and_query(&uri), "/_matrix/client/versions");
+    }
+
+    #[test]
+    fn rate_limiter_throttles_and_recovers() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            per_second: 1.0,
+            burst: 3,
+        });
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check("alice", now), Ok(()));
+        }
+        assert_eq!(limiter.check("alice", now), Err(Duration::from_secs(1)));
+
+        // Other keys have their own bucket
+        assert_eq!(limiter.check("bob", now), Ok(()));
+
+        // One token comes back every second
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check("alice", later), Ok(()));
+        assert!(limiter.check("alice", later).is_err());
+
+        // The bucket never holds more than the burst
+        let much_later = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check("alice", much_later), Ok(()));
+        }
+        assert!(limiter.check("alice", much_later).is_err());
+    }
+
+    #[test]
+    fn canonical_event_validation() {
+        const MAX: usize = 65_536;
+
+        let valid = r#"{"content":{"body":"Hi","count":9007199254740991},"type":"m.room.message"}"#;
+        assert!(validate_canonical_event(valid, &RoomVersionId::V10, MAX).is_ok());
+
+        let duplicate_key = r#"{"content":{"body":"Hi","body":"Bye"},"type":"m.room.message"}"#;
+        assert!(validate_canonical_event(duplicate_key, &RoomVersionId::V1, MAX).is_err());
+        assert!(validate_canonical_event(duplicate_key, &RoomVersionId::V10, MAX).is_err());
+
+        let out_of_range = r#"{"content":{"count":9007199254740992},"type":"m.room.message"}"#;
+        assert!(validate_canonical_event(out_of_range, &RoomVersionId::V10, MAX).is_err());
+        // Older room versions don't enforce canonical JSON number limits
+        assert!(validate_canonical_event(out_of_range, &RoomVersionId::V5, MAX).is_ok());
+
+        let float = r#"{"content":{"count":1.5},"type":"m.room.message"}"#;
+        assert!(validate_canonical_event(float, &RoomVersionId::V10, MAX).is_err());
+
+        assert!(validate_canonical_event(valid, &RoomVersionId::V10, 32).is_err());
+    }
+
+    /// The minimal event from the "Signing Events" example of the spec
+    fn spec_event() -> CanonicalJsonObject {
+        serde_json::from_str(
+            r#"{
+                "room_id": "!x:domain",
+                "sender": "@a:domain",
+                "origin": "domain",
+                "origin_server_ts": 1000000,
+                "signatures": {},
+                "hashes": {},
+                "type": "X",
+                "content": {},
+                "prev_events": [],
+                "auth_events": [],
+                "depth": 3,
+                "unsigned": {
+                    "age_ts": 1000000
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn content_hash_matches_spec() {
+        assert_eq!(
+            content_hash(&spec_event()),
+            "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"
+        );
+    }
+
+    #[test]
+    fn reference_hash_ignores_redacted_content() {
+        let mut event = spec_event();
+        event.insert(
+            "hashes".to_owned(),
+            serde_json::from_str(r#"{"sha256": "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"}"#)
+                .unwrap(),
+        );
+        event.insert(
+            "content".to_owned(),
+            serde_json::from_str(r#"{"body": "redacted away"}"#).unwrap(),
+        );
+
+        let hash = reference_hash(&event, &RoomVersionId::V10).unwrap();
+        assert_eq!(hash, "8yif6p8EqgoSten2BLje9ntKm720NyFLWQv9tn8memc");
+        assert_eq!(
+            hash,
+            ruma::signatures::reference_hash(&event, &RoomVersionId::V10).unwrap()
+        );
+    }
+
+    #[test]
+    fn key_builder_matches_hand_built_keys() {
+        // mediaid_file
+        let mut media = b"mxc://example.com/abc".to_vec();
+        media.push(0xff);
+        media.extend_from_slice(&32_u32.to_be_bytes());
+        media.extend_from_slice(&24_u32.to_be_bytes());
+        media.push(0xff);
+        media.extend_from_slice(b"inline");
+        media.push(0xff);
+        media.extend_from_slice(b"image/png");
+        let built = KeyBuilder::new()
+            .push_field(b"mxc://example.com/abc")
+            .push_field(&32_u32.to_be_bytes())
+            .extend_field(&24_u32.to_be_bytes());
+        assert_eq!(
+            built
+                .clone()
+                .push_field(b"inline")
+                .push_field(b"image/png")
+                .build(),
+            media
+        );
+        assert!(media.starts_with(&built.prefix()));
+
+        // roomuserdataid_accountdata of global account data: the room is empty
+        let mut account_data = vec![0xff];
+        account_data.extend_from_slice(b"@alice:example.com");
+        account_data.push(0xff);
+        account_data.extend_from_slice(&5_u64.to_be_bytes());
+        account_data.push(0xff);
+        account_data.extend_from_slice(b"m.push_rules");
+        assert_eq!(
+            KeyBuilder::new()
+                .push_field(b"")
+                .push_field(b"@alice:example.com")
+                .push_u64_be(5)
+                .push_field(b"m.push_rules")
+                .build(),
+            account_data
+        );
+    }
+}
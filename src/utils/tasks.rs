@@ -0,0 +1,109 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::Notify, task::JoinHandle, time::timeout_at};
+use tracing::{debug, warn};
+
+use crate::services;
+
+/// Keeps the handles of long running background tasks, so that they can be asked to stop and be
+/// awaited on shutdown.
+#[derive(Default)]
+pub struct TaskManager {
+    handles: Mutex<Vec<(&'static str, JoinHandle<()>)>>,
+    shutting_down: AtomicBool,
+    notify: Notify,
+}
+
+impl TaskManager {
+    /// Spawns a task that is awaited on shutdown. Tasks should stop their work once
+    /// `shutdown_requested` completes.
+    pub fn spawn(&self, name: &'static str, task: impl Future<Output = ()> + Send + 'static) {
+        let handle = tokio::spawn(task);
+
+        let mut handles = self.handles.lock().unwrap();
+        handles.retain(|(_, handle)| !handle.is_finished());
+        handles.push((name, handle));
+    }
+
+    /// Completes once `shutdown` was called.
+    pub async fn shutdown_requested(&self) {
+        // Created before checking the flag, so a shutdown in between is not missed
+        let notified = self.notify.notified();
+
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return;
+        }
+
+        notified.await;
+    }
+
+    /// Asks all tasks to stop and waits for them. Tasks that are still running after `timeout` are
+    /// aborted.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+
+        for (name, mut handle) in handles {
+            match timeout_at(deadline, &mut handle).await {
+                Ok(Ok(())) => debug!("Task {} stopped", name),
+                Ok(Err(e)) => warn!("Task {} failed: {}", name, e),
+                Err(_) => {
+                    warn!("Task {} did not stop in time, aborting it", name);
+                    handle.abort();
+                }
+            }
+        }
+    }
+}
+
+/// Spawns a task with the `TaskManager` of the server, see [`TaskManager::spawn`].
+pub fn spawn(name: &'static str, task: impl Future<Output = ()> + Send + 'static) {
+    services().globals.tasks.spawn(name, task);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn shutdown_stops_tasks() {
+        let tasks = Arc::new(TaskManager::default());
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let tasks2 = Arc::clone(&tasks);
+        let finished2 = Arc::clone(&finished);
+        tasks.spawn("graceful", async move {
+            tasks2.shutdown_requested().await;
+            // Work that must not be cut off
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            finished2.store(true, Ordering::SeqCst);
+        });
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        tasks.spawn("stuck", async move {
+            let _tx = tx;
+            std::future::pending::<()>().await;
+        });
+
+        tasks.shutdown(Duration::from_secs(1)).await;
+        assert!(finished.load(Ordering::SeqCst));
+
+        // The stuck task was aborted, which drops its sender
+        assert!(rx.await.is_err());
+
+        // Waiting after the shutdown returns immediately
+        tasks.shutdown_requested().await;
+    }
+}
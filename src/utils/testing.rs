@@ -0,0 +1,199 @@
+//! Helpers for tests that need the services of a running server.
+
+use std::sync::{mpsc, Arc, Once};
+
+use ruma::{
+    events::{
+        room::{
+            create::RoomCreateEventContent,
+            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            member::{MembershipState, RoomMemberEventContent},
+            message::RoomMessageEventContent,
+            power_levels::RoomPowerLevelsEventContent,
+        },
+        TimelineEventType,
+    },
+    EventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, value::to_raw_value};
+
+use crate::{
+    service::pdu::PduBuilder, utils, Config, KeyValueDatabase, Result, Services, SERVICES,
+};
+
+static STARTED: Once = Once::new();
+
+/// The config of the test server. Tests share one server, so they have to use their own rooms and
+/// users.
+fn config() -> Config {
+    let dir = std::env::temp_dir().join(format!("conduit-test-{}", utils::random_string(8)));
+    std::fs::create_dir(&dir).unwrap();
+
+    serde_json::from_value(json!({
+        "server_name": "example.com",
+        "database_path": dir,
+        "allow_check_for_updates": false,
+        "durable_event_types": ["org.example.durable"],
+        "max_federation_request_size": 1_000_000,
+        "smtp": {
+            "host": "localhost",
+            "from": "Conduit <conduit@example.com>",
+            "base_url": "https://matrix.example.com",
+        },
+    }))
+    .unwrap()
+}
+
+/// Starts the services once per test binary, backed by SQLite in a temporary directory. The
+/// background tasks of the server run on their own runtime, which outlives the tests.
+pub fn services() -> &'static Services {
+    STARTED.call_once(|| {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    tx.send(KeyValueDatabase::load_or_create(config()).await)
+                        .unwrap();
+                    std::future::pending::<()>().await
+                })
+        });
+        rx.recv().unwrap().unwrap();
+    });
+
+    SERVICES.read().unwrap().expect("services were started")
+}
+
+/// Builds an unsigned event from `fields`, with placeholders for the fields that are not given. The
+/// result can be deserialized as a `PduEvent` or a `CanonicalJsonObject`.
+pub fn pdu<T: DeserializeOwned>(fields: serde_json::Value) -> T {
+    let mut pdu = json!({
+        "event_id": "$event:example.com",
+        "room_id": "!room:example.com",
+        "sender": "@alice:example.com",
+        "origin_server_ts": 1,
+        "type": "m.room.message",
+        "content": {},
+        "prev_events": [],
+        "depth": 1,
+        "auth_events": [],
+        "hashes": { "sha256": "" },
+    });
+    match (pdu.as_object_mut(), fields) {
+        (Some(pdu), serde_json::Value::Object(fields)) => pdu.extend(fields),
+        _ => panic!("PDU fields have to be an object"),
+    }
+    serde_json::from_value(pdu).unwrap()
+}
+
+/// The config of the shared server, changed by `configure`. Services built with it on the database
+/// of [`services`] only differ from the shared ones in their config.
+pub fn config_with(configure: impl FnOnce(&mut Config)) -> Config {
+    let mut config = services().globals.config.clone();
+    configure(&mut config);
+    config
+}
+
+/// Creates a local user with a random name.
+pub fn create_user() -> OwnedUserId {
+    let user_id = UserId::parse_with_server_name(
+        utils::random_string(8).to_lowercase(),
+        services().globals.server_name(),
+    )
+    .unwrap();
+    services().users.create(&user_id, None).unwrap();
+    user_id
+}
+
+/// Sends an event the way local clients do.
+pub async fn send(
+    room_id: &RoomId,
+    sender: &UserId,
+    event_type: TimelineEventType,
+    content: serde_json::Value,
+    state_key: Option<&str>,
+) -> Result<Arc<EventId>> {
+    let mutex_state = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    services().rooms.timeline.build_and_append_pdu(
+        PduBuilder {
+            event_type,
+            content: to_raw_value(&content).unwrap(),
+            unsigned: None,
+            state_key: state_key.map(ToOwned::to_owned),
+            redacts: None,
+        },
+        sender,
+        room_id,
+        &state_lock,
+    )
+}
+
+/// Sends a text message.
+pub async fn send_message(room_id: &RoomId, sender: &UserId, body: &str) -> Arc<EventId> {
+    send(
+        room_id,
+        sender,
+        TimelineEventType::RoomMessage,
+        serde_json::to_value(RoomMessageEventContent::text_plain(body)).unwrap(),
+        None,
+    )
+    .await
+    .unwrap()
+}
+
+/// Creates a public room that `creator` joined and has power level 100 in.
+pub async fn create_room(creator: &UserId) -> OwnedRoomId {
+    let room_id = RoomId::new(services().globals.server_name());
+    services()
+        .rooms
+        .short
+        .get_or_create_shortroomid(&room_id)
+        .unwrap();
+
+    let mut create = RoomCreateEventContent::new(creator.to_owned());
+    create.room_version = services().globals.default_room_version();
+    let mut power_levels = RoomPowerLevelsEventContent::default();
+    power_levels.users.insert(creator.to_owned(), 100.into());
+
+    for (event_type, content, state_key) in [
+        (
+            TimelineEventType::RoomCreate,
+            serde_json::to_value(create).unwrap(),
+            "",
+        ),
+        (
+            TimelineEventType::RoomMember,
+            serde_json::to_value(RoomMemberEventContent::new(MembershipState::Join)).unwrap(),
+            creator.as_str(),
+        ),
+        (
+            TimelineEventType::RoomPowerLevels,
+            serde_json::to_value(power_levels).unwrap(),
+            "",
+        ),
+        (
+            TimelineEventType::RoomJoinRules,
+            serde_json::to_value(RoomJoinRulesEventContent::new(JoinRule::Public)).unwrap(),
+            "",
+        ),
+    ] {
+        send(&room_id, creator, event_type, content, Some(state_key))
+            .await
+            .unwrap();
+    }
+
+    room_id
+}